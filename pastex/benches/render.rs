@@ -0,0 +1,29 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pastex::{document::process_stream, output::html::render_fragment};
+
+/// A long prose document, made of `paragraphs` paragraphs of a few sentences.
+fn prose(paragraphs: usize, suffix: &str) -> String {
+    let paragraph =
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor \
+        incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud \
+        exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat & co.";
+
+    (0..paragraphs)
+        .map(|_| format!("{}{}\n\n", paragraph, suffix))
+        .collect()
+}
+
+fn render(c: &mut Criterion) {
+    // Paragraphs ending with a formatted span don't get the text-only fast path, which gives a
+    // point of comparison on similar content.
+    for (name, suffix) in [("text only", ""), ("with spans", r" \strong{End}")] {
+        let outline = process_stream(pastex_parser::parse(&prose(2000, suffix)).unwrap()).outline;
+
+        c.bench_function(&format!("render prose ({})", name), |b| {
+            b.iter(|| render_fragment(black_box(&outline)))
+        });
+    }
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -1,8 +1,132 @@
-use pastex::{document, output::html};
-use std::io::{self, Read};
+use pastex::{diagnostics::Code, document, lint, output::html, EngineOptions};
+use pastex_parser::Location;
+use serde_json::json;
+use std::io::{self, Read, Write};
+
+const USAGE: &str =
+    "usage: pastex [--drafts] [--release] [--slides] [--strict] [--diagnostics=json] < input.pastex
+       pastex check [--todos] [--lint] [--strict] [--diagnostics=json] < input.pastex
+       pastex explain [CODE]
+       pastex grammar > pastex.tmLanguage.json";
+
+/// Name given to the input in diagnostics, as it is read from the standard input.
+const INPUT_NAME: &str = "<stdin>";
+
+/// Finds the ` at line:column` the engine appends to diagnostics, see `Context::location`.
+fn message_location(message: &str) -> Option<(usize, usize)> {
+    message.match_indices(" at ").find_map(|(pos, _)| {
+        let rest = &message[pos + 4..];
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(rest.len());
+        let (line, column) = rest[..end].split_once(':')?;
+        Some((line.parse().ok()?, column.parse().ok()?))
+    })
+}
+
+/// Writes a diagnostic as a JSON line on the standard error.
+fn json_diagnostic(severity: &str, code: &str, message: &str, location: Option<(usize, usize)>) {
+    let range = location.map(|(line, column)| {
+        json!({
+            "start": { "line": line, "column": column },
+            "end": { "line": line, "column": column },
+        })
+    });
+    let diagnostic = json!({
+        "severity": severity,
+        "code": code,
+        "message": message,
+        "file": INPUT_NAME,
+        "range": range,
+    });
+
+    writeln!(io::stderr().lock(), "{}", diagnostic).ok();
+}
+
+/// Splits the `[PX0001]` code written before diagnostic messages from the message.
+fn message_code(message: &str) -> Option<(&str, &str)> {
+    let (code, message) = message.strip_prefix('[')?.split_once("] ")?;
+    Code::lookup(code).map(|_| (code, message))
+}
+
+/// Logger writing warnings and errors as JSON lines, for `--diagnostics=json`.
+struct JsonLogger;
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let severity = match record.level() {
+            log::Level::Error => "error",
+            _ => "warning",
+        };
+        let message = record.args().to_string();
+        let (code, text) = message_code(&message).unwrap_or((record.target(), &message));
+        json_diagnostic(severity, code, text, message_location(text));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Shows what a diagnostic code means, or lists them all.
+fn explain(args: &[String]) -> anyhow::Result<()> {
+    match args {
+        [] => {
+            for code in Code::ALL {
+                println!("{} {}", code, code.name());
+            }
+        }
+        [code] => {
+            let code = Code::lookup(code)
+                .ok_or_else(|| anyhow::format_err!("Unknown diagnostic code {}", code))?;
+            println!("{} {}\n\n{}", code, code.name(), code.explanation());
+        }
+        _ => anyhow::bail!("explain takes a single code\n{}", USAGE),
+    }
+
+    Ok(())
+}
 
 fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (check, flags) = match args.split_first() {
+        Some((command, args)) if command == "explain" => return explain(args),
+        Some((command, _)) if command == "grammar" => {
+            let grammar = pastex_parser::textmate_grammar(&Default::default());
+            return Ok(io::stdout().lock().write_all(grammar.as_bytes())?);
+        }
+        Some((command, flags)) if command == "check" => (true, flags),
+        _ => (false, &args[..]),
+    };
+    let flag = |name: &str| flags.iter().any(|arg| arg == name);
+    let known = [
+        "--drafts",
+        "--release",
+        "--slides",
+        "--todos",
+        "--lint",
+        "--strict",
+        "--diagnostics=json",
+        "--diagnostics=text",
+    ];
+
+    if let Some(unknown) = flags.iter().find(|arg| !known.contains(&arg.as_str())) {
+        anyhow::bail!("Unknown argument {}\n{}", unknown, USAGE);
+    }
+
+    let json_diagnostics = flag("--diagnostics=json");
+    if json_diagnostics {
+        log::set_boxed_logger(Box::new(JsonLogger))?;
+        log::set_max_level(log::LevelFilter::Warn);
+    } else {
+        pretty_env_logger::init();
+    }
 
     let buffer = {
         let mut buffer = String::new();
@@ -10,9 +134,56 @@ fn main() -> anyhow::Result<()> {
         buffer
     };
 
-    pastex_parser::parse(&buffer)
-        .map_err(|err| anyhow::format_err!("Parser error: {:?}", err))
-        .map(document::process_stream)
-        .map(|document| html::output_document(&document))
-        .map(|output| println!("{}", output))
+    let mut options = EngineOptions::default();
+    options.release(flag("--release"));
+    options.strict(flag("--strict"));
+
+    let document = match document::process_source(&buffer, &options) {
+        Ok(document) => document,
+        Err(err) if json_diagnostics => {
+            let location = Location::at(&buffer, err.offset(&buffer));
+            json_diagnostic(
+                "error",
+                &err.code().to_string(),
+                &err.to_string(),
+                Some((location.line, location.column)),
+            );
+            std::process::exit(1);
+        }
+        Err(err @ document::ProcessError::Parse(_)) => {
+            anyhow::bail!("[{}] Parser error: {:?}", err.code(), err)
+        }
+        Err(err) => {
+            let location = Location::at(&buffer, err.offset(&buffer));
+            anyhow::bail!("[{}] {} at {}", err.code(), err, location)
+        }
+    };
+
+    if check {
+        if flag("--lint") {
+            lint::report(&lint::Linter::default().run(&document, Some(&buffer)));
+        }
+        if flag("--todos") {
+            for todo in &document.todos {
+                match todo.location {
+                    Some(location) => println!("{}: TODO {}", location, todo.text),
+                    None => println!("TODO {}", todo.text),
+                }
+            }
+        }
+    } else if document.metadata.draft && !flag("--drafts") {
+        eprintln!("Skipping draft document, use --drafts to render it");
+    } else {
+        let html_options = html::HtmlOptions::default();
+        if flag("--slides") {
+            println!(
+                "{}",
+                html::output_slides_document_with(&document, &html_options)
+            );
+        } else {
+            println!("{}", html::output_document_with(&document, &html_options));
+        }
+    }
+
+    Ok(())
 }
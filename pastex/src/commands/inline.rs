@@ -1,41 +1,294 @@
 use crate::{
-    document::{Span, SpanFormat},
-    engine::{self, TextProcessor},
+    diagnostics::diagnostic,
+    document::{metadata::Date, plain_text, Link, Span, SpanFormat, Todo},
+    engine::{self, Context, TextProcessor},
+    locale,
 };
-use pastex_parser::{ParamValue, Params, Stream};
+use pastex_parser::{Element, Location, ParamValue, Params, ParamsExt, Stream};
 
-pub type Command = Box<dyn Fn(Stream, &Params, bool) -> Vec<Span> + Send + Sync>;
+pub type Command = Box<dyn Fn(&mut Context, Stream, &Params, bool) -> Vec<Span> + Send + Sync>;
 
-pub fn code(content: Stream, _: &Params, _: bool) -> Vec<Span> {
-    let inner = engine::PreserveTextProcessor::process_all(content);
+pub fn code(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::PreserveTextProcessor::process_all(ctx, content);
     vec![Span::Format(SpanFormat::Code, inner)]
 }
 
-pub fn strong(content: Stream, _: &Params, _: bool) -> Vec<Span> {
-    let inner = engine::InlineTextProcessor::process_all(content);
+pub fn strong(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
     vec![Span::Format(SpanFormat::Strong, inner)]
 }
 
-pub fn link(content: Stream, params: &Params, _: bool) -> Vec<Span> {
-    let inner = engine::InlineTextProcessor::process_all(content);
-    if let Some(ParamValue::Text(to)) = params.get("to") {
-        vec![Span::Format(
-            SpanFormat::Link {
-                to: to.to_string(),
-                blank: params.contains_key("blank"),
+pub fn link(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+    let flag = |name| super::param("link", params.get_bool(name)).unwrap_or(false);
+    let text = |name| super::param("link", params.get_str(name)).map(str::to_owned);
+    // `[download]` alone keeps the name of the resource
+    let download = match params.get("download") {
+        Some(ParamValue::None) => Some(String::new()),
+        _ => text("download"),
+    };
+
+    if let Some(to) = text("to").filter(|to| !ctx.options.is_url_allowed(to)) {
+        diagnostic!(
+            UnsafeUrl,
+            r"\link to {} is not allowed, only showing its text",
+            to
+        );
+        inner
+    } else if let Some(to) = text("to") {
+        let link = Link {
+            to,
+            blank: flag("blank"),
+            rel: text("rel"),
+            download,
+            title: text("title"),
+            nofollow: flag("nofollow"),
+        };
+        vec![Span::Format(SpanFormat::Link(link), inner)]
+    } else {
+        diagnostic!(
+            MissingParameter,
+            r"\link used without a to parameter, ignoring it"
+        );
+        inner
+    }
+}
+
+/// Marks the first letter or word of a paragraph, as `\initial{L}orem ipsum`.
+pub fn initial(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+    vec![Span::Format(SpanFormat::Initial, inner)]
+}
+
+/// Quotes inline content, with quotation marks depending on the document language and on how
+/// deeply the quote is nested.
+pub fn quote(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let (open, close) = crate::locale::quotes(ctx.metadata.lang.as_deref(), ctx.quote_depth);
+
+    ctx.quote_depth += 1;
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+    ctx.quote_depth -= 1;
+
+    vec![Span::Format(
+        SpanFormat::Quote {
+            open: open.to_owned(),
+            close: close.to_owned(),
+        },
+        inner,
+    )]
+}
+
+/// Formats the number given as content with `format`, or reports it and keeps it as is if it
+/// can't be read.
+fn formatted(
+    ctx: &mut Context,
+    command: &str,
+    content: Stream,
+    format: impl Fn(Option<&str>, &str) -> Option<String>,
+) -> Vec<Span> {
+    let text = plain_text(&engine::InlineTextProcessor::process_all(ctx, content));
+
+    match format(ctx.metadata.lang.as_deref(), text.trim()) {
+        Some(formatted) => vec![Span::Text(formatted)],
+        None => {
+            diagnostic!(
+                InvalidValue,
+                r"\{}: invalid number {:?}",
+                command,
+                text.trim()
+            );
+            vec![Span::Text(text)]
+        }
+    }
+}
+
+fn finite(text: &str) -> Option<f64> {
+    text.parse().ok().filter(|value: &f64| value.is_finite())
+}
+
+/// Formats a number with the thousands separator and decimal mark of the document language.
+pub fn num(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    formatted(ctx, "num", content, locale::number)
+}
+
+/// Formats a size in bytes with a unit, as `1.5 MB`, or `1.5 MiB` with the `binary` parameter.
+pub fn size(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<Span> {
+    let binary = super::param("size", params.get_bool("binary")).unwrap_or(false);
+    formatted(ctx, "size", content, |lang, text| {
+        Some(locale::size(lang, finite(text)?, binary))
+    })
+}
+
+/// Formats a duration given in seconds, as `4.2 ms` or `1 h 2 min`.
+pub fn duration(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    formatted(ctx, "duration", content, |lang, text| {
+        Some(locale::duration(lang, finite(text)?))
+    })
+}
+
+/// Formats a duration given in seconds as a timecode, as `1:02:05`.
+pub fn timecode(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    formatted(ctx, "timecode", content, |_, text| {
+        Some(locale::timecode(finite(text)?))
+    })
+}
+
+/// Links a term to its glossary definition, as `\gloss{API}`, or `\gloss[term = API]{APIs}`
+/// when the text differs from the term.
+pub fn gloss(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+    let text = plain_text(&inner);
+    let term = super::param("gloss", params.get_str("term")).unwrap_or_else(|| text.trim());
+
+    let entry = match ctx.options.glossary_term(term) {
+        Some(entry) => entry,
+        None => {
+            diagnostic!(UnknownTerm, "Term {:?} is not in the glossary", term);
+            return inner;
+        }
+    };
+
+    let inner = match &entry.title {
+        Some(title) => vec![Span::Format(
+            SpanFormat::Abbreviation {
+                title: title.clone(),
             },
             inner,
-        )]
+        )],
+        None => inner,
+    };
+
+    match &entry.href {
+        Some(to) => {
+            let link = Link {
+                to: to.clone(),
+                ..Default::default()
+            };
+            vec![Span::Format(SpanFormat::Link(link), inner)]
+        }
+        None => inner,
+    }
+}
+
+/// Leaves a note to the author, which is highlighted, or removed in release builds. All notes are
+/// listed in [`Document::todos`](crate::document::Document::todos).
+pub fn todo(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let location = ctx.source.and_then(|source| {
+        content.iter().find_map(|el| match el {
+            Element::Raw(text) => Location::of(source, text),
+            _ => None,
+        })
+    });
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+
+    ctx.todos.push(Todo {
+        text: plain_text(&inner).trim().to_owned(),
+        location,
+    });
+
+    if ctx.options.is_release() {
+        Vec::new()
     } else {
-        panic!(r"\link without to");
+        vec![Span::Format(SpanFormat::Todo, inner)]
+    }
+}
+
+/// Inlines an icon from the icon directory of the engine, as `\icon{home}`. Icons are decorative
+/// unless given a `title`.
+pub fn icon(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<Span> {
+    let text = plain_text(&engine::InlineTextProcessor::process_all(ctx, content));
+    let name = text.trim();
+    let title = super::param("icon", params.get_str("title")).map(str::to_owned);
+
+    let directory = match ctx.options.icon_directory() {
+        Some(directory) => directory,
+        None => {
+            diagnostic!(
+                Icon,
+                r"\icon: no icon directory is set, skipping {:?}",
+                name
+            );
+            return Vec::new();
+        }
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        diagnostic!(Icon, r"\icon: invalid icon name {:?}", name);
+        return Vec::new();
+    }
+
+    let path = directory.join(format!("{}.svg", name));
+    let svg = match std::fs::read_to_string(&path) {
+        Ok(svg) => svg,
+        Err(err) => {
+            diagnostic!(Icon, r"\icon: can't read {}: {}", path.display(), err);
+            return Vec::new();
+        }
+    };
+
+    match crate::svg::sanitize(&svg) {
+        Some(svg) => vec![Span::Format(
+            SpanFormat::Icon { title },
+            vec![Span::Raw(svg)],
+        )],
+        None => {
+            diagnostic!(Icon, r"\icon: {} is not a valid SVG file", path.display());
+            Vec::new()
+        }
     }
 }
 
-pub fn raw(content: Stream, _: &Params, _: bool) -> Vec<Span> {
-    let inner = engine::PreserveTextProcessor::process_all(content);
+/// Math, kept as written for the output to render it, as `\math{x^2}`.
+pub fn math(_: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    vec![Span::Math(engine::source_text(&content).trim().to_owned())]
+}
+
+pub fn raw(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let inner = engine::PreserveTextProcessor::process_all(ctx, content);
     match inner.into_iter().next() {
+        Some(Span::Text(span)) if !ctx.options.is_raw_allowed() => {
+            diagnostic!(
+                RawDisabled,
+                r"\raw is disabled, its content is shown as text"
+            );
+            vec![Span::Text(span)]
+        }
         Some(Span::Text(span)) => vec![Span::Raw(span)],
         None => Vec::new(),
         _ => unreachable!(),
     }
 }
+
+/// Renders a date given as `YYYY-MM-DD`, or the document date when called without content.
+pub fn date(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<Span> {
+    let date = if content.is_empty() {
+        ctx.metadata.date
+    } else {
+        let text = plain_text(&engine::InlineTextProcessor::process_all(ctx, content));
+        let date = Date::parse(&text);
+
+        if date.is_none() {
+            diagnostic!(
+                InvalidValue,
+                "Invalid date {:?}, expected YYYY-MM-DD",
+                text.trim()
+            );
+            return vec![Span::Text(text)];
+        }
+        date
+    };
+
+    match date {
+        Some(date) => vec![date.to_span()],
+        None => {
+            diagnostic!(
+                MissingDate,
+                r"\date used without content in a document without date"
+            );
+            Vec::new()
+        }
+    }
+}
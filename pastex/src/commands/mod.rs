@@ -1,12 +1,11 @@
 use crate::{
-    document::{
-        metadata::{Field, Metadata},
-        BlockFormat, Span,
-    },
-    engine::RootSpan,
+    diagnostics::diagnostic,
+    document::{metadata::Field, Block, BlockFormat, Span},
+    engine::{Context, RootSpan},
+    trace,
 };
-use log::warn;
 use once_cell::sync::Lazy;
+use pastex_parser::{Element, Location, Params, Stream};
 use std::collections::HashMap;
 
 type CommandName<'a> = (&'a str, Option<&'a str>);
@@ -17,22 +16,108 @@ mod toplevel;
 macro_rules! meta_impl {
     ($name:ident) => {
         Box::new(toplevel::meta(
-            stringify!(name),
+            stringify!($name),
             |m| &m.$name,
-            |m, v| m.$name = Field::from(&v),
+            |m, v| m.$name = Field::from(v),
         ))
     };
 }
 
+/// Syntax forms a command accepts, declared when registering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Form {
+    /// Only the `\foo{...}` form
+    Inline,
+    /// Only the `\begin{foo} ... \end{foo}` form
+    Block,
+    /// Both forms
+    Both,
+}
+
+impl Form {
+    fn allows(self, block: bool) -> bool {
+        match self {
+            Form::Inline => !block,
+            Form::Block => block,
+            Form::Both => true,
+        }
+    }
+}
+
+/// Content a command accepts, declared when registering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Content {
+    /// Text and inline commands
+    Inline,
+    /// Anything a document can contain, including block commands
+    Blocks,
+    /// Text only, without any command
+    Raw,
+    /// Nothing, the command is used as `\foo` or `\foo{}`
+    Empty,
+    /// Source text for another language, like math, which may look like commands
+    Source,
+}
+
+/// How a command can be called, declared when registering it in a [`CommandRegistry`]. Calls
+/// which don't match it are reported, but the command still runs.
+#[derive(Debug, Clone, Copy)]
+pub struct Signature {
+    pub form: Form,
+    pub content: Content,
+    /// Names of the parameters the command accepts
+    pub params: &'static [&'static str],
+}
+
+impl Default for Signature {
+    /// An inline command with inline content and no parameters, like `\strong`.
+    fn default() -> Self {
+        Signature {
+            form: Form::Inline,
+            content: Content::Inline,
+            params: &[],
+        }
+    }
+}
+
+struct Entry<C> {
+    form: Form,
+    content: Content,
+    /// Names of the parameters the command accepts
+    params: &'static [&'static str],
+    run: C,
+}
+
+impl<C> Entry<C> {
+    fn new(signature: Signature, run: C) -> Self {
+        Entry {
+            form: signature.form,
+            content: signature.content,
+            params: signature.params,
+            run,
+        }
+    }
+}
+
 macro_rules! commands_impl {
-    ($hm:ident, $name:expr => $f:expr, $($r:tt)*) => {
+    ($hm:ident, $form:ident, $content:ident: $name:literal $([$($param:literal),*])? => $f:expr, $($r:tt)*) => {
         log::debug!("Registering command {}", $name);
-        $hm.insert(($name, None), Box::new($f));
+        $hm.insert(($name, None), Entry {
+            form: Form::$form,
+            content: Content::$content,
+            params: &[$($($param),*)?],
+            run: Box::new($f),
+        });
         commands_impl!($hm, $($r)*);
     };
-    ($hm:ident, $ns:expr, $name:expr => $f:expr, $($r:tt)*) => {
+    ($hm:ident, $form:ident, $content:ident: $ns:literal, $name:literal $([$($param:literal),*])? => $f:expr, $($r:tt)*) => {
         log::debug!("Registering command {}:{}", $ns, $name);
-        $hm.insert(($name, Some($ns)), Box::new($f));
+        $hm.insert(($name, Some($ns)), Entry {
+            form: Form::$form,
+            content: Content::$content,
+            params: &[$($($param),*)?],
+            run: Box::new($f),
+        });
         commands_impl!($hm, $($r)*);
     };
     ($hm:ident,) => {};
@@ -40,66 +125,393 @@ macro_rules! commands_impl {
 
 macro_rules! commands {
     ($hive:ident of $type:ty { $($r:tt)* }) => {
-        static $hive: Lazy<HashMap<CommandName<'static>, $type>> = Lazy::new(|| {
-            let mut hm = HashMap::<_, $type>::new();
+        fn $hive() -> HashMap<CommandName<'static>, Entry<$type>> {
+            let mut hm = HashMap::<_, Entry<$type>>::new();
             commands_impl!(hm, $($r)*);
             hm
-        });
+        }
     };
 }
 
-commands!(COMMANDS of inline::Command {
-    "code" => inline::code,
-    "strong" => inline::strong,
-    "link" => inline::link,
-    "raw" => inline::raw,
+commands!(builtin_commands of inline::Command {
+    Both, Raw: "code" => inline::code,
+    Inline, Raw: "verb" => inline::code,
+    Inline, Inline: "strong" => inline::strong,
+    Inline, Inline: "link" ["to", "blank", "rel", "download", "title", "nofollow"] => inline::link,
+    Both, Raw: "raw" => inline::raw,
+    Inline, Raw: "date" => inline::date,
+    Inline, Inline: "initial" => inline::initial,
+    Inline, Inline: "q" => inline::quote,
+    Inline, Inline: "gloss" ["term"] => inline::gloss,
+    Inline, Inline: "todo" => inline::todo,
+    Inline, Raw: "icon" ["title"] => inline::icon,
+    Both, Source: "math" => inline::math,
+    Inline, Raw: "num" => inline::num,
+    Inline, Raw: "size" ["binary"] => inline::size,
+    Inline, Raw: "duration" => inline::duration,
+    Inline, Raw: "timecode" => inline::timecode,
 });
 
-commands!(TOPLEVEL_COMMANDS of toplevel::Command {
-    "code" => toplevel::code,
-    "head1" => toplevel::header::<1>,
-    "head2" => toplevel::header::<2>,
-    "head3" => toplevel::header::<3>,
-    "abstract" => toplevel::r#abstract,
-    "meta", "title" => meta_impl!(title),
-    "meta", "author" => meta_impl!(author),
-    "meta", "date" => meta_impl!(date),
-    "meta", "tags" => meta_impl!(keywords),
-    "meta", "draft" => meta_impl!(draft),
+commands!(builtin_toplevel_commands of toplevel::Command {
+    Both, Raw: "code" ["dir", "lang", "run"] => toplevel::code,
+    Both, Source: "math" => toplevel::math,
+    Block, Raw: "verbatim" ["dir"] => toplevel::verbatim,
+    Inline, Inline: "head1" ["dir", "id", "subtitle"] => toplevel::header::<1>,
+    Inline, Inline: "head2" ["dir", "id", "subtitle"] => toplevel::header::<2>,
+    Inline, Inline: "head3" ["dir", "id", "subtitle"] => toplevel::header::<3>,
+    Inline, Inline: "head4" ["dir", "id", "subtitle"] => toplevel::header::<4>,
+    Inline, Inline: "head5" ["dir", "id", "subtitle"] => toplevel::header::<5>,
+    Block, Blocks: "abstract" => toplevel::r#abstract,
+    Block, Blocks: "blockquote" ["cite", "dir"] => toplevel::blockquote,
+    Block, Blocks: "itemize" ["dir"] => toplevel::list::<false>,
+    Block, Blocks: "enumerate" ["dir"] => toplevel::list::<true>,
+    Inline, Raw: "fragment" => toplevel::fragment,
+    Inline, Inline: "meta", "title" => meta_impl!(title),
+    Inline, Inline: "meta", "author" => meta_impl!(author),
+    Inline, Raw: "meta", "date" => meta_impl!(date),
+    Inline, Raw: "meta", "tags" => meta_impl!(keywords),
+    Inline, Empty: "meta", "draft" => meta_impl!(draft),
+    Inline, Raw: "meta", "lang" => meta_impl!(lang),
+    Inline, Empty: "meta", "dir" ["ltr", "rtl", "auto"] => toplevel::dir,
 });
 
-pub fn toplevel_run(metadata: &mut Metadata, cmd: pastex_parser::Command) -> Vec<RootSpan> {
-    let name = (cmd.name, cmd.namespace);
+static BUILTIN: Lazy<CommandRegistry> = Lazy::new(CommandRegistry::new);
+
+/// The commands documents can call, set with
+/// [`EngineOptions::commands`](crate::EngineOptions::commands).
+///
+/// Inline commands give spans, and can be used anywhere. Top-level commands give whole blocks,
+/// like headings, so they can only be used at the top of documents, and take precedence there
+/// over inline commands with the same name. Names are written with their namespace, if any, as
+/// `meta:title`.
+///
+/// ```
+/// use pastex::document::{plain_text, process_source, Span};
+/// use pastex::{CommandRegistry, EngineOptions, Signature};
+///
+/// let mut registry = CommandRegistry::new();
+/// registry.add_inline("shout", Signature::default(), |ctx, content, _, _| {
+///     let text = plain_text(&ctx.process_inline(content));
+///     vec![Span::Text(text.to_uppercase())]
+/// });
+///
+/// let mut options = EngineOptions::default();
+/// options.commands(registry);
+/// let document = process_source(r"Hello \shout{world}!", &options).unwrap();
+/// assert_eq!(plain_text(&document.outline[0].1), "Hello WORLD!");
+/// ```
+pub struct CommandRegistry {
+    inline: HashMap<CommandName<'static>, Entry<inline::Command>>,
+    toplevel: HashMap<CommandName<'static>, Entry<toplevel::Command>>,
+}
+
+/// Splits the namespace from a command name, as `meta:title`.
+fn split_name(name: &str) -> CommandName<'_> {
+    match name.split_once(':') {
+        Some((namespace, name)) => (name, Some(namespace)),
+        None => (name, None),
+    }
+}
+
+impl CommandRegistry {
+    /// A registry of the built-in commands, to extend with other ones.
+    pub fn new() -> Self {
+        CommandRegistry {
+            inline: builtin_commands(),
+            toplevel: builtin_toplevel_commands(),
+        }
+    }
+
+    /// A registry without any command, not even the built-in ones.
+    pub fn empty() -> Self {
+        CommandRegistry {
+            inline: HashMap::new(),
+            toplevel: HashMap::new(),
+        }
+    }
 
-    if let Some(c) = TOPLEVEL_COMMANDS.get(&name) {
-        c(metadata, cmd.content, cmd.block)
-    } else if let Some(c) = COMMANDS.get(&name) {
-        c(cmd.content, &cmd.params, cmd.block)
-            .into_iter()
-            .map(Into::into)
-            .collect()
+    /// The built-in commands, used when no other registry is set.
+    pub fn builtin() -> &'static CommandRegistry {
+        &BUILTIN
+    }
+
+    /// Adds an inline command, replacing any inline command with the same name.
+    pub fn add_inline(
+        &mut self,
+        name: &'static str,
+        signature: Signature,
+        run: impl Fn(&mut Context, Stream, &Params, bool) -> Vec<Span> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.inline
+            .insert(split_name(name), Entry::new(signature, Box::new(run)));
+        self
+    }
+
+    /// Adds a top-level command, replacing any top-level command with the same name.
+    pub fn add_toplevel(
+        &mut self,
+        name: &'static str,
+        signature: Signature,
+        run: impl Fn(&mut Context, Stream, &Params, bool) -> Vec<RootSpan> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.toplevel
+            .insert(split_name(name), Entry::new(signature, Box::new(run)));
+        self
+    }
+
+    /// Removes both the inline and top-level commands with this name. Calls to them are then
+    /// treated like unknown commands.
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        let name = split_name(name);
+        self.inline.retain(|key, _| *key != name);
+        self.toplevel.retain(|key, _| *key != name);
+        self
+    }
+
+    /// Checks if a command with this name can be called, inline or at the top level.
+    pub fn contains(&self, name: &str) -> bool {
+        let name = split_name(name);
+        self.inline.contains_key(&name) || self.toplevel.contains_key(&name)
+    }
+
+    fn is_block_command(&self, cmd: &pastex_parser::Command) -> bool {
+        let name = (cmd.name, cmd.namespace);
+        self.toplevel.contains_key(&name) && !self.inline.contains_key(&name)
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        CommandRegistry::new()
+    }
+}
+
+/// Number of single-character edits to go from `a` to `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Warns about parameters the command did not declare, suggesting a close known one.
+fn check_params<C>(entry: &Entry<C>, cmd: &pastex_parser::Command, ctx: &Context) {
+    let mut unknown: Vec<&str> = cmd
+        .params
+        .keys()
+        .copied()
+        .filter(|key| !entry.params.contains(key))
+        .collect();
+    unknown.sort_unstable();
+
+    for key in unknown {
+        let suggestion = entry
+            .params
+            .iter()
+            .map(|known| (edit_distance(key, known), known))
+            .filter(|&(distance, _)| distance <= 2)
+            .min();
+
+        match suggestion {
+            Some((_, known)) => diagnostic!(
+                UnknownParameter,
+                r"Unknown parameter {} for \{}{}, did you mean {}?",
+                key,
+                cmd.command_name(),
+                ctx.location(key),
+                known
+            ),
+            None => diagnostic!(
+                UnknownParameter,
+                r"Unknown parameter {} for \{}{}",
+                key,
+                cmd.command_name(),
+                ctx.location(key)
+            ),
+        }
+    }
+}
+
+/// Checks that the command is called in a form and with content and parameters it declared
+/// supporting.
+fn check_usage<C>(entry: &Entry<C>, cmd: &pastex_parser::Command, ctx: &Context) {
+    check_params(entry, cmd, ctx);
+
+    let name = cmd.command_name();
+
+    if !entry.form.allows(cmd.block) {
+        if cmd.block {
+            diagnostic!(
+                WrongForm,
+                r"\begin{{{}}} used, but {} has no block form, use \{}{{...}} instead",
+                name,
+                name,
+                name
+            );
+        } else {
+            diagnostic!(
+                WrongForm,
+                r"\{} used inline, but it only has a block form, use \begin{{{}}} instead",
+                name,
+                name
+            );
+        }
+    }
+
+    if !cmd.arguments.is_empty() {
+        diagnostic!(
+            ExtraArguments,
+            r"\{} takes a single {{...}} group, ignoring the {} following it",
+            name,
+            cmd.arguments.len()
+        );
+    }
+
+    let mut children = cmd.content.iter().filter(|el| match el {
+        Element::Raw(t) => !t.trim().is_empty(),
+        Element::Comment(_) => false,
+        _ => true,
+    });
+
+    match entry.content {
+        // Block commands inside inline content are reported when dispatched, see `run`
+        Content::Blocks | Content::Inline | Content::Source => (),
+        Content::Raw => {
+            for el in children {
+                if let Element::Command(inner) = el {
+                    diagnostic!(
+                        UnexpectedCommand,
+                        r"\{} expects raw text, but contains a call to \{}",
+                        name,
+                        inner.command_name()
+                    );
+                }
+            }
+        }
+        Content::Empty => {
+            if children.next().is_some() {
+                diagnostic!(
+                    UnexpectedContent,
+                    r"\{} takes no content, ignoring it",
+                    name
+                );
+            }
+        }
+    }
+}
+
+/// Reports a parameter with an unexpected value, which is then treated as missing.
+fn param<T>(command: &str, value: Result<Option<T>, pastex_parser::ParamError>) -> Option<T> {
+    value.unwrap_or_else(|e| {
+        diagnostic!(InvalidParameter, r"\{}: {}", command, e);
+        None
+    })
+}
+
+fn unknown(cmd: &pastex_parser::Command, ctx: &mut Context) -> Span {
+    if ctx.options.is_strict() && ctx.unknown_command.is_none() {
+        let span = ctx
+            .source
+            .and_then(|source| Location::of(source, cmd.source))
+            .map(|location| location.offset..location.offset + cmd.source.len());
+        ctx.unknown_command = Some((cmd.command_name().to_string(), span));
+    }
+
+    if ctx.options.is_namespace_enabled(cmd.namespace) {
+        diagnostic!(UnknownCommand, "Unknown command: {}", cmd.command_name());
     } else {
-        warn!("Unknown command: {}", cmd.command_name());
+        diagnostic!(
+            DisabledNamespace,
+            "Command {} belongs to the disabled namespace {}",
+            cmd.command_name(),
+            cmd.namespace.unwrap_or_default()
+        );
+    }
+
+    Span::Text(format!("[[unknown command {}]]", cmd.command_name()))
+}
 
-        let span = Span::Text(format!("[[unknown command {}]]", cmd.command_name()));
+/// The content of a [`Content::Source`] command as written, escapes included, as the parsed
+/// content resolves `\\` to `\`. [`None`] for other commands, which run with the parsed content.
+fn content<'b, C>(entry: &Entry<C>, cmd: &pastex_parser::Command<'b>) -> Option<Stream<'b>> {
+    let source = cmd
+        .content_source
+        .filter(|_| entry.content == Content::Source)?;
+    Some(vec![Element::Raw(source)])
+}
+
+pub fn toplevel_run(ctx: &mut Context, cmd: pastex_parser::Command) -> Vec<RootSpan> {
+    let name = (cmd.name, cmd.namespace);
+    ctx.command_usage.record(cmd.command_name());
+    let enabled = ctx.options.is_namespace_enabled(cmd.namespace);
+    let registry = ctx.options.command_registry();
+
+    if let Some(c) = registry.toplevel.get(&name).filter(|_| enabled) {
+        check_usage(c, &cmd, ctx);
+        let content = content(c, &cmd).unwrap_or(cmd.content);
+        trace::command(cmd.command_name(), || {
+            (c.run)(ctx, content, &cmd.params, cmd.block)
+        })
+    } else if let Some(c) = registry.inline.get(&name).filter(|_| enabled) {
+        check_usage(c, &cmd, ctx);
+        let content = content(c, &cmd).unwrap_or(cmd.content);
+        trace::command(cmd.command_name(), || {
+            (c.run)(ctx, content, &cmd.params, cmd.block)
+        })
+        .into_iter()
+        .map(Into::into)
+        .collect()
+    } else {
+        let span = unknown(&cmd, ctx);
         if cmd.block {
-            vec![RootSpan::Block(BlockFormat::Paragraph, vec![span])]
+            vec![RootSpan::Block(Block::new(
+                BlockFormat::Paragraph,
+                vec![span],
+            ))]
         } else {
             vec![span.into()]
         }
     }
 }
 
-pub fn run(cmd: pastex_parser::Command) -> Vec<Span> {
+pub fn run(ctx: &mut Context, cmd: pastex_parser::Command) -> Vec<Span> {
     let name = (cmd.name, cmd.namespace);
+    ctx.command_usage.record(cmd.command_name());
+    let enabled = ctx.options.is_namespace_enabled(cmd.namespace);
+    let registry = ctx.options.command_registry();
 
-    if let Some(c) = COMMANDS.get(&name) {
-        c(cmd.content, &cmd.params, cmd.block)
-    } else {
-        warn!("Unknown command: {}", cmd.command_name());
+    if let Some(c) = registry.inline.get(&name).filter(|_| enabled) {
+        check_usage(c, &cmd, ctx);
+        let content = content(c, &cmd).unwrap_or(cmd.content);
+        trace::command(cmd.command_name(), || {
+            (c.run)(ctx, content, &cmd.params, cmd.block)
+        })
+    } else if enabled && registry.is_block_command(&cmd) {
+        diagnostic!(
+            MisplacedCommand,
+            r"\{} is a block command and can't be used in inline content",
+            cmd.command_name()
+        );
         vec![Span::Text(format!(
-            "[[unknown command {}]]",
+            "[[misplaced command {}]]",
             cmd.command_name()
         ))]
+    } else {
+        vec![unknown(&cmd, ctx)]
     }
 }
@@ -1,49 +1,180 @@
 use crate::{
+    diagnostics::diagnostic,
     document::{
         metadata::{Field, Metadata},
-        BlockFormat, SpanFormat,
+        plain_text, Block, BlockAttributes, BlockFormat, Direction, Span, SpanFormat,
     },
-    engine::{self, root, RootSpan, TextProcessor},
+    engine::{self, root, Context, RootSpan, TextProcessor},
 };
-use log::warn;
-use pastex_parser::{Element, Stream};
+use pastex_parser::{Element, Params, ParamsExt, Stream};
+use std::iter::once;
 
-pub type Command = Box<dyn Fn(&mut Metadata, Stream, bool) -> Vec<RootSpan> + Send + Sync>;
+pub type Command = Box<dyn Fn(&mut Context, Stream, &Params, bool) -> Vec<RootSpan> + Send + Sync>;
 
-pub fn code(_: &mut Metadata, content: Stream, block: bool) -> Vec<RootSpan> {
-    let inner = engine::PreserveTextProcessor::process_all(content);
+/// Reads the attributes common to all blocks from the command parameters.
+fn attributes(command: &str, params: &Params) -> BlockAttributes {
+    let dir = super::param(command, params.get_str("dir")).and_then(|name| {
+        let dir = Direction::parse(name);
+        if dir.is_none() {
+            diagnostic!(
+                InvalidValue,
+                r"\{}: unknown direction {:?}, expected ltr, rtl or auto",
+                command,
+                name
+            );
+        }
+        dir
+    });
+
+    BlockAttributes {
+        dir,
+        ..Default::default()
+    }
+}
+
+pub fn code(ctx: &mut Context, content: Stream, params: &Params, block: bool) -> Vec<RootSpan> {
+    let inner = engine::PreserveTextProcessor::process_all(ctx, content);
 
     if block {
-        vec![RootSpan::Block(BlockFormat::Code, inner)]
+        let output = if super::param("code", params.get_bool("run")).unwrap_or(false) {
+            run_code(ctx, &inner, params)
+        } else {
+            None
+        };
+
+        let code = Block(BlockFormat::Code, inner, attributes("code", params));
+        once(code).chain(output).map(RootSpan::Block).collect()
     } else {
         vec![RootSpan::Format(SpanFormat::Code, inner)]
     }
 }
 
+/// Preformatted text, kept as written like code.
+pub fn verbatim(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<RootSpan> {
+    let inner = engine::PreserveTextProcessor::process_all(ctx, content);
+    vec![RootSpan::Block(Block(
+        BlockFormat::Code,
+        inner,
+        attributes("verbatim", params),
+    ))]
+}
+
+/// Quotes whole blocks, like paragraphs and code blocks, with the URL of their source given as
+/// `cite`.
+pub fn blockquote(ctx: &mut Context, content: Stream, params: &Params, _: bool) -> Vec<RootSpan> {
+    let cite = super::param("blockquote", params.get_str("cite")).map(str::to_owned);
+    let cite = cite.filter(|cite| {
+        let allowed = ctx.options.is_url_allowed(cite);
+        if !allowed {
+            diagnostic!(
+                UnsafeUrl,
+                "blockquote source {} is not allowed, ignoring it",
+                cite
+            );
+        }
+        allowed
+    });
+    let blocks = root(ctx, content);
+    vec![RootSpan::Block(Block(
+        BlockFormat::Quote { cite, blocks },
+        Vec::new(),
+        attributes("blockquote", params),
+    ))]
+}
+
+/// Lists items introduced by `\item`, each holding whole blocks, as bullets for
+/// `\begin{itemize}` or numbered for `\begin{enumerate}`.
+pub fn list<const ORDERED: bool>(
+    ctx: &mut Context,
+    content: Stream,
+    params: &Params,
+    _: bool,
+) -> Vec<RootSpan> {
+    let command = if ORDERED { "enumerate" } else { "itemize" };
+    let mut items: Vec<Stream> = Vec::new();
+
+    for element in content {
+        match element {
+            Element::Command(item) if item.name == "item" && item.namespace.is_none() => {
+                ctx.command_usage.record(item.command_name());
+                items.push(item.content);
+            }
+            element => match items.last_mut() {
+                Some(item) => item.push(element),
+                None => match element {
+                    Element::Comment(_) => (),
+                    Element::Raw(text) if text.trim().is_empty() => (),
+                    _ => diagnostic!(
+                        ListContent,
+                        r"\{}: content before the first \item is ignored",
+                        command
+                    ),
+                },
+            },
+        }
+    }
+
+    let items = items.into_iter().map(|item| root(ctx, item)).collect();
+    vec![RootSpan::Block(Block(
+        BlockFormat::List {
+            ordered: ORDERED,
+            items,
+        },
+        Vec::new(),
+        attributes(command, params),
+    ))]
+}
+
+/// Runs a code block with the executor of the engine, returning its output as a block.
+fn run_code(ctx: &Context, code: &[Span], params: &Params) -> Option<Block> {
+    let executor = match ctx.options.code_executor() {
+        Some(executor) => executor,
+        None => {
+            diagnostic!(
+                CodeExecution,
+                r"\code: not running the block, code execution is disabled"
+            );
+            return None;
+        }
+    };
+    let lang = super::param("code", params.get_str("lang"));
+
+    match executor.execute(lang, &plain_text(code)) {
+        Ok(output) => Some(Block::new(BlockFormat::Output, vec![Span::Text(output)])),
+        Err(message) => {
+            diagnostic!(
+                CodeExecution,
+                r"\code: running the block failed: {}",
+                message
+            );
+            None
+        }
+    }
+}
+
 fn meta_impl<T, G, S>(
-    metadata: &mut Metadata,
+    ctx: &mut Context,
     name: &'static str,
     get: G,
     set: S,
     content: Stream,
+    _: &Params,
     _: bool,
 ) -> Vec<RootSpan>
 where
     T: Field,
     G: Fn(&Metadata) -> &T,
-    S: Fn(&mut Metadata, String),
+    S: Fn(&mut Metadata, Vec<Span>),
 {
-    if get(metadata).is_set() {
-        warn!("Replacing existing metadata for {}", name);
+    if get(&ctx.metadata).is_set() {
+        diagnostic!(
+            DuplicateMetadata,
+            "Replacing existing metadata for {}",
+            name
+        );
     }
-    let content = content
-        .into_iter()
-        .map(|el| match el {
-            Element::Raw(t) => t,
-            _ => panic!("oops"),
-        })
-        .collect::<String>();
-    set(metadata, content);
+    let content = engine::normalize(engine::InlineTextProcessor::process_all(ctx, content));
+    set(&mut ctx.metadata, content);
 
     vec![]
 }
@@ -52,22 +183,82 @@ pub fn meta<T, G, S>(
     name: &'static str,
     get: G,
     set: S,
-) -> impl Fn(&mut Metadata, Stream, bool) -> Vec<RootSpan>
+) -> impl Fn(&mut Context, Stream, &Params, bool) -> Vec<RootSpan>
 where
     T: Field,
     G: Fn(&Metadata) -> &T + Copy,
-    S: Fn(&mut Metadata, String) + Copy,
+    S: Fn(&mut Metadata, Vec<Span>) + Copy,
 {
-    move |metadata, content, block| meta_impl(metadata, name, get, set, content, block)
+    move |ctx, content, params, block| meta_impl(ctx, name, get, set, content, params, block)
 }
 
-pub fn header<const LEVEL: usize>(_: &mut Metadata, content: Stream, _: bool) -> Vec<RootSpan> {
-    let inner = engine::InlineTextProcessor::process_all(content);
-    vec![RootSpan::Block(BlockFormat::Heading(LEVEL), inner)]
+/// Math, either inline or as a display block with `\begin{math}`.
+pub fn math(_: &mut Context, content: Stream, _: &Params, block: bool) -> Vec<RootSpan> {
+    let source = engine::source_text(&content).trim().to_owned();
+
+    if block {
+        vec![RootSpan::Block(Block::new(
+            BlockFormat::Math,
+            vec![Span::Text(source)],
+        ))]
+    } else {
+        vec![RootSpan::Math(source)]
+    }
+}
+
+pub fn header<const LEVEL: usize>(
+    ctx: &mut Context,
+    content: Stream,
+    params: &Params,
+    _: bool,
+) -> Vec<RootSpan> {
+    let command = format!("head{}", LEVEL);
+    let inner = engine::InlineTextProcessor::process_all(ctx, content);
+    let attributes = BlockAttributes {
+        id: super::param(&command, params.get_str("id")).map(str::to_owned),
+        subtitle: super::param(&command, params.get_str("subtitle")).map(str::to_owned),
+        ..attributes(&command, params)
+    };
+
+    vec![RootSpan::Block(Block(
+        BlockFormat::Heading(LEVEL),
+        inner,
+        attributes,
+    ))]
+}
+
+/// Sets the text direction of the document, as `\meta:dir[rtl]`.
+pub fn dir(ctx: &mut Context, _: Stream, params: &Params, _: bool) -> Vec<RootSpan> {
+    let mut names = params.keys().filter_map(|name| Direction::parse(name));
+
+    match (names.next(), names.next()) {
+        (Some(dir), None) => ctx.metadata.dir = Some(dir),
+        (Some(_), Some(_)) => diagnostic!(InvalidValue, r"\meta:dir takes a single direction"),
+        (None, _) => diagnostic!(
+            MissingParameter,
+            r"\meta:dir used without a direction, use ltr, rtl or auto"
+        ),
+    }
+
+    Vec::new()
+}
+
+/// Puts a fragment given to the engine, see [`EngineOptions::add_fragment`](crate::EngineOptions::add_fragment).
+pub fn fragment(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<RootSpan> {
+    let text = plain_text(&engine::InlineTextProcessor::process_all(ctx, content));
+    let name = text.trim();
+
+    match ctx.options.fragment(name) {
+        Some(fragment) => fragment.iter().cloned().map(RootSpan::Block).collect(),
+        None => {
+            diagnostic!(UnknownFragment, r"\fragment: unknown fragment {:?}", name);
+            Vec::new()
+        }
+    }
 }
 
-pub fn r#abstract(metadata: &mut Metadata, content: Stream, _: bool) -> Vec<RootSpan> {
-    let blocks = root(metadata, content);
-    metadata.r#abstract = Some(blocks);
+pub fn r#abstract(ctx: &mut Context, content: Stream, _: &Params, _: bool) -> Vec<RootSpan> {
+    let blocks = root(ctx, content);
+    ctx.metadata.r#abstract = Some(blocks);
     Vec::new()
 }
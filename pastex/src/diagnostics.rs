@@ -0,0 +1,377 @@
+//! Registry of the diagnostics pastex can report.
+//!
+//! Every warning has a stable [`Code`], written before its message as `[PX0001] ...`, so it can be
+//! referenced from configuration and documentation. Codes are never reused once removed.
+//!
+//! Warnings can be silenced from the source with pragma comments, taking codes or names:
+//!
+//! ```tex
+//! %!allow(unknown-command)
+//! \custom{Only this call is allowed to be unknown}
+//! %!allow-file(PX0012, heading-jump)
+//! ```
+//!
+//! `%!allow` applies to the next command, including everything inside of it, while
+//! `%!allow-file` applies to the whole document and must be at its top level.
+//!
+//! Diagnostics are logged through the `log` crate. Those reported while processing a document are
+//! also kept in [`Document::diagnostics`](crate::document::Document::diagnostics), for host
+//! applications to show or fail on.
+
+use pastex_parser::ParseError;
+use std::{cell::RefCell, fmt};
+
+macro_rules! codes {
+    ($($variant:ident = $number:literal, $name:literal: $explanation:literal;)*) => {
+        /// A kind of diagnostic, see the [module documentation](self).
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Code {
+            $(
+                #[doc = $explanation]
+                $variant,
+            )*
+        }
+
+        impl Code {
+            /// Every known code, in numeric order.
+            pub const ALL: &'static [Code] = &[$(Code::$variant),*];
+
+            /// The number of the code, as in `PX0001`.
+            pub fn number(self) -> u16 {
+                match self {
+                    $(Code::$variant => $number,)*
+                }
+            }
+
+            /// A short name for the code, like `unknown-command`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Code::$variant => $name,)*
+                }
+            }
+
+            /// What the diagnostic means and how to fix it.
+            pub fn explanation(self) -> &'static str {
+                match self {
+                    $(Code::$variant => $explanation,)*
+                }
+            }
+        }
+    };
+}
+
+codes! {
+    UnknownCommand = 1, "unknown-command":
+        "The command is not known. Check its spelling, or that the namespace it belongs to is \
+        available.";
+    DisabledNamespace = 2, "disabled-namespace":
+        "The command belongs to a namespace disabled in the engine options, and is not run.";
+    MisplacedCommand = 3, "misplaced-command":
+        "A block command, like a heading, is used in inline content, like a paragraph or another \
+        command. Move it at the top level of the document.";
+    UnknownParameter = 4, "unknown-parameter":
+        "The command does not take this parameter, which is ignored.";
+    InvalidParameter = 5, "invalid-parameter":
+        "The parameter is given in a form or with a value the command does not expect, and is \
+        treated as missing.";
+    WrongForm = 6, "wrong-form":
+        "The command is used with \\begin and \\end but only has an inline form, or the opposite.";
+    ExtraArguments = 7, "extra-arguments":
+        "The command takes a single {...} group, the ones following it are ignored.";
+    UnexpectedCommand = 8, "unexpected-command":
+        "The command expects raw text, but its content calls another command.";
+    UnexpectedContent = 9, "unexpected-content":
+        "The command takes no content, which is ignored.";
+    MissingParameter = 10, "missing-parameter":
+        "A parameter the command needs is missing, like the destination of \\link.";
+    InvalidValue = 11, "invalid-value":
+        "The content or a parameter of the command can't be read as what it expects, like a date \
+        or a number.";
+    UnknownTerm = 12, "unknown-term":
+        "The term is not in the glossary given to the engine.";
+    Icon = 13, "icon":
+        "The icon can't be loaded: no icon directory is set, the name is invalid, or the file is \
+        missing or not a valid SVG file.";
+    CodeExecution = 14, "code-execution":
+        "A code block asked to be run, but code execution is disabled or the block failed.";
+    DuplicateMetadata = 15, "duplicate-metadata":
+        "The metadata field is set more than once, only the last value is kept.";
+    HeadingJump = 16, "heading-jump":
+        "A heading skips a level, like a level 3 heading right after a level 1 one, which \
+        confuses screen reader navigation.";
+    MissingDate = 17, "missing-date":
+        "\\date is used without content in a document without \\meta:date, so there is no date to \
+        show.";
+    InvalidPragma = 18, "invalid-pragma":
+        "An %!allow or %!allow-file pragma names a diagnostic which does not exist.";
+    LongParagraph = 19, "long-paragraph":
+        "The paragraph is longer than the limit set for the long-paragraph lint. Long paragraphs \
+        are hard to follow, consider splitting them.";
+    EmptyLink = 20, "empty-link":
+        "The link has no text, so readers and screen readers have nothing to follow.";
+    DuplicateId = 21, "duplicate-id":
+        "Several headings are given the same id, only the first one keeps it and the others get a \
+        number appended.";
+    TrailingWhitespace = 22, "trailing-whitespace":
+        "The line ends with spaces or tabs, which are not shown and make diffs noisier.";
+    RawDisabled = 23, "raw-disabled":
+        "\\raw is disabled in the engine options, like for untrusted documents, so its content is \
+        shown as text instead of being put in the output as is.";
+    Unsupported = 24, "unsupported-feature":
+        "The document uses a feature the output format it is written to can't represent, like \
+        math in plain text. A fallback is used instead, like showing the source of the math.";
+    UnknownFragment = 25, "unknown-fragment":
+        "\\fragment names a fragment which was not given to the engine, so nothing is shown in its \
+        place.";
+    HeadingLevel = 26, "heading-level":
+        "The heading is deeper than the output format can represent, like a level 6 heading in \
+        HTML, which only has tags down to <h6> for level 5. The deepest heading is used instead.";
+    ListContent = 27, "list-content":
+        "A list has content before its first \\item, which belongs to no item and is ignored.";
+    UnsafeUrl = 28, "unsafe-url":
+        "A link or quote source uses a URL scheme which is not allowed in the engine options, like \
+        javascript: in untrusted documents. Only its text is kept.";
+    SyntaxError = 100, "syntax-error":
+        "The source can't be parsed, like a command parameter list which is not closed.";
+    TrailingContent = 101, "trailing-content":
+        "Parsing stopped before the end of the source, usually at a } or \\end without anything \
+        to close.";
+    MismatchedBlock = 102, "mismatched-block":
+        "An \\end closes another block than the last \\begin. Check that blocks are closed in the \
+        reverse order they are opened.";
+    UnexpectedEnd = 103, "unexpected-end":
+        "An \\end is found where no block is open.";
+    UnclosedBlock = 104, "unclosed-block":
+        "A \\begin has no matching \\end before the end of the source.";
+    TooDeep = 105, "too-deep":
+        "Commands or blocks are nested deeper than the parser allows. Flatten the document, or \
+        raise the limit in the parser options.";
+    MissingInclude = 106, "missing-include":
+        "An \\input includes a file which was not loaded before parsing. Load the files a document \
+        includes from the same source it is parsed from.";
+    TooLarge = 107, "too-large":
+        "Macros expand to more content than the parser allows, usually because macros call each \
+        other several times. Simplify the macros, or raise the limit in the parser options.";
+}
+
+impl Code {
+    /// Finds a code from its `PX0001` form or its name.
+    pub fn lookup(code: &str) -> Option<Code> {
+        let number = code
+            .get(..2)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("px"))
+            .and_then(|_| code[2..].parse::<u16>().ok());
+
+        Code::ALL
+            .iter()
+            .copied()
+            .find(|c| Some(c.number()) == number || c.name() == code)
+    }
+
+    /// The code of a parser error.
+    pub fn of_parse_error(error: &ParseError) -> Code {
+        match error {
+            ParseError::Syntax(_) => Code::SyntaxError,
+            ParseError::TrailingContent { .. } => Code::TrailingContent,
+            ParseError::MismatchedBlock { .. } => Code::MismatchedBlock,
+            ParseError::UnexpectedEnd { .. } => Code::UnexpectedEnd,
+            ParseError::UnclosedBlock { .. } => Code::UnclosedBlock,
+            ParseError::TooDeep { .. } => Code::TooDeep,
+            ParseError::MissingInclude { .. } => Code::MissingInclude,
+            ParseError::TooLarge { .. } => Code::TooLarge,
+            ParseError::Included { error, .. } => Code::of_parse_error(error),
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PX{:04}", self.number())
+    }
+}
+
+/// Logs a warning with its [`Code`], as `diagnostic!(UnknownCommand, "...", args)`, unless it is
+/// silenced by a pragma.
+macro_rules! diagnostic {
+    ($code:ident, $($arg:tt)+) => {
+        $crate::diagnostics::emit($crate::diagnostics::Code::$code, format_args!($($arg)+))
+    };
+}
+
+pub(crate) use diagnostic;
+
+/// A diagnostic reported while processing a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: Code,
+    /// The message, as logged after the code. It ends with ` at line:column` when the engine
+    /// knows where the problem is.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+/// Logs a warning with its code, unless it is silenced by a pragma. Within pastex, this is used
+/// through the `diagnostic!` macro.
+pub fn emit(code: Code, message: fmt::Arguments) {
+    if is_allowed(code) {
+        return;
+    }
+
+    log::warn!("[{}] {}", code, message);
+    COLLECTED.with(|collected| {
+        if let Some(collected) = collected.borrow_mut().as_mut() {
+            collected.push(Diagnostic {
+                code,
+                message: message.to_string(),
+            });
+        }
+    });
+}
+
+/// Where an `%!allow` pragma applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// The next command
+    Next,
+    /// The whole document
+    File,
+}
+
+/// Reads a pragma comment, without its `%`, giving its scope and the list of codes it holds.
+/// Returns [`None`] for other comments.
+pub(crate) fn pragma(comment: &str) -> Option<(Scope, &str)> {
+    let comment = comment.trim_end();
+    let (scope, list) = if let Some(list) = comment.strip_prefix("!allow(") {
+        (Scope::Next, list)
+    } else {
+        (Scope::File, comment.strip_prefix("!allow-file(")?)
+    };
+
+    Some((scope, list.strip_suffix(')')?))
+}
+
+/// Reads the list of codes of a pragma, warning about unknown ones.
+pub(crate) fn pragma_codes(list: &str) -> Vec<Code> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let code = Code::lookup(name);
+            if code.is_none() {
+                diagnostic!(InvalidPragma, "Unknown diagnostic {} in pragma", name);
+            }
+            code
+        })
+        .collect()
+}
+
+thread_local! {
+    /// Codes allowed by the pragmas applying to what is being processed
+    static ALLOWED: RefCell<Vec<Code>> = const { RefCell::new(Vec::new()) };
+    /// Diagnostics reported so far while [`collecting`] them
+    static COLLECTED: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, also giving the diagnostics it reports. Diagnostics are collected again by an outer
+/// call, if any.
+pub(crate) fn collecting<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    let outer = COLLECTED.with(|collected| collected.borrow_mut().replace(Vec::new()));
+    let res = f();
+    let diagnostics = COLLECTED.with(|collected| {
+        let mut collected = collected.borrow_mut();
+        let diagnostics = collected.take().unwrap_or_default();
+        *collected = outer.map(|mut outer| {
+            outer.extend_from_slice(&diagnostics);
+            outer
+        });
+        diagnostics
+    });
+
+    (res, diagnostics)
+}
+
+/// Runs `f` with the diagnostics of `codes` silenced, on top of the already silenced ones.
+pub(crate) fn allowing<T>(codes: &[Code], f: impl FnOnce() -> T) -> T {
+    if codes.is_empty() {
+        return f();
+    }
+
+    let len = ALLOWED.with(|allowed| {
+        let mut allowed = allowed.borrow_mut();
+        let len = allowed.len();
+        allowed.extend_from_slice(codes);
+        len
+    });
+    let res = f();
+    ALLOWED.with(|allowed| allowed.borrow_mut().truncate(len));
+
+    res
+}
+
+/// Whether diagnostics of `code` are currently silenced, see [`allowing`].
+pub(crate) fn is_allowed(code: Code) -> bool {
+    ALLOWED.with(|allowed| allowed.borrow().contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowing, collecting, emit, is_allowed, pragma, pragma_codes, Code, Scope};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_pragma() {
+        assert_eq!(
+            pragma("!allow(unknown-command, PX0004) "),
+            Some((Scope::Next, "unknown-command, PX0004"))
+        );
+        assert_eq!(pragma("!allow-file()"), Some((Scope::File, "")));
+        assert_eq!(pragma(" allow(unknown-command)"), None);
+        assert_eq!(pragma("!allow(unknown-command"), None);
+        assert_eq!(
+            pragma_codes("unknown-command, PX0004, nope"),
+            [Code::UnknownCommand, Code::UnknownParameter]
+        );
+
+        allowing(&[Code::UnknownCommand], || {
+            assert!(is_allowed(Code::UnknownCommand));
+            allowing(&[Code::Icon], || assert!(is_allowed(Code::UnknownCommand)));
+            assert!(!is_allowed(Code::Icon));
+        });
+        assert!(!is_allowed(Code::UnknownCommand));
+    }
+
+    #[test]
+    fn test_collecting() {
+        let ((), outer) = collecting(|| {
+            emit(Code::Icon, format_args!("outer"));
+            let ((), inner) = collecting(|| {
+                emit(Code::UnknownCommand, format_args!("unknown command x"));
+                allowing(&[Code::Icon], || emit(Code::Icon, format_args!("silenced")));
+            });
+            assert_eq!(inner.len(), 1);
+            assert_eq!(inner[0].to_string(), "[PX0001] unknown command x");
+        });
+
+        let codes: Vec<_> = outer.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Code::Icon, Code::UnknownCommand]);
+    }
+
+    #[test]
+    fn test_codes() {
+        let numbers: HashSet<_> = Code::ALL.iter().map(|c| c.number()).collect();
+        let names: HashSet<_> = Code::ALL.iter().map(|c| c.name()).collect();
+
+        assert_eq!(numbers.len(), Code::ALL.len());
+        assert_eq!(names.len(), Code::ALL.len());
+        assert_eq!(Code::UnknownCommand.to_string(), "PX0001");
+        assert_eq!(Code::lookup("PX0001"), Some(Code::UnknownCommand));
+        assert_eq!(Code::lookup("px0104"), Some(Code::UnclosedBlock));
+        assert_eq!(Code::lookup("heading-jump"), Some(Code::HeadingJump));
+        assert_eq!(Code::lookup("PX9999"), None);
+    }
+}
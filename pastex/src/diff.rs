@@ -0,0 +1,140 @@
+//! Comparison of document revisions.
+
+use crate::document::{Block, Document};
+
+/// An item of a revision, compared to the previous one. Items are blocks by default, or words
+/// for [`diff_words`].
+#[derive(Debug)]
+pub enum Change<'d, T: ?Sized = Block> {
+    /// The item is in both revisions
+    Same(&'d T),
+    /// The item was added in the new revision
+    Inserted(&'d T),
+    /// The item was removed from the old revision
+    Removed(&'d T),
+}
+
+impl<'d, T: ?Sized> Clone for Change<'d, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'d, T: ?Sized> Copy for Change<'d, T> {}
+
+/// Returns the smallest list of changes going from `old` to `new`. Removed items come before the
+/// items inserted at the same place.
+fn diff_slices<'d, T: PartialEq>(old: &'d [T], new: &'d [T]) -> Vec<Change<'d, T>> {
+    // Length of the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            changes.push(Change::Same(&new[j]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            changes.push(Change::Removed(&old[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Inserted(&new[j]));
+            j += 1;
+        }
+    }
+    changes.extend(old[i..].iter().map(Change::Removed));
+    changes.extend(new[j..].iter().map(Change::Inserted));
+
+    changes
+}
+
+/// Compares two lists of blocks.
+pub fn diff_blocks<'d>(old: &'d [Block], new: &'d [Block]) -> Vec<Change<'d>> {
+    diff_slices(old, new)
+}
+
+/// Compares the outlines of two revisions of a document.
+pub fn diff<'d>(old: &'d Document, new: &'d Document) -> Vec<Change<'d>> {
+    diff_blocks(&old.outline, &new.outline)
+}
+
+/// Splits text into words and the whitespace between them.
+fn words(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+
+    for (i, c) in text.char_indices().skip(1) {
+        let previous = text[..i].chars().next_back().unwrap_or(c);
+        if c.is_whitespace() != previous.is_whitespace() {
+            words.push(&text[start..i]);
+            start = i;
+        }
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+
+    words
+}
+
+/// Compares two texts word by word. Whitespace between words is compared too, so joining all the
+/// changes gives back the texts.
+pub fn diff_words<'t>(old: &'t str, new: &'t str) -> Vec<Change<'t, str>> {
+    let (old, new) = (words(old), words(new));
+
+    diff_slices(&old, &new)
+        .into_iter()
+        .map(|change| match change {
+            Change::Same(word) => Change::Same(*word),
+            Change::Inserted(word) => Change::Inserted(*word),
+            Change::Removed(word) => Change::Removed(*word),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{outline, plain_text};
+
+    #[test]
+    fn test_diff_blocks() {
+        let old = outline("One\n\nTwo\n\nThree");
+        let new = outline("One\n\nTwo, edited\n\nThree\n\nFour");
+
+        let changes: Vec<_> = diff_blocks(&old, &new)
+            .into_iter()
+            .map(|change| match change {
+                Change::Same(b) => format!("={}", plain_text(&b.1)),
+                Change::Inserted(b) => format!("+{}", plain_text(&b.1)),
+                Change::Removed(b) => format!("-{}", plain_text(&b.1)),
+            })
+            .collect();
+
+        assert_eq!(changes, ["=One", "-Two", "+Two, edited", "=Three", "+Four"]);
+    }
+
+    #[test]
+    fn test_diff_words() {
+        let changes: Vec<_> = diff_words("The quick fox", "The slow  fox jumps")
+            .into_iter()
+            .map(|change| match change {
+                Change::Same(w) => w.to_owned(),
+                Change::Inserted(w) => format!("+{}", w),
+                Change::Removed(w) => format!("-{}", w),
+            })
+            .collect();
+
+        assert_eq!(changes.concat(), "The -quick- +slow+  fox+ +jumps");
+    }
+}
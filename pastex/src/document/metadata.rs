@@ -1,6 +1,96 @@
+use super::{plain_text, Span, SpanFormat};
+use crate::diagnostics::diagnostic;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A metadata value which can contain formatting, like a title using `\code`.
+///
+/// The plain text version is used where markup is not allowed (e.g. `<title>`), while the
+/// formatted version can be rendered in the document body.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RichText {
+    pub plain: String,
+    pub formatted: Vec<Span>,
+}
+
+/// A calendar date, read from the `YYYY-MM-DD` format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+impl Date {
+    /// Parses an ISO 8601 calendar date (`2021-12-31`).
+    pub fn parse(s: &str) -> Option<Date> {
+        let mut parts = s.trim().splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+
+        if (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day) {
+            Some(Date { year, month, day })
+        } else {
+            None
+        }
+    }
+
+    /// The machine-readable form of the date, usable in a `datetime` attribute.
+    pub fn iso(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// A time span holding both the machine-readable and human-readable forms.
+    pub fn to_span(&self) -> Span {
+        Span::Format(
+            SpanFormat::Time(self.iso()),
+            vec![Span::Text(self.to_string())],
+        )
+    }
+}
+
+/// Number of days of `month` (1 to 12) in `year`, in the Gregorian calendar.
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Human-readable form of the date, e.g. `5 January 2021`.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year
+        )
+    }
+}
+
 pub trait Field {
     fn is_set(&self) -> bool;
-    fn from(s: &str) -> Self;
+    fn from(spans: Vec<Span>) -> Self;
 }
 
 impl Field for Option<String> {
@@ -8,8 +98,41 @@ impl Field for Option<String> {
         self.is_some()
     }
 
-    fn from(s: &str) -> Self {
-        Some(s.to_owned())
+    fn from(spans: Vec<Span>) -> Self {
+        Some(plain_text(&spans).trim().to_owned())
+    }
+}
+
+impl Field for Option<RichText> {
+    fn is_set(&self) -> bool {
+        self.is_some()
+    }
+
+    fn from(spans: Vec<Span>) -> Self {
+        Some(RichText {
+            plain: plain_text(&spans).trim().to_owned(),
+            formatted: spans,
+        })
+    }
+}
+
+impl Field for Option<Date> {
+    fn is_set(&self) -> bool {
+        self.is_some()
+    }
+
+    fn from(spans: Vec<Span>) -> Self {
+        let text = plain_text(&spans);
+        let date = Date::parse(&text);
+
+        if date.is_none() {
+            diagnostic!(
+                InvalidValue,
+                "Invalid date {:?}, expected YYYY-MM-DD",
+                text.trim()
+            );
+        }
+        date
     }
 }
 
@@ -18,7 +141,7 @@ impl Field for bool {
         false
     }
 
-    fn from(_: &str) -> Self {
+    fn from(_: Vec<Span>) -> Self {
         true
     }
 }
@@ -28,19 +151,29 @@ impl Field for Vec<String> {
         !self.is_empty()
     }
 
-    fn from(s: &str) -> Self {
-        s.split(',').map(str::trim).map(str::to_owned).collect()
+    fn from(spans: Vec<Span>) -> Self {
+        plain_text(&spans)
+            .split(',')
+            .map(str::trim)
+            .map(str::to_owned)
+            .collect()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Metadata {
-    pub title: Option<String>,
-    pub author: Option<String>,
-    pub date: Option<String>,
+    pub title: Option<RichText>,
+    pub author: Option<RichText>,
+    pub date: Option<Date>,
     pub keywords: Vec<String>,
     pub draft: bool,
     pub r#abstract: Option<Vec<super::Block>>,
+    /// Text direction of the whole document
+    #[serde(default)]
+    pub dir: Option<super::Direction>,
+    /// Language of the document, as a BCP 47 tag like `en` or `fr-CA`
+    #[serde(default)]
+    pub lang: Option<String>,
 }
 
 impl Default for Metadata {
@@ -52,6 +185,39 @@ impl Default for Metadata {
             keywords: Vec::new(),
             draft: false,
             r#abstract: None,
+            dir: None,
+            lang: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_parse() {
+        let date = Date::parse(" 2021-12-31 ").unwrap();
+        assert_eq!((date.year, date.month, date.day), (2021, 12, 31));
+        assert_eq!(date.to_string(), "31 December 2021");
+
+        let invalid = [
+            "2021-02-31",
+            "2021-04-31",
+            "2021-02-29",
+            "2021-13-01",
+            "2021-01-00",
+            "2021-1",
+            "2021/01/01",
+        ];
+        for date in invalid {
+            assert_eq!(Date::parse(date), None, "{}", date);
+        }
+
+        // Leap years
+        assert!(Date::parse("2024-02-29").is_some());
+        assert!(Date::parse("2000-02-29").is_some());
+        assert!(Date::parse("1900-02-29").is_none());
+        assert!(Date::parse("2021-06-30").is_some());
+    }
+}
@@ -1,60 +1,580 @@
 pub mod metadata;
 
 use metadata::Metadata;
-use pastex_parser::Stream;
+use pastex_parser::{CommandName, Location, ParseError, Stream};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, mem::take, ops::Range};
 
-use crate::engine::TextProcessor;
+use crate::engine::{Context, EngineOptions, TextProcessor};
+use crate::output::capabilities;
+use crate::{
+    diagnostics::{self, diagnostic, Code, Diagnostic},
+    trace,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
 pub enum BlockFormat {
     Paragraph,
     Code,
     Heading(usize),
     Raw,
+    /// Display math, holding its source as a single text span
+    Math,
+    /// Captured output of a code block run when processing the document
+    Output,
+    /// A quotation holding whole blocks, like `\begin{blockquote}`, without any span of its own
+    Quote {
+        /// URL of the source of the quotation
+        cite: Option<String>,
+        blocks: Vec<Block>,
+    },
+    /// A list, numbered when `ordered`, whose items hold whole blocks, including other lists
+    List {
+        ordered: bool,
+        items: Vec<Vec<Block>>,
+    },
 }
 
-#[derive(Debug)]
+/// Destination and attributes of a link, see [`SpanFormat::Link`].
+#[derive(Debug, Clone, Default, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Link {
+    pub to: String,
+    /// Opens the link in a new tab
+    pub blank: bool,
+    /// Relationship with the linked resource, as the HTML `rel` attribute
+    pub rel: Option<String>,
+    /// Downloads the resource instead of opening it, with this file name when it is not empty
+    pub download: Option<String>,
+    /// Advisory text, usually shown on hover
+    pub title: Option<String>,
+    /// Asks search engines not to follow the link
+    pub nofollow: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
 pub enum SpanFormat {
     Code,
     Strong,
-    Link { to: String, blank: bool },
+    Link(Link),
+    /// A date or time, with its machine-readable value
+    Time(String),
+    /// The first letter or word of a paragraph, styled as a drop cap where supported
+    Initial,
+    /// An inline quotation, with the quotation marks to use where they are not added by the output
+    /// format itself
+    Quote {
+        open: String,
+        close: String,
+    },
+    /// An abbreviation or term, with its expansion
+    Abbreviation {
+        title: String,
+    },
+    /// A note left by the author, see [`Todo`]
+    Todo,
+    /// An icon, holding its SVG code as a raw span. Icons without a title are only decorative.
+    Icon {
+        title: Option<String>,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Span {
     Text(String),
     Format(SpanFormat, Vec<Span>),
     LineBreak,
     Raw(String),
+    /// Inline math, as written in the source
+    Math(String),
+}
+
+/// Flattens spans into their textual content, dropping formatting and raw output.
+pub fn plain_text(spans: &[Span]) -> String {
+    let mut out = String::new();
+
+    for span in spans {
+        match span {
+            Span::Text(t) => out.push_str(t),
+            Span::Format(SpanFormat::Quote { open, close }, inner) => {
+                out.push_str(open);
+                out.push_str(&plain_text(inner));
+                out.push_str(close);
+            }
+            Span::Format(_, inner) => out.push_str(&plain_text(inner)),
+            Span::LineBreak => out.push(' '),
+            Span::Raw(_) => (),
+            Span::Math(source) => out.push_str(source),
+        }
+    }
+
+    out
+}
+
+/// Text direction, as in the HTML `dir` attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Direction {
+    /// Left to right
+    Ltr,
+    /// Right to left, e.g. for Arabic or Hebrew
+    Rtl,
+    /// Detected from the content
+    Auto,
+}
+
+impl Direction {
+    /// Gets a direction from its name, as used in parameters and HTML attributes.
+    pub fn parse(name: &str) -> Option<Direction> {
+        match name {
+            "ltr" => Some(Direction::Ltr),
+            "rtl" => Some(Direction::Rtl),
+            "auto" => Some(Direction::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+            Direction::Auto => "auto",
+        }
+    }
+}
+
+/// Presentation attributes any block can carry.
+#[derive(Debug, Clone, Default, PartialEq, Hash, Serialize, Deserialize)]
+pub struct BlockAttributes {
+    /// Text direction, when different from the document one
+    pub dir: Option<Direction>,
+    /// Anchor of a heading, replacing the one generated from its title
+    pub id: Option<String>,
+    /// Secondary title of a heading
+    pub subtitle: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Block(
+    pub BlockFormat,
+    pub Vec<Span>,
+    #[serde(default)] pub BlockAttributes,
+);
+
+impl Block {
+    /// Creates a block without any attribute.
+    pub fn new(format: BlockFormat, content: Vec<Span>) -> Self {
+        Block(format, content, BlockAttributes::default())
+    }
+
+    /// The blocks held by this one, like the content of a [`BlockFormat::Quote`] or the items of
+    /// a [`BlockFormat::List`], in order. Empty for blocks only holding spans.
+    pub fn children(&self) -> impl Iterator<Item = &Block> {
+        let (blocks, items): (&[Block], &[Vec<Block>]) = match &self.0 {
+            BlockFormat::Quote { blocks, .. } => (blocks, &[]),
+            BlockFormat::List { items, .. } => (&[], items),
+            _ => (&[], &[]),
+        };
+        blocks.iter().chain(items.iter().flatten())
+    }
+}
+
+/// Lists blocks with the blocks they hold, at any depth, in document order.
+pub fn all_blocks(blocks: &[Block]) -> Vec<&Block> {
+    fn walk<'b>(blocks: impl Iterator<Item = &'b Block>, all: &mut Vec<&'b Block>) {
+        for block in blocks {
+            all.push(block);
+            walk(block.children(), all);
+        }
+    }
+
+    let mut all = Vec::new();
+    walk(blocks.iter(), &mut all);
+    all
+}
+
+/// Converts heading text into an identifier usable as an URL fragment.
+pub fn slugify(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
 }
 
+/// A heading of the document, as listed in a table of contents.
 #[derive(Debug)]
-pub struct Block(pub BlockFormat, pub Vec<Span>);
+pub struct TocEntry {
+    pub level: usize,
+    /// Unique anchor of the heading in the document
+    pub id: String,
+    pub title: String,
+}
+
+/// Lists the headings of a list of blocks, in order, with unique anchors.
+pub fn toc(blocks: &[Block]) -> Vec<TocEntry> {
+    let mut seen = std::collections::HashSet::new();
+
+    blocks
+        .iter()
+        .filter_map(|block| match block {
+            Block(BlockFormat::Heading(level), content, attributes) => {
+                Some((*level, content, attributes))
+            }
+            _ => None,
+        })
+        .map(|(level, content, attributes)| {
+            let title = plain_text(content);
+            let base = match (&attributes.id, slugify(&title)) {
+                (Some(id), _) => id.clone(),
+                (None, slug) if slug.is_empty() => "section".to_owned(),
+                (None, slug) => slug,
+            };
+
+            let mut id = base.clone();
+            let mut n = 1;
+            while !seen.insert(id.clone()) {
+                n += 1;
+                id = format!("{}-{}", base, n);
+            }
+
+            TocEntry { level, id, title }
+        })
+        .collect()
+}
+
+/// Gives stable anchors to the paragraphs of a list of blocks, for annotation systems to attach to
+/// them across rebuilds. The result has an entry for each block, [`None`] for other blocks.
+///
+/// An anchor is made from a hash of the paragraph text, with its whitespace normalized, so it only
+/// changes when the paragraph text does. Paragraphs with the same text get a number appended, as
+/// `p-1a2b3c4d-2`, in order.
+pub fn paragraph_ids(blocks: &[Block]) -> Vec<Option<String>> {
+    let mut seen = std::collections::HashSet::new();
+
+    blocks
+        .iter()
+        .map(|block| match block {
+            Block(BlockFormat::Paragraph, content, _) => {
+                let text = plain_text(content);
+                let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                let base = format!("p-{:08x}", fnv1a(normalized.as_bytes()) as u32);
+
+                let mut id = base.clone();
+                let mut n = 1;
+                while !seen.insert(id.clone()) {
+                    n += 1;
+                    id = format!("{}-{}", base, n);
+                }
+
+                Some(id)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// FNV-1a hash, which unlike the standard library hasher has a fixed definition, so it is the same
+/// across runs and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(OFFSET, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Reports headings skipping a level, like a level 3 heading directly following a level 1.
+pub fn check_heading_levels(blocks: &[Block]) {
+    let mut previous = None;
+
+    for entry in toc(blocks) {
+        if let Some(previous) = previous {
+            if entry.level > previous + 1 {
+                diagnostic!(
+                    HeadingJump,
+                    "Heading {:?} jumps from level {} to level {}",
+                    entry.title,
+                    previous,
+                    entry.level
+                );
+            }
+        }
+
+        previous = Some(entry.level);
+    }
+}
+
+/// A note left by the author with `\todo`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Todo {
+    pub text: String,
+    /// Where the note is in the source, when known
+    pub location: Option<Location>,
+}
+
+/// How many times each command is called in a document, to find the documents still using a
+/// deprecated command or to measure how much a feature is used.
+///
+/// Every call written in the source is counted, including unknown commands and commands of
+/// disabled namespaces. Macros are counted as the commands they expand to.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandUsage(BTreeMap<String, usize>);
+
+impl CommandUsage {
+    pub(crate) fn record(&mut self, name: CommandName) {
+        *self.0.entry(name.to_string()).or_default() += 1;
+    }
 
+    /// Number of calls to a command, named as in the source, like `strong` or `meta:title`.
+    pub fn count(&self, name: &str) -> usize {
+        self.0.get(name).copied().unwrap_or(0)
+    }
+
+    /// Commands called in the document, sorted by name, with their number of calls.
+    pub fn commands(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.0.iter().map(|(name, count)| (name.as_str(), *count))
+    }
+
+    /// Number of calls by namespace, commands without a namespace being counted under [`None`].
+    pub fn namespaces(&self) -> BTreeMap<Option<&str>, usize> {
+        let mut namespaces = BTreeMap::new();
+        for (name, count) in self.commands() {
+            let namespace = name.split_once(':').map(|(namespace, _)| namespace);
+            *namespaces.entry(namespace).or_default() += count;
+        }
+        namespaces
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Document {
     pub outline: Vec<Block>,
     pub metadata: Metadata,
+    /// Notes left in the document, kept even when they are not rendered
+    #[serde(default)]
+    pub todos: Vec<Todo>,
+    /// Commands called in the document, see [`CommandUsage`]
+    #[serde(default)]
+    pub command_usage: CommandUsage,
+    /// Warnings reported while processing the document, also sent to the `log` crate, see
+    /// [`diagnostics`](crate::diagnostics)
+    #[serde(skip)]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    /// A hash of the outline and metadata of the document, to detect changes in its content.
+    ///
+    /// The hash is the same across runs and platforms, and doesn't change when the source is only
+    /// reformatted (like whitespace changes) as long as the processed document stays the same.
+    pub fn content_hash(&self) -> u64 {
+        let bytes = serde_json::to_vec(&(&self.outline, &self.metadata))
+            .expect("document can always be serialized");
+        fnv1a(&bytes)
+    }
+
+    /// A strong HTTP entity tag for the document, including its quotes, based on
+    /// [`content_hash`](Document::content_hash).
+    pub fn etag(&self) -> String {
+        format!("\"{:016x}\"", self.content_hash())
+    }
 }
 
 pub fn process_stream(stream: Stream) -> Document {
-    let mut metadata = Metadata::default();
-    let outline = crate::engine::root(&mut metadata, stream);
+    process_stream_with(stream, &EngineOptions::default())
+}
+
+pub fn process_stream_with(stream: Stream, options: &EngineOptions) -> Document {
+    process_with_context(&mut Context::new(options), stream)
+}
+
+/// The outline of `source` processed with the default options, for the tests of other modules.
+#[cfg(test)]
+pub(crate) fn outline(source: &str) -> Vec<Block> {
+    process_stream(pastex_parser::parse(source).unwrap()).outline
+}
+
+fn process_with_context(ctx: &mut Context, stream: Stream) -> Document {
+    let allowed = crate::engine::file_pragmas(&stream);
+    let (outline, diagnostics) = trace::phase("process", || {
+        diagnostics::collecting(|| {
+            diagnostics::allowing(&allowed, || {
+                let mut outline = crate::engine::root(ctx, stream);
+                if let Some(backend) = ctx.options.target_backend() {
+                    capabilities::adapt(&mut outline, backend);
+                }
+                outline
+            })
+        })
+    });
+
+    Document {
+        outline,
+        metadata: take(&mut ctx.metadata),
+        todos: take(&mut ctx.todos),
+        command_usage: take(&mut ctx.command_usage),
+        diagnostics,
+    }
+}
+
+/// Error of [`process_source`].
+#[derive(Debug)]
+pub enum ProcessError<'s> {
+    /// The source can't be parsed
+    Parse(ParseError<'s>),
+    /// A command is unknown, or belongs to a disabled namespace, in
+    /// [strict](EngineOptions::strict) mode. Only the first one is returned, but all of them are
+    /// reported as diagnostics.
+    UnknownCommand {
+        name: String,
+        /// Byte range of the whole call in the source, if it comes from it
+        span: Option<Range<usize>>,
+    },
+}
+
+impl<'s> ProcessError<'s> {
+    /// Byte offset in `source` where the error is, 0 when it is not in the source.
+    pub fn offset(&self, source: &str) -> usize {
+        match self {
+            ProcessError::Parse(err) => err.offset(source),
+            ProcessError::UnknownCommand { span, .. } => span.as_ref().map_or(0, |span| span.start),
+        }
+    }
 
-    Document { outline, metadata }
+    /// The diagnostic code matching the error.
+    pub fn code(&self) -> Code {
+        match self {
+            ProcessError::Parse(err) => Code::of_parse_error(err),
+            ProcessError::UnknownCommand { .. } => Code::UnknownCommand,
+        }
+    }
+}
+
+impl<'s> From<ParseError<'s>> for ProcessError<'s> {
+    fn from(err: ParseError<'s>) -> Self {
+        ProcessError::Parse(err)
+    }
+}
+
+impl<'s> fmt::Display for ProcessError<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::Parse(err) => err.fmt(f),
+            ProcessError::UnknownCommand { name, .. } => write!(f, "unknown command {}", name),
+        }
+    }
+}
+
+impl<'s> std::error::Error for ProcessError<'s> {}
+
+/// Parses and processes a document, keeping track of the source to locate diagnostics and notes.
+pub fn process_source<'s>(
+    source: &'s str,
+    options: &EngineOptions,
+) -> Result<Document, ProcessError<'s>> {
+    let parser_options = options.parser_options();
+    let stream = trace::phase("parse", || {
+        let stream = pastex_parser::parse_with(source, &parser_options)?;
+        pastex_parser::expand_macros(source, stream, &parser_options)
+    })?;
+    let mut ctx = Context::new(options);
+    ctx.source = Some(source);
+
+    let document = process_with_context(&mut ctx, stream);
+    match ctx.unknown_command.take() {
+        Some((name, span)) => Err(ProcessError::UnknownCommand { name, span }),
+        None => Ok(document),
+    }
 }
 
 pub fn process_fragment_stream(stream: Stream) -> Vec<Block> {
-    vec![Block(
-        BlockFormat::Paragraph,
-        crate::engine::InlineTextProcessor::process_all(stream),
-    )]
+    process_fragment_stream_with(stream, &EngineOptions::default())
+}
+
+pub fn process_fragment_stream_with(stream: Stream, options: &EngineOptions) -> Vec<Block> {
+    process_fragment_with_context(Context::new(options), stream)
+}
+
+fn process_fragment_with_context(mut ctx: Context, stream: Stream) -> Vec<Block> {
+    let allowed = crate::engine::file_pragmas(&stream);
+    trace::phase("process", || {
+        diagnostics::allowing(&allowed, || {
+            let spans = crate::engine::InlineTextProcessor::process_all(&mut ctx, stream);
+            let mut fragment = vec![Block::new(
+                BlockFormat::Paragraph,
+                crate::engine::normalize(spans),
+            )];
+            if let Some(backend) = ctx.options.target_backend() {
+                capabilities::adapt(&mut fragment, backend);
+            }
+            fragment
+        })
+    })
 }
 
+/// Reads and processes the document at `path`, with the default options. Documents which can't
+/// be processed are an [`std::io::ErrorKind::InvalidData`] error giving where the problem is.
 pub fn process(path: &std::path::Path) -> std::io::Result<Document> {
     let buf = std::fs::read_to_string(path)?;
-    Ok(process_stream(pastex_parser::parse(&buf).unwrap()))
+
+    process_source(&buf, &EngineOptions::default()).map_err(|err| {
+        let location = Location::at(&buf, err.offset(&buf));
+        let message = format!("{}:{}: {}", path.display(), location, err);
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// Parses and processes inline content on its own, with the default options, as for fragments
+/// given to [`EngineOptions::add_fragment`].
+pub fn process_fragment(fragment: &str) -> Result<Vec<Block>, ProcessError<'_>> {
+    let options = EngineOptions::default();
+    let mut ctx = Context::new(&options);
+    ctx.source = Some(fragment);
+
+    let stream = trace::phase("parse", || {
+        pastex_parser::parse_with(fragment, &options.parser_options())
+    })?;
+
+    Ok(process_fragment_with_context(ctx, stream))
 }
 
-pub fn process_fragment(fragment: &str) -> Vec<Block> {
-    process_fragment_stream(pastex_parser::parse(fragment).unwrap())
+#[cfg(test)]
+mod tests {
+    use super::{process_source, Block, BlockFormat, EngineOptions, Span, SpanFormat};
+
+    fn hash(source: &str) -> u64 {
+        process_source(source, &EngineOptions::default())
+            .unwrap()
+            .content_hash()
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let base = hash("\\meta:title{Doc}\n\nSome text.\n\nMore text.");
+
+        assert_eq!(
+            base,
+            hash("\\meta:title{Doc}\n\n\n\nSome   text.\n\n  More\ntext.  ")
+        );
+        assert_ne!(base, hash("\\meta:title{Doc}\n\nSome text.\n\nMore text!"));
+        assert_ne!(base, hash("\\meta:title{Doc.}\n\nSome text.\n\nMore text."));
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "\\define{hl}{\\strong{#1}!}\n\nSome \\hl{text}.";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+
+        assert_eq!(
+            document.outline,
+            [Block::new(
+                BlockFormat::Paragraph,
+                vec![
+                    Span::Text("Some ".into()),
+                    Span::Format(SpanFormat::Strong, vec![Span::Text("text".into())]),
+                    Span::Text("!.".into()),
+                ]
+            )]
+        );
+    }
 }
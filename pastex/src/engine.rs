@@ -1,14 +1,361 @@
-use crate::document::{metadata::Metadata, Block, BlockFormat, Span, SpanFormat};
+use crate::commands::CommandRegistry;
+use crate::diagnostics::{self, pragma_codes, Code, Scope};
+use crate::document::{
+    metadata::Metadata, Block, BlockFormat, CommandUsage, Span, SpanFormat, Todo,
+};
+use crate::output::capabilities::{Backend, Capability};
 use nom::Parser;
-use pastex_parser::{Element, Stream};
-use std::mem::take;
+use pastex_parser::{Element, Location, ParamValue, ParserOptions, Stream};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::once,
+    mem::take,
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
+/// A term of the glossary, see [`EngineOptions::add_glossary_term`].
+#[derive(Debug, Clone, Default)]
+pub struct GlossaryEntry {
+    /// Where the term is defined, e.g. a section of a glossary document
+    pub href: Option<String>,
+    /// Expansion of the term, shown on hover for abbreviations
+    pub title: Option<String>,
+}
+
+/// Runs the code of `\begin{code}[run]` blocks, see [`EngineOptions::executor`].
+pub trait CodeExecutor: Send + Sync {
+    /// Runs `source`, written in `lang` when the block gives it, and returns the captured output
+    /// or an error message.
+    fn execute(&self, lang: Option<&str>, source: &str) -> Result<String, String>;
+}
+
+/// How much the source of documents is trusted, setting the safety options of the engine at once,
+/// see [`EngineOptions::profile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Documents written by the site authors. Everything is allowed but code execution, which
+    /// still has to be allowed with [`EngineOptions::allow_execution`].
+    #[default]
+    Trusted,
+    /// Documents from users, like comments. `\raw` is rendered as text, code execution is
+    /// disabled, links only lead to web pages and addresses, nesting is limited to
+    /// [`UNTRUSTED_MAX_DEPTH`] and macro expansions to [`UNTRUSTED_MAX_EXPANSION`] elements. Icons
+    /// are always sanitized, whatever the profile.
+    Untrusted,
+}
+
+/// How deep commands can be nested in [`Profile::Untrusted`] documents.
+pub const UNTRUSTED_MAX_DEPTH: usize = 32;
+
+/// How many elements macro expansions can add to [`Profile::Untrusted`] documents.
+pub const UNTRUSTED_MAX_EXPANSION: usize = 10_000;
+
+/// Options controlling how documents are processed.
+#[derive(Default)]
+pub struct EngineOptions {
+    disabled_namespaces: HashSet<String>,
+    glossary: HashMap<String, GlossaryEntry>,
+    release: bool,
+    executor: Option<Box<dyn CodeExecutor>>,
+    allow_execution: bool,
+    icons: Option<PathBuf>,
+    disable_raw: bool,
+    max_depth: Option<usize>,
+    max_expansion: Option<usize>,
+    restrict_urls: bool,
+    target: Option<Backend>,
+    fragments: HashMap<String, Vec<Block>>,
+    commands: Option<CommandRegistry>,
+    strict: bool,
+    path: Option<PathBuf>,
+}
+
+impl EngineOptions {
+    /// Disables all commands from a namespace, e.g. `media`. Calls to them are then treated like
+    /// unknown commands.
+    pub fn disable_namespace(&mut self, namespace: &str) -> &mut Self {
+        self.disabled_namespaces.insert(namespace.to_owned());
+        self
+    }
+
+    /// Enables back a namespace previously disabled with [`EngineOptions::disable_namespace`].
+    pub fn enable_namespace(&mut self, namespace: &str) -> &mut Self {
+        self.disabled_namespaces.remove(namespace);
+        self
+    }
+
+    /// Builds the document for publication, without author notes like `\todo`.
+    pub fn release(&mut self, release: bool) -> &mut Self {
+        self.release = release;
+        self
+    }
+
+    pub fn is_release(&self) -> bool {
+        self.release
+    }
+
+    /// Makes unknown commands, and commands of disabled namespaces, fail
+    /// [`process_source`](crate::document::process_source) instead of putting a
+    /// `[[unknown command ...]]` placeholder in the output, so CI builds don't publish it. Other
+    /// processing functions can't fail, and keep the placeholder.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Adds a term to the glossary used by `\gloss`. Terms are matched without regard to case.
+    pub fn add_glossary_term(&mut self, term: &str, entry: GlossaryEntry) -> &mut Self {
+        self.glossary.insert(term.to_lowercase(), entry);
+        self
+    }
+
+    /// Adds a fragment `\fragment{name}` puts in documents, like a box about the author shared by
+    /// several pages. Fragments are already processed, so they are reused as they are.
+    pub fn add_fragment(&mut self, name: &str, fragment: Vec<Block>) -> &mut Self {
+        self.fragments.insert(name.to_owned(), fragment);
+        self
+    }
+
+    pub fn fragment(&self, name: &str) -> Option<&[Block]> {
+        self.fragments.get(name).map(Vec::as_slice)
+    }
+
+    /// Sets the commands documents can call, like the built-in ones extended with commands of
+    /// their own. Documents use [`CommandRegistry::builtin`] otherwise.
+    pub fn commands(&mut self, registry: CommandRegistry) -> &mut Self {
+        self.commands = Some(registry);
+        self
+    }
+
+    pub fn command_registry(&self) -> &CommandRegistry {
+        match &self.commands {
+            Some(registry) => registry,
+            None => CommandRegistry::builtin(),
+        }
+    }
+
+    /// Sets the executor running code blocks marked with `[run]`. It is only used once execution
+    /// is allowed with [`EngineOptions::allow_execution`].
+    pub fn executor(&mut self, executor: impl CodeExecutor + 'static) -> &mut Self {
+        self.executor = Some(Box::new(executor));
+        self
+    }
+
+    /// Allows running code blocks marked with `[run]`. This is disabled by default, as it lets
+    /// documents run arbitrary code: only enable it for trusted sources.
+    pub fn allow_execution(&mut self, allow: bool) -> &mut Self {
+        self.allow_execution = allow;
+        self
+    }
+
+    /// Allows `\raw` to put its content in the output as is, which is the default. Otherwise, it is
+    /// rendered as text.
+    pub fn allow_raw(&mut self, allow: bool) -> &mut Self {
+        self.disable_raw = !allow;
+        self
+    }
+
+    pub fn is_raw_allowed(&self) -> bool {
+        !self.disable_raw
+    }
+
+    /// Sets how deep commands can be nested in parsed sources, see [`ParserOptions::max_depth`].
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Sets how many elements macro expansions can add to parsed sources, see
+    /// [`ParserOptions::max_expansion`].
+    pub fn max_expansion(&mut self, elements: usize) -> &mut Self {
+        self.max_expansion = Some(elements);
+        self
+    }
+
+    /// Only allows links and quote sources leading to web pages (`http` and `https`), addresses
+    /// (`mailto`) or relative URLs, as `javascript:` ones run code when followed. Others are
+    /// dropped. Disabled by default.
+    pub fn restrict_urls(&mut self, restrict: bool) -> &mut Self {
+        self.restrict_urls = restrict;
+        self
+    }
+
+    /// Whether documents can link to `url`, see [`EngineOptions::restrict_urls`].
+    pub fn is_url_allowed(&self, url: &str) -> bool {
+        !self.restrict_urls || crate::svg::is_safe_url(url)
+    }
+
+    /// Sets the safety options for documents from sources trusted as much as `profile`. Options
+    /// can still be changed one by one afterwards.
+    pub fn profile(&mut self, profile: Profile) -> &mut Self {
+        match profile {
+            Profile::Trusted => {
+                self.disable_raw = false;
+                self.max_depth = None;
+                self.max_expansion = None;
+                self.restrict_urls = false;
+            }
+            Profile::Untrusted => {
+                self.disable_raw = true;
+                self.allow_execution = false;
+                self.max_depth = Some(UNTRUSTED_MAX_DEPTH);
+                self.max_expansion = Some(UNTRUSTED_MAX_EXPANSION);
+                self.restrict_urls = true;
+            }
+        }
+        self
+    }
+
+    /// Sets the output format documents are written to, adapting them to what it can represent,
+    /// see [`capabilities`](crate::output::capabilities).
+    pub fn target(&mut self, backend: Backend) -> &mut Self {
+        self.target = Some(backend);
+        self
+    }
+
+    pub fn target_backend(&self) -> Option<Backend> {
+        self.target
+    }
+
+    /// Whether the output format documents are written to can represent `capability`. Always true
+    /// when no target is set.
+    pub fn supports(&self, capability: Capability) -> bool {
+        match self.target {
+            Some(backend) => backend.supports(capability),
+            None => true,
+        }
+    }
+
+    /// The options to parse sources with.
+    pub(crate) fn parser_options(&self) -> ParserOptions {
+        let mut options = ParserOptions::default();
+        if let Some(max_depth) = self.max_depth {
+            options.max_depth = max_depth;
+        }
+        if let Some(max_expansion) = self.max_expansion {
+            options.max_expansion = max_expansion;
+        }
+        options
+    }
+
+    /// The executor to run code blocks with, if one is set and execution is allowed.
+    pub fn code_executor(&self) -> Option<&dyn CodeExecutor> {
+        self.executor.as_deref().filter(|_| self.allow_execution)
+    }
+
+    /// Sets the directory `\icon{name}` loads `name.svg` from.
+    pub fn icons(&mut self, directory: impl Into<PathBuf>) -> &mut Self {
+        self.icons = Some(directory.into());
+        self
+    }
+
+    pub fn icon_directory(&self) -> Option<&Path> {
+        self.icons.as_deref()
+    }
+
+    /// Sets the path of the file documents are read from, for commands locating files next to it.
+    pub fn path(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn file_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Looks a term up in the glossary.
+    pub fn glossary_term(&self, term: &str) -> Option<&GlossaryEntry> {
+        self.glossary.get(&term.to_lowercase())
+    }
+
+    /// Checks if commands from the given namespace can be used. Commands without a namespace are
+    /// always available.
+    pub fn is_namespace_enabled(&self, namespace: Option<&str>) -> bool {
+        match namespace {
+            Some(ns) => !self.disabled_namespaces.contains(ns),
+            None => true,
+        }
+    }
+}
+
+/// State shared by all commands while processing a document.
+pub struct Context<'o> {
+    pub metadata: Metadata,
+    pub options: &'o EngineOptions,
+    /// The parsed source, when available, to locate diagnostics
+    pub source: Option<&'o str>,
+    /// Number of quotations the content being processed is in
+    pub(crate) quote_depth: usize,
+    /// Notes found so far, see [`Todo`]
+    pub todos: Vec<Todo>,
+    /// Commands called so far
+    pub command_usage: CommandUsage,
+    /// Diagnostics silenced for the next command by an `%!allow` pragma
+    pub(crate) allow_next: Vec<Code>,
+    /// The first unknown command and where it is in the source, in strict mode
+    pub(crate) unknown_command: Option<(String, Option<Range<usize>>)>,
+    /// Counters numbering things in the document, by name, see [`Context::step_counter`]
+    pub counters: HashMap<String, usize>,
+    /// Labels defined so far, with the text references to them show
+    pub labels: HashMap<String, String>,
+}
+
+impl<'o> Context<'o> {
+    pub fn new(options: &'o EngineOptions) -> Self {
+        Context {
+            metadata: Metadata::default(),
+            options,
+            source: None,
+            quote_depth: 0,
+            todos: Vec::new(),
+            command_usage: CommandUsage::default(),
+            allow_next: Vec::new(),
+            unknown_command: None,
+            counters: HashMap::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Increments the counter `name`, starting from 0, and gives its new value.
+    pub fn step_counter(&mut self, name: &str) -> usize {
+        let counter = self.counters.entry(name.to_owned()).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    /// Formats where `slice` is in the source, as ` at line:column`, to be appended to a
+    /// diagnostic. Empty when the source is unknown.
+    pub fn location(&self, slice: &str) -> String {
+        self.source
+            .and_then(|source| Location::of(source, slice))
+            .map(|location| format!(" at {}", location))
+            .unwrap_or_default()
+    }
+
+    /// Processes the inline content of a command, running the commands it calls.
+    pub fn process_inline(&mut self, content: Stream) -> Vec<Span> {
+        InlineTextProcessor::process_all(self, content)
+    }
+
+    /// Processes the content of a command holding whole blocks, like `\begin{abstract}`.
+    pub fn process_blocks(&mut self, content: Stream) -> Vec<Block> {
+        root(self, content)
+    }
+}
+
+/// What top-level commands give, before text is gathered into paragraphs.
 pub enum RootSpan {
     Text(String),
-    Block(BlockFormat, Vec<Span>),
+    Block(Block),
     Format(SpanFormat, Vec<Span>),
     ParagraphBreak,
     LineBreak,
+    Math(String),
 }
 
 impl From<Span> for RootSpan {
@@ -17,7 +364,8 @@ impl From<Span> for RootSpan {
             Span::Format(f, s) => RootSpan::Format(f, s),
             Span::LineBreak => RootSpan::LineBreak,
             Span::Text(t) => RootSpan::Text(t),
-            Span::Raw(_) => RootSpan::Block(BlockFormat::Raw, vec![span]),
+            Span::Raw(_) => RootSpan::Block(Block::new(BlockFormat::Raw, vec![span])),
+            Span::Math(source) => RootSpan::Math(source),
         }
     }
 }
@@ -45,18 +393,179 @@ fn toplevel_text(t: &str) -> Vec<RootSpan> {
     // Assemble the previous parsers to get whole paragraphs at once
     let text = many1(text_item).map(|res| RootSpan::Text(res.into_iter().collect::<String>()));
 
-    let (_, tokens) = many1(pbreak.or(text))(t).unwrap();
+    // CRLF line breaks are handled like LF ones
+    let t = t.replace("\r\n", "\n");
+    let (_, tokens) = many1(pbreak.or(text))(t.as_str()).unwrap();
     tokens
-        .into_iter()
-        .skip_while(|t| matches!(t, RootSpan::ParagraphBreak))
-        .collect()
+}
+
+/// Normalizes whitespace in a list of inline spans.
+///
+/// * Adjacent text spans are merged together.
+/// * Whitespace runs collapse into a single space, even when they span across the boundary of a
+///   formatted span (`foo \strong{ bar}` does not produce two spaces).
+/// * Whitespace at the start and end of the list, and around line breaks, is removed.
+///
+/// No whitespace is ever inserted, so punctuation directly following a command stays attached to
+/// it. Code and raw spans are left untouched.
+pub fn normalize(spans: Vec<Span>) -> Vec<Span> {
+    let mut out = Vec::new();
+    let mut space = true;
+
+    normalize_into(spans, &mut out, &mut space);
+    trim_end(&mut out);
+    out
+}
+
+fn normalize_into(spans: Vec<Span>, out: &mut Vec<Span>, space: &mut bool) {
+    for span in spans {
+        match span {
+            Span::Text(t) => {
+                let mut text = String::with_capacity(t.len());
+
+                for c in t.chars() {
+                    if c.is_whitespace() {
+                        if !*space {
+                            text.push(' ');
+                            *space = true;
+                        }
+                    } else {
+                        text.push(c);
+                        *space = false;
+                    }
+                }
+
+                if text.is_empty() {
+                    continue;
+                }
+
+                if let Some(Span::Text(prev)) = out.last_mut() {
+                    prev.push_str(&text);
+                } else {
+                    out.push(Span::Text(text));
+                }
+            }
+            Span::Format(SpanFormat::Code, inner) => {
+                out.push(Span::Format(SpanFormat::Code, inner));
+                *space = false;
+            }
+            Span::Format(f, inner) => {
+                let mut normalized = Vec::new();
+                normalize_into(inner, &mut normalized, space);
+                out.push(Span::Format(f, normalized));
+            }
+            Span::LineBreak => {
+                trim_end(out);
+                out.push(Span::LineBreak);
+                *space = true;
+            }
+            Span::Raw(r) => {
+                out.push(Span::Raw(r));
+                *space = false;
+            }
+            Span::Math(source) => {
+                out.push(Span::Math(source));
+                *space = false;
+            }
+        }
+    }
+}
+
+fn trim_end(spans: &mut Vec<Span>) {
+    match spans.last_mut() {
+        Some(Span::Text(t)) => {
+            let len = t.trim_end().len();
+            t.truncate(len);
+
+            if t.is_empty() {
+                spans.pop();
+                trim_end(spans);
+            }
+        }
+        Some(Span::Format(SpanFormat::Code, _)) => (),
+        Some(Span::Format(_, inner)) => trim_end(inner),
+        _ => (),
+    }
+}
+
+/// Writes a stream back as pastex source, for commands using their content as written. Escapes
+/// are resolved by the parser, so `\\` is written back as `\`.
+pub fn source_text(stream: &[Element]) -> String {
+    let mut out = String::new();
+    for el in stream {
+        write_source(el, &mut out);
+    }
+    out
+}
+
+fn write_source(el: &Element, out: &mut String) {
+    match el {
+        Element::Raw(text) => out.push_str(text),
+        Element::Comment(text) => {
+            out.push('%');
+            out.push_str(text);
+        }
+        Element::LineBreak(source) => out.push_str(source),
+        Element::Math { display, source } => {
+            let (open, close) = if *display {
+                ("\\[", "\\]")
+            } else {
+                ("\\(", "\\)")
+            };
+            out.push_str(open);
+            out.push_str(source);
+            out.push_str(close);
+        }
+        Element::Command(cmd) => {
+            let mut name = cmd.command_name().to_string();
+            if cmd.starred {
+                name.push('*');
+            }
+            if cmd.block {
+                out.push_str(&format!("\\begin{{{}}}", name));
+            } else {
+                out.push('\\');
+                out.push_str(&name);
+            }
+
+            if !cmd.params.is_empty() {
+                let mut params: Vec<_> = cmd.params.iter().collect();
+                params.sort_unstable_by_key(|(key, _)| **key);
+
+                let params = params
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        ParamValue::None => key.to_string(),
+                        ParamValue::Text(text) => format!("{} = {}", key, text.trim()),
+                        ParamValue::Stream(s) => format!("{} = {{{}}}", key, source_text(s)),
+                    })
+                    .collect::<Vec<_>>();
+                out.push_str(&format!("[{}]", params.join(", ")));
+            }
+
+            if cmd.block {
+                out.push_str(&source_text(&cmd.content));
+                out.push_str(&format!("\\end{{{}}}", name));
+            } else if !cmd.content.is_empty() || !cmd.arguments.is_empty() {
+                for group in once(&cmd.content).chain(&cmd.arguments) {
+                    out.push('{');
+                    out.push_str(&source_text(group));
+                    out.push('}');
+                }
+            }
+        }
+    }
 }
 
 pub trait TextProcessor: Sized {
     fn process(t: &str) -> Vec<Span>;
 
-    fn process_all(s: Stream) -> Vec<Span> {
-        s.into_iter().map(element::<Self>).flatten().collect()
+    /// Processes inline content. Commands inside it are dispatched through the command registry,
+    /// so formatting can be nested in any inline context.
+    fn process_all(ctx: &mut Context, s: Stream) -> Vec<Span> {
+        s.into_iter()
+            .flat_map(|el| element::<Self>(ctx, el))
+            .collect()
     }
 }
 
@@ -89,16 +598,46 @@ impl TextProcessor for PreserveTextProcessor {
     }
 }
 
-fn element<P: TextProcessor>(el: Element) -> Vec<Span> {
+/// Keeps the codes of an `%!allow` pragma for the next command.
+fn comment(ctx: &mut Context, text: &str) {
+    if let Some((Scope::Next, list)) = diagnostics::pragma(text) {
+        ctx.allow_next.extend(pragma_codes(list));
+    }
+}
+
+/// Gets the codes of the `%!allow-file` pragmas of a document.
+pub(crate) fn file_pragmas(stream: &Stream) -> Vec<Code> {
+    stream
+        .iter()
+        .filter_map(|el| match el {
+            Element::Comment(text) => match diagnostics::pragma(text) {
+                Some((Scope::File, list)) => Some(pragma_codes(list)),
+                _ => None,
+            },
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+fn element<P: TextProcessor>(ctx: &mut Context, el: Element) -> Vec<Span> {
     match el {
         Element::Raw(text) => P::process(text),
-        Element::Comment(_) => Vec::new(),
-        Element::Command(cmd) => crate::commands::run(cmd),
-        Element::LineBreak => vec![Span::LineBreak],
+        Element::Comment(text) => {
+            comment(ctx, text);
+            Vec::new()
+        }
+        Element::Command(cmd) => {
+            let allowed = take(&mut ctx.allow_next);
+            diagnostics::allowing(&allowed, || crate::commands::run(ctx, cmd))
+        }
+        Element::LineBreak(_) => vec![Span::LineBreak],
+        // Display math can't be shown as a block in inline content
+        Element::Math { source, .. } => vec![Span::Math(source.trim().to_owned())],
     }
 }
 
-pub fn root_spans(metadata: &mut Metadata, stream: Stream) -> Vec<RootSpan> {
+pub fn root_spans(ctx: &mut Context, stream: Stream) -> Vec<RootSpan> {
     let mut text_acc = String::new();
     let mut spans = Vec::new();
 
@@ -107,9 +646,11 @@ pub fn root_spans(metadata: &mut Metadata, stream: Stream) -> Vec<RootSpan> {
             Element::Raw(text) => {
                 text_acc.push_str(text);
             }
-            Element::Comment(_) => (),
+            Element::Comment(text) => comment(ctx, text),
             Element::Command(cmd) => {
-                let res = crate::commands::toplevel_run(metadata, cmd);
+                let allowed = take(&mut ctx.allow_next);
+                let res =
+                    diagnostics::allowing(&allowed, || crate::commands::toplevel_run(ctx, cmd));
                 let mut res = if !res.is_empty() && !text_acc.is_empty() {
                     toplevel_text(&take(&mut text_acc))
                         .into_iter()
@@ -121,7 +662,26 @@ pub fn root_spans(metadata: &mut Metadata, stream: Stream) -> Vec<RootSpan> {
 
                 spans.append(&mut res);
             }
-            Element::LineBreak => spans.push(RootSpan::LineBreak),
+            Element::LineBreak(_) => {
+                if !text_acc.is_empty() {
+                    spans.append(&mut toplevel_text(&take(&mut text_acc)));
+                }
+
+                spans.push(RootSpan::LineBreak);
+            }
+            Element::Math { display, source } => {
+                if !text_acc.is_empty() {
+                    spans.append(&mut toplevel_text(&take(&mut text_acc)));
+                }
+
+                let source = source.trim().to_owned();
+                spans.push(match display {
+                    true => {
+                        RootSpan::Block(Block::new(BlockFormat::Math, vec![Span::Text(source)]))
+                    }
+                    false => RootSpan::Math(source),
+                });
+            }
         }
     }
 
@@ -133,8 +693,8 @@ pub fn root_spans(metadata: &mut Metadata, stream: Stream) -> Vec<RootSpan> {
     spans
 }
 
-pub fn root(metadata: &mut Metadata, stream: Stream) -> Vec<Block> {
-    let document = root_spans(metadata, stream);
+pub fn root(ctx: &mut Context, stream: Stream) -> Vec<Block> {
+    let document = root_spans(ctx, stream);
     let mut outline = Vec::new();
     let mut para = Vec::new();
 
@@ -149,27 +709,568 @@ pub fn root(metadata: &mut Metadata, stream: Stream) -> Vec<Block> {
             }
             RootSpan::Format(f, s) => para.push(Span::Format(f, s)),
             RootSpan::LineBreak => para.push(Span::LineBreak),
-            RootSpan::ParagraphBreak => {
-                if !para.is_empty() {
-                    let para = take(&mut para);
-                    outline.push(Block(BlockFormat::Paragraph, para));
-                }
-            }
-            RootSpan::Block(f, s) => {
-                if !para.is_empty() {
-                    let para = take(&mut para);
-                    outline.push(Block(BlockFormat::Paragraph, para));
-                }
+            RootSpan::Math(source) => para.push(Span::Math(source)),
+            RootSpan::ParagraphBreak => paragraph(&mut outline, take(&mut para)),
+            RootSpan::Block(Block(f, s, attributes)) => {
+                paragraph(&mut outline, take(&mut para));
 
-                outline.push(Block(f, s));
+                let s = match f {
+                    BlockFormat::Code
+                    | BlockFormat::Raw
+                    | BlockFormat::Math
+                    | BlockFormat::Output => s,
+                    _ => normalize(s),
+                };
+                outline.push(Block(f, s, attributes));
             }
         }
     }
 
+    paragraph(&mut outline, para);
+    outline
+}
+
+fn paragraph(outline: &mut Vec<Block>, para: Vec<Span>) {
+    let para = normalize(para);
+
     if !para.is_empty() {
-        let para = take(&mut para);
-        outline.push(Block(BlockFormat::Paragraph, para));
+        outline.push(Block::new(BlockFormat::Paragraph, para));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::document::{process_stream, BlockFormat, Span, SpanFormat};
+
+    fn show(spans: &[Span]) -> String {
+        spans
+            .iter()
+            .map(|span| match span {
+                Span::Text(t) => t.clone(),
+                Span::Format(SpanFormat::Code, s) => format!("`{}`", show(s)),
+                Span::Format(SpanFormat::Link(link), s) => format!("[{}]({})", show(s), link.to),
+                Span::Format(_, s) => format!("*{}*", show(s)),
+                Span::LineBreak => "/".to_owned(),
+                Span::Raw(r) => r.clone(),
+                Span::Math(m) => format!("${}$", m),
+            })
+            .collect()
     }
 
-    outline
+    fn paragraphs(source: &str) -> Vec<String> {
+        let document = process_stream(pastex_parser::parse(source).unwrap());
+        document
+            .outline
+            .iter()
+            .map(|block| {
+                assert!(matches!(block.0, BlockFormat::Paragraph));
+                show(&block.1)
+            })
+            .collect()
+    }
+
+    /// Shows every block, prefixing headings with `#` as many times as their level.
+    fn show_outline(source: &str) -> Vec<String> {
+        crate::document::outline(source)
+            .iter()
+            .map(|block| match block.0 {
+                BlockFormat::Heading(level) => format!("{} {}", "#".repeat(level), show(&block.1)),
+                _ => show(&block.1),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spaces_around_command() {
+        assert_eq!(paragraphs(r"foo \strong{bar} baz"), ["foo *bar* baz"]);
+        assert_eq!(paragraphs("foo\n\\strong{bar}\nbaz"), ["foo *bar* baz"]);
+    }
+
+    #[test]
+    fn test_collapse_across_boundaries() {
+        assert_eq!(paragraphs(r"foo \strong{ bar } baz"), ["foo *bar *baz"]);
+        assert_eq!(paragraphs(r"foo   \strong{bar   baz}"), ["foo *bar baz*"]);
+    }
+
+    #[test]
+    fn test_punctuation() {
+        assert_eq!(paragraphs(r"\strong{foo}, bar."), ["*foo*, bar."]);
+        assert_eq!(paragraphs(r"(\code{foo})"), ["(`foo`)"]);
+    }
+
+    #[test]
+    fn test_trim_paragraph() {
+        assert_eq!(paragraphs("  foo \\strong{bar }  "), ["foo *bar*"]);
+        assert_eq!(paragraphs("foo \\\n bar"), ["foo/bar"]);
+        assert_eq!(paragraphs("a\r\nb \\\r\nc\r\n\r\nd"), ["a b/c", "d"]);
+    }
+
+    #[test]
+    fn test_sentence_boundaries() {
+        assert_eq!(
+            paragraphs("First \\strong{one}.\n\n\\strong{Second} one."),
+            ["First *one*.", "*Second* one."]
+        );
+    }
+
+    #[test]
+    fn test_initial() {
+        assert_eq!(paragraphs(r"\initial{L}orem ipsum"), ["*L*orem ipsum"]);
+        assert_eq!(paragraphs(r"\initial{Once} upon"), ["*Once* upon"]);
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(paragraphs(r"\q{Hello \q{world}}"), ["*Hello *world**"]);
+
+        let document = process_stream(
+            pastex_parser::parse(r"\meta:lang{fr-CA}\q{Bonjour \q{monde}}").unwrap(),
+        );
+        assert_eq!(
+            crate::document::plain_text(&document.outline[0].1),
+            "«\u{a0}Bonjour “monde”\u{a0}»"
+        );
+    }
+
+    #[test]
+    fn test_glossary() {
+        use crate::{document::process_stream_with, EngineOptions, GlossaryEntry};
+
+        let mut options = EngineOptions::default();
+        options.add_glossary_term(
+            "API",
+            GlossaryEntry {
+                href: Some("/glossary#api".to_owned()),
+                title: Some("Application Programming Interface".to_owned()),
+            },
+        );
+
+        let source = r"Many \gloss[term = api]{APIs}, one \gloss{API}, no \gloss{ABI}.";
+        let document = process_stream_with(pastex_parser::parse(source).unwrap(), &options);
+        assert_eq!(
+            show(&document.outline[0].1),
+            "Many [*APIs*](/glossary#api), one [*API*](/glossary#api), no ABI."
+        );
+    }
+
+    #[test]
+    fn test_todos() {
+        use crate::{document::process_source, EngineOptions};
+
+        let source = "Text. \\todo{Check this}\n\nMore \\todo{And \\strong{that}}";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+        assert_eq!(show(&document.outline[0].1), "Text. *Check this*");
+
+        let todos: Vec<_> = document
+            .todos
+            .iter()
+            .map(|todo| {
+                (
+                    todo.text.as_str(),
+                    todo.location.map(|l| (l.line, l.column)),
+                )
+            })
+            .collect();
+        assert_eq!(
+            todos,
+            [("Check this", Some((1, 13))), ("And that", Some((3, 12)))]
+        );
+
+        let mut options = EngineOptions::default();
+        options.release(true);
+        let document = process_source(source, &options).unwrap();
+        assert_eq!(show(&document.outline[0].1), "Text.");
+        assert_eq!(document.todos.len(), 2);
+    }
+
+    #[test]
+    fn test_command_usage() {
+        use crate::{document::process_source, EngineOptions};
+
+        let source =
+            "\\meta:title{\\strong{A}}\\meta:draft\n\n\\strong{B} \\strong*{C} \\nope \\x:y";
+        let mut options = EngineOptions::default();
+        options.disable_namespace("x");
+        let usage = process_source(source, &options).unwrap().command_usage;
+
+        assert_eq!(
+            usage.commands().collect::<Vec<_>>(),
+            [
+                ("meta:draft", 1),
+                ("meta:title", 1),
+                ("nope", 1),
+                ("strong", 3),
+                ("x:y", 1)
+            ]
+        );
+        assert_eq!(usage.count("strong"), 3);
+        assert_eq!(usage.count("code"), 0);
+        assert_eq!(
+            usage.namespaces().into_iter().collect::<Vec<_>>(),
+            [(None, 4), (Some("meta"), 2), (Some("x"), 1)]
+        );
+    }
+
+    #[test]
+    fn test_math() {
+        assert_eq!(
+            paragraphs(r"Let \math{x^2 + \alpha} be"),
+            ["Let $x^2 + \\alpha$ be"]
+        );
+
+        let document =
+            process_stream(pastex_parser::parse("\\begin{math}\n  a = b\n\\end{math}").unwrap());
+        assert!(matches!(document.outline[0].0, BlockFormat::Math));
+        assert_eq!(show(&document.outline[0].1), "a = b");
+
+        // Math is kept as written, escapes included
+        assert_eq!(paragraphs(r"\math{a \\ b \% c}"), [r"$a \\ b \% c$"]);
+        let source = r"\begin{math}a \\ b\end{math}";
+        let options = pastex_parser::ParserOptions {
+            verbatim_blocks: Vec::new(),
+            ..Default::default()
+        };
+        let document = process_stream(pastex_parser::parse_with(source, &options).unwrap());
+        assert_eq!(show(&document.outline[0].1), r"a \\ b");
+
+        assert_eq!(
+            paragraphs(r"Let $x^2$ and \strong{\(y\)} cost \$5"),
+            ["Let $x^2$ and *$y$* cost $5"]
+        );
+        assert_eq!(
+            show_outline("See\n$$ a = b $$\nhere"),
+            ["See", "a = b", "here"]
+        );
+        assert_eq!(
+            paragraphs(r"\math{\frac{a}{b} + \operatorname*{x}{}{y}}"),
+            ["$\\frac{a}{b} + \\operatorname*{x}{}{y}$"]
+        );
+    }
+
+    #[test]
+    fn test_code_execution() {
+        use super::{CodeExecutor, EngineOptions};
+        use crate::document::process_stream_with;
+
+        struct Shout;
+        impl CodeExecutor for Shout {
+            fn execute(&self, lang: Option<&str>, source: &str) -> Result<String, String> {
+                Ok(format!(
+                    "{}: {}",
+                    lang.unwrap_or("?"),
+                    source.trim().to_uppercase()
+                ))
+            }
+        }
+
+        let source = "\\begin[run, lang = sh]{code}\necho hi\n\\end{code}";
+        let formats = |options: &EngineOptions| {
+            let document = process_stream_with(pastex_parser::parse(source).unwrap(), options);
+            document
+                .outline
+                .iter()
+                .map(|block| (format!("{:?}", block.0), show(&block.1)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut options = EngineOptions::default();
+        options.executor(Shout);
+        assert_eq!(formats(&options).len(), 1);
+
+        options.allow_execution(true);
+        assert_eq!(
+            formats(&options)[1],
+            ("Output".to_owned(), "sh: ECHO HI".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        use super::{EngineOptions, Profile, UNTRUSTED_MAX_DEPTH, UNTRUSTED_MAX_EXPANSION};
+        use crate::document::process_source;
+
+        let source = "A \\raw{<b>} B";
+        let show_source = |options: &EngineOptions| {
+            let document = process_source(source, options).unwrap();
+            document
+                .outline
+                .iter()
+                .map(|b| show(&b.1))
+                .collect::<Vec<_>>()
+        };
+
+        let mut options = EngineOptions::default();
+        assert_eq!(show_source(&options), ["A", "<b>", "B"]);
+
+        options.allow_execution(true).profile(Profile::Untrusted);
+        assert_eq!(show_source(&options), ["A <b> B"]);
+        assert!(!options.allow_execution);
+
+        let deep = "\\strong{".repeat(UNTRUSTED_MAX_DEPTH + 1);
+        assert!(process_source(&deep, &options).is_err());
+
+        let large = format!(
+            "\\define{{x}}{{a b}}\n\n{}",
+            "\\x ".repeat(UNTRUSTED_MAX_EXPANSION + 1)
+        );
+        assert!(process_source(&large, &options).is_err());
+
+        let links = "\\link[to = javascript:alert(1)]{A} \\link[to = JavaScript&colon;x]{B} \
+            \\link[to = https://example.com]{C} \\link[to = mailto:a@example.com]{D} \
+            \\link[to = ../a#b]{E}";
+        let document = process_source(links, &options).unwrap();
+        assert_eq!(
+            show(&document.outline[0].1),
+            "A B [C](https://example.com) [D](mailto:a@example.com) [E](../a#b)"
+        );
+        let quote = "\\begin[cite = javascript:x]{blockquote}Q\\end{blockquote}";
+        let document = process_source(quote, &options).unwrap();
+        assert!(matches!(
+            document.outline[0].0,
+            BlockFormat::Quote { cite: None, .. }
+        ));
+
+        options.profile(Profile::Trusted);
+        assert!(options.is_raw_allowed());
+        assert!(options.is_url_allowed("javascript:alert(1)"));
+        assert!(process_source(&large, &options).is_ok());
+    }
+
+    #[test]
+    fn test_fragments() {
+        use super::EngineOptions;
+        use crate::document::{process_fragment, process_source};
+
+        let mut options = EngineOptions::default();
+        options.add_fragment("author", process_fragment("By \\strong{me}.").unwrap());
+        assert!(process_fragment("By \\strong{me").is_err());
+        let document =
+            process_source("Intro \\fragment{author} \\fragment{none}", &options).unwrap();
+
+        assert_eq!(
+            document
+                .outline
+                .iter()
+                .map(|b| show(&b.1))
+                .collect::<Vec<_>>(),
+            ["Intro", "By *me*."]
+        );
+    }
+
+    #[test]
+    fn test_strict() {
+        use super::EngineOptions;
+        use crate::document::{process_source, ProcessError};
+
+        let source = "Some \\strong{text} \\bogus[x]{y} \\other";
+        let mut options = EngineOptions::default();
+        assert!(process_source(source, &options).is_ok());
+
+        options.strict(true);
+        match process_source(source, &options) {
+            Err(ProcessError::UnknownCommand { name, span }) => {
+                assert_eq!(name, "bogus");
+                assert_eq!(&source[span.unwrap()], "\\bogus[x]{y}");
+            }
+            _ => panic!("expected an unknown command"),
+        }
+
+        options.disable_namespace("meta");
+        let err = process_source("\\meta:title{T}", &options).err().unwrap();
+        assert_eq!(err.to_string(), "unknown command meta:title");
+        assert_eq!(err.offset("\\meta:title{T}"), 0);
+        assert!(matches!(
+            process_source("\\strong{", &options),
+            Err(ProcessError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_context_state() {
+        use super::{EngineOptions, RootSpan};
+        use crate::commands::{CommandRegistry, Signature};
+        use crate::document::process_source;
+        use crate::output::capabilities::Backend;
+        use pastex_parser::ParamsExt;
+
+        let mut registry = CommandRegistry::new();
+        registry
+            .add_toplevel("figure", Signature::default(), |ctx, content, params, _| {
+                let number = ctx.step_counter("figure");
+                if let Ok(Some(label)) = params.get_str("label") {
+                    ctx.labels.insert(label.to_owned(), number.to_string());
+                }
+                let mut spans = vec![Span::Text(format!("Figure {}: ", number))];
+                spans.extend(ctx.process_inline(content));
+                vec![RootSpan::Format(SpanFormat::Strong, spans)]
+            })
+            .add_inline("ref", Signature::default(), |ctx, content, _, _| {
+                let label = show(&ctx.process_inline(content));
+                let text = ctx
+                    .labels
+                    .get(&label)
+                    .cloned()
+                    .unwrap_or_else(|| "?".into());
+                let file = ctx.options.file_path().map(|p| p.display().to_string());
+                let backend = ctx.options.target_backend();
+                vec![Span::Text(format!("{} {:?} {:?}", text, file, backend))]
+            });
+
+        let source = "\\figure{A}\n\n\\figure[label = b]{B}\n\nSee \\ref{b}, \\ref{c}";
+        let mut options = EngineOptions::default();
+        options
+            .commands(registry)
+            .path("doc.pastex")
+            .target(Backend::Html);
+        let document = process_source(source, &options).unwrap();
+        let blocks: Vec<_> = document.outline.iter().map(|b| show(&b.1)).collect();
+        assert_eq!(
+            blocks,
+            [
+                "*Figure 1: A*",
+                "*Figure 2: B*",
+                "See 2 Some(\"doc.pastex\") Some(Html), ? Some(\"doc.pastex\") Some(Html)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_document_diagnostics() {
+        use super::EngineOptions;
+        use crate::diagnostics::Code;
+        use crate::document::process_source;
+
+        let source =
+            "\\meta:title{A}\n\\meta:title{B}\n\n\\bogus\n\n%!allow(unknown-command)\n\\other";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+        let codes: Vec<_> = document.diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(codes, [Code::DuplicateMetadata, Code::UnknownCommand]);
+        assert!(document.diagnostics[1]
+            .message
+            .starts_with("Unknown command: bogus"));
+
+        let document = process_source("Hello", &EngineOptions::default()).unwrap();
+        assert!(document.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_command_registry() {
+        use super::{EngineOptions, RootSpan};
+        use crate::commands::{CommandRegistry, Content, Form, Signature};
+        use crate::document::{process_source, Block};
+        use pastex_parser::ParamsExt;
+
+        let mut registry = CommandRegistry::new();
+        let signature = Signature {
+            form: Form::Block,
+            content: Content::Blocks,
+            params: &["title"],
+        };
+        registry
+            .add_toplevel("ext:aside", signature, |ctx, content, params, _| {
+                let title = params.get_str("title").ok().flatten().unwrap_or("Aside");
+                let heading = Block::new(BlockFormat::Heading(3), vec![Span::Text(title.into())]);
+                std::iter::once(heading)
+                    .chain(ctx.process_blocks(content))
+                    .map(RootSpan::Block)
+                    .collect()
+            })
+            .add_inline("strong", Signature::default(), |ctx, content, _, _| {
+                vec![Span::Format(SpanFormat::Code, ctx.process_inline(content))]
+            })
+            .remove("todo");
+        assert!(registry.contains("ext:aside"));
+        assert!(!registry.contains("aside"));
+
+        let source =
+            "\\begin[title = Note]{ext:aside}A \\strong{b}\n\nC\\end{ext:aside}\n\n\\todo{x}";
+        let mut options = EngineOptions::default();
+        options.commands(registry);
+        let document = process_source(source, &options).unwrap();
+        let blocks: Vec<_> = document.outline.iter().map(|b| show(&b.1)).collect();
+        assert_eq!(blocks, ["Note", "A `b`", "C", "[[unknown command todo]]"]);
+
+        // Built-in commands are still used by default
+        let document = process_source("\\strong{b}", &EngineOptions::default()).unwrap();
+        assert_eq!(show(&document.outline[0].1), "*b*");
+
+        let mut options = EngineOptions::default();
+        options.commands(CommandRegistry::empty());
+        let document = process_source("\\strong{b}", &options).unwrap();
+        assert_eq!(
+            document.outline,
+            [Block::new(
+                BlockFormat::Paragraph,
+                vec![Span::Text("[[unknown command strong]]".into())]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_verbatim_blocks() {
+        let source = "\\begin{code}\nif (a) { b(\"\\\\\"); } // 100%\n\\end{code}\n\n\
+            \\begin{verbatim}\\foo{}\\end{verbatim}\n\n\\begin{math}x^{2}\\end{math}";
+        let document = process_stream(pastex_parser::parse(source).unwrap());
+        let blocks = document
+            .outline
+            .iter()
+            .map(|block| (format!("{:?}", block.0), show(&block.1)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            blocks,
+            [
+                (
+                    "Code".to_owned(),
+                    "if (a) { b(\"\\\\\"); } // 100%\n".to_owned()
+                ),
+                ("Code".to_owned(), "\\foo{}".to_owned()),
+                ("Math".to_owned(), "x^{2}".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verbatim_content() {
+        assert_eq!(
+            paragraphs(r"Call \code|f(\x{}, 50%)| now"),
+            ["Call `f(\\x{}, 50%)` now"]
+        );
+        assert_eq!(paragraphs(r"Set \math|\{x\}| here"), ["Set $\\{x\\}$ here"]);
+        assert_eq!(paragraphs(r"Use \verb+a|b}+ now"), ["Use `a|b}` now"]);
+    }
+
+    #[test]
+    fn test_nested_in_heading() {
+        assert_eq!(
+            show_outline(r"\head1{Intro to \code{Vec}}"),
+            ["# Intro to `Vec`"]
+        );
+        assert_eq!(
+            show_outline(r"\head2{A \strong{very \code{deep}} \link[to = /x]{title}}"),
+            ["## A *very `deep`* [title](/x)"]
+        );
+    }
+
+    #[test]
+    fn test_nested_in_link() {
+        assert_eq!(
+            paragraphs(r"See \link[to = /doc]{the \strong{\code{Vec}} docs}."),
+            ["See [the *`Vec`* docs](/doc)."]
+        );
+        assert_eq!(
+            paragraphs(r"\strong{\link[to = /a]{\strong{\link[to = /b]{deep}}}}"),
+            ["*[*[deep](/b)*](/a)*"]
+        );
+    }
+
+    #[test]
+    fn test_nested_in_metadata() {
+        let document = process_stream(
+            pastex_parser::parse(r"\meta:title{On \strong{nested \code{commands}}}").unwrap(),
+        );
+        let title = document.metadata.title.unwrap();
+        assert_eq!(title.plain, "On nested commands");
+        assert_eq!(show(&title.formatted), "On *nested `commands`*");
+    }
 }
@@ -1,4 +1,17 @@
 mod commands;
+pub mod diagnostics;
+pub mod diff;
 pub mod document;
 mod engine;
+pub mod lint;
+mod locale;
 pub mod output;
+mod split;
+mod svg;
+mod trace;
+
+pub use commands::{CommandRegistry, Content, Form, Signature};
+pub use engine::{
+    CodeExecutor, Context, EngineOptions, GlossaryEntry, Profile, RootSpan, UNTRUSTED_MAX_DEPTH,
+};
+pub use split::{split, BodySource, MetadataSource};
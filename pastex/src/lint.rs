@@ -0,0 +1,328 @@
+//! Checks of processed documents beyond syntax, like headings skipping levels or empty links.
+//!
+//! A [`Linter`] runs a set of [`Rule`]s, the built-in ones by default, and gives their
+//! [`Finding`]s. Rules can be disabled or replaced by name, and new ones added by implementing
+//! [`Rule`].
+
+use crate::diagnostics::{self, Code};
+use crate::document::{
+    all_blocks, plain_text, toc, Block, BlockFormat, Document, Span, SpanFormat,
+};
+use pastex_parser::Location;
+use std::collections::HashSet;
+
+/// A problem found by a lint rule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    /// Name of the rule which found the problem
+    pub rule: &'static str,
+    /// Diagnostic code of the problem, for built-in rules
+    pub code: Option<Code>,
+    pub message: String,
+    /// Where the problem is in the source, when known
+    pub location: Option<Location>,
+}
+
+impl Finding {
+    /// A finding of the built-in rule reporting `code`.
+    fn builtin(code: Code, message: String) -> Self {
+        Finding {
+            rule: code.name(),
+            code: Some(code),
+            message,
+            location: None,
+        }
+    }
+}
+
+/// What a rule is given to check.
+pub struct LintContext<'a> {
+    pub document: &'a Document,
+    /// The source of the document, when available, for rules looking at how it is written
+    pub source: Option<&'a str>,
+}
+
+/// A lint rule, checking a document for a kind of problem.
+pub trait Rule: Send + Sync {
+    /// Name of the rule, used to disable it. Built-in rules use the name of their diagnostic code.
+    fn name(&self) -> &'static str;
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>);
+}
+
+/// Headings skipping a level, like a level 3 heading directly following a level 1.
+pub struct HeadingJump;
+
+impl Rule for HeadingJump {
+    fn name(&self) -> &'static str {
+        Code::HeadingJump.name()
+    }
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>) {
+        let mut previous = None;
+
+        for entry in toc(&cx.document.outline) {
+            match previous {
+                Some(previous) if entry.level > previous + 1 => {
+                    findings.push(Finding::builtin(
+                        Code::HeadingJump,
+                        format!(
+                            "Heading {:?} jumps from level {} to level {}",
+                            entry.title, previous, entry.level
+                        ),
+                    ));
+                }
+                _ => (),
+            }
+
+            previous = Some(entry.level);
+        }
+    }
+}
+
+/// Paragraphs with more words than `max_words`.
+pub struct LongParagraph {
+    pub max_words: usize,
+}
+
+impl Default for LongParagraph {
+    fn default() -> Self {
+        LongParagraph { max_words: 250 }
+    }
+}
+
+impl Rule for LongParagraph {
+    fn name(&self) -> &'static str {
+        Code::LongParagraph.name()
+    }
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>) {
+        for Block(format, content, _) in all_blocks(&cx.document.outline) {
+            if *format != BlockFormat::Paragraph {
+                continue;
+            }
+
+            let text = plain_text(content);
+            let words = text.split_whitespace().count();
+            if words > self.max_words {
+                let start: String = text.chars().take(30).collect();
+                findings.push(Finding::builtin(
+                    Code::LongParagraph,
+                    format!(
+                        "Paragraph starting with {:?} has {} words, more than {}",
+                        start, words, self.max_words
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+/// Links without any text.
+pub struct EmptyLink;
+
+impl EmptyLink {
+    fn check_spans(spans: &[Span], findings: &mut Vec<Finding>) {
+        for span in spans {
+            match span {
+                Span::Format(SpanFormat::Link(link), inner)
+                    if plain_text(inner).trim().is_empty() =>
+                {
+                    findings.push(Finding::builtin(
+                        Code::EmptyLink,
+                        format!("Link to {} has no text", link.to),
+                    ));
+                }
+                Span::Format(_, inner) => Self::check_spans(inner, findings),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Rule for EmptyLink {
+    fn name(&self) -> &'static str {
+        Code::EmptyLink.name()
+    }
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>) {
+        for Block(_, content, _) in all_blocks(&cx.document.outline) {
+            Self::check_spans(content, findings);
+        }
+    }
+}
+
+/// Headings given the same id with `[id = ...]`.
+pub struct DuplicateId;
+
+impl Rule for DuplicateId {
+    fn name(&self) -> &'static str {
+        Code::DuplicateId.name()
+    }
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>) {
+        let mut seen = HashSet::new();
+
+        for Block(_, _, attributes) in all_blocks(&cx.document.outline) {
+            if let Some(id) = &attributes.id {
+                if !seen.insert(id) {
+                    findings.push(Finding::builtin(
+                        Code::DuplicateId,
+                        format!("Id {:?} is given to several headings", id),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Lines of the source ending with spaces or tabs.
+pub struct TrailingWhitespace;
+
+impl Rule for TrailingWhitespace {
+    fn name(&self) -> &'static str {
+        Code::TrailingWhitespace.name()
+    }
+
+    fn check(&self, cx: &LintContext, findings: &mut Vec<Finding>) {
+        let source = match cx.source {
+            Some(source) => source,
+            None => return,
+        };
+
+        for line in source.split('\n') {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let trimmed = line.trim_end_matches([' ', '\t']);
+
+            if trimmed.len() < line.len() {
+                let location = Location::of(source, &line[trimmed.len()..]);
+                findings.push(Finding {
+                    location,
+                    ..Finding::builtin(Code::TrailingWhitespace, "Trailing whitespace".to_owned())
+                });
+            }
+        }
+    }
+}
+
+/// Runs lint rules over documents.
+pub struct Linter {
+    rules: Vec<Box<dyn Rule>>,
+    disabled: HashSet<String>,
+}
+
+impl Default for Linter {
+    /// A linter with all the built-in rules.
+    fn default() -> Self {
+        Linter {
+            rules: vec![
+                Box::new(HeadingJump),
+                Box::new(LongParagraph::default()),
+                Box::new(EmptyLink),
+                Box::new(DuplicateId),
+                Box::new(TrailingWhitespace),
+            ],
+            disabled: HashSet::new(),
+        }
+    }
+}
+
+impl Linter {
+    /// A linter without any rule.
+    pub fn empty() -> Self {
+        Linter {
+            rules: Vec::new(),
+            disabled: HashSet::new(),
+        }
+    }
+
+    /// Adds a rule, replacing the one with the same name if any. This is how built-in rules are
+    /// configured, as `rule(LongParagraph { max_words: 100 })`.
+    pub fn rule(&mut self, rule: impl Rule + 'static) -> &mut Self {
+        self.rules.retain(|r| r.name() != rule.name());
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// Disables the rule with the given name.
+    pub fn disable(&mut self, name: &str) -> &mut Self {
+        self.disabled.insert(name.to_owned());
+        self
+    }
+
+    /// Enables back a rule previously disabled with [`Linter::disable`].
+    pub fn enable(&mut self, name: &str) -> &mut Self {
+        self.disabled.remove(name);
+        self
+    }
+
+    /// Checks a document with all the enabled rules. `source` is needed by the rules looking at
+    /// how the document is written, which are skipped without it.
+    pub fn run(&self, document: &Document, source: Option<&str>) -> Vec<Finding> {
+        let cx = LintContext { document, source };
+        let mut findings = Vec::new();
+
+        for rule in &self.rules {
+            if !self.disabled.contains(rule.name()) {
+                rule.check(&cx, &mut findings);
+            }
+        }
+
+        findings
+    }
+}
+
+/// Logs findings like other diagnostics.
+pub fn report(findings: &[Finding]) {
+    for finding in findings {
+        let location = finding
+            .location
+            .map(|location| format!(" at {}", location))
+            .unwrap_or_default();
+
+        match finding.code {
+            Some(code) => diagnostics::emit(code, format_args!("{}{}", finding.message, location)),
+            None => log::warn!("[{}] {}{}", finding.rule, finding.message, location),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::process_source;
+    use crate::EngineOptions;
+
+    #[test]
+    fn test_lint() {
+        let source = "\\head1[id = a]{A}\n\n\\head3[id = a]{B}  \n\nSee \\link[to = /x]{}.\n\n\
+            One two three four.";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+        let mut linter = Linter::default();
+        linter.rule(LongParagraph { max_words: 3 });
+
+        let codes = |findings: Vec<Finding>| -> Vec<_> {
+            findings.into_iter().filter_map(|f| f.code).collect()
+        };
+        let findings = linter.run(&document, Some(source));
+        assert_eq!(findings[3].location.unwrap().line, 3);
+        assert_eq!(
+            codes(findings),
+            [
+                Code::HeadingJump,
+                Code::EmptyLink,
+                Code::DuplicateId,
+                Code::TrailingWhitespace,
+                Code::LongParagraph,
+            ]
+        );
+
+        linter
+            .disable("trailing-whitespace")
+            .disable("long-paragraph");
+        assert_eq!(
+            codes(linter.run(&document, None)),
+            [Code::HeadingJump, Code::EmptyLink, Code::DuplicateId]
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! Language-dependent typography.
+
+/// Gets the primary subtag of a language tag, e.g. `fr` for `fr-CA`.
+fn primary(lang: &str) -> String {
+    lang.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Opening and closing quotation marks for the given language, alternating between the primary
+/// and secondary style as quotes get nested. `depth` starts at 0 for the outermost quote.
+pub fn quotes(lang: Option<&str>, depth: usize) -> (&'static str, &'static str) {
+    let styles = match primary(lang.unwrap_or("en")).as_str() {
+        "fr" => [("«\u{a0}", "\u{a0}»"), ("“", "”")],
+        "de" => [("„", "“"), ("‚", "‘")],
+        "es" | "it" | "pt" | "ru" => [("«", "»"), ("“", "”")],
+        "ja" | "zh" => [("「", "」"), ("『", "』")],
+        _ => [("“", "”"), ("‘", "’")],
+    };
+
+    styles[depth % 2]
+}
+
+/// Thousands separator and decimal mark for the given language.
+fn separators(lang: Option<&str>) -> (&'static str, &'static str) {
+    match primary(lang.unwrap_or("en")).as_str() {
+        "fr" => ("\u{202f}", ","),
+        "de" | "es" | "it" | "pt" | "nl" | "da" => (".", ","),
+        "ru" | "pl" | "cs" | "sv" | "fi" | "nb" => ("\u{a0}", ","),
+        _ => (",", "."),
+    }
+}
+
+/// Formats a decimal number written as `-1234.5`, grouping thousands and using the decimal mark
+/// of the given language. The digits are kept as written, so no precision is lost. Returns `None`
+/// if the text is not a number.
+pub fn number(lang: Option<&str>, text: &str) -> Option<String> {
+    let text = text.trim();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text),
+    };
+    let (int, fraction) = match digits.split_once('.') {
+        Some((int, fraction)) => (int, Some(fraction)),
+        None => (digits, None),
+    };
+
+    let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(int) || matches!(fraction, Some(f) if !is_digits(f)) {
+        return None;
+    }
+
+    let (group, decimal) = separators(lang);
+    let mut out = sign.to_owned();
+
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            out.push_str(group);
+        }
+        out.push(c);
+    }
+
+    if let Some(fraction) = fraction {
+        out.push_str(decimal);
+        out.push_str(fraction);
+    }
+
+    Some(out)
+}
+
+/// Formats a value with at most `decimals` decimals, without trailing zeros.
+fn decimal(lang: Option<&str>, value: f64, decimals: usize) -> String {
+    let text = format!("{:.*}", decimals, value);
+    let text = if text.contains('.') {
+        text.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        &text
+    };
+
+    number(lang, text).unwrap_or_else(|| text.to_owned())
+}
+
+/// Formats a size in bytes with the largest fitting unit, as `1.5 MB`, or `1.5 MiB` when `binary`.
+pub fn size(lang: Option<&str>, bytes: f64, binary: bool) -> String {
+    let (base, units) = if binary {
+        (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"])
+    } else {
+        (1000.0, ["B", "kB", "MB", "GB", "TB", "PB"])
+    };
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value.abs() >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+
+    format!("{}\u{a0}{}", decimal(lang, value, 1), units[unit])
+}
+
+/// Formats a duration in seconds. Durations under a minute use a single unit, down to
+/// nanoseconds (`4.2 ms`), longer ones are split in hours, minutes and seconds (`1 h 2 min 5 s`).
+pub fn duration(lang: Option<&str>, seconds: f64) -> String {
+    if seconds.abs() < 60.0 {
+        let (value, unit) = match seconds.abs() {
+            s if s == 0.0 || s >= 1.0 => (seconds, "s"),
+            s if s >= 1e-3 => (seconds * 1e3, "ms"),
+            s if s >= 1e-6 => (seconds * 1e6, "µs"),
+            _ => (seconds * 1e9, "ns"),
+        };
+
+        return format!("{}\u{a0}{}", decimal(lang, value, 2), unit);
+    }
+
+    let total = seconds.abs().round() as u64;
+    let parts = [
+        (total / 3600, "h"),
+        (total / 60 % 60, "min"),
+        (total % 60, "s"),
+    ];
+    let text = parts
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}\u{a0}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if seconds < 0.0 {
+        format!("-{}", text)
+    } else {
+        text
+    }
+}
+
+/// Formats a duration in seconds as a timecode, `1:02:05` or `2:05` under an hour.
+pub fn timecode(seconds: f64) -> String {
+    let total = seconds.abs().round() as u64;
+    let sign = if seconds < 0.0 && total > 0 { "-" } else { "" };
+    let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+
+    if hours > 0 {
+        format!("{}{}:{:02}:{:02}", sign, hours, minutes, seconds)
+    } else {
+        format!("{}{}:{:02}", sign, minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number(None, "1234567").unwrap(), "1,234,567");
+        assert_eq!(number(Some("de"), "-1234.50").unwrap(), "-1.234,50");
+        assert_eq!(number(Some("fr-FR"), "1234").unwrap(), "1\u{202f}234");
+        assert_eq!(number(None, "123").unwrap(), "123");
+        assert_eq!(number(None, "12a"), None);
+        assert_eq!(number(None, "1."), None);
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(size(None, 512.0, false), "512\u{a0}B");
+        assert_eq!(size(None, 1_500_000.0, false), "1.5\u{a0}MB");
+        assert_eq!(size(Some("fr"), 1536.0, true), "1,5\u{a0}KiB");
+        assert_eq!(size(None, 2048.0, true), "2\u{a0}KiB");
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(duration(None, 0.0042), "4.2\u{a0}ms");
+        assert_eq!(duration(None, 12.5), "12.5\u{a0}s");
+        assert_eq!(duration(None, 3725.0), "1\u{a0}h 2\u{a0}min 5\u{a0}s");
+        assert_eq!(timecode(3725.0), "1:02:05");
+        assert_eq!(timecode(125.4), "2:05");
+    }
+}
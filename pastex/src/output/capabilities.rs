@@ -0,0 +1,222 @@
+//! What each output format can represent, so documents can be adapted to the one they are written
+//! to.
+//!
+//! Processing a document with a target set by [`EngineOptions::target`](crate::EngineOptions::target)
+//! warns about the features the target can't represent, once per feature, and replaces them with
+//! a fallback, see [`adapt`].
+
+use crate::diagnostics::diagnostic;
+use crate::document::{all_blocks, Block, BlockFormat, Span, SpanFormat};
+use std::{collections::BTreeSet, fmt, mem::take};
+
+/// A feature of documents which not every output format can represent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Inline and display math, shown as its source when not supported
+    Math,
+    /// Content of `\raw`, left out when not supported
+    RawHtml,
+    /// SVG icons, replaced by their title when not supported
+    Icons,
+}
+
+impl Capability {
+    /// Every capability.
+    pub const ALL: &'static [Capability] =
+        &[Capability::Math, Capability::RawHtml, Capability::Icons];
+
+    /// What is done with the feature when the output format can't represent it.
+    fn fallback(self) -> &'static str {
+        match self {
+            Capability::Math => "it is shown as its source",
+            Capability::RawHtml => "it is left out",
+            Capability::Icons => "they are replaced by their title",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Math => "math",
+            Capability::RawHtml => "raw HTML",
+            Capability::Icons => "icons",
+        })
+    }
+}
+
+/// An output format of processed documents, one of the modules of [`output`](super).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Backend {
+    Html,
+    /// JSON keeps the whole document model, so it supports everything
+    Json,
+    Ssml,
+    Text,
+}
+
+impl Backend {
+    /// The features documents written to this format can use.
+    pub fn capabilities(self) -> &'static [Capability] {
+        match self {
+            Backend::Html | Backend::Json => Capability::ALL,
+            Backend::Ssml | Backend::Text => &[],
+        }
+    }
+
+    pub fn supports(self, capability: Capability) -> bool {
+        self.capabilities().contains(&capability)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Html => "HTML",
+            Backend::Json => "JSON",
+            Backend::Ssml => "SSML",
+            Backend::Text => "Text",
+        })
+    }
+}
+
+fn used_in_spans(spans: &[Span], used: &mut BTreeSet<Capability>) {
+    for span in spans {
+        match span {
+            // The content of icons is their SVG, not raw HTML from the document
+            Span::Format(SpanFormat::Icon { .. }, _) => {
+                used.insert(Capability::Icons);
+            }
+            Span::Format(_, inner) => used_in_spans(inner, used),
+            Span::Raw(_) => {
+                used.insert(Capability::RawHtml);
+            }
+            Span::Math(_) => {
+                used.insert(Capability::Math);
+            }
+            Span::Text(_) | Span::LineBreak => (),
+        }
+    }
+}
+
+/// The features used by blocks, to check them against [`Backend::capabilities`].
+pub fn used_capabilities(fragment: &[Block]) -> BTreeSet<Capability> {
+    let mut used = BTreeSet::new();
+    for Block(format, content, _) in all_blocks(fragment) {
+        match format {
+            BlockFormat::Math => {
+                used.insert(Capability::Math);
+            }
+            _ => used_in_spans(content, &mut used),
+        }
+    }
+    used
+}
+
+/// Replaces the spans `backend` can't represent with their fallback.
+fn adapt_spans(spans: &mut Vec<Span>, backend: Backend) {
+    for span in take(spans) {
+        match span {
+            Span::Format(SpanFormat::Icon { title }, _) if !backend.supports(Capability::Icons) => {
+                spans.extend(title.map(Span::Text))
+            }
+            // Icons hold their SVG, which is kept with them
+            Span::Format(format, mut inner) if !matches!(format, SpanFormat::Icon { .. }) => {
+                adapt_spans(&mut inner, backend);
+                spans.push(Span::Format(format, inner));
+            }
+            Span::Raw(_) if !backend.supports(Capability::RawHtml) => (),
+            span => spans.push(span),
+        }
+    }
+}
+
+/// Replaces the content of blocks `backend` can't represent with its fallback, in the blocks they
+/// hold as well.
+fn adapt_blocks(fragment: &mut Vec<Block>, backend: Backend) {
+    for Block(format, content, _) in fragment.iter_mut() {
+        match format {
+            BlockFormat::Math => (),
+            BlockFormat::Quote { blocks, .. } => adapt_blocks(blocks, backend),
+            BlockFormat::List { items, .. } => {
+                for item in items {
+                    adapt_blocks(item, backend);
+                }
+            }
+            _ => adapt_spans(content, backend),
+        }
+    }
+    fragment.retain(|Block(format, content, _)| *format != BlockFormat::Raw || !content.is_empty());
+}
+
+/// Adapts blocks to what `backend` can represent, replacing the features it does not support with
+/// their fallback, and warning once for each of them.
+pub fn adapt(fragment: &mut Vec<Block>, backend: Backend) {
+    let used = used_capabilities(fragment);
+    if used.iter().all(|capability| backend.supports(*capability)) {
+        return;
+    }
+
+    adapt_blocks(fragment, backend);
+
+    for capability in used {
+        if !backend.supports(capability) {
+            diagnostic!(
+                Unsupported,
+                "{} output can't represent {}, {}",
+                backend,
+                capability,
+                capability.fallback()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{plain_text, process_source};
+    use crate::EngineOptions;
+
+    #[test]
+    fn test_capabilities() {
+        let source = "Some $x$ \\strong{\\raw{<b>}bold} text.\n\n\\raw{<hr>}\n\n$$y$$";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+        assert_eq!(
+            used_capabilities(&document.outline),
+            BTreeSet::from([Capability::Math, Capability::RawHtml])
+        );
+        assert!(Backend::Html.supports(Capability::RawHtml));
+        assert!(!Backend::Text.supports(Capability::Math));
+
+        let mut options = EngineOptions::default();
+        options.target(Backend::Text);
+        assert!(!options.supports(Capability::RawHtml));
+        let document = process_source(source, &options).unwrap();
+        assert_eq!(document.outline.len(), 2);
+        assert_eq!(plain_text(&document.outline[0].1), "Some x bold text.");
+        assert_eq!(
+            used_capabilities(&document.outline),
+            BTreeSet::from([Capability::Math])
+        );
+
+        let mut fragment = vec![Block::new(
+            BlockFormat::Paragraph,
+            vec![
+                Span::Format(
+                    SpanFormat::Icon {
+                        title: Some("Home".into()),
+                    },
+                    vec![Span::Raw("<svg/>".into())],
+                ),
+                Span::Format(SpanFormat::Icon { title: None }, vec![]),
+            ],
+        )];
+        assert_eq!(
+            used_capabilities(&fragment),
+            BTreeSet::from([Capability::Icons])
+        );
+        adapt(&mut fragment, Backend::Ssml);
+        assert_eq!(fragment[0].1, [Span::Text("Home".into())]);
+    }
+}
@@ -1,67 +1,816 @@
-use crate::document::{metadata::Metadata, Block, BlockFormat, Document, Span, SpanFormat};
+use crate::diagnostics::diagnostic;
+use crate::diff::{diff_blocks, diff_words, Change};
+use crate::document::{
+    all_blocks, check_heading_levels, metadata::Metadata, paragraph_ids, plain_text, toc, Block,
+    BlockFormat, Direction, Document, Link, Span, SpanFormat, TocEntry,
+};
+use crate::trace;
 use dolmen::{prelude::*, Fragment, RawFragment};
 use dolmen_dsl::element as tag;
-use std::iter::once;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet, HashMap},
+    fmt::Write,
+    hash::{Hash, Hasher},
+    iter::once,
+    mem::take,
+};
+
+pub(super) fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub(super) fn escape_text_into(out: &mut String, text: &str) {
+    let mut rest = text;
+
+    while let Some(pos) = rest.find(['&', '<', '>']) {
+        out.push_str(&rest[..pos]);
+        out.push_str(match rest.as_bytes()[pos] {
+            b'&' => "&amp;",
+            b'<' => "&lt;",
+            _ => "&gt;",
+        });
+        rest = &rest[pos + 1..];
+    }
+
+    out.push_str(rest);
+}
+
+/// Attributes written by [`dynamic_tag`], whose values are escaped text. Attributes running code,
+/// like `onclick`, or loading resources, like `src`, are not allowed.
+const DYNAMIC_ATTRIBUTES: [&str; 14] = [
+    "aria-hidden",
+    "aria-label",
+    "cite",
+    "class",
+    "data-enhance",
+    "dir",
+    "download",
+    "href",
+    "id",
+    "lang",
+    "rel",
+    "role",
+    "target",
+    "title",
+];
+
+/// Writes `attributes` as in an opening tag, skipping the ones with a [`None`] value. Panics on
+/// an attribute which is not in [`DYNAMIC_ATTRIBUTES`].
+fn write_attributes(out: &mut String, attributes: &[(&str, Option<&str>)]) {
+    for (key, value) in attributes {
+        assert!(
+            DYNAMIC_ATTRIBUTES.contains(key),
+            "attribute {} can't be written",
+            key
+        );
+        if let Some(value) = value {
+            write!(out, " {}=\"{}\"", key, escape_attribute(value)).unwrap();
+        }
+    }
+}
+
+/// Builds an element whose attributes are only known at runtime, or can't be written with the
+/// `tag!` macro (like `aria-*` attributes). Attributes with a [`None`] value are skipped.
+fn dynamic_tag(name: &str, attributes: &[(&str, Option<&str>)], inner: Fragment) -> Box<dyn Node> {
+    let mut open = format!("<{}", name);
+    write_attributes(&mut open, attributes);
+    open.push('>');
+    let close = format!("</{}>", name);
+
+    Fragment::new([
+        unsafe { RawFragment::new(&open) }.into_node(),
+        inner.into_node(),
+        unsafe { RawFragment::new(&close) }.into_node(),
+    ])
+    .into_node()
+}
+
+/// Rendering options used down to the block and span level, taken from [`HtmlOptions`].
+#[derive(Clone, Copy, Default)]
+struct Flags<'o> {
+    /// See [`HtmlOptions::enhancement_hooks`]
+    hooks: bool,
+    /// Whether the [`Profile::Print`] profile is used
+    print: bool,
+    /// See [`HtmlOptions::id_prefix`]
+    id_prefix: Option<&'o str>,
+}
+
+impl<'o> From<&'o HtmlOptions> for Flags<'o> {
+    fn from(options: &'o HtmlOptions) -> Self {
+        Flags {
+            hooks: options.enhancement_hooks,
+            print: options.profile == Profile::Print,
+            id_prefix: options.id_prefix.as_deref(),
+        }
+    }
+}
+
+impl Flags<'_> {
+    /// The id to give to an element, with the prefix if any.
+    fn id(&self, id: &str) -> String {
+        match self.id_prefix {
+            Some(prefix) => format!("{}-{}", prefix, id),
+            None => id.to_owned(),
+        }
+    }
+
+    /// The destination of a link, pointing to the prefixed id for links within the document.
+    fn link(&self, to: &str) -> String {
+        match to.strip_prefix('#') {
+            Some(id) if self.id_prefix.is_some() => format!("#{}", self.id(id)),
+            _ => to.to_owned(),
+        }
+    }
+}
 
 fn span(s: &Span) -> Box<dyn Node> {
+    span_with(s, Flags::default())
+}
+
+fn span_with(s: &Span, flags: Flags) -> Box<dyn Node> {
     match s {
         Span::Text(t) => t.into_node(),
         Span::Format(f, t) => {
-            let inner = Fragment::new(t.iter().map(span));
+            let inner = Fragment::new(t.iter().map(|s| span_with(s, flags)));
 
             match f {
                 SpanFormat::Code => tag!(code {{ inner }}),
                 SpanFormat::Strong => tag!(strong {{ inner }}),
-                SpanFormat::Link { to, blank } if *blank => {
-                    tag!(a[href: {to.clone()}, target: "_blank", rel: "noopener noreferrer"] {{ inner }})
+                SpanFormat::Link(link) => return link_tag(link, flags, inner),
+                SpanFormat::Time(datetime) => tag!(time[datetime: {datetime.clone()}] {{ inner }}),
+                SpanFormat::Initial => tag!(span[class: "initial"] {{ inner }}),
+                // Browsers add the quotation marks matching the document language
+                SpanFormat::Quote { .. } => tag!(q {{ inner }}),
+                SpanFormat::Abbreviation { title } => {
+                    tag!(abbr[title: {title.clone()}] {{ inner }})
+                }
+                SpanFormat::Todo => tag!(mark[class: "todo"] {{ inner }}),
+                SpanFormat::Icon { title: Some(title) } => {
+                    let attributes = [
+                        ("class", Some("icon")),
+                        ("role", Some("img")),
+                        ("aria-label", Some(title.as_str())),
+                    ];
+                    return dynamic_tag("span", &attributes, inner);
+                }
+                SpanFormat::Icon { title: None } => {
+                    let attributes = [("class", Some("icon")), ("aria-hidden", Some("true"))];
+                    return dynamic_tag("span", &attributes, inner);
                 }
-                SpanFormat::Link { to, .. } => tag!(a[href: {to.clone()}] {{ inner }}),
             }
             .into_node()
         }
         Span::LineBreak => tag!(br).into_node(),
         Span::Raw(r) => unsafe { RawFragment::new(r) }.into_node(),
+        Span::Math(source) => tag!(span[class: "math"] {{ source }}).into_node(),
     }
 }
 
-fn heading(level: usize, inner: Fragment) -> Box<dyn Node> {
-    match level {
-        1 => tag!(h2 {{ inner }}),
-        2 => tag!(h3 {{ inner }}),
-        3 => tag!(h4 {{ inner }}),
-        _ => unimplemented!(),
+fn link_tag(link: &Link, flags: Flags, inner: Fragment) -> Box<dyn Node> {
+    // Links within the document are not worth printing
+    let print_url = flags.print && !link.to.starts_with('#');
+    let href = match print_url {
+        true => link.to.clone(),
+        false => flags.link(&link.to),
+    };
+    // New tabs make no sense on paper
+    let blank = link.blank && !flags.print;
+
+    let rel = link
+        .rel
+        .as_deref()
+        .into_iter()
+        .chain(link.nofollow.then_some("nofollow"))
+        .chain(blank.then_some("noopener noreferrer"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let attributes = [
+        ("href", Some(href.as_str())),
+        ("class", print_url.then_some("print-url")),
+        ("target", blank.then_some("_blank")),
+        ("rel", Some(rel.as_str()).filter(|rel| !rel.is_empty())),
+        ("title", link.title.as_deref()),
+        ("download", link.download.as_deref()),
+    ];
+
+    dynamic_tag("a", &attributes, inner)
+}
+
+/// Deepest heading level, written as `<h6>` since `<h1>` is left to the title of the document.
+const MAX_HEADING_LEVEL: usize = 5;
+
+fn heading(level: usize, id: String, dir: Option<&str>, inner: Fragment) -> Box<dyn Node> {
+    let name = match level {
+        1 => "h2",
+        2 => "h3",
+        3 => "h4",
+        4 => "h5",
+        MAX_HEADING_LEVEL => "h6",
+        _ => {
+            diagnostic!(
+                HeadingLevel,
+                "Heading level {} can't be written in HTML, level {} is used instead",
+                level,
+                MAX_HEADING_LEVEL
+            );
+            "h6"
+        }
+    };
+
+    let id = Some(id.as_str()).filter(|id| !id.is_empty());
+    dynamic_tag(name, &[("id", id), ("dir", dir)], inner)
+}
+
+/// Renders a paragraph only made of text in a single buffer, which is much faster than going
+/// through a node for each span in long prose documents.
+fn text_paragraph(content: &[Span], attributes: &[(&str, Option<&str>)]) -> Box<dyn Node> {
+    let mut html = String::with_capacity(estimate_spans(content) + 32);
+
+    html.push_str("<p");
+    write_attributes(&mut html, attributes);
+    html.push('>');
+    for s in content {
+        if let Span::Text(t) = s {
+            escape_text_into(&mut html, t);
+        }
     }
-    .into_node()
+    html.push_str("</p>");
+
+    // Everything written above is escaped
+    unsafe { RawFragment::new(&html) }.into_node()
 }
 
-fn block(block: &Block) -> Box<dyn Node> {
-    let Block(format, content) = block;
-    let inner = Fragment::new(content.iter().map(span));
+/// A script-driven feature the output can be progressively enhanced with, see
+/// [`HtmlOptions::enhancement_hooks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Enhancement {
+    /// A button copying the content of code blocks
+    CopyCode,
+    /// Rendering of math, written as its source in `.math` elements
+    Math,
+}
 
-    match format {
-        &BlockFormat::Paragraph => tag!(p {{ inner }}).into_node(),
-        &BlockFormat::Code => tag!(pre {
-            code[class: "code-block"] {{ inner }}
+impl Enhancement {
+    /// The value of the `data-enhance` attribute of the elements to enhance.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Enhancement::CopyCode => "copy-code",
+            Enhancement::Math => "math",
+        }
+    }
+}
+
+/// Lists the enhancements the rendering of some blocks can use, so the matching scripts are only
+/// included where needed.
+pub fn enhancements(blocks: &[Block]) -> BTreeSet<Enhancement> {
+    fn has_math(spans: &[Span]) -> bool {
+        spans.iter().any(|s| match s {
+            Span::Math(_) => true,
+            Span::Format(_, inner) => has_math(inner),
+            _ => false,
         })
-        .into_node(),
-        &BlockFormat::Heading(lvl) => heading(lvl, inner),
+    }
+
+    let mut enhancements = BTreeSet::new();
+    for Block(format, content, _) in all_blocks(blocks) {
+        match format {
+            BlockFormat::Code => enhancements.insert(Enhancement::CopyCode),
+            BlockFormat::Math => enhancements.insert(Enhancement::Math),
+            _ if has_math(content) => enhancements.insert(Enhancement::Math),
+            _ => false,
+        };
+    }
+
+    enhancements
+}
+
+/// Lists the enhancements a document can use, see [`enhancements`].
+pub fn document_enhancements(document: &Document) -> BTreeSet<Enhancement> {
+    let mut all = enhancements(&document.outline);
+    if let Some(blocks) = &document.metadata.r#abstract {
+        all.extend(enhancements(blocks));
+    }
+
+    all
+}
+
+fn block(block: &Block, ids: &mut impl Iterator<Item = String>, flags: Flags) -> Box<dyn Node> {
+    block_with_id(block, ids, flags, None)
+}
+
+/// Renders a block, giving `paragraph_id` as the `id` of paragraphs, see
+/// [`HtmlOptions::paragraph_ids`].
+fn block_with_id(
+    block: &Block,
+    ids: &mut impl Iterator<Item = String>,
+    flags: Flags,
+    paragraph_id: Option<&str>,
+) -> Box<dyn Node> {
+    let Block(format, content, attributes) = block;
+    let inner = Fragment::new(content.iter().map(|s| span_with(s, flags)));
+    let dir = attributes.dir.map(Direction::as_str);
+    let hook = |enhancement: Enhancement| flags.hooks.then(|| enhancement.as_str());
+
+    match format {
+        &BlockFormat::Paragraph if content.iter().all(|s| matches!(s, Span::Text(_))) => {
+            text_paragraph(content, &[("id", paragraph_id), ("dir", dir)])
+        }
+        &BlockFormat::Paragraph => dynamic_tag("p", &[("id", paragraph_id), ("dir", dir)], inner),
+        &BlockFormat::Code => {
+            let code = tag!(code[class: "code-block"] {{ inner }}).into_node();
+            let attributes = [("dir", dir), ("data-enhance", hook(Enhancement::CopyCode))];
+            dynamic_tag("pre", &attributes, Fragment::new(once(code)))
+        }
+        &BlockFormat::Heading(lvl) => {
+            let heading = heading(lvl, ids.next().unwrap_or_default(), dir, inner);
+
+            match &attributes.subtitle {
+                Some(subtitle) => {
+                    let subtitle = Fragment::new(once(subtitle.into_node()));
+                    let inner = Fragment::new([
+                        heading,
+                        tag!(p[class: "subtitle"] {{ subtitle }}).into_node(),
+                    ]);
+                    tag!(hgroup {{ inner }}).into_node()
+                }
+                None => heading,
+            }
+        }
         &BlockFormat::Raw => inner.into_node(),
+        &BlockFormat::Output => {
+            let output = tag!(samp {{ inner }}).into_node();
+            dynamic_tag(
+                "pre",
+                &[("class", Some("code-output")), ("dir", dir)],
+                Fragment::new(once(output)),
+            )
+        }
+        &BlockFormat::Math => dynamic_tag(
+            "div",
+            &[
+                ("class", Some("math math-display")),
+                ("dir", dir),
+                ("data-enhance", hook(Enhancement::Math)),
+            ],
+            inner,
+        ),
+        // Headings within quotations are not part of the outline, so they get no anchor
+        BlockFormat::Quote { cite, blocks } => {
+            let mut ids = std::iter::empty();
+            let inner = Fragment::new(blocks.iter().map(|b| self::block(b, &mut ids, flags)));
+            let cite = cite.as_deref().map(|cite| flags.link(cite));
+            dynamic_tag(
+                "blockquote",
+                &[("cite", cite.as_deref()), ("dir", dir)],
+                inner,
+            )
+        }
+        BlockFormat::List { ordered, items } => {
+            let mut ids = std::iter::empty();
+            let mut nodes = Vec::with_capacity(items.len());
+            for item in items {
+                let inner = match item.as_slice() {
+                    // Items only holding a paragraph are written without it, as compact lists
+                    [Block(BlockFormat::Paragraph, content, _)] => {
+                        Fragment::new(content.iter().map(|s| span_with(s, flags)))
+                    }
+                    blocks => Fragment::new(blocks.iter().map(|b| self::block(b, &mut ids, flags))),
+                };
+                nodes.push(tag!(li {{ inner }}).into_node());
+            }
+
+            let name = if *ordered { "ol" } else { "ul" };
+            dynamic_tag(name, &[("dir", dir)], Fragment::new(nodes))
+        }
     }
 }
 
-fn head(metadata: &Metadata) -> Fragment {
-    Fragment::new([
+/// An extra resource referenced from the document `<head>`.
+pub enum HeadResource {
+    /// A `<link rel="stylesheet">` to the given URL
+    Stylesheet(String),
+    /// A `<script>` loaded from the given URL
+    Script(String),
+    /// A `<link rel="alternate">`, e.g. for RSS or Atom feeds
+    Alternate {
+        href: String,
+        r#type: String,
+        title: Option<String>,
+    },
+    /// A `<meta name="..." content="...">` tag
+    Meta { name: String, content: String },
+}
+
+impl HeadResource {
+    fn render(&self) -> Box<dyn Node> {
+        match self {
+            HeadResource::Stylesheet(href) => {
+                tag!(link[rel: "stylesheet", href: {href.clone()}]).into_node()
+            }
+            HeadResource::Script(src) => tag!(script[src: {src.clone()}]).into_node(),
+            HeadResource::Alternate {
+                href,
+                r#type,
+                title: Some(title),
+            } => tag!(link[rel: "alternate", type: {r#type.clone()}, href: {href.clone()}, title: {title.clone()}])
+                .into_node(),
+            HeadResource::Alternate { href, r#type, .. } => {
+                tag!(link[rel: "alternate", type: {r#type.clone()}, href: {href.clone()}]).into_node()
+            }
+            HeadResource::Meta { name, content } => {
+                tag!(meta[name: {name.clone()}, content: {content.clone()}]).into_node()
+            }
+        }
+    }
+}
+
+/// Callback producing additional `<head>` nodes from the document metadata.
+pub type HeadHook = Box<dyn Fn(&Metadata) -> Fragment>;
+
+/// Options for full document output, see [`output_document_with`].
+#[derive(Default)]
+pub struct HtmlOptions {
+    /// Resources appended to `<head>`, after the charset and title
+    pub head: Vec<HeadResource>,
+    /// Called last when building `<head>`, for anything [`HeadResource`] does not cover
+    pub head_hook: Option<HeadHook>,
+    /// Adds a table of contents navigation before the document body
+    pub toc: bool,
+    /// Adds a `data-enhance` attribute to the elements scripts can enhance, like code blocks
+    /// getting a copy button. See [`Enhancement`] and [`document_enhancements`].
+    pub enhancement_hooks: bool,
+    /// The medium the output is made for
+    pub profile: Profile,
+    /// Gives paragraphs an `id` which stays the same across rebuilds as long as their text does,
+    /// for annotation systems to anchor to. See [`paragraph_ids`].
+    pub paragraph_ids: bool,
+    /// Prefix of the ids given to headings and paragraphs, as `prefix-id`. Links within the
+    /// document and the table of contents point to the prefixed ids. This avoids collisions with
+    /// the ids of the page a fragment is embedded in.
+    pub id_prefix: Option<String>,
+    pub accessibility: AccessibilityOptions,
+}
+
+/// The medium the HTML output is made for, see [`HtmlOptions::profile`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Output read on a screen
+    #[default]
+    Screen,
+    /// Output made to be printed. Links to other documents get a `print-url` class, and
+    /// [`PRINT_STYLESHEET`] is added to `<head>`, which writes their URL after them and avoids
+    /// page breaks after headings or inside code and math blocks.
+    Print,
+}
+
+/// Style rules of the [`Profile::Print`] profile.
+pub const PRINT_STYLESHEET: &str = "@media print {\
+a.print-url::after { content: \" (\" attr(href) \")\"; }\
+h2, h3, h4, h5, h6, hgroup { break-after: avoid; }\
+pre, .math-display { break-inside: avoid; }\
+.skip-link { display: none; }\
+}";
+
+/// Accessibility-related options of the HTML output.
+#[derive(Default)]
+pub struct AccessibilityOptions {
+    /// Adds a "skip to content" link at the start of the body, targeting the main content
+    pub skip_link: bool,
+    /// Warns about headings skipping levels, which confuses screen reader navigation
+    pub report_heading_jumps: bool,
+}
+
+fn head(metadata: &Metadata, options: &HtmlOptions) -> Fragment {
+    let base = [
         tag!(meta[charset: "utf-8"]).into_node(),
         metadata
             .title
             .as_ref()
-            .map(|value| tag!(title {{ value }}).into_node())
+            .map(|value| tag!(title {{ &value.plain }}).into_node())
             .unwrap_or_else(|| Fragment::empty().into_node()),
-    ])
+    ];
+    let print = (options.profile == Profile::Print)
+        .then(|| tag!(style {{ unsafe { RawFragment::new(PRINT_STYLESHEET) } }}).into_node());
+    let hook = options
+        .head_hook
+        .as_ref()
+        .map(|hook| hook(metadata).into_node());
+
+    Fragment::new(
+        base.into_iter()
+            .chain(print)
+            .chain(options.head.iter().map(HeadResource::render))
+            .chain(hook),
+    )
+}
+
+/// Overhead of a tag pair with a few attributes, used when estimating the rendered size
+const NODE_OVERHEAD: usize = 24;
+/// Overhead of the document skeleton (`<html>`, `<head>`, header and so on)
+const DOCUMENT_OVERHEAD: usize = 512;
+
+fn estimate_spans(spans: &[Span]) -> usize {
+    spans
+        .iter()
+        .map(|s| match s {
+            // Leave some room for escaped characters
+            Span::Text(t) => t.len() + t.len() / 16,
+            Span::Format(SpanFormat::Link(link), inner) => {
+                NODE_OVERHEAD + link.to.len() + estimate_spans(inner)
+            }
+            Span::Format(SpanFormat::Abbreviation { title }, inner) => {
+                NODE_OVERHEAD + title.len() + estimate_spans(inner)
+            }
+            Span::Format(_, inner) => NODE_OVERHEAD + estimate_spans(inner),
+            Span::LineBreak => 4,
+            Span::Raw(r) => r.len(),
+            Span::Math(source) => NODE_OVERHEAD + source.len(),
+        })
+        .sum()
+}
+
+fn estimate_block(block: &Block) -> usize {
+    let Block(_, content, attributes) = block;
+    let subtitle = attributes
+        .subtitle
+        .as_ref()
+        .map_or(0, |s| NODE_OVERHEAD * 2 + s.len());
+    let children = block.children().map(estimate_block).sum::<usize>();
+
+    NODE_OVERHEAD * 2 + subtitle + estimate_spans(content) + children
+}
+
+/// Estimates the size in bytes of the HTML rendering of a fragment.
+///
+/// The estimate is meant to preallocate buffers and is usually a bit larger than the actual
+/// output, but it is not guaranteed to be an upper bound.
+pub fn estimate_size(fragment: &[Block]) -> usize {
+    fragment.iter().map(estimate_block).sum()
+}
+
+/// Estimates the size in bytes of the HTML rendering of a whole document, see [`estimate_size`].
+pub fn estimate_document_size(document: &Document) -> usize {
+    let metadata = &document.metadata;
+    let rich = [&metadata.title, &metadata.author]
+        .into_iter()
+        .flatten()
+        .map(|text| text.plain.len() + estimate_spans(&text.formatted))
+        .sum::<usize>();
+    let keywords = metadata.keywords.iter().map(|k| k.len() + 2).sum::<usize>();
+
+    DOCUMENT_OVERHEAD + rich + keywords + estimate_size(&document.outline)
+}
+
+/// Renders a fragment to a string, allocated once from [`estimate_size`] in most cases.
+pub fn render_fragment(fragment: &[Block]) -> String {
+    trace::phase("render", || {
+        let mut out = String::with_capacity(estimate_size(fragment));
+        write!(out, "{}", output_fragment(fragment)).unwrap();
+        out
+    })
+}
+
+/// Renders a whole document to a string, see [`render_fragment`].
+pub fn render_document_with(document: &Document, options: &HtmlOptions) -> String {
+    trace::phase("render", || {
+        let mut out = String::with_capacity(estimate_document_size(document));
+        write!(out, "{}", output_document_with(document, options)).unwrap();
+        out
+    })
+}
+
+pub fn render_document(document: &Document) -> String {
+    render_document_with(document, &HtmlOptions::default())
 }
 
 pub fn output_fragment(fragment: &[Block]) -> Fragment {
-    Fragment::new(fragment.into_iter().map(block))
+    output_fragment_with(fragment, &HtmlOptions::default())
+}
+
+/// Renders a fragment with the block-level options of `options`, like
+/// [`HtmlOptions::enhancement_hooks`] or [`HtmlOptions::profile`].
+pub fn output_fragment_with(fragment: &[Block], options: &HtmlOptions) -> Fragment {
+    let flags = Flags::from(options);
+    let mut ids = toc(fragment).into_iter().map(|entry| flags.id(&entry.id));
+    let paragraph_ids = match options.paragraph_ids {
+        true => paragraph_ids(fragment)
+            .into_iter()
+            .map(|id| id.map(|id| flags.id(&id)))
+            .collect(),
+        false => vec![None; fragment.len()],
+    };
+
+    Fragment::new(
+        fragment
+            .iter()
+            .zip(&paragraph_ids)
+            .map(|(b, id)| block_with_id(b, &mut ids, flags, id.as_deref())),
+    )
+}
+
+/// Cache of rendered blocks for [`output_fragment_cached`], to avoid rendering unchanged blocks
+/// again when the same document is rendered repeatedly, like in a live preview.
+///
+/// Blocks are looked up by a hash of their content and of the id given to them. Only the blocks
+/// of the last rendered fragment are kept.
+#[derive(Default)]
+pub struct RenderCache {
+    blocks: HashMap<u64, String>,
+}
+
+impl RenderCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of rendered blocks currently stored.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+/// Same as [`output_fragment`], reusing the rendering of blocks found in `cache`.
+pub fn output_fragment_cached(fragment: &[Block], cache: &mut RenderCache) -> Fragment {
+    let mut ids = toc(fragment).into_iter().map(|entry| entry.id);
+    let mut previous = take(&mut cache.blocks);
+
+    let nodes = fragment.iter().map(|b| {
+        let id = matches!(b.0, BlockFormat::Heading(_)).then(|| ids.next().unwrap_or_default());
+
+        let mut hasher = DefaultHasher::new();
+        (b, &id).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let html = match cache.blocks.get(&key) {
+            Some(html) => html.clone(),
+            None => previous.remove(&key).unwrap_or_else(|| {
+                let mut html = String::with_capacity(estimate_block(b));
+                let node = block(b, &mut id.into_iter(), Flags::default());
+                write!(html, "{}", Fragment::new(once(node))).unwrap();
+                html
+            }),
+        };
+        cache.blocks.insert(key, html.clone());
+
+        // The cached content was rendered by `block`, with everything escaped already
+        unsafe { RawFragment::new(&html) }.into_node()
+    });
+
+    Fragment::new(nodes.collect::<Vec<_>>())
+}
+
+/// Renders a paragraph modified between two revisions, with the words inserted and removed
+/// marked with `<ins>` and `<del>`. Formatting is not compared, so the paragraph is rendered as
+/// plain text.
+fn modified_paragraph(old: &Block, new: &Block) -> Box<dyn Node> {
+    let (old, new) = (plain_text(&old.1), plain_text(&new.1));
+    let words = diff_words(&old, &new)
+        .into_iter()
+        .map(|change| match change {
+            Change::Same(word) => word.into_node(),
+            Change::Inserted(word) => tag!(ins {{ word }}).into_node(),
+            Change::Removed(word) => tag!(del {{ word }}).into_node(),
+        })
+        .collect::<Vec<_>>();
+
+    let inner = Fragment::new(words);
+    tag!(p[class: "diff-modified"] {{ inner }}).into_node()
+}
+
+/// Renders the changes between two revisions of a list of blocks, wrapping inserted blocks in
+/// `<ins>` and removed blocks in `<del>`. Paragraphs replacing each other are shown as a single
+/// paragraph with the changed words marked.
+pub fn output_diff(old: &[Block], new: &[Block]) -> Fragment {
+    let mut old_ids = toc(old)
+        .into_iter()
+        .map(|entry| format!("removed-{}", entry.id));
+    let mut new_ids = toc(new).into_iter().map(|entry| entry.id);
+    let is_paragraph = |b: &Block| matches!(b.0, BlockFormat::Paragraph);
+
+    let changes = diff_blocks(old, new);
+    let mut nodes = Vec::new();
+    let mut rest = &changes[..];
+
+    while let Some(change) = rest.first() {
+        if let Change::Same(b) = change {
+            if matches!(b.0, BlockFormat::Heading(_)) {
+                old_ids.next();
+            }
+            nodes.push(block(b, &mut new_ids, Flags::default()));
+            rest = &rest[1..];
+            continue;
+        }
+
+        // A run of removed blocks followed by inserted ones, replacing them
+        let removed = rest
+            .iter()
+            .take_while(|c| matches!(c, Change::Removed(_)))
+            .count();
+        let inserted = rest[removed..]
+            .iter()
+            .take_while(|c| matches!(c, Change::Inserted(_)))
+            .count();
+        let (run, tail) = rest.split_at(removed + inserted);
+        let (removed, inserted) = run.split_at(removed);
+
+        // Blocks are paired in order, and the other pairs between two modified paragraphs are
+        // grouped in a single removal and insertion
+        let mut deleted = Vec::new();
+        let mut added = Vec::new();
+        let flush = |nodes: &mut Vec<_>, deleted: &mut Vec<_>, added: &mut Vec<_>| {
+            if !deleted.is_empty() {
+                let inner = Fragment::new(std::mem::take(deleted));
+                nodes.push(tag!(del[class: "diff"] {{ inner }}).into_node());
+            }
+            if !added.is_empty() {
+                let inner = Fragment::new(std::mem::take(added));
+                nodes.push(tag!(ins[class: "diff"] {{ inner }}).into_node());
+            }
+        };
+
+        for i in 0..removed.len().max(inserted.len()) {
+            match (removed.get(i), inserted.get(i)) {
+                (Some(Change::Removed(a)), Some(Change::Inserted(b)))
+                    if is_paragraph(a) && is_paragraph(b) =>
+                {
+                    flush(&mut nodes, &mut deleted, &mut added);
+                    nodes.push(modified_paragraph(a, b));
+                }
+                (a, b) => {
+                    if let Some(Change::Removed(a)) = a {
+                        deleted.push(block(a, &mut old_ids, Flags::default()));
+                    }
+                    if let Some(Change::Inserted(b)) = b {
+                        added.push(block(b, &mut new_ids, Flags::default()));
+                    }
+                }
+            }
+        }
+        flush(&mut nodes, &mut deleted, &mut added);
+
+        rest = tail;
+    }
+
+    Fragment::new(nodes)
+}
+
+fn toc_list(entries: &[TocEntry], flags: Flags) -> Box<dyn Node> {
+    let mut items = Vec::new();
+    let mut rest = entries;
+
+    while let Some((entry, tail)) = rest.split_first() {
+        let end = tail
+            .iter()
+            .position(|e| e.level <= entry.level)
+            .unwrap_or(tail.len());
+        let (children, next) = tail.split_at(end);
+
+        let link =
+            tag!(a[href: {format!("#{}", flags.id(&entry.id))}] {{ &entry.title }}).into_node();
+        let inner = if children.is_empty() {
+            Fragment::new(once(link))
+        } else {
+            Fragment::new([link, toc_list(children, flags)])
+        };
+        items.push(tag!(li {{ inner }}).into_node());
+
+        rest = next;
+    }
+
+    let inner = Fragment::new(items);
+    tag!(ol {{ inner }}).into_node()
+}
+
+/// Renders a table of contents of the given blocks, as a labelled navigation landmark.
+pub fn output_toc(fragment: &[Block]) -> Box<dyn Node> {
+    toc_nav(fragment, Flags::default())
+}
+
+fn toc_nav(fragment: &[Block], flags: Flags) -> Box<dyn Node> {
+    let entries = toc(fragment);
+    let inner = Fragment::new(once(toc_list(&entries, flags)));
+
+    dynamic_tag(
+        "nav",
+        &[
+            ("class", Some("toc")),
+            ("aria-label", Some("Table of contents")),
+        ],
+        inner,
+    )
 }
 
 pub fn output(document: &Document) -> (Fragment, Option<Fragment>) {
@@ -75,11 +824,498 @@ pub fn output(document: &Document) -> (Fragment, Option<Fragment>) {
     )
 }
 
+fn byline(metadata: &Metadata) -> Option<Box<dyn Node>> {
+    let author = metadata.author.as_ref().map(|author| {
+        Fragment::new(once("By ".into_node()).chain(author.formatted.iter().map(span)))
+    });
+    let date = metadata.date.as_ref().map(|date| span(&date.to_span()));
+
+    let inner = match (author, date) {
+        (Some(author), Some(date)) => Fragment::new([author.into_node(), ", ".into_node(), date]),
+        (Some(author), None) => author,
+        (None, Some(date)) => Fragment::new(once(date)),
+        (None, None) => return None,
+    };
+
+    Some(tag!(p[class: "byline"] {{ inner }}).into_node())
+}
+
+fn header(metadata: &Metadata) -> Fragment {
+    let title = metadata.title.as_ref().map(|title| {
+        let inner = Fragment::new(title.formatted.iter().map(span));
+        tag!(h1 {{ inner }}).into_node()
+    });
+    let children = title
+        .into_iter()
+        .chain(byline(metadata))
+        .collect::<Vec<_>>();
+
+    if children.is_empty() {
+        Fragment::empty()
+    } else {
+        let inner = Fragment::new(children);
+        Fragment::new(once(tag!(header {{ inner }}).into_node()))
+    }
+}
+
 pub fn output_document(document: &Document) -> Fragment {
-    let html = tag!(html[lang: "en"] {
-        head {{ head(&document.metadata) }};
-        body {{ output_fragment(&document.outline) }}
-    })
-    .into_node();
+    output_document_with(document, &HtmlOptions::default())
+}
+
+fn body(document: &Document, options: &HtmlOptions) -> Fragment {
+    if options.accessibility.report_heading_jumps {
+        check_heading_levels(&document.outline);
+    }
+
+    let toc = options
+        .toc
+        .then(|| toc_nav(&document.outline, Flags::from(options)));
+    let banner = document.metadata.draft.then(|| {
+        let label = Fragment::new(once("Draft".into_node()));
+        tag!(p[class: "draft-banner", role: "note"] {{ label }}).into_node()
+    });
+    let content = Fragment::new(
+        banner
+            .into_iter()
+            .chain(once(header(&document.metadata).into_node()))
+            .chain(toc)
+            .chain(once(
+                output_fragment_with(&document.outline, options).into_node(),
+            )),
+    );
+
+    if options.accessibility.skip_link {
+        let label = Fragment::new(once("Skip to content".into_node()));
+        Fragment::new([
+            tag!(a[class: "skip-link", href: "#content"] {{ label }}).into_node(),
+            tag!(main[id: "content"] {{ content }}).into_node(),
+        ])
+    } else {
+        content
+    }
+}
+
+/// Splits a fragment into slides, each level 1 or 2 heading starting a new one.
+fn slides(fragment: &[Block]) -> Vec<&[Block]> {
+    let mut slides = Vec::new();
+    let mut start = 0;
+
+    for (i, b) in fragment.iter().enumerate() {
+        if matches!(b.0, BlockFormat::Heading(1 | 2)) && i > start {
+            slides.push(&fragment[start..i]);
+            start = i;
+        }
+    }
+    if start < fragment.len() {
+        slides.push(&fragment[start..]);
+    }
+
+    slides
+}
+
+/// Renders the document as a reveal.js slide deck, starting a `<section>` at every level 1 or 2
+/// heading. The title and byline, if any, make the first slide.
+pub fn output_slides(document: &Document) -> Fragment {
+    output_slides_with(document, &HtmlOptions::default())
+}
+
+/// Renders the document as a slide deck, like [`output_slides`], with the block-level options of
+/// `options`, like [`output_fragment_with`]. Drafts get their banner on the first slide.
+pub fn output_slides_with(document: &Document, options: &HtmlOptions) -> Fragment {
+    if options.accessibility.report_heading_jumps {
+        check_heading_levels(&document.outline);
+    }
+
+    let flags = Flags::from(options);
+    let mut ids = toc(&document.outline)
+        .into_iter()
+        .map(|entry| flags.id(&entry.id));
+    let paragraph_ids: Vec<Option<String>> = match options.paragraph_ids {
+        true => paragraph_ids(&document.outline)
+            .into_iter()
+            .map(|id| id.map(|id| flags.id(&id)))
+            .collect(),
+        false => vec![None; document.outline.len()],
+    };
+    let mut paragraph_ids = paragraph_ids.into_iter();
+
+    let metadata = &document.metadata;
+    let banner = metadata.draft.then(|| {
+        let label = Fragment::new(once("Draft".into_node()));
+        tag!(p[class: "draft-banner", role: "note"] {{ label }}).into_node()
+    });
+    let title = (metadata.title.is_some() || metadata.author.is_some() || metadata.date.is_some())
+        .then(|| header(metadata).into_node());
+    let title = (banner.is_some() || title.is_some())
+        .then(|| Fragment::new(banner.into_iter().chain(title)));
+
+    let sections = title
+        .into_iter()
+        .chain(slides(&document.outline).into_iter().map(|slide| {
+            Fragment::new(
+                slide
+                    .iter()
+                    .zip(paragraph_ids.by_ref())
+                    .map(|(b, id)| block_with_id(b, &mut ids, flags, id.as_deref()))
+                    .collect::<Vec<_>>(),
+            )
+        }))
+        .map(|inner| tag!(section {{ inner }}).into_node())
+        .collect::<Vec<_>>();
+
+    let slides = Fragment::new(once(
+        tag!(div[class: "slides"] {{ Fragment::new(sections) }}).into_node(),
+    ));
+    Fragment::new(once(tag!(div[class: "reveal"] {{ slides }}).into_node()))
+}
+
+/// Renders a standalone slide deck page, see [`output_slides_with`]. The reveal.js stylesheet and
+/// script are expected to be added through [`HtmlOptions::head`].
+pub fn output_slides_document_with(document: &Document, options: &HtmlOptions) -> Fragment {
+    page(document, options, output_slides_with(document, options))
+}
+
+pub fn output_document_with(document: &Document, options: &HtmlOptions) -> Fragment {
+    page(document, options, body(document, options))
+}
+
+/// Builds the `<html>` root of a standalone page around `body`.
+fn page(document: &Document, options: &HtmlOptions, body: Fragment) -> Fragment {
+    let inner = Fragment::new([
+        tag!(head {{ head(&document.metadata, options) }}).into_node(),
+        tag!(body {{ body }}).into_node(),
+    ]);
+    let dir = document.metadata.dir.map(Direction::as_str);
+    let lang = document.metadata.lang.as_deref().unwrap_or("en");
+    let html = dynamic_tag("html", &[("lang", Some(lang)), ("dir", dir)], inner);
     Fragment::new(once(html))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{outline, process_stream};
+
+    #[test]
+    fn test_direction() {
+        let document = process_stream(
+            pastex_parser::parse("\\meta:dir[rtl]\\head1[dir = ltr]{Title}\n\nText").unwrap(),
+        );
+        let html = output_document(&document).to_string();
+
+        assert!(html.contains(r#"<html lang="en" dir="rtl">"#));
+        assert!(html.contains(r#"<h2 id="title" dir="ltr">"#));
+        assert!(html.contains("<p>Text</p>"));
+    }
+
+    #[test]
+    fn test_heading_attributes() {
+        let blocks = outline(r"\head1[id = intro, subtitle = Why bother?]{Introduction}");
+        let html = output_fragment(&blocks).to_string();
+
+        assert_eq!(toc(&blocks)[0].id, "intro");
+        assert!(html.contains(r#"<h2 id="intro">Introduction</h2>"#));
+        assert!(html.contains("Why bother?"));
+
+        let blocks = outline("\\head4{Four}\n\n\\head5{Five}");
+        let html = output_fragment(&blocks).to_string();
+        assert!(html.contains(r#"<h5 id="four">Four</h5>"#));
+        assert!(html.contains(r#"<h6 id="five">Five</h6>"#));
+
+        // Levels no command gives are written as the deepest one
+        let blocks = [Block::new(
+            BlockFormat::Heading(9),
+            vec![Span::Text("Deep".into())],
+        )];
+        assert!(output_fragment(&blocks).to_string().contains("<h6"));
+    }
+
+    #[test]
+    fn test_blockquote() {
+        let source = "\\head1{Quoted}\n\n\\begin[cite = https://example.com/q, dir = rtl]{blockquote}\n\\head1{Quoted}\n\nSome $x$\n\n\\begin{code}a\\end{code}\n\\end{blockquote}";
+        let blocks = outline(source);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].children().count(), 3);
+        assert_eq!(all_blocks(&blocks).len(), 5);
+
+        let html = output_fragment(&blocks).to_string();
+        assert!(
+            html.starts_with(r#"<h2 id="quoted">Quoted</h2><blockquote cite="https://example.com/q" dir="rtl"><h2>Quoted</h2>"#)
+        );
+        assert!(html.ends_with("</pre></blockquote>"));
+        assert_eq!(
+            enhancements(&blocks),
+            BTreeSet::from([Enhancement::CopyCode, Enhancement::Math])
+        );
+        assert_eq!(
+            crate::output::text::output_fragment(&blocks),
+            "Quoted\n\nQuoted\n\nSome x\n\na"
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        let source = "\\begin{itemize}\n\\item One\n\\item Two\n\n\\begin{enumerate}\\item A\n\\item B\\end{enumerate}\n\\end{itemize}";
+        let blocks = outline(source);
+        assert_eq!(blocks.len(), 1);
+        let items = match &blocks[0].0 {
+            BlockFormat::List { ordered, items } => {
+                assert!(!ordered);
+                items
+            }
+            _ => panic!("expected a list"),
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].len(), 2);
+        assert!(matches!(
+            items[1][1].0,
+            BlockFormat::List { ordered: true, .. }
+        ));
+        assert_eq!(all_blocks(&blocks).len(), 6);
+
+        let html = output_fragment(&blocks).to_string();
+        assert!(html.starts_with("<ul>"));
+        assert!(html.contains("One"));
+        assert!(html.contains("<p>Two</p><ol>"));
+        assert!(html.ends_with("</ol></ul>"));
+        assert_eq!(
+            crate::output::text::output_fragment(&blocks),
+            "- One\n- Two\n\n  1. A\n  2. B"
+        );
+
+        let source = "\\begin{enumerate}Lost \\item Kept\\end{enumerate}";
+        let document = process_stream(pastex_parser::parse(source).unwrap());
+        assert_eq!(crate::output::text::output(&document), "1. Kept\n");
+        assert_eq!(
+            document.diagnostics[0].code,
+            crate::diagnostics::Code::ListContent
+        );
+    }
+
+    #[test]
+    fn test_render_cache() {
+        let mut cache = RenderCache::new();
+        let blocks = outline("\\head1{Title}\n\nFirst.\n\nSecond.\n\n\\head1{Title}");
+
+        let expected = output_fragment(&blocks).to_string();
+        assert_eq!(
+            output_fragment_cached(&blocks, &mut cache).to_string(),
+            expected
+        );
+        assert_eq!(
+            output_fragment_cached(&blocks, &mut cache).to_string(),
+            expected
+        );
+        // Both headings have the same content, but not the same id
+        assert_eq!(cache.len(), 4);
+
+        let edited = outline("\\head1{Title}\n\nFirst, edited.");
+        assert_eq!(
+            output_fragment_cached(&edited, &mut cache).to_string(),
+            output_fragment(&edited).to_string()
+        );
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let source = "\\head1{Title}\n\nSome \\strong{text} & more.\n\n".repeat(50);
+        let blocks = outline(&source);
+        let html = render_fragment(&blocks);
+
+        assert_eq!(html, output_fragment(&blocks).to_string());
+        assert!(estimate_size(&blocks) >= html.len());
+    }
+
+    #[test]
+    fn test_icon() {
+        let directory = std::env::temp_dir().join("pastex-test-icons");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("star.svg"),
+            r#"<svg onclick="x()"><path d="M0 0"/></svg>"#,
+        )
+        .unwrap();
+
+        let mut options = crate::EngineOptions::default();
+        options.icons(&directory);
+        let source = r"\icon{star} \icon[title = Rated]{star} \icon{../star}";
+        let blocks =
+            crate::document::process_stream_with(pastex_parser::parse(source).unwrap(), &options)
+                .outline;
+        let html = output_fragment(&blocks).to_string();
+
+        assert!(html.contains(
+            r#"<span class="icon" aria-hidden="true"><svg><path d="M0 0"/></svg></span>"#
+        ));
+        assert!(html.contains(r#"<span class="icon" role="img" aria-label="Rated">"#));
+        assert_eq!(html.matches("<svg>").count(), 2);
+    }
+
+    #[test]
+    fn test_enhancement_hooks() {
+        let blocks = outline("\\begin{code}x\\end{code}\n\nSee \\math{y}.");
+        let options = HtmlOptions {
+            enhancement_hooks: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            enhancements(&blocks).into_iter().collect::<Vec<_>>(),
+            [Enhancement::CopyCode, Enhancement::Math]
+        );
+        assert!(enhancements(&outline("Text")).is_empty());
+        assert!(output_fragment_with(&blocks, &options)
+            .to_string()
+            .contains(r#"<pre data-enhance="copy-code">"#));
+        assert!(!output_fragment(&blocks)
+            .to_string()
+            .contains("data-enhance"));
+    }
+
+    #[test]
+    fn test_paragraph_ids() {
+        let options = HtmlOptions {
+            paragraph_ids: true,
+            ..Default::default()
+        };
+        let render = |source| output_fragment_with(&outline(source), &options).to_string();
+
+        let before = render("\\head1{A}\n\nSome   text.\n\nSome text.");
+        let after = render("\\head1{A}\n\nNew \\strong{one}.\n\nSome text.\n\nSome\ntext.");
+        let ids = |html: &str| -> Vec<String> {
+            html.match_indices("<p id=\"")
+                .map(|(pos, _)| {
+                    html[pos + 7..pos + 7 + html[pos + 7..].find('"').unwrap()].to_owned()
+                })
+                .collect()
+        };
+
+        let (before, after) = (ids(&before), ids(&after));
+        assert_eq!(before.len(), 2);
+        assert_eq!(before[1], format!("{}-2", before[0]));
+        assert_eq!(after.len(), 3);
+        assert_eq!(after[1..], before[..]);
+        assert!(!output_fragment(&outline("Text"))
+            .to_string()
+            .contains("id="));
+    }
+
+    #[test]
+    fn test_id_prefix() {
+        let options = HtmlOptions {
+            id_prefix: Some("post".to_owned()),
+            paragraph_ids: true,
+            ..Default::default()
+        };
+        let html = output_fragment_with(&outline("\\head1{Intro}\n\nText"), &options).to_string();
+        assert!(html.contains("<h2 id=\"post-intro\">"));
+        assert!(html.contains("<p id=\"post-p-"));
+
+        let flags = Flags::from(&options);
+        assert_eq!(flags.link("#intro"), "#post-intro");
+        assert_eq!(flags.link("/page#intro"), "/page#intro");
+        assert_eq!(Flags::default().link("#intro"), "#intro");
+    }
+
+    #[test]
+    fn test_print_profile() {
+        let document = process_stream(
+            pastex_parser::parse(r"\link[to = /a, blank]{A} \link[to = #b]{B}").unwrap(),
+        );
+        let options = HtmlOptions {
+            profile: Profile::Print,
+            ..Default::default()
+        };
+
+        let html = output_document_with(&document, &options).to_string();
+        assert!(html.contains(PRINT_STYLESHEET));
+        assert!(!html.contains("_blank"));
+        assert!(!output_document(&document).to_string().contains("print-url"));
+    }
+
+    #[test]
+    fn test_link_attributes() {
+        let html = output_fragment(&outline(
+            r#"\link[to = /a.pdf, blank, nofollow, rel = author, title = "A" & B, download]{A}"#,
+        ))
+        .to_string();
+        assert!(html.contains(
+            "<a href=\"/a.pdf\" target=\"_blank\" rel=\"author nofollow noopener noreferrer\" \
+            title=\"&quot;A&quot; &amp; B\" download=\"\">A</a>"
+        ));
+
+        let html = output_fragment(&outline(r"\link[to = /a, download = b.txt]{A}")).to_string();
+        assert!(html.contains("<a href=\"/a\" download=\"b.txt\">A</a>"));
+    }
+
+    #[test]
+    fn test_dynamic_tag() {
+        let inner = Fragment::new(once("a & <c>".into_node()));
+        let attributes = [
+            ("title", Some("\"quoted\" & <b>")),
+            ("class", None),
+            ("href", Some("/?a=1&b=\"><script>")),
+        ];
+        let html = Fragment::new(once(dynamic_tag("a", &attributes, inner))).to_string();
+        assert_eq!(
+            html,
+            "<a title=\"&quot;quoted&quot; &amp; &lt;b&gt;\" \
+            href=\"/?a=1&amp;b=&quot;&gt;&lt;script&gt;\">a &amp; &lt;c&gt;</a>"
+        );
+
+        let paragraph = [Span::Text("x".into())];
+        let html = Fragment::new(once(text_paragraph(&paragraph, &[("id", Some("\"&<"))])));
+        assert_eq!(html.to_string(), "<p id=\"&quot;&amp;&lt;\">x</p>");
+
+        let event = std::panic::catch_unwind(|| {
+            dynamic_tag("a", &[("onclick", Some("x"))], Fragment::new(Vec::new()))
+        });
+        assert!(event.is_err());
+    }
+
+    #[test]
+    fn test_slides() {
+        let blocks = outline("Intro\n\n\\head1{A}\n\nText\n\n\\head2{B}\n\n\\head3{C}\n\nMore");
+        let lengths = slides(&blocks).iter().map(|s| s.len()).collect::<Vec<_>>();
+
+        assert_eq!(lengths, [1, 2, 3]);
+        assert!(slides(&[]).is_empty());
+
+        let document =
+            process_stream(pastex_parser::parse("\\meta:draft\n\n\\head1{A}\n\nText").unwrap());
+        let options = HtmlOptions {
+            id_prefix: Some("deck".to_owned()),
+            paragraph_ids: true,
+            ..Default::default()
+        };
+        let html = output_slides_with(&document, &options).to_string();
+        assert!(html.contains(r#"<section><p class="draft-banner" role="note">Draft</p>"#));
+        assert!(html.contains(r#"<h2 id="deck-a">A</h2>"#));
+        assert!(html.contains(r#"<p id="deck-p-"#));
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = outline("Some old text\n\n\\head1{Old}\n\nMore old text\n\n\\head1{Gone}");
+        let new = outline("Some new text\n\n\\head1{New}\n\nMore new text");
+        let html = output_diff(&old, &new).to_string();
+
+        // Replaced blocks are shown in the order of the document
+        let positions = [
+            "<p class=\"diff-modified\">Some ",
+            "<del class=\"diff\"><h2 id=\"removed-old\">Old</h2></del>",
+            "<ins class=\"diff\"><h2 id=\"new\">New</h2></ins>",
+            "<p class=\"diff-modified\">More ",
+            "<del class=\"diff\"><h2 id=\"removed-gone\">Gone</h2></del>",
+        ]
+        .map(|part| html.find(part).expect(part));
+        assert!(positions.windows(2).all(|w| w[0] < w[1]), "{}", html);
+    }
+
+    #[test]
+    fn test_text_paragraph() {
+        let html = output_fragment(&outline("1 < 2 & 3 > 2")).to_string();
+        assert_eq!(html, "<p>1 &lt; 2 &amp; 3 &gt; 2</p>");
+    }
+}
@@ -0,0 +1,166 @@
+//! JSON export of processed documents and parsed streams.
+//!
+//! # Compatibility
+//!
+//! Every exported value is wrapped in an envelope holding its kind and [`SCHEMA_VERSION`]:
+//!
+//! ```json
+//! { "schema": 1, "kind": "document", "data": { ... } }
+//! ```
+//!
+//! The schema version is bumped on any incompatible change to the format (removed or renamed
+//! fields or variants, changed representation). Adding a field or a variant does not change it,
+//! so consumers should ignore what they do not know. Loaders refuse values with a different schema
+//! version rather than guessing.
+//!
+//! The output is deterministic: fields keep their declaration order and command parameters are
+//! sorted by name.
+
+use crate::document::{metadata::Metadata, Document};
+use pastex_parser::Stream;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the JSON format produced by this module.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const DOCUMENT: &str = "document";
+const METADATA: &str = "metadata";
+const AST: &str = "ast";
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    schema: u32,
+    kind: &'a str,
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct OwnedEnvelope<T> {
+    data: T,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The input is not valid JSON or does not match the expected structure
+    Json(serde_json::Error),
+    /// The input was produced with an incompatible schema version
+    UnsupportedSchema(u32),
+    /// The input holds another kind of value, e.g. metadata instead of a document
+    UnexpectedKind(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Json(e) => write!(f, "invalid JSON: {}", e),
+            Error::UnsupportedSchema(v) => write!(
+                f,
+                "unsupported schema version {}, expected {}",
+                v, SCHEMA_VERSION
+            ),
+            Error::UnexpectedKind(k) => write!(f, "unexpected value kind {:?}", k),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+fn to_json<T: Serialize>(kind: &str, data: T) -> String {
+    let envelope = Envelope {
+        schema: SCHEMA_VERSION,
+        kind,
+        data,
+    };
+    serde_json::to_string(&envelope).expect("document types always serialize")
+}
+
+fn from_json<T: DeserializeOwned>(kind: &str, json: &str) -> Result<T, Error> {
+    #[derive(Deserialize)]
+    struct Header {
+        schema: u32,
+        kind: String,
+    }
+
+    // Check the envelope first, so an incompatible value is not reported as malformed
+    let header: Header = serde_json::from_str(json)?;
+    if header.schema != SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchema(header.schema));
+    }
+    if header.kind != kind {
+        return Err(Error::UnexpectedKind(header.kind));
+    }
+
+    let envelope: OwnedEnvelope<T> = serde_json::from_str(json)?;
+    Ok(envelope.data)
+}
+
+pub fn output(document: &Document) -> String {
+    to_json(DOCUMENT, document)
+}
+
+pub fn output_metadata(metadata: &Metadata) -> String {
+    to_json(METADATA, metadata)
+}
+
+/// Exports a parsed stream, before any processing. There is no loader for this format, as parsed
+/// streams borrow from their source.
+pub fn output_ast(stream: &Stream) -> String {
+    to_json(AST, stream)
+}
+
+pub fn document_from_json(json: &str) -> Result<Document, Error> {
+    from_json(DOCUMENT, json)
+}
+
+pub fn metadata_from_json(json: &str) -> Result<Metadata, Error> {
+    from_json(METADATA, json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::process_stream;
+
+    #[test]
+    fn test_load_without_new_fields() {
+        let json = r#"{"schema": 1, "kind": "document", "data": {
+            "outline": [["Paragraph", [{"Text": "Hello"}]]],
+            "metadata": {"title": null, "author": null, "date": null, "keywords": [],
+                "draft": false, "abstract": null}
+        }}"#;
+        let document = document_from_json(json).unwrap();
+
+        assert!(document.outline[0].2.dir.is_none());
+        assert!(document.metadata.dir.is_none());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let source = r"\meta:title{A \code{title}}Some \strong{text}.";
+        let document = process_stream(pastex_parser::parse(source).unwrap());
+        let json = output(&document);
+        let loaded = document_from_json(&json).unwrap();
+
+        assert_eq!(output(&loaded), json);
+        assert!(matches!(
+            metadata_from_json(&json),
+            Err(Error::UnexpectedKind(_))
+        ));
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let json = r#"{"schema":0,"kind":"document","data":null}"#;
+        assert!(matches!(
+            document_from_json(json),
+            Err(Error::UnsupportedSchema(0))
+        ));
+    }
+}
@@ -1 +1,6 @@
+pub mod capabilities;
 pub mod html;
+pub mod json;
+pub mod negotiate;
+pub mod ssml;
+pub mod text;
@@ -0,0 +1,140 @@
+//! Choice of the output format from an HTTP `Accept` header, for servers exposing documents in
+//! several representations.
+
+use super::{html, json, text};
+use crate::document::Document;
+
+/// An output format a document can be served in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Html,
+    Text,
+    Json,
+}
+
+impl Representation {
+    /// Every representation, in the order preferred when the client accepts several equally.
+    pub const ALL: [Representation; 3] = [
+        Representation::Html,
+        Representation::Text,
+        Representation::Json,
+    ];
+
+    /// The `Content-Type` of the representation.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Representation::Html => "text/html; charset=utf-8",
+            Representation::Text => "text/plain; charset=utf-8",
+            Representation::Json => "application/json",
+        }
+    }
+
+    fn media_type(self) -> (&'static str, &'static str) {
+        match self {
+            Representation::Html => ("text", "html"),
+            Representation::Text => ("text", "plain"),
+            Representation::Json => ("application", "json"),
+        }
+    }
+
+    /// Renders the document in this representation.
+    pub fn render(self, document: &Document) -> String {
+        match self {
+            Representation::Html => html::render_document(document),
+            Representation::Text => text::output(document),
+            Representation::Json => json::output(document),
+        }
+    }
+}
+
+/// A document rendered for a response, with its `Content-Type`.
+#[derive(Debug)]
+pub struct Response {
+    pub body: String,
+    pub content_type: &'static str,
+}
+
+/// Gets the quality the client gives to `representation`, from the most specific media range of
+/// the `Accept` header matching it. Returns [`None`] if no range matches.
+fn quality(accept: &str, representation: Representation) -> Option<f32> {
+    let (r#type, subtype) = representation.media_type();
+    let mut best: Option<(u8, f32)> = None;
+
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or_default().trim();
+        let (t, s) = media.split_once('/').unwrap_or((media, ""));
+
+        let specificity = match (t, s) {
+            ("*", "*") => 0,
+            (t, "*") if t.eq_ignore_ascii_case(r#type) => 1,
+            (t, s) if t.eq_ignore_ascii_case(r#type) && s.eq_ignore_ascii_case(subtype) => 2,
+            _ => continue,
+        };
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+
+        if !matches!(best, Some((s, _)) if s >= specificity) {
+            best = Some((specificity, q));
+        }
+    }
+
+    best.map(|(_, q)| q)
+}
+
+/// Picks the representation the client prefers among `available`, given its `Accept` header.
+/// Without header, the first available one is used. Returns [`None`] if the client accepts none
+/// of them, which servers usually answer with a `406 Not Acceptable`.
+pub fn negotiate(accept: Option<&str>, available: &[Representation]) -> Option<Representation> {
+    let accept = match accept.map(str::trim) {
+        Some(accept) if !accept.is_empty() => accept,
+        _ => return available.first().copied(),
+    };
+
+    let mut best: Option<(f32, Representation)> = None;
+    for &representation in available {
+        let q = match quality(accept, representation) {
+            Some(q) if q > 0.0 => q,
+            _ => continue,
+        };
+
+        if !matches!(best, Some((b, _)) if b >= q) {
+            best = Some((q, representation));
+        }
+    }
+
+    best.map(|(_, representation)| representation)
+}
+
+/// Renders the document in the representation the client prefers, see [`negotiate`].
+pub fn respond(accept: Option<&str>, document: &Document) -> Option<Response> {
+    negotiate(accept, &Representation::ALL).map(|representation| Response {
+        body: representation.render(document),
+        content_type: representation.content_type(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Representation::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = &Representation::ALL;
+
+        assert_eq!(negotiate(None, all), Some(Html));
+        assert_eq!(negotiate(Some("application/json"), all), Some(Json));
+        assert_eq!(negotiate(Some("text/*"), all), Some(Html));
+        assert_eq!(negotiate(Some("text/*;q=0.5, text/plain"), all), Some(Text));
+        assert_eq!(
+            negotiate(Some("text/html;q=0.9, application/json"), all),
+            Some(Json)
+        );
+        assert_eq!(negotiate(Some("*/*, text/html;q=0"), all), Some(Text));
+        assert_eq!(negotiate(Some("image/png"), all), None);
+        assert_eq!(negotiate(Some("*/*"), &[Json, Html]), Some(Json));
+    }
+}
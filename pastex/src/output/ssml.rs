@@ -0,0 +1,133 @@
+//! SSML export of processed documents, for text-to-speech engines making audio versions of them.
+//!
+//! Paragraphs become `<p>` elements separated by pauses, strong text is emphasized and
+//! abbreviations are read as their expansion. Code and display math can't be read aloud, so they
+//! are replaced by a short announcement.
+
+use super::html::{escape_attribute, escape_text_into};
+use crate::document::{Block, BlockFormat, Document, Span, SpanFormat};
+use std::fmt::Write;
+
+/// Read in place of code blocks and their output.
+pub const CODE_ANNOUNCEMENT: &str = "Code sample skipped.";
+
+/// Read in place of display math.
+pub const MATH_ANNOUNCEMENT: &str = "Equation skipped.";
+
+/// Pause between blocks.
+const BLOCK_BREAK: &str = "<break strength=\"strong\"/>";
+
+fn spans_into(out: &mut String, spans: &[Span]) {
+    for span in spans {
+        match span {
+            Span::Text(t) | Span::Math(t) => escape_text_into(out, t),
+            Span::Format(SpanFormat::Strong, inner) => {
+                out.push_str("<emphasis>");
+                spans_into(out, inner);
+                out.push_str("</emphasis>");
+            }
+            Span::Format(SpanFormat::Abbreviation { title }, inner) => {
+                write!(out, "<sub alias=\"{}\">", escape_attribute(title)).unwrap();
+                spans_into(out, inner);
+                out.push_str("</sub>");
+            }
+            Span::Format(SpanFormat::Icon { title }, _) => {
+                if let Some(title) = title {
+                    escape_text_into(out, title);
+                }
+            }
+            // Notes for the author only
+            Span::Format(SpanFormat::Todo, _) => (),
+            Span::Format(_, inner) => spans_into(out, inner),
+            Span::LineBreak => out.push_str("<break strength=\"weak\"/>"),
+            Span::Raw(_) => (),
+        }
+    }
+}
+
+/// Renders a block as SSML, or [`None`] for raw blocks, which are only meaningful to the output
+/// format they were written for. Quotations and lists are read as the blocks they hold.
+fn block(block: &Block) -> Option<String> {
+    let Block(format, content, _) = block;
+    let mut out = String::from("<p>");
+
+    match format {
+        BlockFormat::Paragraph => spans_into(&mut out, content),
+        BlockFormat::Heading(_) => {
+            out.push_str("<emphasis level=\"strong\">");
+            spans_into(&mut out, content);
+            out.push_str("</emphasis>");
+        }
+        BlockFormat::Code | BlockFormat::Output => out.push_str(CODE_ANNOUNCEMENT),
+        BlockFormat::Math => out.push_str(MATH_ANNOUNCEMENT),
+        BlockFormat::Raw => return None,
+        BlockFormat::Quote { .. } | BlockFormat::List { .. } => {
+            let children: Vec<_> = block.children().filter_map(self::block).collect();
+            return Some(children.join(BLOCK_BREAK)).filter(|out| !out.is_empty());
+        }
+    }
+
+    out.push_str("</p>");
+    Some(out)
+}
+
+/// Renders blocks as SSML elements, with pauses between them. The result is meant to be put in a
+/// `<speak>` element, see [`output`].
+pub fn output_fragment(fragment: &[Block]) -> String {
+    fragment
+        .iter()
+        .filter_map(block)
+        .collect::<Vec<_>>()
+        .join(BLOCK_BREAK)
+}
+
+/// Renders the document as an SSML `<speak>` document, its title first.
+pub fn output(document: &Document) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <speak version=\"1.1\" xmlns=\"http://www.w3.org/2001/10/synthesis\"",
+    );
+    if let Some(lang) = &document.metadata.lang {
+        write!(out, " xml:lang=\"{}\"", escape_attribute(lang)).unwrap();
+    }
+    out.push('>');
+
+    if let Some(title) = &document.metadata.title {
+        out.push_str("<p><emphasis level=\"strong\">");
+        spans_into(&mut out, &title.formatted);
+        out.push_str("</emphasis></p>");
+        out.push_str(BLOCK_BREAK);
+    }
+    out.push_str(&output_fragment(&document.outline));
+    out.push_str("</speak>\n");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::process_source;
+    use crate::EngineOptions;
+
+    #[test]
+    fn test_ssml() {
+        let source =
+            "\\meta:title{Title}\\meta:lang{fr}\n\nSome \\strong{bold} & \\todo{fix}text.\n\n\
+            \\begin{code}x < y\\end{code}\n\n\\raw{<b>}";
+        let document = process_source(source, &EngineOptions::default()).unwrap();
+
+        assert_eq!(
+            output_fragment(&document.outline),
+            format!(
+                "<p>Some <emphasis>bold</emphasis> &amp; text.</p>{}<p>{}</p>",
+                BLOCK_BREAK, CODE_ANNOUNCEMENT
+            )
+        );
+
+        let ssml = output(&document);
+        assert!(ssml.contains(" xml:lang=\"fr\">"));
+        assert!(ssml.contains("<p><emphasis level=\"strong\">Title</emphasis></p>"));
+        assert!(ssml.ends_with("</speak>\n"));
+    }
+}
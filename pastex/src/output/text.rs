@@ -0,0 +1,56 @@
+//! Plain text export of processed documents, without any markup.
+
+use crate::document::{plain_text, Block, BlockFormat, Document};
+
+/// Writes a list item, its first line after `marker` and the next ones indented to match.
+fn list_item(marker: &str, item: &[Block]) -> String {
+    let indent = " ".repeat(marker.len());
+    let mut out = marker.to_owned();
+
+    for (i, line) in output_fragment(item).lines().enumerate() {
+        if i > 0 {
+            out.push('\n');
+            if !line.is_empty() {
+                out.push_str(&indent);
+            }
+        }
+        out.push_str(line);
+    }
+
+    out
+}
+
+/// Renders blocks as plain text, separated by blank lines. Raw blocks are dropped, as their
+/// content is only meaningful to the output format they were written for, quotations are
+/// written as the blocks they hold, and list items on their own lines after `-` or their number.
+pub fn output_fragment(fragment: &[Block]) -> String {
+    fragment
+        .iter()
+        .filter(|Block(format, ..)| *format != BlockFormat::Raw)
+        .map(|block| match block {
+            Block(BlockFormat::Quote { blocks, .. }, ..) => output_fragment(blocks),
+            Block(BlockFormat::List { ordered, items }, ..) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| match ordered {
+                    true => list_item(&format!("{}. ", i + 1), item),
+                    false => list_item("- ", item),
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Block(_, content, _) => plain_text(content),
+        })
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Renders the document as plain text, its title first.
+pub fn output(document: &Document) -> String {
+    let body = output_fragment(&document.outline);
+
+    match &document.metadata.title {
+        Some(title) => format!("{}\n\n{}\n", title.plain, body),
+        None => format!("{}\n", body),
+    }
+}
@@ -0,0 +1,178 @@
+//! Quick split of a source between its metadata and its body, without processing it.
+
+use pastex_parser::lexer::{tokenize, Token, TokenKind};
+
+/// The metadata at the start of a document, see [`split`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataSource<'s> {
+    /// `\meta:...` commands, with the comments and whitespace before and between them. Empty when
+    /// the document starts with anything else.
+    Commands(&'s str),
+    /// YAML front matter between `---` lines, without them. pastex does not read it itself, it is
+    /// only recognized for tools sharing documents with other generators.
+    FrontMatter(&'s str),
+}
+
+/// The rest of a document, after its metadata, see [`split`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodySource<'s> {
+    pub source: &'s str,
+    /// Byte offset of the body in the whole source
+    pub offset: usize,
+}
+
+const FRONT_MATTER_FENCE: &str = "---";
+
+/// Encoding marker some editors put at the start of UTF-8 files, part of neither the metadata nor
+/// the body
+const BYTE_ORDER_MARK: char = '\u{feff}';
+
+/// Splits YAML front matter from the rest of the source, if the source starts with it.
+fn front_matter(source: &str) -> Option<(&str, usize)> {
+    let mut lines = source.split_inclusive('\n');
+    if lines.next()?.trim_end() != FRONT_MATTER_FENCE {
+        return None;
+    }
+
+    let start = source.find('\n')? + 1;
+    let mut pos = start;
+    for line in lines {
+        if line.trim_end() == FRONT_MATTER_FENCE {
+            return Some((&source[start..pos], pos + line.len()));
+        }
+        pos += line.len();
+    }
+
+    None
+}
+
+/// Finds where the `\meta` command starting at `CommandStart` token ends, or [`None`] for other
+/// commands.
+fn meta_command_end<'s>(
+    tokens: &mut std::iter::Peekable<impl Iterator<Item = Token<'s>>>,
+) -> Option<usize> {
+    let namespace = tokens.next().filter(|t| t.kind == TokenKind::Ident)?;
+    let separator = tokens.next()?;
+    if namespace.text != "meta" || separator.kind != TokenKind::NamespaceSeparator {
+        return None;
+    }
+
+    let mut end = separator.span.end;
+    let mut depth = 0usize;
+    while let Some(token) = tokens.peek() {
+        match token.kind {
+            TokenKind::BraceOpen => depth += 1,
+            TokenKind::BraceClose if depth <= 1 => {
+                end = token.span.end;
+                tokens.next();
+                break;
+            }
+            TokenKind::BraceClose => depth -= 1,
+            TokenKind::Verbatim if depth == 0 => {
+                end = token.span.end;
+                tokens.next();
+                break;
+            }
+            // Anything but the name and parameters ends a command without content
+            TokenKind::Ident
+            | TokenKind::ParamsOpen
+            | TokenKind::ParamsClose
+            | TokenKind::ParamAssign
+            | TokenKind::ParamSeparator
+            | TokenKind::ParamValue
+            | TokenKind::Whitespace => (),
+            _ if depth == 0 => break,
+            _ => (),
+        }
+
+        end = token.span.end;
+        tokens.next();
+    }
+
+    Some(end)
+}
+
+/// Splits a document between its metadata and its body, only looking at its syntax, so tools like
+/// git hooks can check metadata without processing whole documents.
+///
+/// The metadata is either YAML front matter, or the `\meta:...` commands the document starts
+/// with. The body starts at the first element which is not part of them.
+pub fn split(source: &str) -> (MetadataSource, BodySource) {
+    let start = match source.starts_with(BYTE_ORDER_MARK) {
+        true => BYTE_ORDER_MARK.len_utf8(),
+        false => 0,
+    };
+    let (metadata, body) = split_from(&source[start..]);
+    let body = BodySource {
+        offset: body.offset + start,
+        ..body
+    };
+    (metadata, body)
+}
+
+fn split_from(source: &str) -> (MetadataSource, BodySource) {
+    if let Some((front_matter, offset)) = front_matter(source) {
+        let body = BodySource {
+            source: &source[offset..],
+            offset,
+        };
+        return (MetadataSource::FrontMatter(front_matter), body);
+    }
+
+    let mut tokens = tokenize(source).peekable();
+    let mut end = 0;
+    while let Some(token) = tokens.next() {
+        match token.kind {
+            TokenKind::Comment => end = token.span.end,
+            TokenKind::Text if token.text.trim().is_empty() => end = token.span.end,
+            TokenKind::CommandStart => match meta_command_end(&mut tokens) {
+                Some(command_end) => end = command_end,
+                None => break,
+            },
+            _ => break,
+        }
+    }
+
+    // Leave the whitespace after the metadata in the body, so it starts at the next line
+    let metadata = source[..end].trim_end();
+    let offset = metadata.len();
+    let body = BodySource {
+        source: &source[offset..],
+        offset,
+    };
+    (MetadataSource::Commands(metadata), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let source =
+            "% intro\n\\meta:title{A \\strong{b}}\n\\meta:draft\n\\meta:tags[x = y]|a, b|\n\n\
+            \\head1{Body}";
+        let (metadata, body) = split(source);
+        assert_eq!(
+            metadata,
+            MetadataSource::Commands(
+                "% intro\n\\meta:title{A \\strong{b}}\n\\meta:draft\n\\meta:tags[x = y]|a, b|"
+            )
+        );
+        assert_eq!(body.source, "\n\n\\head1{Body}");
+        assert_eq!(&source[body.offset..], body.source);
+
+        let (metadata, body) = split("Text \\meta:draft");
+        assert_eq!(metadata, MetadataSource::Commands(""));
+        assert_eq!(body.offset, 0);
+
+        let (metadata, body) = split("---\ntitle: A\n---\nText");
+        assert_eq!(metadata, MetadataSource::FrontMatter("title: A\n"));
+        assert_eq!(body.source, "Text");
+
+        let source = "\u{feff}\\meta:draft\r\n\r\nText";
+        let (metadata, body) = split(source);
+        assert_eq!(metadata, MetadataSource::Commands("\\meta:draft"));
+        assert_eq!(&source[body.offset..], "\r\n\r\nText");
+    }
+}
@@ -0,0 +1,452 @@
+//! Cleaning of SVG files inlined in the output, like icons.
+//!
+//! This is not a full XML parser: it only keeps the drawing elements and attributes it knows, and
+//! links to web pages. Everything else is dropped, like scripts, animations, styles and foreign
+//! content, as any of them could run code when the SVG is inlined in a page.
+
+/// Elements kept, in lower case. Other elements are removed with their whole content.
+const ELEMENTS: &[&str] = &[
+    "a",
+    "circle",
+    "clippath",
+    "defs",
+    "desc",
+    "ellipse",
+    "g",
+    "line",
+    "lineargradient",
+    "marker",
+    "mask",
+    "path",
+    "pattern",
+    "polygon",
+    "polyline",
+    "radialgradient",
+    "rect",
+    "stop",
+    "svg",
+    "symbol",
+    "text",
+    "textpath",
+    "title",
+    "tspan",
+    "use",
+];
+
+/// Removed elements whose content is taken as text by browsers, up to their closing tag
+const RAW_TEXT_ELEMENTS: [&str; 2] = ["script", "style"];
+
+/// Attributes kept, in lower case
+const ATTRIBUTES: &[&str] = &[
+    "aria-hidden",
+    "aria-label",
+    "aria-labelledby",
+    "class",
+    "clip-path",
+    "clip-rule",
+    "clippathunits",
+    "color",
+    "cx",
+    "cy",
+    "d",
+    "display",
+    "dominant-baseline",
+    "dx",
+    "dy",
+    "fill",
+    "fill-opacity",
+    "fill-rule",
+    "focusable",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "fx",
+    "fy",
+    "gradienttransform",
+    "gradientunits",
+    "height",
+    "href",
+    "id",
+    "lang",
+    "lengthadjust",
+    "marker-end",
+    "marker-mid",
+    "marker-start",
+    "markerheight",
+    "markerunits",
+    "markerwidth",
+    "mask",
+    "maskcontentunits",
+    "maskunits",
+    "offset",
+    "opacity",
+    "orient",
+    "pathlength",
+    "patterncontentunits",
+    "patterntransform",
+    "patternunits",
+    "points",
+    "preserveaspectratio",
+    "r",
+    "refx",
+    "refy",
+    "role",
+    "rotate",
+    "rx",
+    "ry",
+    "spreadmethod",
+    "startoffset",
+    "stop-color",
+    "stop-opacity",
+    "stroke",
+    "stroke-dasharray",
+    "stroke-dashoffset",
+    "stroke-linecap",
+    "stroke-linejoin",
+    "stroke-miterlimit",
+    "stroke-opacity",
+    "stroke-width",
+    "text-anchor",
+    "textlength",
+    "title",
+    "transform",
+    "version",
+    "vector-effect",
+    "viewbox",
+    "visibility",
+    "width",
+    "x",
+    "x1",
+    "x2",
+    "xlink:href",
+    "xml:space",
+    "xmlns",
+    "xmlns:xlink",
+    "y",
+    "y1",
+    "y2",
+];
+
+/// Attributes holding a link, only kept when it is safe, see [`is_safe_url`]
+const URL_ATTRIBUTES: [&str; 2] = ["href", "xlink:href"];
+
+/// Schemes of the links kept. Links without a scheme, as `#id`, are kept too.
+const URL_SCHEMES: [&str; 3] = ["http", "https", "mailto"];
+
+/// Named character references resolved in links. Links with other references are dropped, as
+/// they can't be checked.
+const ENTITIES: [(&str, char); 9] = [
+    ("amp", '&'),
+    ("apos", '\''),
+    ("colon", ':'),
+    ("gt", '>'),
+    ("lt", '<'),
+    ("newline", '\n'),
+    ("quot", '"'),
+    ("sol", '/'),
+    ("tab", '\t'),
+];
+
+/// Finds the `>` ending the tag `tag` starts with, skipping quoted attribute values.
+fn tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.')
+}
+
+/// Resolves the character references of an attribute value, like a browser does. Returns
+/// [`None`] if it holds a named reference which is not in [`ENTITIES`].
+fn decode(value: &str) -> Option<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp + 1..];
+
+        if let Some(number) = rest.strip_prefix('#') {
+            let (digits, radix) = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => (hex, 16),
+                None => (number, 10),
+            };
+            let len = digits
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(digits.len());
+
+            if len == 0 {
+                out.push('&');
+                continue;
+            }
+
+            let c = u32::from_str_radix(&digits[..len], radix)
+                .ok()
+                .and_then(char::from_u32)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            out.push(c);
+            rest = &digits[len..];
+            rest = rest.strip_prefix(';').unwrap_or(rest);
+        } else {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let name = rest[..len].to_ascii_lowercase();
+
+            match ENTITIES.iter().find(|(entity, _)| *entity == name) {
+                Some((_, c)) => {
+                    out.push(*c);
+                    rest = &rest[len..];
+                    rest = rest.strip_prefix(';').unwrap_or(rest);
+                }
+                // Without a semicolon, as in `?a=1&b=2`, it is not a reference
+                None if !rest[len..].starts_with(';') => out.push('&'),
+                None => return None,
+            }
+        }
+    }
+
+    out.push_str(rest);
+    Some(out)
+}
+
+/// Whether a link only leads to a web page, an address or inside the file, once its character
+/// references are resolved.
+pub(crate) fn is_safe_url(value: &str) -> bool {
+    let url = match decode(value) {
+        Some(url) => url,
+        None => return false,
+    };
+    // Browsers ignore whitespace and control characters in links
+    let url = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => URL_SCHEMES.contains(&&url[..i]),
+        _ => true,
+    }
+}
+
+fn is_safe_attribute(name: &str, value: Option<&str>) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    ATTRIBUTES.contains(&name.as_str())
+        && (!URL_ATTRIBUTES.contains(&name.as_str()) || value.is_some_and(is_safe_url))
+}
+
+/// Writes the opening tag of an element named `name` with the safe attributes of `attributes`,
+/// the part of the tag following the name.
+fn clean_tag(out: &mut String, name: &str, attributes: &str, self_closing: bool) {
+    let mut rest = attributes;
+
+    out.push('<');
+    out.push_str(name);
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        if rest.is_empty() {
+            break;
+        }
+
+        let name_len = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len())
+            .max(1);
+        let name = &rest[..name_len];
+        rest = &rest[name_len..];
+
+        let mut value = None;
+        let after = rest.trim_start();
+        if let Some(after) = after.strip_prefix('=') {
+            let after = after.trim_start();
+            let (inner, len) = match after.chars().next() {
+                Some(q @ ('"' | '\'')) => match after[1..].find(q) {
+                    Some(i) => (&after[1..=i], i + 2),
+                    None => (&after[1..], after.len()),
+                },
+                _ => {
+                    let len = after.find(char::is_whitespace).unwrap_or(after.len());
+                    (&after[..len], len)
+                }
+            };
+
+            value = Some(inner);
+            rest = &after[len..];
+        }
+
+        if name.chars().all(is_name_char) && is_safe_attribute(name, value) {
+            out.push(' ');
+            out.push_str(name);
+            if let Some(value) = value {
+                // Written again between double quotes, so it can't end up read differently
+                out.push_str(&format!("=\"{}\"", value.replace('"', "&quot;")));
+            }
+        }
+    }
+
+    if self_closing {
+        out.push('/');
+    }
+    out.push('>');
+}
+
+/// Gets the `<svg>` element of an SVG file, keeping only known drawing elements and attributes,
+/// and links to web pages. Returns [`None`] if there is no such element or it is malformed.
+pub fn sanitize(svg: &str) -> Option<String> {
+    let start = svg.find("<svg")?;
+    let end = svg.rfind("</svg>")? + "</svg>".len();
+    let mut svg = svg.get(start..end)?;
+    let mut out = String::with_capacity(svg.len());
+    // How many elements deep in a removed element the current position is
+    let mut removed = 0usize;
+
+    while let Some(open) = svg.find('<') {
+        if removed == 0 {
+            out.push_str(&svg[..open]);
+        }
+        svg = &svg[open..];
+
+        if let Some(comment) = svg.strip_prefix("<!--") {
+            svg = &comment[comment.find("-->")? + 3..];
+            continue;
+        }
+        if let Some(data) = svg.strip_prefix("<![CDATA[") {
+            svg = &data[data.find("]]>")? + 3..];
+            continue;
+        }
+
+        let end = tag_end(svg)?;
+        let tag = &svg[1..end];
+        svg = &svg[end + 1..];
+
+        // Declarations and processing instructions are dropped
+        if tag.starts_with(['!', '?']) {
+            continue;
+        }
+
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let (tag, self_closing) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let name_len = tag.find(|c| !is_name_char(c)).unwrap_or(tag.len());
+        let (name, attributes) = tag.split_at(name_len);
+        let known = ELEMENTS.contains(&name.to_ascii_lowercase().as_str())
+            && (attributes.is_empty()
+                || attributes.starts_with(|c: char| c.is_whitespace() || c == '/'));
+
+        match (closing, self_closing) {
+            (true, _) if removed > 0 => removed -= 1,
+            (true, _) if known => out.push_str(&format!("</{}>", name)),
+            (true, _) => (),
+            (false, true) if removed > 0 || !known => (),
+            (false, false) if RAW_TEXT_ELEMENTS.contains(&name.to_ascii_lowercase().as_str()) => {
+                // Their content is not markup, and can hold anything up to their closing tag
+                let closing = svg
+                    .to_ascii_lowercase()
+                    .find(&format!("</{}", name.to_ascii_lowercase()))?;
+                svg = &svg[closing..];
+                svg = &svg[tag_end(svg)? + 1..];
+            }
+            (false, false) if removed > 0 || !known => removed += 1,
+            (false, _) => clean_tag(&mut out, name, attributes, self_closing),
+        }
+    }
+
+    if removed > 0 {
+        return None;
+    }
+
+    out.push_str(svg);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sanitize;
+
+    #[test]
+    fn test_sanitize() {
+        let svg = r#"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)" viewBox="0 0 1 1"><!-- <b> -->
+<script>alert("</svg>")</script><a href = 'javascript:x()' title="a > b"><path d="M0 0"/></a>
+<SCRIPT src=x /><foreignObject><p>x</p></foreignObject></svg>
+"#;
+
+        assert_eq!(
+            sanitize(svg).unwrap(),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 1 1\">\n\
+            <a title=\"a > b\"><path d=\"M0 0\"/></a>\n</svg>"
+        );
+        assert_eq!(sanitize("<p>not an svg</p>"), None);
+        assert_eq!(sanitize("<svg><path d='x</svg>"), None);
+    }
+
+    #[test]
+    fn test_sanitize_bypasses() {
+        let clean = |inner: &str| sanitize(&format!("<svg>{}</svg>", inner)).unwrap();
+
+        // Encoded schemes
+        for href in [
+            "javascript&#58;alert(1)",
+            "javascript&#x3A;alert(1)",
+            "javascript&colon;alert(1)",
+            "java&Tab;script:alert(1)",
+            "&#106;avascript:alert(1)",
+            " JavaScript:alert(1)",
+            "data:text/html,x",
+            "javascript&unknown;alert(1)",
+        ] {
+            for attribute in ["href", "xlink:href"] {
+                let svg = clean(&format!("<a {}='{}'>x</a>", attribute, href));
+                assert_eq!(svg, "<svg><a>x</a></svg>", "{}", href);
+            }
+        }
+
+        // Animations rewriting links
+        assert_eq!(
+            clean(
+                "<a href='#'><animate attributeName='href' values='javascript:alert(1)'/>\
+                <set attributeName=\"href\" to=\"javascript:alert(1)\"></set>x</a>"
+            ),
+            "<svg><a href=\"#\">x</a></svg>"
+        );
+
+        // Unknown elements and their content, even nested
+        assert_eq!(
+            clean(
+                "<switch><switch></switch>x</switch><style>a</b></style><iframe/><g><image/></g>"
+            ),
+            "<svg><g></g></svg>"
+        );
+
+        // Names glued to the element name, and attributes written again
+        assert_eq!(
+            clean("<path/onload=alert(1) d='a\"b'/><svg/onload=alert(1)></svg>"),
+            "<svg><path d=\"a&quot;b\"/><svg></svg></svg>"
+        );
+
+        assert_eq!(
+            clean("<a href='https://example.com/?a=1&b=2' style='x'>x</a>"),
+            "<svg><a href=\"https://example.com/?a=1&b=2\">x</a></svg>"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! Instrumentation of the pipeline, for servers embedding pastex to find out why a document is
+//! slow to render.
+//!
+//! With the `tracing` feature, the parse, process and render phases each run inside a span, and
+//! every command call emits an event with its duration. Without it, these helpers only call the
+//! given function.
+
+use pastex_parser::CommandName;
+
+#[cfg(feature = "tracing")]
+pub(crate) fn phase<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let _span = tracing::info_span!("phase", name).entered();
+    let start = std::time::Instant::now();
+    let res = f();
+
+    tracing::debug!(
+        phase = name,
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "phase done"
+    );
+    res
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn phase<T>(_: &'static str, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn command<T>(name: CommandName, f: impl FnOnce() -> T) -> T {
+    let _span = tracing::debug_span!("command", name = %name).entered();
+    let start = std::time::Instant::now();
+    let res = f();
+
+    tracing::trace!(
+        command = %name,
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "command done"
+    );
+    res
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn command<T>(_: CommandName, f: impl FnOnce() -> T) -> T {
+    f()
+}
@@ -0,0 +1,45 @@
+//! Checks that parsing never panics nor loops on any input, with the default syntax and with
+//! multibyte syntax characters. Run with `cargo fuzz run parse`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pastex_parser::{
+    expand_macros, format_with, lexer::tokenize_with, parse_lenient, parse_recovering, parse_with,
+    FormatOptions, Pair, ParserOptions,
+};
+
+fn check(source: &str, options: &ParserOptions) {
+    if let Ok(stream) = parse_with(source, options) {
+        let format = FormatOptions {
+            syntax: options.clone(),
+            ..Default::default()
+        };
+        format_with(&stream, &format);
+        let _ = expand_macros(source, stream, options);
+    }
+    tokenize_with(source, options).for_each(drop);
+}
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    let _ = parse_lenient(source);
+    parse_recovering(source);
+    check(source, &ParserOptions::default());
+    check(
+        source,
+        &ParserOptions {
+            command_char: '¤',
+            comment_char: None,
+            content_chars: Pair {
+                open: '«',
+                close: '»',
+            },
+            ..Default::default()
+        },
+    );
+});
@@ -0,0 +1,38 @@
+//! Checks that incremental reparsing never panics and gives the same result as parsing the edited
+//! source from scratch. Run with `cargo fuzz run reparse`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pastex_parser::{parse, reparse, TextEdit};
+
+/// The char boundary at or before `pos`, clamped to the source.
+fn boundary(source: &str, pos: u16) -> usize {
+    let mut pos = usize::from(pos).min(source.len());
+    while !source.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
+}
+
+fuzz_target!(|input: (&str, u16, u16, &str)| {
+    let (source, start, end, replacement) = input;
+    let (start, end) = (boundary(source, start), boundary(source, end));
+    let edit = TextEdit {
+        range: start.min(end)..start.max(end),
+        replacement,
+    };
+
+    let old = match parse(source) {
+        Ok(old) => old,
+        Err(_) => return,
+    };
+    let new_source = edit.apply(source);
+    let res = reparse(source, old, edit.range, &new_source);
+
+    assert_eq!(
+        format!("{:?}", res),
+        format!("{:?}", parse(&new_source)),
+        "reparse differs from parse"
+    );
+});
@@ -0,0 +1,140 @@
+//! Attachment of comments to the command following them, see [`ParserOptions::attach_comments`].
+//!
+//! [`ParserOptions::attach_comments`]: super::ParserOptions::attach_comments
+
+use super::{Command, Element, ParamValue, Stream};
+
+/// Whether a comment following `previous` starts its line, like a doc comment, rather than ending
+/// a line of text.
+fn starts_line(previous: Option<&Element>) -> bool {
+    match previous {
+        None => true,
+        Some(Element::Raw(text)) => match text.rfind('\n') {
+            Some(pos) => text[pos + 1..].trim().is_empty(),
+            None => false,
+        },
+        Some(_) => false,
+    }
+}
+
+/// Moves the comment lines right before commands into their [`Command::comments`], in `stream`
+/// and the streams nested in it.
+pub(crate) fn attach(stream: Stream) -> Stream {
+    let mut res: Stream = Vec::with_capacity(stream.len());
+    // Positions in `res` of the comments the next command would get, from the first one on
+    let mut pending: Vec<usize> = Vec::new();
+
+    for el in stream {
+        match el {
+            Element::Comment(_) => {
+                // Anything before the comment on the same line makes it a trailing comment
+                match starts_line(res.last()) {
+                    true => pending.push(res.len()),
+                    false => pending.clear(),
+                }
+                res.push(el);
+            }
+            // A single line break keeps the comments with what follows, a blank line does not
+            Element::Raw(text) if text.trim().is_empty() && text.matches('\n').count() <= 1 => {
+                res.push(el)
+            }
+            Element::Command(cmd) => {
+                let mut cmd = nested(cmd);
+                // Only line breaks are left between the comments, and they go with them
+                if let Some(&first) = pending.first() {
+                    cmd.comments
+                        .extend(res.drain(first..).filter_map(|el| match el {
+                            Element::Comment(comment) => Some(comment),
+                            _ => None,
+                        }));
+                }
+                pending.clear();
+                res.push(Element::Command(cmd));
+            }
+            el => {
+                pending.clear();
+                res.push(el);
+            }
+        }
+    }
+
+    res
+}
+
+/// Attaches the comments of the streams nested in a command.
+fn nested(cmd: Command) -> Command {
+    Command {
+        content: attach(cmd.content),
+        arguments: cmd.arguments.into_iter().map(attach).collect(),
+        params: cmd
+            .params
+            .into_iter()
+            .map(|(key, value)| match value {
+                ParamValue::Stream(stream) => (key, ParamValue::Stream(attach(stream))),
+                value => (key, value),
+            })
+            .collect(),
+        ..cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_with, Element, ParserOptions};
+
+    fn parse(source: &str) -> Vec<Element<'_>> {
+        let options = ParserOptions {
+            attach_comments: true,
+            ..Default::default()
+        };
+        parse_with(source, &options).unwrap()
+    }
+
+    /// The comments attached to each command of `stream`.
+    fn attached<'b>(stream: &[Element<'b>]) -> Vec<Vec<&'b str>> {
+        stream
+            .iter()
+            .filter_map(|el| match el {
+                Element::Command(cmd) => Some(cmd.comments.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_before_text() {
+        let stream = parse("% note\nText \\foo");
+        assert!(matches!(stream[0], Element::Comment(" note")));
+        assert_eq!(attached(&stream), [Vec::<&str>::new()]);
+
+        let stream = parse("% note\n$x$ \\foo");
+        assert!(matches!(stream[0], Element::Comment(" note")));
+        assert_eq!(attached(&stream), [Vec::<&str>::new()]);
+    }
+
+    #[test]
+    fn test_blank_line() {
+        let stream = parse("% apart\n\n\\foo");
+        assert!(matches!(stream[0], Element::Comment(" apart")));
+        assert_eq!(attached(&stream), [Vec::<&str>::new()]);
+
+        // Lines of spaces are blank too, and only the comments after them are attached
+        let stream = parse("% apart\n  \n% doc\n\\foo");
+        assert!(matches!(stream[0], Element::Comment(" apart")));
+        assert_eq!(attached(&stream), [[" doc"]]);
+    }
+
+    #[test]
+    fn test_trailing() {
+        let stream = parse("Text % trailing\n\\foo");
+        assert!(matches!(stream[1], Element::Comment(" trailing")));
+        assert_eq!(attached(&stream), [Vec::<&str>::new()]);
+
+        let stream = parse("\\foo % end");
+        assert!(matches!(stream.last(), Some(Element::Comment(" end"))));
+        assert_eq!(attached(&stream), [Vec::<&str>::new()]);
+
+        let stream = parse("Text % trailing\n% doc\n\\foo");
+        assert_eq!(attached(&stream), [[" doc"]]);
+    }
+}
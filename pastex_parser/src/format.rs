@@ -0,0 +1,423 @@
+//! Canonical formatting of parsed documents, for a `fmt` workflow and deterministic diffs.
+//!
+//! [`format`] writes a stream back as source with normalized spacing: whitespace runs collapse
+//! into a single space, paragraphs are separated by a single blank line and wrapped at
+//! [`FormatOptions::width`], parameters are sorted by name within their `[...]` group and blocks
+//! always use the `\begin[...]{name}` form. Verbatim contents are kept as is. Formatting a formatted document
+//! gives it back unchanged.
+
+use super::{
+    is_ident, paragraph_ends, Command, Element, ParamValue, Params, ParserOptions, Stream,
+    COMMAND_PARAMS_ASSIGN_CHAR, COMMAND_PARAMS_SEP_CHAR, MATH_CHAR, STAR_CHAR,
+};
+use std::collections::HashSet;
+
+/// Settings of [`format_with`].
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// Column paragraphs are wrapped at, 80 by default. Longer words and commands are not split.
+    pub width: usize,
+    /// The syntax the stream was parsed with, to write it back the same way
+    pub syntax: ParserOptions,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            width: 80,
+            syntax: ParserOptions::default(),
+        }
+    }
+}
+
+struct Writer<'o> {
+    out: String,
+    width: usize,
+    syntax: &'o ParserOptions,
+    column: usize,
+    /// Whether a space is due before the next word
+    space: bool,
+    /// Whether nothing was written since the start of the document or of a block, where
+    /// whitespace is dropped
+    fresh: bool,
+    /// Whether the last thing written is a forced line break, whose line break is part of it
+    line_break: bool,
+}
+
+impl<'o> Writer<'o> {
+    fn new(width: usize, syntax: &'o ParserOptions) -> Self {
+        Writer {
+            out: String::new(),
+            width,
+            syntax,
+            column: 0,
+            space: false,
+            fresh: true,
+            line_break: false,
+        }
+    }
+
+    /// Writes a word, on the next line if it does not fit on the current one.
+    fn word(&mut self, word: &str) {
+        if self.space && self.column > 0 {
+            if self.column + 1 + word.chars().count() > self.width {
+                self.newline();
+            } else {
+                self.out.push(' ');
+                self.column += 1;
+            }
+        }
+
+        self.out.push_str(word);
+        self.column = match word.rfind('\n') {
+            Some(pos) => word[pos + 1..].chars().count(),
+            None => self.column + word.chars().count(),
+        };
+        self.space = false;
+        self.fresh = false;
+        self.line_break = false;
+    }
+
+    fn space(&mut self) {
+        if self.fresh {
+            self.end_line();
+        } else {
+            self.space = true;
+        }
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.column = 0;
+        self.space = false;
+    }
+
+    /// Goes to the next line, unless already at the start of one.
+    fn end_line(&mut self) {
+        if self.column > 0 {
+            self.newline();
+        }
+    }
+
+    fn paragraph_break(&mut self) {
+        self.end_line();
+        if self.line_break {
+            // The blank line comes after the line break ending the forced one
+            self.newline();
+            self.newline();
+            self.line_break = false;
+        } else if !self.fresh && !self.out.ends_with("\n\n") {
+            self.newline();
+        }
+    }
+
+    fn escape(&self, word: &str) -> String {
+        let syntax = self.syntax;
+        let mut out = String::with_capacity(word.len());
+
+        for c in word.chars() {
+            if c == syntax.command_char
+                || c == syntax.content_chars.close
+                || syntax.is_comment(c)
+                || syntax.is_math(c)
+            {
+                out.push(syntax.command_char);
+            }
+            out.push(c);
+        }
+
+        out
+    }
+
+    fn text(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n");
+
+        for (i, paragraph) in text.split("\n\n").enumerate() {
+            if i > 0 {
+                self.paragraph_break();
+            }
+            if paragraph.starts_with(char::is_whitespace) {
+                self.space();
+            }
+
+            let mut words = paragraph.split_whitespace().peekable();
+            while let Some(word) = words.next() {
+                self.word(&self.escape(word));
+                if words.peek().is_some() || paragraph.ends_with(char::is_whitespace) {
+                    self.space();
+                }
+            }
+        }
+    }
+
+    fn math(&mut self, display: bool, source: &str) {
+        let command = self.syntax.command_char;
+        let math = match display {
+            _ if source.is_empty() || source.contains(MATH_CHAR) => None,
+            // Math between dollars ends with its paragraph
+            _ if paragraph_ends(source).next().is_some() => None,
+            false if source.starts_with(char::is_whitespace) => None,
+            false if source.ends_with(char::is_whitespace) => None,
+            true => Some(format!("{0}{0}{1}{0}{0}", MATH_CHAR, source)),
+            false => Some(format!("{0}{1}{0}", MATH_CHAR, source)),
+        };
+        let math = math.unwrap_or_else(|| match display {
+            true => format!("{0}[{1}{0}]", command, source),
+            false => format!("{0}({1}{0})", command, source),
+        });
+
+        self.word(&math);
+    }
+
+    /// Formats a stream on its own, without wrapping, as for parameter values.
+    fn nested(&self, stream: &[Element]) -> String {
+        let mut writer = Writer::new(usize::MAX, self.syntax);
+        writer.stream(stream);
+        writer.out.trim_end().to_owned()
+    }
+
+    /// Writes the parameter groups of a command. A parameter given in several groups is only
+    /// written in the last one, where its value comes from.
+    fn params(&self, cmd: &Command) -> String {
+        let mut groups = match &cmd.param_groups[..] {
+            // Commands built by hand may only fill the parameters
+            [] if !cmd.params.is_empty() => vec![cmd.params.keys().copied().collect()],
+            groups => groups.to_vec(),
+        };
+
+        let mut seen = HashSet::new();
+        for group in groups.iter_mut().rev() {
+            group.reverse();
+            group.retain(|name| seen.insert(*name));
+            group.sort_unstable();
+        }
+
+        groups
+            .iter()
+            .map(|group| self.param_group(group, &cmd.params))
+            .collect()
+    }
+
+    fn param_group(&self, names: &[&str], params: &Params) -> String {
+        let (open, close) = (
+            self.syntax.content_chars.open,
+            self.syntax.content_chars.close,
+        );
+
+        let params = names
+            .iter()
+            .filter_map(|key| Some((key, params.get(key)?)))
+            .map(|(key, value)| match value {
+                ParamValue::None => key.to_string(),
+                ParamValue::Text(text) => {
+                    format!("{} {} {}", key, COMMAND_PARAMS_ASSIGN_CHAR, text.trim())
+                }
+                ParamValue::Stream(stream) => format!(
+                    "{} {} {}{}{}",
+                    key,
+                    COMMAND_PARAMS_ASSIGN_CHAR,
+                    open,
+                    self.nested(stream),
+                    close
+                ),
+            })
+            .collect::<Vec<_>>()
+            .join(&format!("{} ", COMMAND_PARAMS_SEP_CHAR));
+
+        format!(
+            "{}{}{}",
+            self.syntax.params_chars.open, params, self.syntax.params_chars.close
+        )
+    }
+
+    /// The verbatim content of a command with its delimiters, as `|...|`, when it is written that
+    /// way.
+    fn verbatim_content<'b>(&self, cmd: &Command<'b>) -> Option<&'b str> {
+        let raw = match &cmd.content[..] {
+            [Element::Raw(raw)] => raw,
+            _ => return None,
+        };
+
+        // The content may not come from the source of the call in streams built by hand
+        let start = (raw.as_ptr() as usize).checked_sub(cmd.source.as_ptr() as usize)?;
+        let end = start + raw.len();
+        let before = cmd.source.get(..start)?.chars().next_back()?;
+        let after = cmd.source.get(end..)?.chars().next()?;
+
+        (before == after && before != self.syntax.content_chars.open)
+            .then(|| &cmd.source[start - before.len_utf8()..end + after.len_utf8()])
+    }
+
+    /// Whether a command without content needs empty braces not to run into what follows it.
+    fn needs_braces(&self, next: Option<&Element>) -> bool {
+        let syntax = self.syntax;
+        let first = match next {
+            Some(Element::Raw(text)) => text.chars().next(),
+            _ => None,
+        };
+
+        first.is_some_and(|c| {
+            is_ident(c)
+                || c == STAR_CHAR
+                || c == syntax.params_chars.open
+                || c == syntax.content_chars.open
+                || c == syntax.verbatim_char
+        })
+    }
+
+    fn command(&mut self, cmd: &Command, next: Option<&Element>) {
+        let syntax = self.syntax;
+        let (open, close) = (syntax.content_chars.open, syntax.content_chars.close);
+        let star = if cmd.starred { "*" } else { "" };
+        let name = format!("{}{}", cmd.command_name(), star);
+        let params = self.params(cmd);
+
+        // Attached comments go on their own lines, where they were taken from
+        if !cmd.comments.is_empty() {
+            let comment_char = syntax.comment_char.unwrap_or_default();
+            self.end_line();
+            for comment in &cmd.comments {
+                self.word(&format!("{}{}", comment_char, comment.trim_end()));
+                self.newline();
+            }
+        }
+
+        if cmd.block {
+            if self.space {
+                self.end_line();
+            }
+            self.word(&format!(
+                "{}{}{}{}{}{}",
+                syntax.command_char, syntax.block_keywords.0, params, open, name, close
+            ));
+
+            match &cmd.content[..] {
+                [Element::Raw(raw)] if syntax.is_verbatim(cmd.command_name()) => self.word(raw),
+                content => {
+                    self.fresh = true;
+                    self.stream(content);
+                    if self.space {
+                        self.end_line();
+                    }
+                    while self.out.ends_with("\n\n") {
+                        self.out.pop();
+                    }
+                }
+            }
+
+            self.word(&syntax.block_end(name));
+            return;
+        }
+
+        let head = format!("{}{}{}", syntax.command_char, name, params);
+        if let Some(content) = self.verbatim_content(cmd) {
+            self.word(&format!("{}{}", head, content));
+        } else if cmd.content.is_empty() {
+            match self.needs_braces(next) {
+                true => self.word(&format!("{}{}{}", head, open, close)),
+                false => self.word(&head),
+            }
+        } else {
+            self.word(&format!("{}{}", head, open));
+            self.stream(&cmd.content);
+            for argument in &cmd.arguments {
+                self.word(&format!("{}{}", close, open));
+                self.stream(argument);
+            }
+            self.word(&close.to_string());
+        }
+    }
+
+    fn stream(&mut self, stream: &[Element]) {
+        for (i, el) in stream.iter().enumerate() {
+            match el {
+                Element::Raw(text) => self.text(text),
+                Element::Comment(comment) => {
+                    let comment_char = self.syntax.comment_char.unwrap_or_default();
+                    self.word(&format!("{}{}", comment_char, comment.trim_end()));
+                    self.newline();
+                }
+                Element::LineBreak(_) => {
+                    self.space = false;
+                    self.word(&self.syntax.command_char.to_string());
+                    self.newline();
+                    self.line_break = true;
+                }
+                Element::Math { display, source } => self.math(*display, source),
+                Element::Command(cmd) => self.command(cmd, stream.get(i + 1)),
+            }
+        }
+    }
+}
+
+/// Writes a parsed stream back as canonical source, see the [module documentation](self).
+pub fn format(stream: &Stream) -> String {
+    format_with(stream, &FormatOptions::default())
+}
+
+/// Writes a parsed stream back as canonical source, like [`format`], with the given options.
+pub fn format_with(stream: &Stream, options: &FormatOptions) -> String {
+    let mut writer = Writer::new(options.width, &options.syntax);
+    writer.stream(stream);
+
+    let mut out = writer.out.trim_end().to_owned();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn reformat(source: &str, width: usize) -> String {
+        let options = FormatOptions {
+            width,
+            ..Default::default()
+        };
+        format_with(&parse(source).unwrap(), &options)
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "\n  Some   \\strong{bold\n text}, 50\\%  off.\n\n\n\n\\head1[ id = a ,dir=rtl ]{Title} \
+            % note  \nA\\\nB $x$ \\meta:draft{} \\begin{quote}\n\n  Quoted \\code|a  {b}|\n\n\\end{quote}\n\n\
+            \\begin{code}\n  keep   this\n\\end{code}";
+
+        assert_eq!(
+            reformat(source, 80),
+            "Some \\strong{bold text}, 50\\% off.\n\n\\head1[dir = rtl, id = a]{Title} % note\n\
+            A\\\nB $x$ \\meta:draft\n\\begin{quote}\nQuoted \\code|a  {b}|\n\\end{quote}\n\n\
+            \\begin{code}\n  keep   this\n\\end{code}\n"
+        );
+        assert_eq!(
+            reformat("\\foo[b = 1, a][][c, b = 2]{x}", 80),
+            "\\foo[a][][b = 2, c]{x}\n"
+        );
+        assert_eq!(
+            reformat("one two three \\strong{four five} six", 10),
+            "one two\nthree\n\\strong{four\nfive} six\n"
+        );
+    }
+
+    #[test]
+    fn test_format_idempotent() {
+        let source = "\\foo{}bar \\foo*[x = {a \\b{c}}]{d}{e} \\verb+a|b+ \\(a$b\\) $$c$$ \\}\n\n\
+            \\begin[run]{x*}\nText % c\nmore\\end{x*}";
+        let once = reformat(source, 20);
+
+        assert_eq!(reformat(&once, 20), once);
+        assert!(once.starts_with("\\foo{}bar"));
+
+        // A forced line break ending a paragraph keeps the blank line after it
+        for source in ["a\\\n\n\nb", "a\\\n\nb", "\\begin{x}a\\\n\n\n\\end{x}"] {
+            let once = reformat(source, 80);
+            assert_eq!(reformat(&once, 80), once, "{:?}", source);
+        }
+        assert_eq!(reformat("a\\\n\n\nb", 80), "a\\\n\n\nb\n");
+
+        assert_eq!(reformat("\\(a\n\nb\\)", 80), "\\(a\n\nb\\)\n");
+    }
+}
@@ -0,0 +1,383 @@
+//! Syntax definitions for editors, generated from the [`ParserOptions`] documents are parsed with,
+//! so highlighting follows customized syntax characters and verbatim blocks.
+//!
+//! [`textmate_grammar`] writes a TextMate grammar in its JSON form, which VS Code loads directly
+//! and other editors, like Sublime Text, can convert. TextMate grammars match regular expressions
+//! line by line, so this is an approximation of the parser: a `]` in a parameter value of a
+//! `\begin` ends its parameters early, for instance.
+
+use super::{is_ident, ParserOptions, COMMAND_PARAMS_ASSIGN_CHAR, NAMESPACE_CHAR, STAR_CHAR};
+use std::fmt::Write;
+
+/// Scope of pastex documents in the grammar, which other grammars can include.
+pub const SCOPE_NAME: &str = "text.pastex";
+
+/// Pattern matching a command, namespace or parameter name, see `is_ident`.
+const IDENT: &str = r"[\w-]+";
+
+/// A JSON value, enough of it to write grammars without a JSON library.
+enum Json {
+    Str(String),
+    List(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn str(value: impl Into<String>) -> Json {
+        Json::Str(value.into())
+    }
+
+    fn object<'k>(fields: impl IntoIterator<Item = (&'k str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect(),
+        )
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        let (items, open, close): (Vec<_>, _, _) = match self {
+            Json::Str(value) => return write_str(out, value),
+            Json::List(values) => (values.iter().map(|v| (None, v)).collect(), '[', ']'),
+            Json::Object(fields) => (fields.iter().map(|(k, v)| (Some(k), v)).collect(), '{', '}'),
+        };
+
+        out.push(open);
+        for (i, (key, value)) in items.iter().enumerate() {
+            out.push_str(if i == 0 { "\n" } else { ",\n" });
+            out.push_str(&"  ".repeat(indent + 1));
+            if let Some(key) = key {
+                write_str(out, key);
+                out.push_str(": ");
+            }
+            value.write(out, indent + 1);
+        }
+        if !items.is_empty() {
+            out.push('\n');
+            out.push_str(&"  ".repeat(indent));
+        }
+        out.push(close);
+    }
+}
+
+fn write_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// `c` as a regular expression matching it.
+fn re(c: char) -> String {
+    match is_ident(c) {
+        true => c.to_string(),
+        false => format!("\\{}", c),
+    }
+}
+
+/// `text` as a regular expression matching it.
+fn re_str(text: &str) -> String {
+    text.chars().map(re).collect()
+}
+
+/// A rule giving `name` to what `pattern` matches.
+fn rule(name: &str, pattern: String) -> Json {
+    Json::object([("name", Json::str(name)), ("match", Json::Str(pattern))])
+}
+
+/// The fields of a rule giving `name` to what is between `begin` and `end`, both excluded.
+fn region(name: &str, begin: String, end: String) -> Vec<(&'static str, Json)> {
+    vec![
+        ("contentName", Json::str(name)),
+        ("begin", Json::Str(begin)),
+        ("end", Json::Str(end)),
+    ]
+}
+
+fn include(name: &str) -> Json {
+    Json::object([("include", Json::str(name))])
+}
+
+/// Scopes given to the groups of a pattern, numbered from 1. Groups may also be highlighted with
+/// other rules. Empty names are skipped.
+fn captures(groups: &[(&str, Option<&str>)]) -> Json {
+    Json::Object(
+        groups
+            .iter()
+            .enumerate()
+            .filter(|(_, (name, rule))| !name.is_empty() || rule.is_some())
+            .map(|(i, (name, rule))| {
+                let mut capture = Vec::new();
+                if !name.is_empty() {
+                    capture.push(("name", Json::str(*name)));
+                }
+                if let Some(rule) = rule {
+                    capture.push(("patterns", Json::List(vec![include(rule)])));
+                }
+                ((i + 1).to_string(), Json::object(capture))
+            })
+            .collect(),
+    )
+}
+
+/// Scopes of `\begin[...]{name}` and `\end{name}`, see [`block_pattern`].
+const BLOCK_GROUPS: [(&str, Option<&str>); 6] = [
+    ("punctuation.definition.function.pastex", None),
+    ("keyword.control.block.pastex", None),
+    ("", Some("#parameters")),
+    ("punctuation.definition.block.begin.pastex", None),
+    ("entity.name.section.pastex", None),
+    ("punctuation.definition.block.end.pastex", None),
+];
+
+/// Pattern of a block start or end, with the groups of [`BLOCK_GROUPS`].
+fn block_pattern(options: &ParserOptions, keyword: &str, name: &str) -> String {
+    let (open, close) = (options.params_chars.open, options.params_chars.close);
+    format!(
+        "({})({})((?:{}[^{}]*{})*)({})({})({})",
+        re(options.command_char),
+        keyword,
+        re(open),
+        re(close),
+        re(close),
+        re(options.content_chars.open),
+        name,
+        re(options.content_chars.close),
+    )
+}
+
+/// The rules of the grammar, by name.
+fn repository(options: &ParserOptions) -> Vec<(&'static str, Json)> {
+    let cmd = re(options.command_char);
+    let (block_start, block_end) = &options.block_keywords;
+    let mut rules = Vec::new();
+
+    if let Some(comment_char) = options.comment_char {
+        let pattern = format!("{}.*$", re(comment_char));
+        rules.push(("comment", rule("comment.line.pastex", pattern)));
+    }
+
+    let mut escaped = vec![options.command_char, options.content_chars.close];
+    escaped.extend(options.comment_char);
+    escaped.extend(options.math_delimiters.then_some('$'));
+    let escaped: String = escaped.into_iter().map(re).collect();
+    let pattern = format!("{}(?:[{}]|$)", cmd, escaped);
+    rules.push(("escape", rule("constant.character.escape.pastex", pattern)));
+
+    if options.math_delimiters {
+        let math = |display, begin: &str, end: &str| {
+            let name = match display {
+                true => "string.other.math.display.pastex",
+                false => "string.other.math.inline.pastex",
+            };
+            Json::object(region(name, begin.to_owned(), end.to_owned()))
+        };
+        let patterns = vec![
+            math(true, r"\$\$", r"\$\$"),
+            math(true, &format!(r"{}\[", cmd), &format!(r"{}\]", cmd)),
+            // Like the parser, no whitespace right inside single dollars
+            math(false, r"\$(?=\S)", r"(?<=\S)\$"),
+            math(false, &format!(r"{}\(", cmd), &format!(r"{}\)", cmd)),
+        ];
+        rules.push(("math", Json::object([("patterns", Json::List(patterns))])));
+    }
+
+    if !options.verbatim_blocks.is_empty() {
+        let names: Vec<_> = options.verbatim_blocks.iter().map(|n| re_str(n)).collect();
+        let begin = block_pattern(options, &re_str(block_start), &names.join("|"));
+        // Ends with the name the block starts with, the fifth group of its start
+        let end = block_pattern(options, &re_str(block_end), r"\5");
+        let mut fields = region("markup.raw.block.pastex", begin, end);
+        fields.push(("beginCaptures", captures(&BLOCK_GROUPS)));
+        fields.push(("endCaptures", captures(&BLOCK_GROUPS)));
+        rules.push(("verbatim-block", Json::object(fields)));
+    }
+
+    let keywords = format!("{}|{}", re_str(block_start), re_str(block_end));
+    let name = format!(
+        "(?:{}{})?{}{}?",
+        IDENT,
+        re(NAMESPACE_CHAR),
+        IDENT,
+        re(STAR_CHAR)
+    );
+    rules.push((
+        "block",
+        Json::object([
+            ("match", Json::Str(block_pattern(options, &keywords, &name))),
+            ("captures", captures(&BLOCK_GROUPS)),
+        ]),
+    ));
+
+    if let Some(verb) = &options.verb_command {
+        // The delimiter can't be whitespace nor a character of names
+        let begin = format!(r"({})({})([^\s\w-])", cmd, re_str(verb));
+        let mut fields = region("markup.raw.inline.pastex", begin, r"\3".to_owned());
+        let groups = [
+            ("punctuation.definition.function.pastex", None),
+            ("entity.name.function.pastex", None),
+            ("punctuation.definition.verbatim.pastex", None),
+        ];
+        fields.push(("beginCaptures", captures(&groups)));
+        rules.push(("verb", Json::object(fields)));
+    }
+
+    let (params_open, params_close) = (
+        re(options.params_chars.open),
+        re(options.params_chars.close),
+    );
+    let verbatim = re(options.verbatim_char);
+    let groups = [
+        ("punctuation.definition.function.pastex", None),
+        ("entity.name.namespace.pastex", None),
+        ("punctuation.separator.namespace.pastex", None),
+        ("entity.name.function.pastex", None),
+        ("keyword.operator.star.pastex", None),
+    ];
+    rules.push((
+        "command",
+        Json::object([
+            (
+                "begin",
+                Json::Str(format!(
+                    "({})(?:({})({}))?({})({}?)",
+                    cmd,
+                    IDENT,
+                    re(NAMESPACE_CHAR),
+                    IDENT,
+                    re(STAR_CHAR)
+                )),
+            ),
+            ("beginCaptures", captures(&groups)),
+            // The call ends once no parameters or verbatim content follow, braces are matched
+            // on their own
+            (
+                "end",
+                Json::Str(format!("(?![{}{}])", params_open, verbatim)),
+            ),
+            (
+                "patterns",
+                Json::List(vec![
+                    include("#parameters"),
+                    Json::object(region(
+                        "markup.raw.inline.pastex",
+                        verbatim.clone(),
+                        verbatim,
+                    )),
+                ]),
+            ),
+        ]),
+    ));
+
+    let assign = format!(r"({})\s*({})", IDENT, re(COMMAND_PARAMS_ASSIGN_CHAR));
+    let groups = [
+        ("variable.parameter.pastex", None),
+        ("keyword.operator.assignment.pastex", None),
+    ];
+    rules.push((
+        "parameters",
+        Json::object([
+            ("name", Json::str("meta.parameters.pastex")),
+            ("begin", Json::Str(params_open)),
+            ("end", Json::Str(params_close)),
+            (
+                "patterns",
+                Json::List(vec![
+                    Json::object([
+                        ("match", Json::Str(assign)),
+                        ("captures", captures(&groups)),
+                    ]),
+                    include("#content"),
+                    rule("variable.parameter.pastex", IDENT.to_owned()),
+                ]),
+            ),
+        ]),
+    ));
+
+    rules.push((
+        "content",
+        Json::object([
+            ("begin", Json::Str(re(options.content_chars.open))),
+            ("end", Json::Str(re(options.content_chars.close))),
+            ("patterns", Json::List(vec![include("$self")])),
+        ]),
+    ));
+
+    rules
+}
+
+/// Writes a TextMate grammar of documents parsed with `options`, as JSON, see the
+/// [module documentation](self).
+///
+/// ```
+/// use pastex_parser::{textmate_grammar, ParserOptions};
+///
+/// let options = ParserOptions {
+///     comment_char: Some('#'),
+///     ..Default::default()
+/// };
+/// assert!(textmate_grammar(&options).contains(r#""match": "\\#.*$""#));
+/// ```
+pub fn textmate_grammar(options: &ParserOptions) -> String {
+    let repository = repository(options);
+    // Parameters only follow commands
+    let patterns = repository
+        .iter()
+        .filter(|(name, _)| *name != "parameters")
+        .map(|(name, _)| include(&format!("#{}", name)))
+        .collect();
+    let grammar = Json::object([
+        ("name", Json::str("pastex")),
+        ("scopeName", Json::str(SCOPE_NAME)),
+        ("fileTypes", Json::List(vec![Json::str("pastex")])),
+        ("patterns", Json::List(patterns)),
+        ("repository", Json::object(repository)),
+    ]);
+
+    let mut out = String::new();
+    grammar.write(&mut out, 0);
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Pair;
+
+    #[test]
+    fn test_textmate_grammar() {
+        let grammar = textmate_grammar(&ParserOptions::default());
+        assert!(
+            grammar.starts_with("{\n  \"name\": \"pastex\",\n  \"scopeName\": \"text.pastex\",")
+        );
+        assert!(grammar.contains(r#""match": "\\%.*$""#));
+        assert!(grammar.contains(r#""begin": "(\\\\)(?:([\\w-]+)(\\:))?([\\w-]+)(\\*?)""#));
+        assert!(grammar.contains("{)(verbatim|code|math)(\\\\})"));
+        assert!(grammar.contains("string.other.math.inline.pastex"));
+
+        let options = ParserOptions {
+            command_char: '@',
+            comment_char: None,
+            content_chars: Pair::make('<', '>'),
+            math_delimiters: false,
+            verb_command: None,
+            verbatim_blocks: Vec::new(),
+            ..Default::default()
+        };
+        let grammar = textmate_grammar(&options);
+        assert!(grammar.contains(r#""begin": "(\\@)(?:"#));
+        assert!(grammar.contains(r#""end": "\\>""#));
+        for rule in ["#comment", "#math", "#verb\"", "#verbatim-block"] {
+            assert!(!grammar.contains(rule), "{} is disabled", rule);
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Inclusion of other files with `\input{path}`, spliced into the including stream when parsing.
+//!
+//! As parsed streams borrow from their source, included files are loaded first, with
+//! [`Includes::load`], then [`parse_with_includes`] parses the document and the files it includes
+//! from there:
+//!
+//! ```no_run
+//! use pastex_parser::{parse_with_includes, Includes, ParserOptions};
+//!
+//! let source = "Intro.\n\n\\input{chapter.pastex}";
+//! let options = ParserOptions::default();
+//! let includes = Includes::load(source, &options, |path| std::fs::read_to_string(path)).unwrap();
+//! let stream = parse_with_includes(source, &includes, &options).unwrap();
+//! ```
+
+use super::{
+    offset, parse_with, visit, Command, Element, ParamValue, ParseError, ParserOptions, Stream,
+};
+use std::{collections::HashMap, io};
+
+/// Name of the command including a file
+pub const COMMAND_INPUT: &str = "input";
+
+/// The path given to `cmd` if it includes a file, as `\input{path}`.
+fn include_path<'b>(cmd: &Command<'b>) -> Option<&'b str> {
+    match &cmd.content[..] {
+        [Element::Raw(path)]
+            if cmd.name == COMMAND_INPUT && cmd.namespace.is_none() && !cmd.block =>
+        {
+            Some(path.trim())
+        }
+        _ => None,
+    }
+}
+
+/// Collects the paths of the files included by a stream.
+struct Paths<'b>(Vec<&'b str>);
+
+impl<'b> visit::Visitor<'b> for Paths<'b> {
+    fn visit_command(&mut self, command: &Command<'b>) {
+        match include_path(command) {
+            Some(path) => self.0.push(path),
+            None => visit::walk_command(self, command),
+        }
+    }
+}
+
+/// Files included by a document, and the ones they include themselves, by their path.
+#[derive(Debug, Default)]
+pub struct Includes {
+    files: HashMap<String, String>,
+}
+
+impl Includes {
+    /// Loads the files included by `source`, and the ones they include, with `resolver`. Paths are
+    /// given to the resolver as written in `\input{...}`, and each path is only loaded once.
+    ///
+    /// Files including themselves, directly or not, are an [`io::ErrorKind::InvalidData`] error.
+    pub fn load(
+        source: &str,
+        options: &ParserOptions,
+        resolver: impl Fn(&str) -> io::Result<String>,
+    ) -> io::Result<Self> {
+        let mut includes = Includes::default();
+        includes.load_from(source, options, &resolver, &mut Vec::new())?;
+        Ok(includes)
+    }
+
+    fn load_from(
+        &mut self,
+        source: &str,
+        options: &ParserOptions,
+        resolver: &dyn Fn(&str) -> io::Result<String>,
+        chain: &mut Vec<String>,
+    ) -> io::Result<()> {
+        // Files which can't be parsed are reported by `parse_with_includes`
+        let stream = match parse_with(source, options) {
+            Ok(stream) => stream,
+            Err(_) => return Ok(()),
+        };
+        let mut paths = Paths(Vec::new());
+        visit::walk_stream(&mut paths, &stream);
+
+        for path in paths.0 {
+            if chain.iter().any(|p| p == path) {
+                let message = format!("{} includes itself", path);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            if self.files.contains_key(path) {
+                continue;
+            }
+
+            let file = resolver(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))?;
+            chain.push(path.to_owned());
+            self.load_from(&file, options, resolver, chain)?;
+            chain.pop();
+            self.files.insert(path.to_owned(), file);
+        }
+
+        Ok(())
+    }
+
+    /// Finds the included file `slice` comes from, giving its path and its content, to locate
+    /// elements with [`Location::of`](super::Location::of). [`None`] for slices of other buffers,
+    /// like the including document.
+    pub fn file_of(&self, slice: &str) -> Option<(&str, &str)> {
+        let start = slice.as_ptr() as usize;
+
+        self.files
+            .iter()
+            .find(|(_, file)| {
+                let file_start = file.as_ptr() as usize;
+                start >= file_start && start + slice.len() <= file_start + file.len()
+            })
+            .map(|(path, file)| (path.as_str(), file.as_str()))
+    }
+}
+
+/// Replaces the includes of `stream`, parsed from `buf`, with the content of the included files.
+fn splice<'b>(
+    stream: Stream<'b>,
+    buf: &'b str,
+    includes: &'b Includes,
+    options: &ParserOptions,
+) -> Result<Stream<'b>, ParseError<'b>> {
+    let mut res = Vec::with_capacity(stream.len());
+
+    for el in stream {
+        let cmd = match el {
+            Element::Command(cmd) => cmd,
+            el => {
+                res.push(el);
+                continue;
+            }
+        };
+
+        if let Some(path) = include_path(&cmd) {
+            let file = includes
+                .files
+                .get(path)
+                .ok_or_else(|| ParseError::MissingInclude {
+                    rest: &buf[offset(buf, cmd.source)..],
+                    path,
+                })?;
+            // Errors are given at the `\input`, as their input is in another buffer
+            let included = parse_with(file, options)
+                .and_then(|included| splice(included, file, includes, options))
+                .map_err(|error| ParseError::Included {
+                    rest: &buf[offset(buf, cmd.source)..],
+                    path,
+                    offset: error.offset(file),
+                    error: Box::new(error),
+                })?;
+            res.extend(included);
+            continue;
+        }
+
+        let mut splice_stream = |stream| splice(stream, buf, includes, options);
+        let params = cmd
+            .params
+            .into_iter()
+            .map(|(key, value)| match value {
+                ParamValue::Stream(stream) => Ok((key, ParamValue::Stream(splice_stream(stream)?))),
+                value => Ok((key, value)),
+            })
+            .collect::<Result<_, _>>()?;
+
+        res.push(Element::Command(Command {
+            content: splice_stream(cmd.content)?,
+            arguments: cmd
+                .arguments
+                .into_iter()
+                .map(&mut splice_stream)
+                .collect::<Result<_, _>>()?,
+            params,
+            ..cmd
+        }));
+    }
+
+    Ok(res)
+}
+
+/// Parses a document, like [`parse_with`], replacing each `\input{path}` with the content of the
+/// file at `path`, loaded beforehand in `includes`. Including a file missing from `includes` is a
+/// [`ParseError::MissingInclude`] error, and errors in included files are given as
+/// [`ParseError::Included`].
+///
+/// Included elements borrow from the included files, see [`Includes::file_of`] to find where they
+/// come from.
+pub fn parse_with_includes<'b>(
+    source: &'b str,
+    includes: &'b Includes,
+    options: &ParserOptions,
+) -> Result<Stream<'b>, ParseError<'b>> {
+    let stream = parse_with(source, options)?;
+    splice(stream, source, includes, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Location;
+
+    #[test]
+    fn test_includes() {
+        let files = HashMap::from([
+            ("a", "A \\input{ b } % a"),
+            ("b", "B\n\\strong[x = {\\input{c}}]{\\input{c}}"),
+            ("c", "C"),
+            ("loop", "\\input{again}"),
+            ("again", "\\input{loop}"),
+        ]);
+        let resolver = |path: &str| {
+            files
+                .get(path)
+                .map(|file| file.to_string())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        };
+        let options = ParserOptions::default();
+
+        let source = "\\input{a}.";
+        let includes = Includes::load(source, &options, resolver).unwrap();
+        let stream = parse_with_includes(source, &includes, &options).unwrap();
+
+        let mut raw = Vec::new();
+        visit::walk_stream(&mut Raw(&mut raw), &stream);
+        assert_eq!(raw, ["A ", "B\n", "C", "C", " ", "."]);
+
+        let (path, file) = includes.file_of(raw[1]).unwrap();
+        assert_eq!(path, "b");
+        assert_eq!(Location::of(file, raw[1]).unwrap().line, 1);
+        assert_eq!(includes.file_of(raw[5]), None);
+
+        let err = Includes::load("\\input{loop}", &options, resolver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Includes::load("\\input{none}", &options, resolver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let source = "Text \\input{d}";
+        let err = parse_with_includes(source, &includes, &options).unwrap_err();
+        assert!(matches!(err, ParseError::MissingInclude { path: "d", .. }));
+        assert_eq!(err.offset(source), 5);
+
+        let files = HashMap::from([("e", "\\input{f}"), ("f", "ok\n\\bad{")]);
+        let resolver = |path: &str| Ok(files[path].to_string());
+        let source = "Text\n\\input{e}";
+        let includes = Includes::load(source, &options, resolver).unwrap();
+        let err = parse_with_includes(source, &includes, &options).unwrap_err();
+        assert_eq!(err.offset(source), 5);
+        match err {
+            ParseError::Included {
+                path: "e",
+                offset: 0,
+                error,
+                ..
+            } => match *error {
+                ParseError::Included {
+                    path: "f",
+                    offset,
+                    error,
+                    ..
+                } => {
+                    assert_eq!(offset, 8);
+                    assert!(matches!(*error, ParseError::Syntax(_)));
+                }
+                error => panic!("unexpected error {:?}", error),
+            },
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    /// Collects raw text, to check the order elements end up in.
+    struct Raw<'r, 'b>(&'r mut Vec<&'b str>);
+
+    impl<'r, 'b> visit::Visitor<'b> for Raw<'r, 'b> {
+        fn visit_raw(&mut self, text: &'b str) {
+            self.0.push(text);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Incremental reparsing, to avoid parsing a whole document again after a small edit.
+//!
+//! A position strictly inside a top-level [`Element::Raw`] is a point where the parser is in a
+//! known state, with nothing open. When math can be written between dollars, which pair across
+//! elements up to the end of their paragraph, only such positions starting a paragraph are used.
+//! After an edit, only the region between the closest such points
+//! around the edit is parsed again, and the elements outside of it are taken from the previous
+//! tree.
+//!
+//! Editors keep the previous source and tree, apply each [`TextEdit`] to the source, and call
+//! [`reparse`] with both versions of the source:
+//!
+//! ```
+//! use pastex_parser::{parse, reparse, TextEdit};
+//!
+//! let source = "Some text.\n\nMore \\strong{text}.";
+//! let tree = parse(source).unwrap();
+//! let edit = TextEdit { range: 5..9, replacement: "words" };
+//! let new_source = edit.apply(source);
+//! let tree = reparse(source, tree, edit.range, &new_source).unwrap();
+//! ```
+
+use super::{
+    offset, parse_with, push, top_loop, Command, Element, ParamValue, Params, ParseError,
+    ParserOptions, State, Stream, BYTE_ORDER_MARK, MATH_CHAR,
+};
+use std::ops::Range;
+
+/// A change of the source, replacing the text at `range` with `replacement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit<'r> {
+    /// Byte range of the replaced text in the source before the edit
+    pub range: Range<usize>,
+    pub replacement: &'r str,
+}
+
+impl TextEdit<'_> {
+    /// The source after the edit.
+    pub fn apply(&self, source: &str) -> String {
+        let mut new = String::with_capacity(source.len() + self.replacement.len());
+        new.push_str(&source[..self.range.start]);
+        new.push_str(self.replacement);
+        new.push_str(&source[self.range.end..]);
+        new
+    }
+}
+
+/// Maps slices of an old source buffer into a new one, where the text may have moved by `delta`
+/// bytes.
+struct Rebase<'o, 'n> {
+    old: &'o str,
+    new: &'n str,
+    delta: isize,
+}
+
+impl<'o, 'n> Rebase<'o, 'n> {
+    fn str(&self, s: &str) -> &'n str {
+        let start = offset(self.old, s) as isize + self.delta;
+        &self.new[start as usize..start as usize + s.len()]
+    }
+
+    fn element(&self, el: Element) -> Element<'n> {
+        match el {
+            Element::Command(cmd) => Element::Command(self.command(cmd)),
+            Element::Raw(t) => Element::Raw(self.str(t)),
+            Element::Comment(t) => Element::Comment(self.str(t)),
+            Element::LineBreak(t) => Element::LineBreak(self.str(t)),
+            Element::Math { display, source } => Element::Math {
+                display,
+                source: self.str(source),
+            },
+        }
+    }
+
+    fn stream(&self, stream: Stream) -> Stream<'n> {
+        stream.into_iter().map(|el| self.element(el)).collect()
+    }
+
+    fn command(&self, cmd: Command) -> Command<'n> {
+        Command {
+            name: self.str(cmd.name),
+            namespace: cmd.namespace.map(|ns| self.str(ns)),
+            content: self.stream(cmd.content),
+            arguments: cmd.arguments.into_iter().map(|s| self.stream(s)).collect(),
+            params: self.params(cmd.params),
+            param_groups: cmd
+                .param_groups
+                .into_iter()
+                .map(|group| group.into_iter().map(|name| self.str(name)).collect())
+                .collect(),
+            block: cmd.block,
+            starred: cmd.starred,
+            comments: cmd.comments.into_iter().map(|c| self.str(c)).collect(),
+            source: self.str(cmd.source),
+            content_source: cmd.content_source.map(|s| self.str(s)),
+        }
+    }
+
+    fn params(&self, params: Params) -> Params<'n> {
+        params
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    ParamValue::None => ParamValue::None,
+                    ParamValue::Text(t) => ParamValue::Text(self.str(t)),
+                    ParamValue::Stream(s) => ParamValue::Stream(self.stream(s)),
+                };
+                (self.str(key), value)
+            })
+            .collect()
+    }
+}
+
+/// Whether `pos` is right after a paragraph break of `text`.
+fn starts_paragraph(text: &str, pos: usize) -> bool {
+    let before = &text[..pos];
+    before.ends_with("\n\n") || before.ends_with("\n\r\n")
+}
+
+/// Finds the range of the old source to parse again, in the old source coordinates, and the index
+/// of the top-level raw elements the range starts and ends in. With `paragraphs`, the range starts
+/// and ends at paragraph breaks.
+fn reparse_range(
+    old_source: &str,
+    old: &Stream,
+    edit: &Range<usize>,
+    paragraphs: bool,
+) -> (usize, Option<usize>, usize, Option<usize>) {
+    let sync = |text: &str, p: usize| !paragraphs || starts_paragraph(text, p);
+
+    let mut start = (0, None);
+    let mut end = (old_source.len(), None);
+
+    for (i, el) in old.iter().enumerate() {
+        let text = match el {
+            Element::Raw(t) => t,
+            _ => continue,
+        };
+        let s = offset(old_source, text);
+        // A comment runs up to the end of its line, which the edit may remove
+        let first = match i.checked_sub(1).map(|prev| &old[prev]) {
+            Some(Element::Comment(_)) => text.find('\n').map_or(text.len(), |p| p + 1),
+            _ => 1,
+        };
+
+        // Start anywhere inside the element, as close as possible before the edit.
+        if let Some((p, _)) = text
+            .char_indices()
+            .skip_while(|(p, _)| *p < first)
+            .take_while(|(p, _)| s + p <= edit.start)
+            .filter(|&(p, _)| sync(text, p))
+            .last()
+        {
+            start = (s + p, Some(i));
+        }
+
+        // End right after a line break, as a line break ends everything that could be affected by
+        // the edited text (identifiers, comments).
+        if end.1.is_none() {
+            if let Some((p, _)) = text.char_indices().find(|&(p, c)| {
+                c == '\n' && p + 1 < text.len() && s + p + 1 > edit.end && sync(text, p + 1)
+            }) {
+                end = (s + p + 1, Some(i));
+            }
+        }
+    }
+
+    (start.0, start.1, end.0, end.1)
+}
+
+fn raw_range(buf: &str, stream: &Stream, index: Option<usize>) -> Option<Range<usize>> {
+    match index.map(|i| &stream[i]) {
+        Some(Element::Raw(t)) => Some(offset(buf, t)..offset(buf, t) + t.len()),
+        _ => None,
+    }
+}
+
+/// Parses a document again after an edit, reusing the top-level elements of the previous tree
+/// which were not affected by it.
+///
+/// `old` must be the result of parsing `old_source`. `edit` is the byte range of `old_source`
+/// which was replaced to obtain `new_source`. The result is the same as calling
+/// [`parse`](super::parse) on `new_source`, which is what happens when the edit can't be
+/// localized.
+pub fn reparse<'n>(
+    old_source: &str,
+    old: Stream,
+    edit: Range<usize>,
+    new_source: &'n str,
+) -> Result<Stream<'n>, ParseError<'n>> {
+    reparse_with(old_source, old, edit, new_source, &ParserOptions::default())
+}
+
+/// Parses a document again after an edit, like [`reparse`], with the options `old` was parsed with.
+pub fn reparse_with<'n>(
+    old_source: &str,
+    old: Stream,
+    edit: Range<usize>,
+    new_source: &'n str,
+    options: &ParserOptions,
+) -> Result<Stream<'n>, ParseError<'n>> {
+    let delta = new_source.len() as isize - old_source.len() as isize;
+    let shift = |offset: usize| (offset as isize + delta) as usize;
+    // Dollars in the new source may pair with ones outside of the edited lines
+    let paragraphs = options.math_delimiters && new_source.contains(MATH_CHAR);
+    let (start, start_idx, end, end_idx) = reparse_range(old_source, &old, &edit, paragraphs);
+
+    // The byte order mark is only skipped when parsing from the start of the document, and
+    // comments may attach to commands outside of the edited range
+    if (start == 0 && new_source.starts_with(BYTE_ORDER_MARK)) || options.attach_comments {
+        return parse_with(new_source, options);
+    }
+
+    let middle = match top_loop(
+        &State::new(new_source, options.clone(), false),
+        &new_source[start..shift(end)],
+    ) {
+        Ok(("", middle)) => middle,
+        _ => return parse_with(new_source, options),
+    };
+
+    let head = raw_range(old_source, &old, start_idx).map(|r| &new_source[r.start..start]);
+    let tail = raw_range(old_source, &old, end_idx).map(|r| &new_source[shift(end)..shift(r.end)]);
+
+    let before = Rebase {
+        old: old_source,
+        new: new_source,
+        delta: 0,
+    };
+    let after = Rebase { delta, ..before };
+    let mut prefix = Vec::new();
+    let mut suffix = Vec::new();
+
+    for (i, el) in old.into_iter().enumerate() {
+        if matches!(start_idx, Some(s) if i < s) {
+            prefix.push(before.element(el));
+        } else if matches!(end_idx, Some(e) if i > e) {
+            suffix.push(after.element(el));
+        }
+    }
+
+    let mut res = prefix;
+    let elements = head
+        .map(Element::Raw)
+        .into_iter()
+        .chain(middle)
+        .chain(tail.map(Element::Raw))
+        .chain(suffix);
+    for el in elements {
+        push(new_source, &mut res, el);
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,374 @@
+//! Token-level view of a pastex document, for tools like syntax highlighters which need to work on
+//! the source text without building a tree.
+//!
+//! The lexer never fails: anything it does not recognize is returned as [`TokenKind::Text`], and
+//! the concatenation of all token texts is always the input buffer.
+
+use super::{
+    is_ident, BYTE_ORDER_MARK, COMMAND_PARAMS_ASSIGN_CHAR, COMMAND_PARAMS_SEP_CHAR, CRLF,
+    LINE_BREAK_CHAR, NAMESPACE_CHAR, STAR_CHAR,
+};
+use crate::ParserOptions;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// The backslash starting a command call
+    CommandStart,
+    /// A command name, namespace or parameter name
+    Ident,
+    /// The colon between a namespace and a command name
+    NamespaceSeparator,
+    /// The star of a starred command, after its name
+    Star,
+    /// Opening brace of a command content
+    BraceOpen,
+    /// Closing brace of a command content
+    BraceClose,
+    /// Command content between pipes, or the delimiters of `\verb`, including them
+    Verbatim,
+    /// Opening bracket of command parameters
+    ParamsOpen,
+    /// Closing bracket of command parameters
+    ParamsClose,
+    /// The `=` between a parameter name and its value
+    ParamAssign,
+    /// The `,` between parameters
+    ParamSeparator,
+    /// Textual value of a parameter
+    ParamValue,
+    /// An escaped character, including its backslash
+    Escape,
+    /// A forced line break, a backslash followed by a line break
+    LineBreak,
+    /// A run of text
+    Text,
+    /// Whitespace between parameters, or the byte order mark starting a buffer
+    Whitespace,
+    /// A comment, including its `%`
+    Comment,
+    /// Math, including its delimiters, see [`ParserOptions::math_delimiters`]
+    Math,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'b> {
+    pub kind: TokenKind,
+    pub text: &'b str,
+    /// Byte range of the token in the input buffer
+    pub span: Range<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Text,
+    /// After a command name, where parameters and content may follow
+    Command,
+    /// After the name of `\begin` or `\end`, where the block name follows in braces
+    BlockCommand,
+    BlockName,
+    Params,
+    ParamValue,
+    /// Inside a verbatim block, see [`ParserOptions::verbatim_blocks`]
+    Verbatim,
+    /// After the name of the command set in [`ParserOptions::verb_command`], where its delimiter
+    /// follows
+    Verb,
+    /// After a command content, where more brace groups may follow
+    Arguments,
+}
+
+/// Iterator over the tokens of a buffer, see [`tokenize`].
+pub struct Lexer<'b> {
+    buf: &'b str,
+    pos: usize,
+    state: State,
+    options: ParserOptions,
+    /// Set after a `\begin`, to find out if the block is verbatim
+    block_start: bool,
+    /// The `\end{}` closing the verbatim block being read
+    verbatim_end: Option<String>,
+    /// Number of content braces open
+    depth: usize,
+    /// Depths at which the `[name = {...}]` parameter values being read were opened
+    param_streams: Vec<usize>,
+}
+
+/// Splits a buffer into tokens.
+pub fn tokenize(buf: &str) -> Lexer<'_> {
+    tokenize_with(buf, &ParserOptions::default())
+}
+
+/// Splits a buffer into tokens, with the given parser options.
+pub fn tokenize_with<'b>(buf: &'b str, options: &ParserOptions) -> Lexer<'b> {
+    Lexer {
+        buf,
+        pos: 0,
+        state: State::Text,
+        options: options.clone(),
+        block_start: false,
+        verbatim_end: None,
+        depth: 0,
+        param_streams: Vec::new(),
+    }
+}
+
+impl<'b> Lexer<'b> {
+    fn rest(&self) -> &'b str {
+        &self.buf[self.pos..]
+    }
+
+    fn take(&mut self, kind: TokenKind, len: usize) -> Token<'b> {
+        let span = self.pos..self.pos + len;
+        self.pos += len;
+
+        Token {
+            kind,
+            text: &self.buf[span.clone()],
+            span,
+        }
+    }
+
+    fn take_while(&mut self, kind: TokenKind, f: impl Fn(char) -> bool) -> Token<'b> {
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.take(kind, len)
+    }
+
+    /// Takes math up to its closing delimiter, or only its opening one when it is not closed.
+    fn math(&mut self, open: usize, close: &str) -> Token<'b> {
+        match self.options.math_len(self.rest(), open, close) {
+            Some(len) => self.take(TokenKind::Math, open + len + close.len()),
+            None => self.take(TokenKind::Text, open),
+        }
+    }
+
+    fn command(&mut self) -> Token<'b> {
+        if let Some((open, close, _)) = self.options.math_open(self.rest()) {
+            return self.math(open, &close);
+        }
+
+        let start = self.options.command_char.len_utf8();
+        let rest = &self.rest()[start..];
+        let (block_start, block_end) = &self.options.block_keywords;
+
+        match rest.chars().next() {
+            Some(LINE_BREAK_CHAR) => self.take(TokenKind::LineBreak, start + 1),
+            Some(_) if rest.starts_with(CRLF) => {
+                self.take(TokenKind::LineBreak, start + CRLF.len())
+            }
+            Some(c)
+                if c == self.options.command_char
+                    || c == self.options.content_chars.close
+                    || self.options.comment_char == Some(c)
+                    || self.options.is_math(c) =>
+            {
+                self.take(TokenKind::Escape, start + c.len_utf8())
+            }
+            Some(c) if c.is_alphanumeric() => {
+                let len = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+                let name = &rest[..len];
+                self.block_start = name == block_start;
+                self.state = if name == block_start || name == block_end {
+                    State::BlockCommand
+                } else if self.options.verb_command.as_deref() == Some(name) {
+                    State::Verb
+                } else {
+                    State::Command
+                };
+
+                self.take(TokenKind::CommandStart, start)
+            }
+            _ => self.take(TokenKind::Text, start),
+        }
+    }
+}
+
+impl<'b> Iterator for Lexer<'b> {
+    type Item = Token<'b>;
+
+    fn next(&mut self) -> Option<Token<'b>> {
+        let c = self.rest().chars().next()?;
+        let (content, params) = (self.options.content_chars, self.options.params_chars);
+        let (command, comment) = (self.options.command_char, self.options.comment_char);
+        let verbatim = self.options.verbatim_char;
+        let len = c.len_utf8();
+
+        if self.pos == 0 && c == BYTE_ORDER_MARK {
+            return Some(self.take(TokenKind::Whitespace, len));
+        }
+
+        let token = match self.state {
+            State::Verbatim => {
+                let end = self.verbatim_end.take().unwrap_or_default();
+                let rest = self.rest();
+
+                self.state = State::Text;
+                match rest.find(&end) {
+                    Some(0) => return self.next(),
+                    Some(len) => self.take(TokenKind::Text, len),
+                    None => self.take(TokenKind::Text, rest.len()),
+                }
+            }
+            State::BlockName if c == content.close => {
+                self.state = match self.verbatim_end {
+                    Some(_) => State::Verbatim,
+                    None => State::Text,
+                };
+                self.take(TokenKind::BraceClose, len)
+            }
+            State::Text if c == content.close => {
+                self.depth = self.depth.saturating_sub(1);
+                self.state = if self.param_streams.last() == Some(&self.depth) {
+                    self.param_streams.pop();
+                    State::Params
+                } else {
+                    State::Arguments
+                };
+                self.take(TokenKind::BraceClose, len)
+            }
+            State::Arguments if c == content.open => {
+                self.state = State::Text;
+                self.depth += 1;
+                self.take(TokenKind::BraceOpen, len)
+            }
+            State::Arguments => {
+                self.state = State::Text;
+                return self.next();
+            }
+            State::BlockName if c == STAR_CHAR => {
+                if let Some(end) = &mut self.verbatim_end {
+                    // Starred verbatim blocks end with a starred `\end`
+                    end.insert(end.len() - content.close.len_utf8(), STAR_CHAR);
+                }
+                self.take(TokenKind::Star, len)
+            }
+            // Anything else than a name, like a line break in `\end{\n`, is left to the text
+            State::BlockName if !is_ident(c) && c != NAMESPACE_CHAR => {
+                self.state = State::Text;
+                return self.next();
+            }
+            State::BlockName => {
+                let token =
+                    self.take_while(TokenKind::Ident, |c| is_ident(c) || c == NAMESPACE_CHAR);
+                if self.block_start && self.options.verbatim_blocks.iter().any(|b| b == token.text)
+                {
+                    self.verbatim_end = Some(self.options.block_end(token.text));
+                }
+                token
+            }
+            State::Text if c == command => self.command(),
+            State::Text if comment == Some(c) => {
+                let rest = self.rest();
+                let line = &rest[..rest.find(LINE_BREAK_CHAR).unwrap_or(rest.len())];
+                // Like in the parser, the carriage return of a CRLF line break is left as text
+                self.take(
+                    TokenKind::Comment,
+                    line.strip_suffix('\r').unwrap_or(line).len(),
+                )
+            }
+            State::Text if self.options.is_math(c) => {
+                let (open, close, _) = self.options.math_open(self.rest()).unwrap_or_default();
+                self.math(open, &close)
+            }
+            State::Text => {
+                let rest = self.rest();
+                let len = rest
+                    .find(|c| {
+                        c == command
+                            || c == content.close
+                            || comment == Some(c)
+                            || self.options.is_math(c)
+                    })
+                    .unwrap_or(rest.len());
+                self.take(TokenKind::Text, len)
+            }
+            State::Command | State::BlockCommand if is_ident(c) => {
+                self.take_while(TokenKind::Ident, is_ident)
+            }
+            // Only right after the name, not after the parameters
+            State::Command if c == STAR_CHAR && self.buf[..self.pos].ends_with(is_ident) => {
+                self.take(TokenKind::Star, len)
+            }
+            // The name, unless it is already followed by a star
+            State::Verb if is_ident(c) && !self.buf[..self.pos].ends_with(STAR_CHAR) => {
+                self.take_while(TokenKind::Ident, is_ident)
+            }
+            State::Verb if c == STAR_CHAR && self.buf[..self.pos].ends_with(is_ident) => {
+                self.take(TokenKind::Star, len)
+            }
+            State::Verb if c == NAMESPACE_CHAR => {
+                self.state = State::Command;
+                self.take(TokenKind::NamespaceSeparator, len)
+            }
+            State::Verb if !c.is_whitespace() && !is_ident(c) => {
+                self.state = State::Text;
+                match self.rest()[len..].find(c) {
+                    Some(inner) => self.take(TokenKind::Verbatim, inner + 2 * len),
+                    None => self.take(TokenKind::Text, len),
+                }
+            }
+            State::Verb => {
+                self.state = State::Text;
+                return self.next();
+            }
+            State::Command | State::BlockCommand if c == NAMESPACE_CHAR => {
+                self.take(TokenKind::NamespaceSeparator, len)
+            }
+            State::Command | State::BlockCommand if c == params.open => {
+                self.state = State::Params;
+                self.take(TokenKind::ParamsOpen, len)
+            }
+            State::BlockCommand if c == content.open => {
+                self.state = State::BlockName;
+                self.take(TokenKind::BraceOpen, len)
+            }
+            State::Command if c == content.open => {
+                self.state = State::Text;
+                self.depth += 1;
+                self.take(TokenKind::BraceOpen, len)
+            }
+            State::Command if c == verbatim => {
+                self.state = State::Text;
+                match self.rest()[len..].find(verbatim) {
+                    Some(inner) => self.take(TokenKind::Verbatim, inner + 2 * len),
+                    None => self.take(TokenKind::Text, len),
+                }
+            }
+            State::Command | State::BlockCommand => {
+                self.state = State::Text;
+                return self.next();
+            }
+            State::Params if c == params.close => {
+                self.state = State::Command;
+                self.take(TokenKind::ParamsClose, len)
+            }
+            State::Params if c == COMMAND_PARAMS_ASSIGN_CHAR => {
+                self.state = State::ParamValue;
+                self.take(TokenKind::ParamAssign, len)
+            }
+            State::Params if c == COMMAND_PARAMS_SEP_CHAR => {
+                self.take(TokenKind::ParamSeparator, len)
+            }
+            State::Params | State::ParamValue if c.is_whitespace() => {
+                self.take_while(TokenKind::Whitespace, char::is_whitespace)
+            }
+            State::Params if is_ident(c) => self.take_while(TokenKind::Ident, is_ident),
+            State::Params => self.take(TokenKind::Text, len),
+            State::ParamValue if c == content.open => {
+                self.state = State::Text;
+                self.param_streams.push(self.depth);
+                self.depth += 1;
+                self.take(TokenKind::BraceOpen, len)
+            }
+            State::ParamValue => {
+                self.state = State::Params;
+                self.take_while(TokenKind::ParamValue, |c| {
+                    c != COMMAND_PARAMS_SEP_CHAR && c != params.close
+                })
+            }
+        };
+
+        Some(token)
+    }
+}
@@ -2,9 +2,32 @@
 
 use either::Either;
 use nom::Parser;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fmt;
 
+mod comments;
+mod format;
+mod grammar;
+mod include;
+mod incremental;
+pub mod lexer;
+mod macros;
+pub mod owned;
+pub mod params;
+pub mod reader;
+pub mod visit;
+
+pub use format::{format, format_with, FormatOptions};
+pub use grammar::{textmate_grammar, SCOPE_NAME};
+pub use include::{parse_with_includes, Includes, COMMAND_INPUT};
+pub use incremental::{reparse, reparse_with, TextEdit};
+pub use macros::{expand_macros, COMMAND_DEFINE};
+pub use owned::IntoOwned;
+pub use params::{Dimension, ParamError, ParamsExt, Unit};
+pub use reader::{parse_reader, ReadError};
+pub use visit::{walk_stream, Visitor};
+
 /// A command parameters can take different forms. Depending on the form read from the file, it will
 /// have a different associated value from this enum.
 ///
@@ -14,7 +37,8 @@ use std::fmt;
 /// * For arguments used with an evaluation span, like `[foo = { bar }]`, you will obtain a
 ///   [`ParamValue::Stream`] and a [`Stream`] value to work with. You will have to process it
 ///   like you would a top-level stream.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ParamValue<'b> {
     /// Simple parameter without value
     None,
@@ -42,12 +66,22 @@ pub type Stream<'b> = Vec<Element<'b>>;
 /// \foo{}
 /// % Calls a function `foo` in the namespace `bar`:
 /// \bar:foo
+/// % Names can also hold dashes and underscores:
+/// \foo-bar_baz
+/// % A star right after the name asks for a variant of the command, like an unnumbered heading:
+/// \foo*{...}
 /// % Calls a function `foo` with some content:
 /// \foo{contents here...}
 /// % Can provide parameters, too:
 /// \foo[bar]{...}
 /// \foo[bar, baz = 1]{...}
 /// \foo[bar = {some more content and \commands}]{...}
+/// % Takes more brace groups as arguments:
+/// \foo{first}{second}
+/// % Content between pipes is taken as is, without looking for commands:
+/// \foo|some \verbatim {content}|
+/// % With \verb, any character can be used instead of the pipes:
+/// \verb+some |verbatim| {content}+
 /// ```
 ///
 /// To use a function with a large block of text, you can use the `begin` and `end` special commands
@@ -61,15 +95,23 @@ pub type Stream<'b> = Vec<Element<'b>>;
 /// \begin[bar, baz = 1]{foo} % Parameters to `begin` are passed to `foo`
 /// ...
 /// \end{foo}
+///
+/// \begin{foo*} % The starred variant
+/// ...
+/// \end{foo*}
 /// ```
 ///
+/// The content of some blocks, like `code`, is not parsed: it is kept as is, up to the matching
+/// `\end`. See [`ParserOptions::verbatim_blocks`].
+///
 /// `begin` and `end` commands are converted into a command call to `foo`, like if you used
 /// `\foo{ ... }`. However, such uses are marked, and your engine can choose to act differently
 /// on block commands with the same name.
 ///
 /// All forms given above will all be saved into the given structure below, filling different fields
 /// with the appropriate information.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Command<'b> {
     /// The name of the command
     pub name: &'b str,
@@ -78,10 +120,33 @@ pub struct Command<'b> {
     /// The contents inside of the command call. Empty list if the call is done without any
     /// contents
     pub content: Stream<'b>,
-    /// Parameters given to the command. Check [`Params`] and [`ParamValue`]
+    /// The brace groups directly following the content, in order, as the reading in
+    /// `\ruby{漢字}{かんじ}`. Empty for most calls.
+    pub arguments: Vec<Stream<'b>>,
+    /// Parameters given to the command. Check [`Params`] and [`ParamValue`]. When several `[...]`
+    /// groups give the same parameter, the value of the last one is kept.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_sorted"))]
     pub params: Params<'b>,
+    /// Names of the parameters of each `[...]` group, in the order they are written, as
+    /// `[["a"], ["b", "c"]]` for `\foo[a][b, c = 1]`. Values are in [`params`](Command::params).
+    pub param_groups: Vec<Vec<&'b str>>,
     /// `true` when the block (`begin`/`end`) form has been used, `false` for standard syntax
     pub block: bool,
+    /// `true` when the name is followed by a star, as `\foo*`. For the block form, the star is
+    /// written in `\begin`, as `\begin{foo*}`.
+    pub starred: bool,
+    /// The comment lines right before the call, without their comment character, when parsed with
+    /// [`ParserOptions::attach_comments`]. Empty otherwise.
+    pub comments: Vec<&'b str>,
+    /// The whole call in the source, from its backslash to the end of its content, or to the end
+    /// of its `\end{}` for the block form. Use [`Location::of`] to get where it is.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub source: &'b str,
+    /// The content as written in the source, escapes included: between the braces of `\foo{...}`,
+    /// or between `\begin{foo}` and `\end{foo}` for the block form. [`None`] for calls without
+    /// content, and for commands built by hand or by a macro, see [`expand_macros`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub content_source: Option<&'b str>,
 }
 
 /// Helper value to represent the name of a function call. Only holds the name and optionally
@@ -92,7 +157,7 @@ pub struct Command<'b> {
 /// if two command calls call into the same function.
 ///
 /// If you need to get the inner name and/or namespace, use the source [`Command`] value.
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct CommandName<'b>(&'b str, Option<&'b str>);
 
 impl<'b> fmt::Display for CommandName<'b> {
@@ -111,17 +176,102 @@ impl<'b> Command<'b> {
     }
 }
 
+/// A position in a source buffer, as shown to users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Location {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column number in characters, starting at 1
+    pub column: usize,
+}
+
+impl Location {
+    /// Gets the location of a byte offset in `source`.
+    pub fn at(source: &str, offset: usize) -> Location {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+
+        Location {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Gets the location where `slice` starts in `source`. As the parser output borrows from the
+    /// parsed buffer, this works for any text obtained from it, like [`Element::Raw`] contents or
+    /// parameter names. Returns `None` if `slice` is not part of `source`.
+    pub fn of(source: &str, slice: &str) -> Option<Location> {
+        let start = source.as_ptr() as usize;
+        let ptr = slice.as_ptr() as usize;
+
+        if ptr < start || ptr + slice.len() > start + source.len() {
+            return None;
+        }
+
+        Some(Location::at(source, ptr - start))
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl<'b> Element<'b> {
+    /// The source text of the element, see [`Command::source`]. Escaped characters and math only
+    /// hold their text, without the backslash or delimiters.
+    pub fn source(&self) -> &'b str {
+        match self {
+            Element::Command(c) => c.source,
+            Element::Raw(t) | Element::Comment(t) | Element::LineBreak(t) => t,
+            Element::Math { source, .. } => source,
+        }
+    }
+
+    /// Where the element starts in `source`, the buffer it was parsed from. Returns [`None`] for
+    /// elements from another buffer, like the ones built by hand.
+    pub fn location(&self, source: &str) -> Option<Location> {
+        Location::of(source, self.source())
+    }
+}
+
 /// Any recognized pastex syntax element from a stream.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Element<'b> {
     /// A command call. See [`Command`] for more details.
     Command(Command<'b>),
-    /// Raw, unprocessed text
+    /// Raw, unprocessed text. Text directly following an escaped character is kept in the same
+    /// element, so escapes only split the text where the escaping backslash was.
     Raw(&'b str),
     /// A comment, usually ignored
     Comment(&'b str),
-    /// A forced line break, obtained by putting a backslash before a line break.
-    LineBreak,
+    /// A forced line break, obtained by putting a backslash before a line break. Holds its source,
+    /// the backslash and the line break.
+    LineBreak(&'b str),
+    /// Math, written between `$` or `\(` and `\)` inline, or between `$$` or `\[` and `\]` for
+    /// display math. The source is kept as is, for a math renderer to handle. See
+    /// [`ParserOptions::math_delimiters`].
+    Math { display: bool, source: &'b str },
+}
+
+/// Serializes parameters ordered by name, so the output does not depend on the hash map order.
+#[cfg(feature = "serde")]
+fn serialize_sorted<S: serde::Serializer>(
+    params: &Params,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    use serde::Serialize;
+
+    params
+        .iter()
+        .collect::<std::collections::BTreeMap<_, _>>()
+        .serialize(serializer)
 }
 
 enum CommandType<'b> {
@@ -131,34 +281,370 @@ enum CommandType<'b> {
     Escape(&'b str),
 }
 
-type Result<'t, T> = nom::IResult<&'t str, T>;
+/// Errors reported by the parser.
+#[derive(Debug)]
+pub enum ParseError<'b> {
+    /// The input does not follow the pastex syntax
+    Syntax(nom::error::Error<&'b str>),
+    /// A closing brace without a matching opening one was found. `rest` is the content left from
+    /// that brace, and `open_block` the innermost block (`\begin`/`\end`) it was found in.
+    TrailingContent {
+        rest: &'b str,
+        open_block: Option<CommandName<'b>>,
+    },
+    /// An `\end` closes another block than the innermost open one. `rest` starts at the `\end`.
+    MismatchedBlock {
+        rest: &'b str,
+        open: CommandName<'b>,
+        close: CommandName<'b>,
+    },
+    /// An `\end` was found outside of any block. `rest` starts at the `\end`.
+    UnexpectedEnd {
+        rest: &'b str,
+        close: CommandName<'b>,
+    },
+    /// The end of the input was reached with a block still open. `rest` starts at its `\begin`.
+    UnclosedBlock {
+        rest: &'b str,
+        open: CommandName<'b>,
+    },
+    /// Commands and blocks are nested deeper than [`ParserOptions::max_depth`]. `rest` starts at
+    /// the content which would be too deep.
+    TooDeep { rest: &'b str },
+    /// Macro expansions add more elements than [`ParserOptions::max_expansion`]. `rest` starts at
+    /// the call going over it.
+    TooLarge { rest: &'b str },
+    /// An `\input{path}` includes a file which is not loaded, see [`parse_with_includes`]. `rest`
+    /// starts at the `\input`.
+    MissingInclude { rest: &'b str, path: &'b str },
+    /// An error in the file included at `path` by an `\input{path}`, see [`parse_with_includes`].
+    /// `rest` starts at the `\input`, and `offset` is the position of the error in the included
+    /// file, as given by [`offset`](ParseError::offset) for it.
+    Included {
+        rest: &'b str,
+        path: &'b str,
+        offset: usize,
+        error: Box<ParseError<'b>>,
+    },
+}
+
+impl<'b> ParseError<'b> {
+    /// The input left when the error was found.
+    pub fn input(&self) -> &'b str {
+        match self {
+            ParseError::Syntax(e) => e.input,
+            ParseError::TrailingContent { rest, .. }
+            | ParseError::MismatchedBlock { rest, .. }
+            | ParseError::UnexpectedEnd { rest, .. }
+            | ParseError::UnclosedBlock { rest, .. }
+            | ParseError::TooDeep { rest }
+            | ParseError::TooLarge { rest }
+            | ParseError::MissingInclude { rest, .. }
+            | ParseError::Included { rest, .. } => rest,
+        }
+    }
+
+    /// The byte offset of the error in the source buffer it was obtained from.
+    pub fn offset(&self, source: &str) -> usize {
+        source.len() - self.input().len()
+    }
 
-struct Pair {
-    open: char,
-    close: char,
+    /// Extends the input left, found while parsing a part of `source`, up to the end of `source`,
+    /// so [`offset`](ParseError::offset) works with `source`.
+    fn extend_to(mut self, source: &'b str) -> Self {
+        let input = &source[offset(source, self.input())..];
+
+        match &mut self {
+            ParseError::Syntax(e) => e.input = input,
+            ParseError::TrailingContent { rest, .. }
+            | ParseError::MismatchedBlock { rest, .. }
+            | ParseError::UnexpectedEnd { rest, .. }
+            | ParseError::UnclosedBlock { rest, .. }
+            | ParseError::TooDeep { rest }
+            | ParseError::TooLarge { rest }
+            | ParseError::MissingInclude { rest, .. }
+            | ParseError::Included { rest, .. } => *rest = input,
+        }
+        self
+    }
+}
+
+impl<'b> fmt::Display for ParseError<'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax(e) => write!(f, "syntax error ({:?})", e.code),
+            ParseError::TrailingContent {
+                open_block: Some(block),
+                ..
+            } => write!(f, "unmatched closing brace inside the {} block", block),
+            ParseError::TrailingContent { .. } => write!(f, "unmatched closing brace"),
+            ParseError::MismatchedBlock { open, close, .. } => {
+                write!(f, "closing a {} block while a {} is open", close, open)
+            }
+            ParseError::UnexpectedEnd { close, .. } => {
+                write!(f, "closing a {} block outside of any block", close)
+            }
+            ParseError::UnclosedBlock { open, .. } => write!(f, "unclosed {} block", open),
+            ParseError::TooDeep { .. } => write!(f, "commands nested too deeply"),
+            ParseError::TooLarge { .. } => write!(f, "macro expansions too large"),
+            ParseError::MissingInclude { path, .. } => {
+                write!(f, "included file {} is not loaded", path)
+            }
+            ParseError::Included { path, error, .. } => write!(f, "in {}: {}", path, error),
+        }
+    }
+}
+
+impl<'b> std::error::Error for ParseError<'b> {}
+
+impl<'b> nom::error::ParseError<&'b str> for ParseError<'b> {
+    fn from_error_kind(input: &'b str, kind: nom::error::ErrorKind) -> Self {
+        ParseError::Syntax(nom::error::Error::new(input, kind))
+    }
+
+    fn append(_: &'b str, _: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type Result<'t, T> = nom::IResult<&'t str, T, ParseError<'t>>;
+
+/// Settings of the parser, see [`parse_with`].
+///
+/// The syntax characters can be changed to avoid conflicts with the content, like `%` being
+/// common in it. They should all be different, and none of them alphanumeric.
+#[derive(Debug, Clone)]
+pub struct ParserOptions {
+    /// Character starting a command call, `\` by default
+    pub command_char: char,
+    /// Character starting a comment, `%` by default. Comments are disabled with [`None`].
+    pub comment_char: Option<char>,
+    /// Delimiters of command contents, `{` and `}` by default
+    pub content_chars: Pair,
+    /// Delimiters of command parameters, `[` and `]` by default
+    pub params_chars: Pair,
+    /// Delimiter of verbatim command contents, `|` by default
+    pub verbatim_char: char,
+    /// Name of the command taking the character following it as the delimiter of its verbatim
+    /// content, `verb` by default, so `\verb+a|b+` holds `a|b`. The delimiter can't be whitespace
+    /// or a character allowed in names. Disabled with [`None`].
+    pub verb_command: Option<String>,
+    /// Recognizes math between `$`, `$$`, `\(` and `\)` or `\[` and `\]` as [`Element::Math`],
+    /// enabled by default. Math between dollars ends with its paragraph, math between single
+    /// dollars can't start or end with whitespace, and a dollar sign which doesn't open math is
+    /// kept as text, so prices as in `$5 and $10` are left alone. Other dollar signs are written
+    /// `\$`.
+    pub math_delimiters: bool,
+    /// Names of the commands starting and ending a block, `begin` and `end` by default
+    pub block_keywords: (String, String),
+    /// Names of the blocks whose content is kept as a single [`Element::Raw`], up to the matching
+    /// `\end{}`, without interpreting commands, braces or comments. Namespaced blocks are written
+    /// with their namespace, as `ns:name`.
+    ///
+    /// Defaults to `verbatim`, `code` and `math`.
+    pub verbatim_blocks: Vec<String>,
+    /// How deep command contents and blocks can be nested, to avoid overflowing the stack on
+    /// hostile input. Deeper nesting is a [`ParseError::TooDeep`] error. 64 by default, which fits
+    /// in the stack of a spawned thread even in debug builds.
+    pub max_depth: usize,
+    /// How many elements macro expansions can add to a document, counting the elements nested in
+    /// commands, see [`expand_macros`]. This avoids running out of memory on hostile input, like
+    /// macros calling another one several times, which grows exponentially. Going over it is a
+    /// [`ParseError::TooLarge`] error. 100 000 by default.
+    pub max_expansion: usize,
+    /// Moves the comments written on their own lines right before a command, as doc comments, to
+    /// its [`Command::comments`] instead of keeping them as [`Element::Comment`]s. A blank line
+    /// or text between them and the command keeps them in the stream. Disabled by default.
+    pub attach_comments: bool,
+}
+
+impl Default for ParserOptions {
+    fn default() -> Self {
+        ParserOptions {
+            command_char: COMMAND_CHAR,
+            comment_char: Some(COMMENT_CHAR),
+            content_chars: COMMAND_CONTENT_CHARS,
+            params_chars: COMMAND_PARAMS_CHARS,
+            verbatim_char: COMMAND_VERBATIM_CHAR,
+            verb_command: Some(COMMAND_VERB.to_owned()),
+            math_delimiters: true,
+            block_keywords: (COMMAND_BLOCK_START.to_owned(), COMMAND_BLOCK_END.to_owned()),
+            verbatim_blocks: ["verbatim", "code", "math"].map(String::from).to_vec(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_expansion: DEFAULT_MAX_EXPANSION,
+            attach_comments: false,
+        }
+    }
+}
+
+impl ParserOptions {
+    fn is_verbatim(&self, name: CommandName) -> bool {
+        self.verbatim_blocks
+            .iter()
+            .any(|block| *block == name.to_string())
+    }
+
+    fn is_verb(&self, name: &str) -> bool {
+        self.verb_command.as_deref() == Some(name)
+    }
+
+    fn is_math(&self, c: char) -> bool {
+        self.math_delimiters && c == MATH_CHAR
+    }
+
+    /// Finds the math delimiter `cur` starts with, giving its length, the delimiter closing it
+    /// and whether it is display math.
+    fn math_open(&self, cur: &str) -> Option<(usize, String, bool)> {
+        let mut chars = cur.chars();
+
+        match (chars.next()?, chars.next()) {
+            _ if !self.math_delimiters => None,
+            (MATH_CHAR, Some(MATH_CHAR)) => Some((2, MATH_CHAR.to_string().repeat(2), true)),
+            (MATH_CHAR, _) => Some((1, MATH_CHAR.to_string(), false)),
+            (c, Some(open @ ('(' | '['))) if c == self.command_char => {
+                let close = if open == '(' { ')' } else { ']' };
+                Some((c.len_utf8() + 1, format!("{}{}", c, close), open == '['))
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the length of the math content of `cur`, after its opening delimiter of `open`
+    /// bytes, up to `close`. Between dollars, the content can't span paragraphs, and between
+    /// single dollars, it can't start or end with whitespace. Returns [`None`] if the math is not
+    /// properly closed.
+    fn math_len(&self, cur: &str, open: usize, close: &str) -> Option<usize> {
+        let content = &cur[open..];
+
+        if !close.starts_with(MATH_CHAR) {
+            return content.find(close);
+        }
+        let content = &content[..paragraph_ends(content).next().unwrap_or(content.len())];
+        if close.len() > MATH_CHAR.len_utf8() {
+            return content.find(close);
+        }
+        if content.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        let len = content.find(close)?;
+        (len > 0 && !content[..len].ends_with(char::is_whitespace)).then_some(len)
+    }
+
+    fn is_comment(&self, c: char) -> bool {
+        self.comment_char == Some(c)
+    }
+
+    /// The call ending the block `cmd` starts, as `\end{name}` or `\end{name*}`.
+    fn block_end_of(&self, cmd: &Command) -> String {
+        match cmd.starred {
+            true => self.block_end(format_args!("{}{}", cmd.command_name(), STAR_CHAR)),
+            false => self.block_end(cmd.command_name()),
+        }
+    }
+
+    /// The call ending a block, as `\end{name}`.
+    fn block_end(&self, name: impl fmt::Display) -> String {
+        format!(
+            "{}{}{}{}{}",
+            self.command_char,
+            self.block_keywords.1,
+            self.content_chars.open,
+            name,
+            self.content_chars.close
+        )
+    }
+}
+
+/// Settings and collected warnings for a single parser run.
+struct State<'b> {
+    /// The whole buffer being parsed, which all parsed slices come from
+    source: &'b str,
+    options: ParserOptions,
+    /// Recover from unmatched closing braces and blocks and report them as warnings, instead of
+    /// failing
+    lenient: bool,
+    warnings: RefCell<Vec<ParseError<'b>>>,
+    /// How many streams are being parsed inside each other
+    depth: Cell<usize>,
+    /// The input from the top-level element being parsed, to find which one fails
+    element: Cell<&'b str>,
+}
+
+impl<'b> State<'b> {
+    fn new(source: &'b str, options: ParserOptions, lenient: bool) -> Self {
+        State {
+            source,
+            options,
+            lenient,
+            warnings: RefCell::new(Vec::new()),
+            depth: Cell::new(0),
+            element: Cell::new(source),
+        }
+    }
+
+    /// Reports an error, which is only a warning in lenient mode.
+    fn recover(&self, error: ParseError<'b>) -> std::result::Result<(), nom::Err<ParseError<'b>>> {
+        if self.lenient {
+            self.warnings.borrow_mut().push(error);
+            Ok(())
+        } else {
+            Err(nom::Err::Failure(error))
+        }
+    }
+}
+
+/// An opening and closing pair of delimiters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pair {
+    pub open: char,
+    pub close: char,
 }
 
 impl Pair {
-    const fn make(open: char, close: char) -> Pair {
+    pub const fn make(open: char, close: char) -> Pair {
         Pair { open, close }
     }
 }
 
 const COMMAND_CHAR: char = '\\';
 const NAMESPACE_CHAR: char = ':';
+const STAR_CHAR: char = '*';
 const COMMAND_CONTENT_CHARS: Pair = Pair::make('{', '}');
 const COMMAND_PARAMS_CHARS: Pair = Pair::make('[', ']');
+const COMMAND_VERBATIM_CHAR: char = '|';
+const COMMAND_VERB: &str = "verb";
 const COMMAND_PARAMS_ASSIGN_CHAR: char = '=';
 const COMMAND_PARAMS_SEP_CHAR: char = ',';
 const COMMENT_CHAR: char = '%';
+const MATH_CHAR: char = '$';
 const LINE_BREAK_CHAR: char = '\n';
+/// Line break of documents written on Windows, handled like a single [`LINE_BREAK_CHAR`]
+const CRLF: &str = "\r\n";
+/// Encoding marker some editors put at the start of UTF-8 files, ignored
+const BYTE_ORDER_MARK: char = '\u{feff}';
 const COMMAND_BLOCK_START: &str = "begin";
 const COMMAND_BLOCK_END: &str = "end";
+const DEFAULT_MAX_DEPTH: usize = 64;
+const DEFAULT_MAX_EXPANSION: usize = 100_000;
+
+/// Whether `c` can be part of a command or parameter name, which must start with an alphanumeric
+/// character.
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
 
 fn ident(cur: &str) -> Result<&str> {
-    use nom::bytes::complete::take_while1;
+    use nom::{bytes::complete::take_while, character::complete::satisfy, combinator::recognize};
 
-    take_while1(char::is_alphanumeric)(cur)
+    recognize(satisfy(char::is_alphanumeric).and(take_while(is_ident)))(cur)
+}
+
+/// Parses the star of a starred command, if there is one.
+fn star(cur: &str) -> Result<bool> {
+    use nom::{character::complete::char, combinator::opt};
+
+    opt(char(STAR_CHAR)).map(|star| star.is_some()).parse(cur)
 }
 
 fn whitespace(cur: &str) -> Result<&str> {
@@ -167,15 +653,20 @@ fn whitespace(cur: &str) -> Result<&str> {
     take_while(char::is_whitespace)(cur)
 }
 
-fn command_params(mut cur: &str) -> Result<Params> {
-    use nom::{bytes::complete::take_till1, character::complete::char, combinator::opt};
+/// Parses a `[...]` group of parameters, after its opening bracket, keeping their order.
+fn command_params<'b>(
+    state: &State<'b>,
+    mut cur: &'b str,
+) -> Result<'b, Vec<(&'b str, ParamValue<'b>)>> {
+    use nom::{bytes::complete::take_till1, character::complete::char};
 
-    let mut params = Params::new();
+    let options = &state.options;
+    let mut params = Vec::new();
 
     loop {
         let (i, _) = whitespace(cur)?;
 
-        if let Ok((i, _)) = char::<_, ()>(COMMAND_PARAMS_CHARS.close)(i) {
+        if let Ok((i, _)) = char::<_, ()>(options.params_chars.close)(i) {
             cur = i;
             break;
         }
@@ -183,19 +674,35 @@ fn command_params(mut cur: &str) -> Result<Params> {
         let (i, (ident, _)) = ident.and(whitespace).parse(i)?;
         let i = if let Ok((i, _)) = char::<_, ()>(COMMAND_PARAMS_ASSIGN_CHAR)(i) {
             let (i, _) = whitespace(i)?;
-            let (i, param) =
-                take_till1(|c| c == COMMAND_PARAMS_SEP_CHAR || c == COMMAND_PARAMS_CHARS.close)(i)?;
 
-            params.insert(ident, ParamValue::Text(param));
-            i
+            if let Ok((i, _)) = char::<_, ()>(options.content_chars.open)(i) {
+                // Braces and commands are balanced by the stream parser, so separators and
+                // closing brackets can be used inside
+                let (i, (stream, _)) = (|i| top_loop(state, i))
+                    .and(char(options.content_chars.close))
+                    .parse(i)?;
+                let (i, _) = whitespace(i)?;
+
+                params.push((ident, ParamValue::Stream(stream)));
+                i
+            } else {
+                let (i, param) = take_till1(|c| {
+                    c == COMMAND_PARAMS_SEP_CHAR || c == options.params_chars.close
+                })(i)?;
+
+                params.push((ident, ParamValue::Text(param)));
+                i
+            }
         } else {
-            params.insert(ident, ParamValue::None);
+            params.push((ident, ParamValue::None));
             i
         };
 
-        let (i, _) = opt(char(COMMAND_PARAMS_SEP_CHAR)).parse(i)?;
-
-        cur = i;
+        // Entries are separated by commas, and the last one can be followed by one
+        cur = match char::<_, ()>(options.params_chars.close)(i) {
+            Ok(_) => i,
+            Err(_) => char(COMMAND_PARAMS_SEP_CHAR)(i)?.0,
+        };
     }
 
     Ok((cur, params))
@@ -216,55 +723,119 @@ fn command_name(cur: &str) -> Result<CommandName> {
         .parse(cur)
 }
 
-fn command(cur: &str) -> Result<CommandType> {
-    use nom::{character::complete::char, combinator::recognize, sequence::tuple};
+/// Parses a command, `cur` being right after the backslash which `start` begins with.
+fn command<'b>(state: &State<'b>, start: &'b str, cur: &'b str) -> Result<'b, CommandType<'b>> {
+    use nom::{
+        bytes::complete::{tag, take_till},
+        character::complete::{char, satisfy},
+        combinator::recognize,
+        sequence::tuple,
+    };
 
+    let options = &state.options;
     if let Ok((i, c)) = recognize(
-        char::<_, ()>(COMMENT_CHAR)
-            .or(char::<_, ()>(COMMAND_CHAR))
-            .or(char::<_, ()>(COMMAND_CONTENT_CHARS.close))
+        satisfy::<_, _, ()>(|c| options.is_comment(c) || options.is_math(c))
+            .or(char::<_, ()>(options.command_char))
+            .or(char::<_, ()>(options.content_chars.close))
             .or(char::<_, ()>(LINE_BREAK_CHAR)),
-    )(cur)
+    )
+    .or(tag(CRLF))
+    .parse(cur)
     {
         return Ok((i, CommandType::Escape(c)));
     }
 
-    let (mut cur, name) = command_name(cur)?;
+    let (cur, name) = command_name(cur)?;
+    let (mut cur, starred) = star(cur)?;
+
+    if name.1.is_none() && options.is_verb(name.0) {
+        let delimiter = cur.chars().next();
+        if let Some(delimiter) = delimiter.filter(|&c| !c.is_whitespace() && !is_ident(c)) {
+            let (i, (inner, _)) = take_till(|c| c == delimiter)
+                .and(char(delimiter))
+                .parse(&cur[delimiter.len_utf8()..])?;
+
+            let command = Command {
+                name: name.0,
+                namespace: None,
+                content: vec![Element::Raw(inner)],
+                arguments: Vec::new(),
+                params: Params::new(),
+                param_groups: Vec::new(),
+                block: false,
+                starred,
+                comments: Vec::new(),
+                source: &start[..start.len() - i.len()],
+                content_source: Some(inner),
+            };
+            return Ok((i, CommandType::Normal(command)));
+        }
+    }
+
     let mut content = None;
-    let mut params = None;
+    let mut content_source = None;
+    let mut arguments = Vec::new();
+    let mut params = Params::new();
+    let mut param_groups = Vec::new();
 
-    if let Ok((i, _)) = char::<_, ()>(COMMAND_PARAMS_CHARS.open)(cur) {
-        let (i, res) = command_params(i)?;
+    while let Ok((i, _)) = char::<_, ()>(options.params_chars.open)(cur) {
+        let (i, group) = command_params(state, i)?;
 
+        param_groups.push(group.iter().map(|(name, _)| *name).collect());
+        params.extend(group);
         cur = i;
-        params = Some(res);
     }
 
-    if name == CommandName(COMMAND_BLOCK_START, None)
-        || name == CommandName(COMMAND_BLOCK_END, None)
-    {
-        let (i, (_, real_name, _)) = tuple((
-            char(COMMAND_CONTENT_CHARS.open),
+    let (block_start, block_end) = &options.block_keywords;
+    if name == CommandName(block_start, None) || name == CommandName(block_end, None) {
+        let (i, (_, real_name, starred, _)) = tuple((
+            char(options.content_chars.open),
             command_name,
-            char(COMMAND_CONTENT_CHARS.close),
+            star,
+            char(options.content_chars.close),
         ))(cur)?;
 
         let command = Command {
             name: real_name.0,
             namespace: real_name.1,
-            params: params.unwrap_or_default(),
+            params,
+            param_groups,
             content: Vec::new(),
+            arguments: Vec::new(),
             block: false,
+            starred,
+            comments: Vec::new(),
+            source: &start[..start.len() - i.len()],
+            content_source: None,
         };
 
-        if name.0 == COMMAND_BLOCK_START {
+        if name.0 == block_start {
             return Ok((i, CommandType::Start(command)));
         } else {
             return Ok((i, CommandType::End(command)));
         }
-    } else if let Ok((i, _)) = char::<_, ()>(COMMAND_CONTENT_CHARS.open)(cur) {
-        let (i, (inner, _)) = top_loop.and(char(COMMAND_CONTENT_CHARS.close)).parse(i)?;
+    } else if let Ok((open, _)) = char::<_, ()>(options.content_chars.open)(cur) {
+        let (i, (inner, _)) = (|i| top_loop(state, i))
+            .and(char(options.content_chars.close))
+            .parse(open)?;
         content = Some(inner);
+        content_source =
+            Some(&open[..open.len() - i.len() - options.content_chars.close.len_utf8()]);
+        cur = i;
+
+        while let Ok((i, _)) = char::<_, ()>(options.content_chars.open)(cur) {
+            let (i, (inner, _)) = (|i| top_loop(state, i))
+                .and(char(options.content_chars.close))
+                .parse(i)?;
+            arguments.push(inner);
+            cur = i;
+        }
+    } else if let Ok((i, _)) = char::<_, ()>(options.verbatim_char)(cur) {
+        let (i, (inner, _)) = take_till(|c| c == options.verbatim_char)
+            .and(char(options.verbatim_char))
+            .parse(i)?;
+        content = Some(vec![Element::Raw(inner)]);
+        content_source = Some(inner);
         cur = i;
     }
 
@@ -272,106 +843,221 @@ fn command(cur: &str) -> Result<CommandType> {
         name: name.0,
         namespace: name.1,
         content: content.unwrap_or_default(),
-        params: params.unwrap_or_default(),
+        arguments,
+        params,
+        param_groups,
         block: false,
+        starred,
+        comments: Vec::new(),
+        source: &start[..start.len() - cur.len()],
+        content_source,
     };
     Ok((cur, CommandType::Normal(command)))
 }
 
-fn raw(cur: &str) -> Result<Element> {
+fn raw<'b>(options: &ParserOptions, cur: &'b str) -> Result<'b, Element<'b>> {
     use nom::bytes::complete::take_till;
 
-    take_till(|c| c == COMMAND_CHAR || c == COMMAND_CONTENT_CHARS.close || c == COMMENT_CHAR)
-        .map(Element::Raw)
-        .parse(cur)
+    take_till(|c| {
+        c == options.command_char
+            || c == options.content_chars.close
+            || options.is_comment(c)
+            || options.is_math(c)
+    })
+    .map(Element::Raw)
+    .parse(cur)
 }
 
 fn comment(cur: &str) -> Result<Element> {
-    use nom::bytes::complete::take_till;
+    let line = &cur[..cur.find(LINE_BREAK_CHAR).unwrap_or(cur.len())];
+    // The carriage return of a CRLF line break is left out of the comment, like the line break
+    let comment = line.strip_suffix('\r').unwrap_or(line);
 
-    take_till(|c| c == LINE_BREAK_CHAR)
-        .map(Element::Comment)
-        .parse(cur)
+    Ok((&cur[comment.len()..], Element::Comment(comment)))
 }
 
-fn top(cur: &str) -> Result<Either<Element, CommandType>> {
-    use nom::character::complete::char;
+/// Parses math, `cur` starting with its opening delimiter of `open` bytes, up to `close`. Dollar
+/// signs not opening math are kept as text.
+fn math<'b>(
+    options: &ParserOptions,
+    cur: &'b str,
+    (open, close, display): (usize, String, bool),
+) -> Result<'b, Element<'b>> {
+    use nom::error::ErrorKind;
+
+    match options.math_len(cur, open, &close) {
+        Some(len) => {
+            let source = &cur[open..open + len];
+            Ok((
+                &cur[open + len + close.len()..],
+                Element::Math { display, source },
+            ))
+        }
+        None if cur.starts_with(MATH_CHAR) => Ok((&cur[open..], Element::Raw(&cur[..open]))),
+        None => Err(nom::Err::Error(ParseError::Syntax(nom::error::Error::new(
+            &cur[open..],
+            ErrorKind::TakeUntil,
+        )))),
+    }
+}
+
+fn top<'b>(state: &State<'b>, cur: &'b str) -> Result<'b, Either<Element<'b>, CommandType<'b>>> {
+    use nom::character::complete::{char, satisfy};
 
-    if let Ok((cur, _)) = char::<_, ()>(COMMAND_CHAR)(cur) {
-        command.map(Either::Right).parse(cur)
-    } else if let Ok((cur, _)) = char::<_, ()>(COMMENT_CHAR)(cur) {
+    if let Some(delimiters) = state.options.math_open(cur) {
+        let (i, math) = math(&state.options, cur, delimiters)?;
+        Ok((i, Either::Left(math)))
+    } else if let Ok((i, _)) = char::<_, ()>(state.options.command_char)(cur) {
+        (|i| command(state, cur, i)).map(Either::Right).parse(i)
+    } else if let Ok((cur, _)) = satisfy::<_, _, ()>(|c| state.options.is_comment(c))(cur) {
         comment.map(Either::Left).parse(cur)
     } else {
-        raw.map(Either::Left).parse(cur)
+        (|i| raw(&state.options, i)).map(Either::Left).parse(cur)
+    }
+}
+
+/// Gets the position of `slice` inside of `buf`, which it must come from.
+fn offset(buf: &str, slice: &str) -> usize {
+    let offset = slice.as_ptr() as usize - buf.as_ptr() as usize;
+    debug_assert!(offset + slice.len() <= buf.len(), "slice outside of buffer");
+    offset
+}
+
+/// Pushes an element to a stream, merging it with the previous one if both are raw text directly
+/// following each other in the source.
+fn push<'b>(buf: &'b str, stream: &mut Stream<'b>, el: Element<'b>) {
+    if let (Some(Element::Raw(prev)), Element::Raw(t)) = (stream.last_mut(), &el) {
+        let start = offset(buf, prev);
+
+        if start + prev.len() == offset(buf, t) {
+            *prev = &buf[start..start + prev.len() + t.len()];
+            return;
+        }
     }
+
+    stream.push(el);
 }
 
-fn top_loop(buf: &str) -> Result<Stream> {
-    top_loop_ctx(buf, None)
+fn top_loop<'b>(state: &State<'b>, buf: &'b str) -> Result<'b, Stream<'b>> {
+    top_loop_ctx(state, buf, None)
+}
+
+/// Parses a stream, up to a closing brace or, when `ctx` is given, the `\end` of the block it
+/// holds the name and `\begin` position of.
+fn top_loop_ctx<'b>(
+    state: &State<'b>,
+    buf: &'b str,
+    ctx: Option<(CommandName<'b>, &'b str)>,
+) -> Result<'b, Stream<'b>> {
+    let depth = state.depth.get();
+    if depth >= state.options.max_depth {
+        // Not recoverable: the content would have to be parsed to be skipped
+        return Err(nom::Err::Failure(ParseError::TooDeep { rest: buf }));
+    }
+
+    state.depth.set(depth + 1);
+    let res = stream_ctx(state, buf, ctx);
+    state.depth.set(depth);
+    res
 }
 
-fn top_loop_ctx<'b>(mut buf: &'b str, ctx: Option<CommandName>) -> Result<'b, Stream<'b>> {
+/// The loop of [`top_loop_ctx`], once the depth is checked.
+fn stream_ctx<'b>(
+    state: &State<'b>,
+    mut buf: &'b str,
+    ctx: Option<(CommandName<'b>, &'b str)>,
+) -> Result<'b, Stream<'b>> {
     use nom::character::complete::char;
 
     let mut res = Vec::new();
 
     loop {
-        if char::<_, ()>(COMMAND_CONTENT_CHARS.close)(buf).is_ok() {
-            // We leave the closing character in the flux to be consumed by the parent, so we
-            // can have proper diagnostics in case of mismatched closings.
-            break;
+        if char::<_, ()>(state.options.content_chars.close)(buf).is_ok() {
+            if ctx.is_none() {
+                // We leave the closing character in the flux to be consumed by the parent, so we
+                // can have proper diagnostics in case of mismatched closings.
+                break;
+            }
+
+            // Inside a block, there is no opening brace this one could match.
+            state.recover(ParseError::TrailingContent {
+                rest: buf,
+                open_block: ctx.map(|(name, _)| name),
+            })?;
+            let len = state.options.content_chars.close.len_utf8();
+            push(state.source, &mut res, Element::Raw(&buf[..len]));
+            buf = &buf[len..];
+            continue;
         }
 
         if buf.is_empty() {
+            if let Some((open, rest)) = ctx {
+                // The block is considered closed at the end of the input
+                state.recover(ParseError::UnclosedBlock { rest, open })?;
+            }
             break;
         }
 
-        let (cur, e) = top(buf)?;
+        if state.depth.get() == 1 {
+            state.element.set(buf);
+        }
+        let (cur, e) = top(state, buf)?;
 
         match e {
-            Either::Left(e) => res.push(e),
+            Either::Left(e) => push(state.source, &mut res, e),
             Either::Right(CommandType::Normal(cmd)) => res.push(Element::Command(cmd)),
             Either::Right(CommandType::Escape(e)) => {
-                if e.chars().next() == Some(LINE_BREAK_CHAR) {
-                    res.push(Element::LineBreak);
+                if e.ends_with(LINE_BREAK_CHAR) {
+                    res.push(Element::LineBreak(&buf[..buf.len() - cur.len()]));
                 } else {
-                    res.push(Element::Raw(e));
+                    push(state.source, &mut res, Element::Raw(e));
                 }
             }
             Either::Right(CommandType::Start(cmd)) => {
-                let (cur, content) = top_loop_ctx(cur, Some(cmd.command_name()))?;
+                let (rest, content) = if state.options.is_verbatim(cmd.command_name()) {
+                    verbatim_block(state, cur, &cmd, buf)?
+                } else {
+                    top_loop_ctx(state, cur, Some((cmd.command_name(), buf)))?
+                };
+                // The content runs up to the `\end`, or to the end of the input when the block is
+                // not closed
+                let inner = &cur[..cur.len() - rest.len()];
+                let end = state.options.block_end_of(&cmd);
+                let inner = inner.strip_suffix(end.as_str()).unwrap_or(inner);
 
                 res.push(Element::Command(Command {
                     name: cmd.name,
                     namespace: cmd.namespace,
                     content,
+                    arguments: Vec::new(),
                     params: cmd.params,
+                    param_groups: cmd.param_groups,
                     block: true,
+                    starred: cmd.starred,
+                    comments: cmd.comments,
+                    source: &buf[..buf.len() - rest.len()],
+                    content_source: Some(inner),
                 }));
 
-                buf = cur;
+                buf = rest;
                 continue;
             }
-            Either::Right(CommandType::End(cmd)) => {
-                if let Some(start_name) = ctx {
-                    if start_name != cmd.command_name() {
-                        panic!(
-                            "Closing a {} block while a {} is open",
-                            cmd.command_name(),
-                            start_name
-                        );
-                    }
-
+            // Stray `\end`s are dropped when recovering
+            Either::Right(CommandType::End(cmd)) => match ctx {
+                Some((open, _)) if open == cmd.command_name() => {
                     buf = cur;
                     break;
-                } else {
-                    panic!(
-                        "Closing a {} block outside of any block near {:?}",
-                        cmd.command_name(),
-                        cur
-                    )
                 }
-            }
+                Some((open, _)) => state.recover(ParseError::MismatchedBlock {
+                    rest: buf,
+                    open,
+                    close: cmd.command_name(),
+                })?,
+                None => state.recover(ParseError::UnexpectedEnd {
+                    rest: buf,
+                    close: cmd.command_name(),
+                })?,
+            },
         }
 
         buf = cur;
@@ -380,19 +1066,204 @@ fn top_loop_ctx<'b>(mut buf: &'b str, ctx: Option<CommandName>) -> Result<'b, St
     Ok((buf, res))
 }
 
+/// Takes the content of a verbatim block as is, up to its `\end`. `start` is the input from the
+/// `\begin` of `cmd`.
+fn verbatim_block<'b>(
+    state: &State<'b>,
+    buf: &'b str,
+    cmd: &Command<'b>,
+    start: &'b str,
+) -> Result<'b, Stream<'b>> {
+    let name = cmd.command_name();
+    let end = state.options.block_end_of(cmd);
+    let (content, rest) = match buf.find(&end) {
+        Some(pos) => (&buf[..pos], &buf[pos + end.len()..]),
+        None => {
+            // The block is considered closed at the end of the input
+            state.recover(ParseError::UnclosedBlock {
+                rest: start,
+                open: name,
+            })?;
+            (buf, &buf[buf.len()..])
+        }
+    };
+
+    let stream = if content.is_empty() {
+        Vec::new()
+    } else {
+        vec![Element::Raw(content)]
+    };
+    Ok((rest, stream))
+}
+
+fn document<'b>(state: &State<'b>, mut buf: &'b str) -> Result<'b, Stream<'b>> {
+    let mut res = Vec::new();
+    if offset(state.source, buf) == 0 {
+        buf = buf.strip_prefix(BYTE_ORDER_MARK).unwrap_or(buf);
+    }
+
+    loop {
+        let (rest, stream) = top_loop(state, buf)?;
+        for el in stream {
+            push(state.source, &mut res, el);
+        }
+
+        if rest.is_empty() {
+            return Ok((rest, res));
+        }
+
+        // The top-level loop only stops early on a closing brace
+        state.recover(ParseError::TrailingContent {
+            rest,
+            open_block: None,
+        })?;
+        let len = state.options.content_chars.close.len_utf8();
+        push(state.source, &mut res, Element::Raw(&rest[..len]));
+        buf = &rest[len..];
+    }
+}
+
 /// Parses a pastex document
 ///
 /// Reads the whole document from a text buffer `buf`, then returns, as a [`Stream`], a tree
 /// structure of the document and all function calls inside for processing by a compatible
 /// engine.
-pub fn parse(buf: &str) -> std::result::Result<Stream, nom::error::Error<&str>> {
+pub fn parse(buf: &str) -> std::result::Result<Stream, ParseError> {
+    parse_with(buf, &ParserOptions::default())
+}
+
+/// Parses a pastex document, like [`parse`], with the given options.
+pub fn parse_with<'b>(
+    buf: &'b str,
+    options: &ParserOptions,
+) -> std::result::Result<Stream<'b>, ParseError<'b>> {
     use nom::Finish;
 
-    match top_loop(buf).finish() {
-        Ok((buf, _)) if !buf.is_empty() => panic!("Extra content at end of file..."),
-        Ok((_, res)) => Ok(res),
-        Err(e) => Err(e),
+    let state = State::new(buf, options.clone(), false);
+    let res = document(&state, buf).finish().map(|(_, res)| res)?;
+
+    match options.attach_comments {
+        true => Ok(comments::attach(res)),
+        false => Ok(res),
+    }
+}
+
+/// Parses a pastex document, like [`parse`], but recovering from unmatched closing braces and
+/// blocks.
+///
+/// Such braces are kept in the stream as raw text, unmatched `\end`s are dropped and unclosed blocks
+/// end with the document. All of them are reported in the returned list of warnings. Other errors
+/// are still returned as errors.
+pub fn parse_lenient(
+    buf: &str,
+) -> std::result::Result<(Stream<'_>, Vec<ParseError<'_>>), ParseError<'_>> {
+    use nom::Finish;
+
+    let state = State::new(buf, ParserOptions::default(), true);
+    let (_, res) = document(&state, buf).finish()?;
+    Ok((res, state.warnings.into_inner()))
+}
+
+/// Parses a chunk of a document for [`parse_recovering`]. Unless it is the `last` one, the chunk
+/// fails to parse when it leaves a block open, as the block then ends after the chunk.
+fn recovering_chunk<'b>(
+    state: &State<'b>,
+    chunk: &'b str,
+    last: bool,
+) -> std::result::Result<Stream<'b>, Option<ParseError<'b>>> {
+    use nom::Finish;
+
+    let warnings = state.warnings.borrow().len();
+    let res = document(state, chunk).finish();
+    let unclosed = state.warnings.borrow()[warnings..]
+        .iter()
+        .any(|w| matches!(w, ParseError::UnclosedBlock { .. }));
+
+    let mut all = state.warnings.borrow_mut();
+    match res {
+        Ok((_, res)) if last || !unclosed => {
+            let added: Vec<_> = all.drain(warnings..).collect();
+            all.extend(added.into_iter().map(|w| w.extend_to(state.source)));
+            Ok(res)
+        }
+        res => {
+            all.truncate(warnings);
+            Err(res.err().map(|e| e.extend_to(state.source)))
+        }
+    }
+}
+
+/// Positions right after the paragraph breaks of `text`, with LF or CRLF line breaks.
+fn paragraph_ends(text: &str) -> impl Iterator<Item = usize> + Clone + '_ {
+    let mut start = 0;
+
+    std::iter::from_fn(move || loop {
+        let pos = start + text[start..].find(LINE_BREAK_CHAR)?;
+        let next = &text[pos + 1..];
+
+        start = pos + 1;
+        if next.starts_with(LINE_BREAK_CHAR) {
+            start += 1;
+        } else if next.starts_with(CRLF) {
+            start += CRLF.len();
+        } else {
+            continue;
+        }
+
+        return Some(start);
+    })
+}
+
+/// Parses a pastex document, like [`parse_lenient`], but never failing: a paragraph which can't be
+/// parsed is reported and skipped, and parsing goes on after it.
+///
+/// The document is parsed as a whole, so commands and blocks can span several paragraphs. When it
+/// fails, the paragraph where the failing top-level element starts is parsed on its own, to report
+/// its errors, and dropped, only closing the blocks it leaves open if that is enough. Parsing then
+/// resumes at the next paragraph. This is meant for editors and linters, which need every problem
+/// of a document and a tree to work on even when it is broken.
+///
+/// All errors and warnings are returned in the order they appear in the document.
+pub fn parse_recovering(buf: &str) -> (Stream<'_>, Vec<ParseError<'_>>) {
+    let state = State::new(buf, ParserOptions::default(), true);
+    let mut stream = Stream::new();
+    let mut errors = Vec::new();
+    let mut rest = buf;
+
+    while !rest.is_empty() {
+        state.element.set(rest);
+        if let Ok(res) = recovering_chunk(&state, rest, true) {
+            for el in res {
+                push(buf, &mut stream, el);
+            }
+            break;
+        }
+
+        // Everything before the failing element parsed, keep it up to the paragraph the element
+        // starts in, or up to the element itself when that paragraph starts inside another one
+        let start = offset(rest, state.element.get());
+        let mut cut = paragraph_ends(&rest[..start]).last().unwrap_or(0);
+        let before = recovering_chunk(&state, &rest[..cut], false).or_else(|_| {
+            cut = start;
+            recovering_chunk(&state, &rest[..cut], true)
+        });
+        let end = paragraph_ends(rest)
+            .find(|&end| end > start)
+            .unwrap_or(rest.len());
+        let paragraph = recovering_chunk(&state, &rest[cut..end], true);
+
+        for res in [before, paragraph] {
+            match res {
+                Ok(res) => res.into_iter().for_each(|el| push(buf, &mut stream, el)),
+                Err(e) => errors.extend(e),
+            }
+        }
+        rest = &rest[end..];
     }
+
+    errors.extend(state.warnings.into_inner());
+    errors.sort_by_key(|e| e.offset(buf));
+    (stream, errors)
 }
 
 #[cfg(test)]
@@ -444,6 +1315,61 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_line_break() {
+        test_document!("a\\\nb \\\\\n" => {
+            Element::Raw(r) => assert_eq!(r, "a"),
+            Element::LineBreak(s) => assert_eq!(s, "\\\n"),
+            Element::Raw(r) => assert_eq!(r, "b "),
+            // An escaped backslash doesn't make a line break
+            Element::Raw(r) => assert_eq!(r, "\\\n"),
+        })
+    }
+
+    #[test]
+    fn test_crlf_and_bom() {
+        use super::parse_recovering;
+        use crate::lexer::{tokenize, TokenKind::*};
+
+        let source = "\u{feff}a\\\r\nb %c\r\nd";
+        test_document!(source => {
+            Element::Raw(r) => assert_eq!(r, "a"),
+            Element::LineBreak(s) => assert_eq!(s, "\\\r\n"),
+            Element::Raw(r) => assert_eq!(r, "b "),
+            Element::Comment(c) => assert_eq!(c, "c"),
+            Element::Raw(r) => assert_eq!(r, "\r\nd"),
+        });
+
+        let tokens: Vec<_> = tokenize(source).map(|t| (t.kind, t.text)).collect();
+        assert_eq!(
+            tokens,
+            [
+                (Whitespace, "\u{feff}"),
+                (Text, "a"),
+                (LineBreak, "\\\r\n"),
+                (Text, "b "),
+                (Comment, "%c"),
+                (Text, "\r\nd"),
+            ]
+        );
+
+        let (stream, errors) = parse_recovering("a \\foo[=] b\r\n\r\nc");
+        test_stream!(stream => {
+            Element::Raw(t) => assert_eq!(t, "c"),
+        });
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_escapes_coalesced() {
+        test_document!(r"50\% off, \\o/ \}" => {
+            Element::Raw(r) => assert_eq!(r, "50"),
+            Element::Raw(r) => assert_eq!(r, "% off, "),
+            Element::Raw(r) => assert_eq!(r, r"\o/ "),
+            Element::Raw(r) => assert_eq!(r, "}"),
+        })
+    }
+
     #[test]
     fn test_command() {
         test_document!(r"\foo" => {
@@ -451,6 +1377,96 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_command_starred() {
+        test_document!(r"\head1*[id = a]{A} \my-ns:foo_bar-2 \begin{code*}\end{code}\end{code*}" => {
+            Element::Command(c) => {
+                assert_eq!(("head1", true), (c.name, c.starred));
+                assert_eq!(c.params.len(), 1);
+            },
+            Element::Raw(_) => (),
+            Element::Command(c) => {
+                assert_eq!(c.command_name().to_string(), "my-ns:foo_bar-2");
+                assert!(!c.starred);
+            },
+            Element::Raw(_) => (),
+            Element::Command(c) => {
+                assert_eq!(("code", true, true), (c.name, c.block, c.starred));
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, r"\end{code}"),
+                });
+            },
+        });
+
+        assert!(parse(r"\-foo").is_err());
+    }
+
+    #[test]
+    fn test_command_verbatim() {
+        test_document!(r"\code|\foo{}|, \code||" => {
+            Element::Command(c) => {
+                assert_eq!("code", c.name);
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, r"\foo{}"),
+                });
+            },
+            Element::Raw(r) => assert_eq!(r, ", "),
+            Element::Command(c) => test_stream!(c.content => {
+                Element::Raw(r) => assert_eq!(r, ""),
+            }),
+        });
+
+        assert!(parse(r"\code|unclosed").is_err());
+    }
+
+    #[test]
+    fn test_command_verb() {
+        use super::{parse_with, ParserOptions};
+
+        test_document!(r"\verb+\x{|}+ \verb*!a! \verb x" => {
+            Element::Command(c) => {
+                assert_eq!(("verb", false), (c.name, c.starred));
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, r"\x{|}"),
+                });
+            },
+            Element::Raw(_) => (),
+            Element::Command(c) => {
+                assert!(c.starred);
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, "a"),
+                });
+            },
+            Element::Raw(_) => (),
+            Element::Command(c) => assert!(c.content.is_empty()),
+            Element::Raw(r) => assert_eq!(r, " x"),
+        });
+
+        assert!(parse(r"\verb+unclosed").is_err());
+
+        let options = ParserOptions {
+            verb_command: None,
+            ..ParserOptions::default()
+        };
+        assert_eq!(parse_with(r"\verb+x+", &options).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_command_arguments() {
+        test_document!(r"\ruby{漢字}{かんじ}{\x{}} {y" => {
+            Element::Command(c) => {
+                test_stream!(c.content => { Element::Raw(r) => assert_eq!(r, "漢字"), });
+                let mut arguments = c.arguments.into_iter();
+                test_stream!(arguments.next().unwrap() => {
+                    Element::Raw(r) => assert_eq!(r, "かんじ"),
+                });
+                test_stream!(arguments.next().unwrap() => { Element::Command(_) => (), });
+                assert!(arguments.next().is_none());
+            },
+            Element::Raw(r) => assert_eq!(r, " {y"),
+        });
+    }
+
     #[test]
     fn test_command_ns() {
         test_document!(r"\foo:bar" => {
@@ -461,6 +1477,803 @@ mod tests {
         })
     }
 
+    fn reparse_matches(old: &str, edit: std::ops::Range<usize>, text: &str) {
+        let edit = super::TextEdit {
+            range: edit,
+            replacement: text,
+        };
+        let new = edit.apply(old);
+        let tree = parse(old).unwrap();
+        let res = super::reparse(old, tree, edit.range, &new).unwrap();
+
+        assert_eq!(format!("{:?}", res), format!("{:?}", parse(&new).unwrap()));
+    }
+
+    #[test]
+    fn test_into_owned() {
+        use super::{owned, IntoOwned, ParamValue};
+
+        let stream = {
+            let source = String::from("a \\foo[x = y, z = {\\b}]{c}{d}\\\n% e");
+            parse(&source).unwrap().into_owned()
+        };
+        let handle = std::thread::spawn(move || stream);
+        let stream = handle.join().unwrap();
+
+        test_stream!(owned::borrowed(&stream) => {
+            Element::Raw(t) => assert_eq!(t, "a "),
+            Element::Command(c) => {
+                assert_eq!(c.name, "foo");
+                assert_eq!(c.source, r"\foo[x = y, z = {\b}]{c}{d}");
+                assert!(matches!(c.params["x"], ParamValue::Text("y")));
+                test_stream!(c.content => { Element::Raw(t) => assert_eq!(t, "c"), });
+                assert_eq!(c.arguments.len(), 1);
+            },
+            Element::LineBreak(_) => (),
+            Element::Comment(t) => assert_eq!(t, " e"),
+        });
+    }
+
+    #[test]
+    fn test_content_source() {
+        use super::parse_lenient;
+
+        let content = |source| match &parse(source).unwrap()[..] {
+            [Element::Command(c)] => c.content_source,
+            stream => panic!("not a single command: {:?}", stream),
+        };
+
+        assert_eq!(content(r"\foo[x = {a}]{b \\ c}{d}"), Some(r"b \\ c"));
+        assert_eq!(content(r"\foo"), None);
+        assert_eq!(content(r"\foo{}"), Some(""));
+        assert_eq!(content(r"\foo|a\b|"), Some(r"a\b"));
+        assert_eq!(content(r"\verb+a\b+"), Some(r"a\b"));
+        assert_eq!(content(r"\begin[x]{y}a \% b\end{y}"), Some(r"a \% b"));
+        assert_eq!(
+            content(r"\begin{code*}\end{code}\end{code*}"),
+            Some(r"\end{code}")
+        );
+
+        // An unclosed block runs to the end of the input
+        match &parse_lenient("\\begin{x}a\\\\").unwrap().0[..] {
+            [Element::Command(c)] => assert_eq!(c.content_source, Some("a\\\\")),
+            stream => panic!("not a single command: {:?}", stream),
+        }
+    }
+
+    #[test]
+    fn test_math() {
+        use super::{parse_with, ParserOptions};
+
+        test_document!(r"$x_{1}$, $$\sum$$ \(a\) \[b}\] \$5" => {
+            Element::Math { display: false, source } => assert_eq!(source, "x_{1}"),
+            Element::Raw(r) => assert_eq!(r, ", "),
+            Element::Math { display: true, source } => assert_eq!(source, r"\sum"),
+            Element::Raw(_) => (),
+            Element::Math { display: false, source } => assert_eq!(source, "a"),
+            Element::Raw(_) => (),
+            Element::Math { display: true, source } => assert_eq!(source, "b}"),
+            Element::Raw(r) => assert_eq!(r, " "),
+            Element::Raw(r) => assert_eq!(r, "$5"),
+        });
+
+        // Dollar signs not opening math are text
+        test_stream!(parse("It costs $5.").unwrap() => {
+            Element::Raw(r) => assert_eq!(r, "It costs $5."),
+        });
+        test_stream!(parse("a $$ b").unwrap() => {
+            Element::Raw(r) => assert_eq!(r, "a $$ b"),
+        });
+        test_stream!(parse("$5 and $10, $ x$ and $y $").unwrap() => {
+            Element::Raw(r) => assert_eq!(r, "$5 and $10, $ x$ and $y $"),
+        });
+        test_stream!(parse("$a$5 and $ b $c$").unwrap() => {
+            Element::Math { display: false, source } => assert_eq!(source, "a"),
+            Element::Raw(r) => assert_eq!(r, "5 and $ b "),
+            Element::Math { display: false, source } => assert_eq!(source, "c"),
+        });
+        // Math between dollars ends with its paragraph
+        test_stream!(parse("$a\nb$ $c\n\nd$ $$e\r\n\r\nf$$").unwrap() => {
+            Element::Math { display: false, source } => assert_eq!(source, "a\nb"),
+            Element::Raw(r) => assert_eq!(r, " $c\n\nd$ $$e\r\n\r\nf$$"),
+        });
+        test_stream!(parse("\\[a\n\nb\\]").unwrap() => {
+            Element::Math { display: true, source } => assert_eq!(source, "a\n\nb"),
+        });
+        assert!(parse(r"\(unclosed").is_err());
+
+        let options = ParserOptions {
+            math_delimiters: false,
+            ..ParserOptions::default()
+        };
+        test_stream!(parse_with("$5 and $6", &options).unwrap() => {
+            Element::Raw(r) => assert_eq!(r, "$5 and $6"),
+        });
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        use super::{owned::OwnedElement, parse_reader, ReadError};
+
+        let source = "A \\strong{b}\n\n\\begin{x}\n\nc\n\n\\end{x}\n\nd";
+        let elements: Vec<_> = parse_reader(source.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        test_stream!(elements => {
+            OwnedElement::Raw(t) => assert_eq!(t, "A "),
+            OwnedElement::Command(c) => assert_eq!(c.name, "strong"),
+            OwnedElement::Raw(t) => assert_eq!(t, "\n\n"),
+            OwnedElement::Command(c) => {
+                assert_eq!(c.name, "x");
+                assert_eq!(c.content.len(), 1);
+            },
+            OwnedElement::Raw(t) => assert_eq!(t, "\n\n"),
+            OwnedElement::Raw(t) => assert_eq!(t, "d"),
+        });
+
+        let mut elements = parse_reader("a\n\nb\n\\begin{x}\nc".as_bytes());
+        assert!(matches!(elements.next(), Some(Ok(OwnedElement::Raw(_)))));
+        match elements.next() {
+            Some(Err(ReadError::Parse { location, .. })) => {
+                assert_eq!((location.offset, location.line, location.column), (5, 4, 1));
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+        assert!(elements.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        use super::{parse_recovering, ParseError};
+
+        let source = "a \\foo[=] b\n\nc \\bar{d\n\ne}\n\n\\baz{f\n\n\\begin{x}\n\ng \\end{y}\n\nh";
+        let (stream, errors) = parse_recovering(source);
+
+        test_stream!(stream => {
+            Element::Raw(t) => assert_eq!(t, "c "),
+            Element::Command(c) => assert_eq!(c.name, "bar"),
+            Element::Raw(t) => assert_eq!(t, "\n\n"),
+            Element::Command(c) => assert_eq!(c.name, "x"),
+        });
+        let offsets: Vec<_> = errors.iter().map(|e| e.offset(source)).collect();
+        assert_eq!(offsets, [7, 35, 35, 48]);
+        assert!(matches!(
+            errors[..],
+            [
+                ParseError::Syntax(_),
+                ParseError::Syntax(_),
+                ParseError::UnclosedBlock { .. },
+                ParseError::MismatchedBlock { .. },
+            ]
+        ));
+
+        // An unclosed brace early in a long document only drops its paragraph
+        let source = format!("\\foo{{a\n\n{}", "b \\bar{c}\n\n".repeat(5000));
+        let (stream, errors) = parse_recovering(&source);
+        assert_eq!(stream.len(), 10001);
+        assert!(matches!(errors[..], [ParseError::Syntax(_)]));
+        assert_eq!(errors[0].offset(&source), 8);
+
+        // A paragraph starting inside a block is kept up to the failing element
+        let source = "\\begin{x}a\n\nb\\end{x} c \\foo{d\n\ne";
+        let (stream, _) = parse_recovering(source);
+        test_stream!(stream => {
+            Element::Command(c) => assert_eq!(c.name, "x"),
+            Element::Raw(t) => assert_eq!(t, " c "),
+            Element::Raw(t) => assert_eq!(t, "e"),
+        });
+
+        assert!(parse_recovering("").1.is_empty());
+    }
+
+    #[test]
+    fn test_visitor() {
+        use super::{walk_stream, Command, Visitor};
+
+        #[derive(Default)]
+        struct Collect<'b> {
+            commands: Vec<&'b str>,
+            raw: Vec<&'b str>,
+            comments: usize,
+        }
+
+        impl<'b> Visitor<'b> for Collect<'b> {
+            fn visit_command(&mut self, command: &Command<'b>) {
+                self.commands.push(command.name);
+                super::visit::walk_command(self, command);
+            }
+
+            fn visit_raw(&mut self, text: &'b str) {
+                self.raw.push(text);
+            }
+
+            fn visit_comment(&mut self, _: &'b str) {
+                self.comments += 1;
+            }
+        }
+
+        let stream = parse("a \\foo[z, y = x]{d \\e}{f}\n% g").unwrap();
+        let mut visitor = Collect::default();
+        walk_stream(&mut visitor, &stream);
+
+        assert_eq!(visitor.commands, ["foo", "e"]);
+        assert_eq!(visitor.raw, ["a ", "d ", "f", "\n"]);
+        assert_eq!(visitor.comments, 1);
+    }
+
+    #[test]
+    fn test_reparse() {
+        let doc = "Intro \\foo{bar}.\n\nSome text\nwith lines.\n\n\\baz{qux} % comment\nend\n";
+
+        reparse_matches(doc, 20..20, "more ");
+        reparse_matches(doc, 20..24, "");
+        reparse_matches(doc, 20..20, "\\strong{new} ");
+        reparse_matches(doc, 0..0, "Start. ");
+        reparse_matches(doc, doc.len()..doc.len(), "Appended");
+        reparse_matches(doc, 7..10, "bar");
+        reparse_matches(doc, 24..24, "% now a comment ");
+        reparse_matches(doc, 24..24, "\\foo");
+        reparse_matches(&format!("\u{feff}{}", doc), 0..0, "");
+        // Removing the line break ending a comment extends it
+        reparse_matches("a % b\r\nc\n", 6..7, "\\foo");
+        // Closing math opened before the edited line, or in another element of the paragraph
+        reparse_matches("a $x b\nc\n", 4..4, "$");
+        reparse_matches("a $x \\foo{} b c\nd\n\ne\n", 15..15, "$");
+        reparse_matches("a $x$ b\nc $y$\n", 4..5, "");
+        reparse_matches("a $$x\n\nb $$\nc\n", 12..12, "$$");
+    }
+
+    #[test]
+    fn test_reparse_with() {
+        use super::{parse_with, reparse_with, ParserOptions};
+
+        let options = ParserOptions {
+            comment_char: Some('#'),
+            ..Default::default()
+        };
+        let old = "Text 100% # note\n\nMore text\n";
+        let new = "Text 100% # note\n\nMore words\n";
+        let tree = parse_with(old, &options).unwrap();
+        let res = reparse_with(old, tree, 23..27, new, &options).unwrap();
+
+        assert_eq!(
+            format!("{:?}", res),
+            format!("{:?}", parse_with(new, &options).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_tokenize() {
+        use crate::lexer::{tokenize, TokenKind::*};
+
+        let doc = "a \\ns:foo[x, y = 1]{b \\% c} % d\n\\begin{e}";
+        let tokens = tokenize(doc).collect::<Vec<_>>();
+        let kinds = tokens.iter().map(|t| t.kind).collect::<Vec<_>>();
+
+        assert_eq!(
+            kinds,
+            [
+                Text,
+                CommandStart,
+                Ident,
+                NamespaceSeparator,
+                Ident,
+                ParamsOpen,
+                Ident,
+                ParamSeparator,
+                Whitespace,
+                Ident,
+                Whitespace,
+                ParamAssign,
+                Whitespace,
+                ParamValue,
+                ParamsClose,
+                BraceOpen,
+                Text,
+                Escape,
+                Text,
+                BraceClose,
+                Text,
+                Comment,
+                Text,
+                CommandStart,
+                Ident,
+                BraceOpen,
+                Ident,
+                BraceClose,
+            ]
+        );
+        assert_eq!(tokens.iter().map(|t| t.text).collect::<String>(), doc);
+
+        let kinds = tokenize(r"\begin{code}\x}\end{code}")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                CommandStart,
+                Ident,
+                BraceOpen,
+                Ident,
+                BraceClose,
+                Text,
+                CommandStart,
+                Ident,
+                BraceOpen,
+                Ident,
+                BraceClose
+            ]
+        );
+
+        let kinds = tokenize(r"\a-b*[c]*\begin{code*}\end{code}\end{code*}")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                CommandStart,
+                Ident,
+                Star,
+                ParamsOpen,
+                Ident,
+                ParamsClose,
+                Text,
+                CommandStart,
+                Ident,
+                BraceOpen,
+                Ident,
+                Star,
+                BraceClose,
+                Text,
+                CommandStart,
+                Ident,
+                BraceOpen,
+                Ident,
+                Star,
+                BraceClose
+            ]
+        );
+
+        let kinds = tokenize(r"\verb*+\x{+ \verb:x")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                CommandStart,
+                Ident,
+                Star,
+                Verbatim,
+                Text,
+                CommandStart,
+                Ident,
+                NamespaceSeparator,
+                Ident
+            ]
+        );
+
+        let kinds = tokenize(r"$a$ \$ \[b\]$$")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [Math, Text, Escape, Text, Math, Text]);
+
+        let kinds = tokenize("$5 and $10").map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [Text, Text, Text, Text]);
+
+        let kinds = tokenize(r"\code|\x{| \y|")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                CommandStart,
+                Ident,
+                Verbatim,
+                Text,
+                CommandStart,
+                Ident,
+                Text
+            ]
+        );
+
+        // A block name interrupted by a line break ends, instead of yielding empty tokens
+        let kinds = tokenize("\\end{\r\n").map(|t| t.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, [CommandStart, Ident, BraceOpen, Text]);
+    }
+
+    #[test]
+    fn test_trailing_content() {
+        use super::{parse_lenient, ParseError};
+
+        let doc = r"foo} bar";
+        match parse(doc) {
+            Err(ParseError::TrailingContent {
+                rest,
+                open_block: None,
+            }) => assert_eq!(rest, "} bar"),
+            other => panic!("Expected trailing content error, got {:?}", other),
+        }
+
+        let (res, warnings) = parse_lenient(doc).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].offset(doc), 3);
+        test_stream!(res => {
+            Element::Raw(r) => assert_eq!(r, "foo} bar"),
+        });
+    }
+
+    #[test]
+    fn test_trailing_content_in_block() {
+        use super::ParseError;
+
+        match parse(r"\begin{foo} } \end{foo}") {
+            Err(ParseError::TrailingContent {
+                open_block: Some(block),
+                ..
+            }) => assert_eq!(block.to_string(), "foo"),
+            other => panic!("Expected trailing content error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_too_deep() {
+        use super::{parse_lenient, parse_with, ParseError, ParserOptions};
+
+        let options = ParserOptions {
+            max_depth: 3,
+            ..ParserOptions::default()
+        };
+        assert!(parse_with(r"\a{\b{c}}", &options).is_ok());
+        assert!(parse_with(r"\begin{a}\b{c}\end{a}", &options).is_ok());
+
+        let doc = r"\a{\b{\c{d}}}";
+        match parse_with(doc, &options) {
+            Err(ParseError::TooDeep { rest }) => assert_eq!(rest, "d}}}"),
+            other => panic!("Expected too deep error, got {:?}", other),
+        }
+
+        // Far deeper than the stack would allow without the limit
+        let doc = "\\a{".repeat(100_000);
+        assert!(matches!(parse(&doc), Err(ParseError::TooDeep { .. })));
+        assert!(matches!(
+            parse_lenient(&doc),
+            Err(ParseError::TooDeep { .. })
+        ));
+    }
+
+    #[test]
+    fn test_block_errors() {
+        use super::{parse_lenient, ParseError};
+
+        let doc = r"\begin{a} x \end{b} y";
+        match parse(doc) {
+            Err(ParseError::MismatchedBlock { rest, open, close }) => {
+                assert_eq!(rest, r"\end{b} y");
+                assert_eq!(
+                    (open.to_string(), close.to_string()),
+                    ("a".into(), "b".into())
+                );
+            }
+            other => panic!("Expected mismatched block error, got {:?}", other),
+        }
+
+        let (res, warnings) = parse_lenient(doc).unwrap();
+        assert!(matches!(
+            warnings[..],
+            [
+                ParseError::MismatchedBlock { .. },
+                ParseError::UnclosedBlock { .. }
+            ]
+        ));
+        assert_eq!(warnings[1].offset(doc), 0);
+        test_stream!(res => {
+            Element::Command(c) => assert!(c.block && c.name == "a"),
+        });
+
+        assert!(matches!(
+            parse(r"x \end{a}"),
+            Err(ParseError::UnexpectedEnd {
+                rest: r"\end{a}",
+                ..
+            })
+        ));
+        let doc = r"x \begin{a} \begin{b} \end{b}";
+        match parse(doc) {
+            Err(e @ ParseError::UnclosedBlock { .. }) => assert_eq!(e.offset(doc), 2),
+            other => panic!("Expected unclosed block error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_element_source() {
+        use super::{Location, ParamValue};
+
+        let doc = "a \\foo[x, y = z]{b \\bar} %c\n\\begin{baz}\nd\n\\end{baz}\\\n";
+        let stream = parse(doc).unwrap();
+        let sources = stream.iter().map(Element::source).collect::<Vec<_>>();
+
+        assert_eq!(
+            sources,
+            [
+                "a ",
+                r"\foo[x, y = z]{b \bar}",
+                " ",
+                "c",
+                "\n",
+                "\\begin{baz}\nd\n\\end{baz}",
+                "\\\n",
+            ]
+        );
+
+        let location = stream[5].location(doc).unwrap();
+        assert_eq!((location.line, location.column), (2, 1));
+        let location = stream[6].location(doc).unwrap();
+        assert_eq!((location.line, location.column), (4, 10));
+        match &stream[1] {
+            Element::Command(c) => {
+                assert_eq!(c.content[1].source(), r"\bar");
+                // Parameter names and text values are slices of the source too
+                let (key, value) = c.params.get_key_value("y").unwrap();
+                assert_eq!(Location::of(doc, key).unwrap().offset, 10);
+                match value {
+                    ParamValue::Text(value) => {
+                        assert_eq!(Location::of(doc, value).unwrap().offset, 14)
+                    }
+                    other => panic!("Expected text, got {:?}", other),
+                }
+            }
+            other => panic!("Expected command, got {:?}", other),
+        }
+        assert!(Element::Raw("a ").location(doc).is_none());
+    }
+
+    #[test]
+    fn test_verbatim_block() {
+        use super::{parse_lenient, parse_with, ParseError, ParserOptions};
+
+        let doc = "\\begin[x]{code}\n\\foo{ } % \\end{math}\n\\end{code}\\begin{math}\\end{math}";
+        test_document!(doc => {
+            Element::Command(c) => {
+                assert_eq!((c.name, c.block, c.params.len()), ("code", true, 1));
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, "\n\\foo{ } % \\end{math}\n"),
+                });
+            },
+            Element::Command(c) => assert!(c.content.is_empty()),
+        });
+
+        let options = ParserOptions {
+            verbatim_blocks: vec!["ns:raw".to_owned()],
+            ..Default::default()
+        };
+        test_stream!(parse_with(r"\begin{ns:raw}}\end{ns:raw}", &options).unwrap() => {
+            Element::Command(c) => test_stream!(c.content => {
+                Element::Raw(r) => assert_eq!(r, "}"),
+            }),
+        });
+        assert!(parse_with(r"\begin{code}}\end{code}", &options).is_err());
+
+        let doc = r"\begin{code} \end{cod}";
+        assert!(matches!(parse(doc), Err(ParseError::UnclosedBlock { .. })));
+        let (res, warnings) = parse_lenient(doc).unwrap();
+        assert_eq!(warnings.len(), 1);
+        test_stream!(res => {
+            Element::Command(c) => test_stream!(c.content => {
+                Element::Raw(r) => assert_eq!(r, r" \end{cod}"),
+            }),
+        });
+    }
+
+    #[test]
+    fn test_custom_syntax() {
+        use super::{lexer::tokenize_with, parse_with, Pair, ParserOptions};
+
+        let options = ParserOptions {
+            command_char: '@',
+            comment_char: None,
+            content_chars: Pair::make('<', '>'),
+            params_chars: Pair::make('(', ')'),
+            block_keywords: ("open".to_owned(), "close".to_owned()),
+            ..Default::default()
+        };
+        let doc = r"50% \o/ @b(x = 1)<@@ {}> @open<code>@b<>@close<code>";
+
+        test_stream!(parse_with(doc, &options).unwrap() => {
+            Element::Raw(r) => assert_eq!(r, r"50% \o/ "),
+            Element::Command(c) => {
+                assert!(c.params.contains_key("x"));
+                test_stream!(c.content => {
+                    Element::Raw(r) => assert_eq!(r, "@ {}"),
+                });
+            },
+            Element::Raw(r) => assert_eq!(r, " "),
+            Element::Command(c) => test_stream!(c.content => {
+                Element::Raw(r) => assert_eq!(r, "@b<>"),
+            }),
+        });
+
+        let tokens = tokenize_with(doc, &options).collect::<Vec<_>>();
+        assert_eq!(tokens.iter().map(|t| t.text).collect::<String>(), doc);
+        assert_eq!(tokens[0].text, r"50% \o/ ");
+    }
+
+    #[test]
+    fn test_location() {
+        use super::Location;
+
+        let doc = "first\nsé\\foo[bar]";
+        let stream = parse(doc).unwrap();
+        let key = match &stream[1] {
+            Element::Command(c) => *c.params.keys().next().unwrap(),
+            other => panic!("Expected command, got {:?}", other),
+        };
+
+        let location = Location::of(doc, key).unwrap();
+        assert_eq!((location.line, location.column), (2, 8));
+        assert_eq!(location.offset, 14);
+        assert_eq!(Location::of(doc, "bar"), None);
+    }
+
+    #[test]
+    fn test_params() {
+        use super::{Dimension, ParamError, ParamsExt, Unit};
+
+        let doc = parse(
+            r"\foo[text = hello , flag, no = no, num = -12, bad = 1x, f = 1.5, w = 50 %, h = 2rem]",
+        )
+        .unwrap();
+        let params = match &doc[0] {
+            Element::Command(c) => &c.params,
+            other => panic!("Expected command, got {:?}", other),
+        };
+
+        assert_eq!(params.get_str("text"), Ok(Some("hello")));
+        assert_eq!(params.get_str("missing"), Ok(None));
+        assert_eq!(params.get_bool("flag"), Ok(Some(true)));
+        assert_eq!(params.get_bool("no"), Ok(Some(false)));
+        assert_eq!(params.get_int("num"), Ok(Some(-12)));
+        assert!(matches!(
+            params.get_int("bad"),
+            Err(ParamError::Invalid { value, .. }) if value == "1x"
+        ));
+        assert_eq!(params.get_float("f"), Ok(Some(1.5)));
+        assert!(params.get_float("bad").is_err());
+        let dimension = |value, unit| Ok(Some(Dimension { value, unit }));
+        assert_eq!(params.get_dimension("w"), dimension(50.0, Unit::Percent));
+        assert_eq!(params.get_dimension("h"), dimension(2.0, Unit::Rem));
+        assert_eq!(params.get_dimension("num"), dimension(-12.0, Unit::Px));
+        assert!(params.get_dimension("bad").is_err());
+        assert_eq!(Dimension::parse("12px").unwrap().to_string(), "12px");
+        assert_eq!(Dimension::parse("inf"), None);
+        assert!(matches!(
+            params.get_str("flag"),
+            Err(ParamError::MissingValue { .. })
+        ));
+        assert!(matches!(
+            params.get_stream("text"),
+            Err(ParamError::WrongForm { .. })
+        ));
+    }
+
+    #[test]
+    fn test_param_groups() {
+        use super::{IntoOwned, ParamsExt};
+
+        test_document!(r"\foo[a, b = 1][][b = 2, c]{x} \begin[d][e]{f}\end{f}" => {
+            Element::Command(c) => {
+                assert_eq!(c.param_groups, [vec!["a", "b"], vec![], vec!["b", "c"]]);
+                assert_eq!(c.params.len(), 3);
+                assert_eq!(c.params.get_int("b"), Ok(Some(2)));
+
+                let owned = c.clone().into_owned();
+                assert_eq!(owned.borrowed().param_groups, c.param_groups);
+            },
+            Element::Raw(_) => (),
+            Element::Command(c) => {
+                assert!(c.block);
+                assert_eq!(c.param_groups, [["d"], ["e"]]);
+            },
+        });
+    }
+
+    #[test]
+    fn test_attach_comments() {
+        use super::{format_with, parse_with, FormatOptions, ParserOptions};
+
+        let options = ParserOptions {
+            attach_comments: true,
+            ..Default::default()
+        };
+        let source =
+            "% doc\n% more\n\\foo{% inner\n\\bar} text % trailing\n\\baz\n% apart\n\n\\qux";
+        let stream = parse_with(source, &options).unwrap();
+
+        assert_eq!(stream.len(), 9);
+        assert!(matches!(&stream[2], Element::Comment(" trailing")));
+        assert!(matches!(&stream[6], Element::Comment(" apart")));
+        fn comments<'b>(el: &Element<'b>) -> Vec<&'b str> {
+            match el {
+                Element::Command(cmd) => cmd.comments.clone(),
+                el => panic!("expected a command, got {:?}", el),
+            }
+        }
+        assert_eq!(comments(&stream[0]), [" doc", " more"]);
+        assert!(comments(&stream[4]).is_empty());
+        assert!(comments(&stream[8]).is_empty());
+        match &stream[0] {
+            Element::Command(foo) => assert_eq!(comments(&foo.content[0]), [" inner"]),
+            _ => unreachable!(),
+        }
+
+        let options = FormatOptions {
+            syntax: options,
+            ..Default::default()
+        };
+        let formatted = format_with(&stream, &options);
+        assert!(formatted.starts_with("% doc\n% more\n\\foo{\n% inner\n\\bar} text"));
+        let reparsed = parse_with(&formatted, &options.syntax).unwrap();
+        assert_eq!(comments(&reparsed[0]), [" doc", " more"]);
+        assert_eq!(format_with(&reparsed, &options), formatted);
+
+        // Comments are kept as elements by default
+        assert!(matches!(
+            parse(source).unwrap()[0],
+            Element::Comment(" doc")
+        ));
+    }
+
+    #[test]
+    fn test_param_streams() {
+        use super::{
+            lexer::{tokenize, TokenKind::*},
+            ParamsExt,
+        };
+
+        let doc = r"\foo[a = {x, \b[c = {\d{e}}]{f}{g}, y]}, b = {}, z]{h}";
+        test_document!(doc => {
+            Element::Command(c) => {
+                assert_eq!(c.params.len(), 3);
+                test_stream!(c.params.get_stream("a").unwrap().unwrap() => {
+                    Element::Raw(r) => assert_eq!(*r, "x, "),
+                    Element::Command(b) => {
+                        assert_eq!(b.name, "b");
+                        test_stream!(b.params.get_stream("c").unwrap().unwrap() => {
+                            Element::Command(d) => assert_eq!(d.name, "d"),
+                        });
+                        test_stream!(&b.content => { Element::Raw(r) => assert_eq!(*r, "f"), });
+                        assert_eq!(b.arguments.len(), 1);
+                    },
+                    Element::Raw(r) => assert_eq!(*r, ", y]"),
+                });
+                assert!(c.params.get_stream("b").unwrap().unwrap().is_empty());
+                test_stream!(c.content => { Element::Raw(r) => assert_eq!(r, "h"), });
+            },
+        });
+
+        assert!(parse(r"\foo[a = {b]").is_err());
+        // Entries need a comma between them, which can also end the group
+        assert!(parse(r"\foo[a = {b} c]").is_err());
+        assert!(parse(r"\foo[a b]").is_err());
+        assert!(parse(r"\foo[a = {b}, c,]").is_ok());
+
+        // The lexer goes back to the parameters after the value
+        let kinds = tokenize(r"\foo[a = {\b{c}}, d]")
+            .map(|t| t.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds[12..],
+            [
+                BraceClose,
+                BraceClose,
+                ParamSeparator,
+                Whitespace,
+                Ident,
+                ParamsClose
+            ]
+        );
+    }
+
     #[test]
     fn test_command_content() {
         test_document!(r"\foo{bar}" => {
@@ -0,0 +1,310 @@
+//! Shorthands defined in documents with `\define{name}{replacement}`, expanded in parsed streams.
+//!
+//! Once defined, each `\name` call is replaced with the replacement, where `#1` is the content of
+//! the call, `#2` the brace group following it, and so on, as in `\name{first}{second}`. `##`
+//! writes a single `#`. Replacements may call other macros, which are expanded in turn:
+//!
+//! ```
+//! use pastex_parser::{expand_macros, parse, Element};
+//!
+//! let source = "\\define{hl}{\\strong{#1} (#2)}\\hl{Rust}{a language}";
+//! let stream = expand_macros(source, parse(source).unwrap(), &Default::default()).unwrap();
+//! assert!(matches!(&stream[0], Element::Command(cmd) if cmd.name == "strong"));
+//! ```
+//!
+//! Macros apply from their definition to the end of the document, wherever they are defined, and
+//! take precedence over the commands of the engine with the same name. Only the text of the
+//! replacement is substituted: `#1` in a parameter given without braces is kept as is.
+
+use super::{offset, Command, Element, ParamValue, ParseError, ParserOptions, Stream};
+use std::collections::HashMap;
+
+/// Name of the command defining a macro
+pub const COMMAND_DEFINE: &str = "define";
+
+/// Character introducing an argument in a replacement, as `#1`
+const ARGUMENT_CHAR: char = '#';
+
+/// The name and replacement of `cmd` if it defines a macro, as `\define{name}{replacement}`.
+fn definition<'b>(cmd: &mut Command<'b>) -> Option<(&'b str, Stream<'b>)> {
+    if cmd.name != COMMAND_DEFINE || cmd.namespace.is_some() || cmd.block {
+        return None;
+    }
+
+    match (&cmd.content[..], &mut cmd.arguments[..]) {
+        ([Element::Raw(name)], [replacement]) if !name.trim().is_empty() => {
+            Some((name.trim(), std::mem::take(replacement)))
+        }
+        _ => None,
+    }
+}
+
+/// Replaces the `#n` of `text` with the arguments of the call.
+fn substitute_raw<'b>(text: &'b str, arguments: &[Stream<'b>], res: &mut Stream<'b>) {
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((pos, c)) = chars.next() {
+        if c != ARGUMENT_CHAR {
+            continue;
+        }
+
+        let argument = match chars.peek() {
+            Some(&(_, ARGUMENT_CHAR)) => None,
+            Some(&(_, digit @ '1'..='9')) => digit.to_digit(10).map(|n| n as usize - 1),
+            _ => continue,
+        };
+        chars.next();
+
+        match argument {
+            // Keeps the first `#` of `##`
+            None => res.push(Element::Raw(&text[start..pos + 1])),
+            Some(n) => {
+                if pos > start {
+                    res.push(Element::Raw(&text[start..pos]));
+                }
+                res.extend(arguments.get(n).into_iter().flatten().cloned());
+            }
+        }
+        start = pos + 2;
+    }
+
+    if start < text.len() {
+        res.push(Element::Raw(&text[start..]));
+    }
+}
+
+/// Builds the expansion of a macro from its replacement and the arguments of the call.
+fn substitute<'b>(replacement: &[Element<'b>], arguments: &[Stream<'b>]) -> Stream<'b> {
+    let mut res = Vec::with_capacity(replacement.len());
+
+    for el in replacement {
+        match el {
+            Element::Raw(text) => substitute_raw(text, arguments, &mut res),
+            Element::Command(cmd) => res.push(Element::Command(Command {
+                content: substitute(&cmd.content, arguments),
+                arguments: cmd
+                    .arguments
+                    .iter()
+                    .map(|argument| substitute(argument, arguments))
+                    .collect(),
+                params: cmd
+                    .params
+                    .iter()
+                    .map(|(key, value)| match value {
+                        ParamValue::Stream(stream) => {
+                            (*key, ParamValue::Stream(substitute(stream, arguments)))
+                        }
+                        value => (*key, value.clone()),
+                    })
+                    .collect(),
+                // The arguments replace the `#n` written in the content
+                content_source: None,
+                ..cmd.clone()
+            })),
+            el => res.push(el.clone()),
+        }
+    }
+
+    res
+}
+
+/// The input left from `slice` in `buf`, to locate errors. Elements from other buffers, like
+/// included files, are located from their own start.
+fn rest<'b>(buf: &'b str, slice: &'b str) -> &'b str {
+    let start = slice.as_ptr() as usize;
+    let buf_start = buf.as_ptr() as usize;
+
+    match start >= buf_start && start + slice.len() <= buf_start + buf.len() {
+        true => &buf[offset(buf, slice)..],
+        false => slice,
+    }
+}
+
+struct Expander<'b, 'o> {
+    buf: &'b str,
+    options: &'o ParserOptions,
+    macros: HashMap<&'b str, Stream<'b>>,
+    /// How many elements expansions added so far
+    expanded: usize,
+}
+
+/// How many elements `stream` holds, counting the ones nested in commands.
+fn size(stream: &[Element]) -> usize {
+    stream
+        .iter()
+        .map(|el| match el {
+            Element::Command(cmd) => {
+                let params = cmd.params.values().map(|value| match value {
+                    ParamValue::Stream(stream) => size(stream),
+                    _ => 0,
+                });
+                let arguments = cmd.arguments.iter().map(|argument| size(argument));
+
+                1 + size(&cmd.content) + params.chain(arguments).sum::<usize>()
+            }
+            _ => 1,
+        })
+        .sum()
+}
+
+impl<'b, 'o> Expander<'b, 'o> {
+    /// Expands the macros of `stream`, `depth` being how deep it is nested, counting both
+    /// command contents and expansions.
+    fn stream(&mut self, stream: Stream<'b>, depth: usize) -> Result<Stream<'b>, ParseError<'b>> {
+        let mut res = Vec::with_capacity(stream.len());
+
+        for el in stream {
+            let mut cmd = match el {
+                Element::Command(cmd) => cmd,
+                el => {
+                    res.push(el);
+                    continue;
+                }
+            };
+
+            if depth >= self.options.max_depth {
+                return Err(ParseError::TooDeep {
+                    rest: rest(self.buf, cmd.source),
+                });
+            }
+
+            if let Some((name, replacement)) = definition(&mut cmd) {
+                self.macros.insert(name, replacement);
+                continue;
+            }
+
+            let is_call = cmd.namespace.is_none() && !cmd.block && !cmd.starred;
+            if let Some(replacement) = self.macros.get(cmd.name).filter(|_| is_call) {
+                let mut arguments = cmd.arguments;
+                arguments.insert(0, cmd.content);
+                let expansion = substitute(replacement, &arguments);
+
+                self.expanded += size(&expansion);
+                if self.expanded > self.options.max_expansion {
+                    return Err(ParseError::TooLarge {
+                        rest: rest(self.buf, cmd.source),
+                    });
+                }
+                res.extend(self.stream(expansion, depth + 1)?);
+                continue;
+            }
+
+            let params = cmd
+                .params
+                .into_iter()
+                .map(|(key, value)| match value {
+                    ParamValue::Stream(stream) => {
+                        Ok((key, ParamValue::Stream(self.stream(stream, depth + 1)?)))
+                    }
+                    value => Ok((key, value)),
+                })
+                .collect::<Result<_, _>>()?;
+            let arguments = cmd
+                .arguments
+                .into_iter()
+                .map(|argument| self.stream(argument, depth + 1))
+                .collect::<Result<_, _>>()?;
+
+            res.push(Element::Command(Command {
+                content: self.stream(cmd.content, depth + 1)?,
+                arguments,
+                params,
+                ..cmd
+            }));
+        }
+
+        Ok(res)
+    }
+}
+
+/// Expands the macros defined in `stream`, parsed from `source`, see the
+/// [module documentation](self). Definitions are removed from the stream, and malformed ones are
+/// kept as regular commands.
+///
+/// Expansions nest like command contents, so nesting them deeper than
+/// [`ParserOptions::max_depth`], as a macro calling itself does, is a [`ParseError::TooDeep`]
+/// error. Expansions adding more than [`ParserOptions::max_expansion`] elements in total are a
+/// [`ParseError::TooLarge`] error.
+pub fn expand_macros<'b>(
+    source: &'b str,
+    stream: Stream<'b>,
+    options: &ParserOptions,
+) -> Result<Stream<'b>, ParseError<'b>> {
+    let mut expander = Expander {
+        buf: source,
+        options,
+        macros: HashMap::new(),
+        expanded: 0,
+    };
+    expander.stream(stream, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, visit};
+
+    fn expand(source: &str) -> Result<Vec<&str>, ParseError<'_>> {
+        let stream = expand_macros(source, parse(source).unwrap(), &ParserOptions::default())?;
+        let mut raw = Vec::new();
+        visit::walk_stream(&mut Raw(&mut raw), &stream);
+        Ok(raw)
+    }
+
+    #[test]
+    fn test_macros() {
+        let source = "\\define{a}{<#1|#2|##1>}\\a{x}{y} \\a{\\strong{z}} \\a*{w}";
+        assert_eq!(
+            expand(source).unwrap(),
+            ["<", "x", "|", "y", "|#", "1>", " ", "<", "z", "|", "|#", "1>", " ", "w"]
+        );
+
+        let source = "\\b \\define{b}{B}\\define{c}{\\em[x = {#1}]{\\b}}\\c{C}";
+        assert_eq!(expand(source).unwrap(), [" ", "C", "B"]);
+
+        // Malformed definitions are left to the engine
+        let source = "\\define{d}";
+        let stream = expand_macros(source, parse(source).unwrap(), &Default::default()).unwrap();
+        assert!(matches!(&stream[..], [Element::Command(cmd)] if cmd.name == COMMAND_DEFINE));
+
+        let source = "Text \\define{loop}{\\loop}\\loop";
+        let err = expand(source).unwrap_err();
+        assert!(matches!(err, ParseError::TooDeep { .. }));
+        assert_eq!(err.offset(source), 19);
+    }
+
+    #[test]
+    fn test_expansion_limit() {
+        // Each macro calls the previous one twice, doubling the expansion at each level
+        let mut source = "\\define{m0}{x}".to_owned();
+        for n in 1..40 {
+            source.push_str(&format!("\\define{{m{0}}}{{\\m{1} \\m{1} }}", n, n - 1));
+        }
+        source.push_str("Text \\m39");
+
+        let start = std::time::Instant::now();
+        let err = expand(&source).unwrap_err();
+        assert!(matches!(err, ParseError::TooLarge { .. }));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let options = ParserOptions {
+            max_expansion: 3,
+            ..ParserOptions::default()
+        };
+        let source = "\\define{a}{x \\b{y}}\\a \\a";
+        let stream = parse(source).unwrap();
+        let err = expand_macros(source, stream, &options).unwrap_err();
+        assert!(matches!(err, ParseError::TooLarge { .. }));
+        assert_eq!(err.offset(source), 22);
+    }
+
+    /// Collects raw text, to check the result of expansions.
+    struct Raw<'r, 'b>(&'r mut Vec<&'b str>);
+
+    impl<'r, 'b> visit::Visitor<'b> for Raw<'r, 'b> {
+        fn visit_raw(&mut self, text: &'b str) {
+            self.0.push(text);
+        }
+    }
+}
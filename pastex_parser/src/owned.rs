@@ -0,0 +1,254 @@
+//! Parser output which owns its text, for when a parsed document has to outlive its source buffer.
+//!
+//! The [`Element`] tree borrows from the buffer it was parsed from. [`IntoOwned::into_owned`]
+//! copies it into the `Owned*` types below, which can be stored, cached or sent to another thread.
+//! Use their `borrowed` methods to get a regular tree back, to process it as usual.
+
+use super::{Command, Element, ParamValue, Params, Stream};
+use std::collections::HashMap;
+
+/// Conversion of a borrowed parser output into its owned version.
+pub trait IntoOwned {
+    type Owned;
+
+    /// Copies the borrowed text, so the result does not depend on the source buffer anymore.
+    fn into_owned(self) -> Self::Owned;
+}
+
+/// Owned version of [`ParamValue`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedParamValue {
+    None,
+    Text(String),
+    Stream(OwnedStream),
+}
+
+/// Owned version of [`Params`].
+pub type OwnedParams = HashMap<String, OwnedParamValue>;
+
+/// Owned version of [`Stream`].
+pub type OwnedStream = Vec<OwnedElement>;
+
+/// Owned version of [`Command`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedCommand {
+    pub name: String,
+    pub namespace: Option<String>,
+    pub content: OwnedStream,
+    pub arguments: Vec<OwnedStream>,
+    pub params: OwnedParams,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub param_groups: Vec<Vec<String>>,
+    pub block: bool,
+    pub starred: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub comments: Vec<String>,
+    pub source: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub content_source: Option<String>,
+}
+
+/// Owned version of [`Element`].
+// Commands are kept inline, like in `Element`, so matching on both reads the same
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedElement {
+    Command(OwnedCommand),
+    Raw(String),
+    Comment(String),
+    LineBreak(String),
+    Math { display: bool, source: String },
+}
+
+impl<'b> IntoOwned for ParamValue<'b> {
+    type Owned = OwnedParamValue;
+
+    fn into_owned(self) -> OwnedParamValue {
+        match self {
+            ParamValue::None => OwnedParamValue::None,
+            ParamValue::Text(t) => OwnedParamValue::Text(t.to_owned()),
+            ParamValue::Stream(s) => OwnedParamValue::Stream(s.into_owned()),
+        }
+    }
+}
+
+impl<'b> IntoOwned for Params<'b> {
+    type Owned = OwnedParams;
+
+    fn into_owned(self) -> OwnedParams {
+        self.into_iter()
+            .map(|(k, v)| (k.to_owned(), v.into_owned()))
+            .collect()
+    }
+}
+
+impl<'b> IntoOwned for Command<'b> {
+    type Owned = OwnedCommand;
+
+    fn into_owned(self) -> OwnedCommand {
+        OwnedCommand {
+            name: self.name.to_owned(),
+            namespace: self.namespace.map(str::to_owned),
+            content: self.content.into_owned(),
+            arguments: self.arguments.into_iter().map(|a| a.into_owned()).collect(),
+            params: self.params.into_owned(),
+            param_groups: self
+                .param_groups
+                .into_iter()
+                .map(|group| group.into_iter().map(str::to_owned).collect())
+                .collect(),
+            block: self.block,
+            starred: self.starred,
+            comments: self.comments.into_iter().map(str::to_owned).collect(),
+            source: self.source.to_owned(),
+            content_source: self.content_source.map(str::to_owned),
+        }
+    }
+}
+
+impl<'b> IntoOwned for Element<'b> {
+    type Owned = OwnedElement;
+
+    fn into_owned(self) -> OwnedElement {
+        match self {
+            Element::Command(c) => OwnedElement::Command(c.into_owned()),
+            Element::Raw(t) => OwnedElement::Raw(t.to_owned()),
+            Element::Comment(t) => OwnedElement::Comment(t.to_owned()),
+            Element::LineBreak(t) => OwnedElement::LineBreak(t.to_owned()),
+            Element::Math { display, source } => OwnedElement::Math {
+                display,
+                source: source.to_owned(),
+            },
+        }
+    }
+}
+
+impl<'b> IntoOwned for Stream<'b> {
+    type Owned = OwnedStream;
+
+    fn into_owned(self) -> OwnedStream {
+        self.into_iter().map(Element::into_owned).collect()
+    }
+}
+
+impl OwnedParamValue {
+    /// Gets a [`ParamValue`] borrowing from this one.
+    pub fn borrowed(&self) -> ParamValue<'_> {
+        match self {
+            OwnedParamValue::None => ParamValue::None,
+            OwnedParamValue::Text(t) => ParamValue::Text(t),
+            OwnedParamValue::Stream(s) => ParamValue::Stream(borrowed(s)),
+        }
+    }
+}
+
+impl OwnedCommand {
+    /// Gets a [`Command`] borrowing from this one.
+    pub fn borrowed(&self) -> Command<'_> {
+        Command {
+            name: &self.name,
+            namespace: self.namespace.as_deref(),
+            content: borrowed(&self.content),
+            arguments: self.arguments.iter().map(|a| borrowed(a)).collect(),
+            params: self
+                .params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.borrowed()))
+                .collect(),
+            param_groups: self
+                .param_groups
+                .iter()
+                .map(|group| group.iter().map(String::as_str).collect())
+                .collect(),
+            block: self.block,
+            starred: self.starred,
+            comments: self.comments.iter().map(String::as_str).collect(),
+            source: &self.source,
+            content_source: self.content_source.as_deref(),
+        }
+    }
+}
+
+impl OwnedElement {
+    /// Gets an [`Element`] borrowing from this one.
+    pub fn borrowed(&self) -> Element<'_> {
+        match self {
+            OwnedElement::Command(c) => Element::Command(c.borrowed()),
+            OwnedElement::Raw(t) => Element::Raw(t),
+            OwnedElement::Comment(t) => Element::Comment(t),
+            OwnedElement::LineBreak(t) => Element::LineBreak(t),
+            OwnedElement::Math { display, source } => Element::Math {
+                display: *display,
+                source,
+            },
+        }
+    }
+}
+
+/// Gets a [`Stream`] borrowing from an owned one.
+///
+/// As the source of each command is copied separately, [`Location::of`](super::Location::of)
+/// can't locate the borrowed elements anymore.
+pub fn borrowed(stream: &[OwnedElement]) -> Stream<'_> {
+    stream.iter().map(OwnedElement::borrowed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    const SOURCE: &str = "a \\foo*[x, y = z][w = {\\b{c}}]{d $e$}{f}\\\n% g\n\\begin{h}i\\end{h}";
+
+    #[test]
+    fn test_round_trip() {
+        let owned = {
+            let source = SOURCE.to_owned();
+            parse(&source).unwrap().into_owned()
+        };
+
+        // Nothing is lost going through the owned tree, which doesn't depend on the source
+        assert_eq!(borrowed(&owned).into_owned(), owned);
+        assert_eq!(parse(SOURCE).unwrap().into_owned(), owned);
+
+        let stream = borrowed(&owned);
+        assert_eq!(stream.len(), 6);
+        assert!(matches!(stream[0], Element::Raw("a ")));
+        match &stream[1] {
+            Element::Command(c) => {
+                assert_eq!((c.name, c.namespace), ("foo", None));
+                assert!(c.starred && !c.block);
+                assert_eq!(c.param_groups, [vec!["x", "y"], vec!["w"]]);
+                assert!(matches!(c.params["x"], ParamValue::None));
+                assert!(matches!(c.params["y"], ParamValue::Text("z")));
+                match &c.params["w"] {
+                    ParamValue::Stream(s) => {
+                        assert!(matches!(&s[..], [Element::Command(b)] if b.source == "\\b{c}"))
+                    }
+                    other => panic!("Expected a stream, got {:?}", other),
+                }
+                assert!(matches!(
+                    &c.content[..],
+                    [Element::Raw("d "), Element::Math { source: "e", .. }]
+                ));
+                assert!(matches!(&c.arguments[..], [f] if matches!(&f[..], [Element::Raw("f")])));
+                assert_eq!(c.source, "\\foo*[x, y = z][w = {\\b{c}}]{d $e$}{f}");
+                assert_eq!(c.content_source, Some("d $e$"));
+            }
+            other => panic!("Expected a command, got {:?}", other),
+        }
+        assert!(matches!(stream[2], Element::LineBreak("\\\n")));
+        assert!(matches!(stream[3], Element::Comment(" g")));
+        assert!(matches!(stream[4], Element::Raw("\n")));
+        match &stream[5] {
+            Element::Command(c) => {
+                assert!(c.block);
+                assert!(matches!(&c.content[..], [Element::Raw("i")]));
+            }
+            other => panic!("Expected a command, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Typed access to command parameters.
+//!
+//! Commands read their parameters through [`ParamsExt`], which checks the form of the value and
+//! converts it, so wrong parameter types are reported the same way for every command.
+
+use super::{ParamValue, Params, Stream};
+use std::fmt;
+
+/// Error returned by the [`ParamsExt`] accessors when a parameter has an unexpected value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamError {
+    /// The parameter is given as `[name]`, without the value it needs
+    MissingValue {
+        name: String,
+        expected: &'static str,
+    },
+    /// The parameter is given as `[name = { ... }]` where a textual value is expected, or the
+    /// opposite
+    WrongForm {
+        name: String,
+        expected: &'static str,
+    },
+    /// The textual value can't be read as the expected type
+    Invalid {
+        name: String,
+        expected: &'static str,
+        value: String,
+    },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamError::MissingValue { name, expected } => {
+                write!(
+                    f,
+                    "parameter {} expects {}, but has no value",
+                    name, expected
+                )
+            }
+            ParamError::WrongForm { name, expected } => {
+                write!(f, "parameter {} expects {}", name, expected)
+            }
+            ParamError::Invalid {
+                name,
+                expected,
+                value,
+            } => write!(
+                f,
+                "parameter {} expects {}, got {:?}",
+                name, expected, value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+type ParamResult<T> = Result<Option<T>, ParamError>;
+
+/// Unit of a [`Dimension`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Px,
+    Percent,
+    Em,
+    Rem,
+}
+
+impl Unit {
+    /// Units by their suffix, longest first so `rem` is not read as `em`.
+    const SUFFIXES: [(&'static str, Unit); 4] = [
+        ("rem", Unit::Rem),
+        ("px", Unit::Px),
+        ("em", Unit::Em),
+        ("%", Unit::Percent),
+    ];
+
+    /// The suffix written after values in this unit, as in `12px`.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Unit::Px => "px",
+            Unit::Percent => "%",
+            Unit::Em => "em",
+            Unit::Rem => "rem",
+        }
+    }
+}
+
+/// A length, as in `[width = 50%]`. Values without a unit are in pixels, like in HTML attributes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dimension {
+    pub value: f64,
+    pub unit: Unit,
+}
+
+impl Dimension {
+    /// Reads a dimension, as `300`, `12px`, `1.5em` or `50%`.
+    pub fn parse(text: &str) -> Option<Dimension> {
+        let text = text.trim();
+        let (number, unit) = Unit::SUFFIXES
+            .iter()
+            .find_map(|&(suffix, unit)| Some((text.strip_suffix(suffix)?, unit)))
+            .unwrap_or((text, Unit::Px));
+
+        Some(Dimension {
+            value: parse_float(number.trim_end())?,
+            unit,
+        })
+    }
+}
+
+/// Written as CSS reads it, as `12px`.
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.value, self.unit.suffix())
+    }
+}
+
+/// Reads a finite number, as `12` or `-0.5`.
+fn parse_float(text: &str) -> Option<f64> {
+    text.parse().ok().filter(|value: &f64| value.is_finite())
+}
+
+/// Typed accessors for [`Params`].
+///
+/// All accessors return `Ok(None)` when the parameter is not given at all, so commands can fall
+/// back to a default value. Whitespace around textual values is ignored.
+pub trait ParamsExt<'b> {
+    /// Gets a textual value, as in `[name = value]`.
+    fn get_str(&self, name: &str) -> ParamResult<&'b str>;
+
+    /// Gets a flag. `[name]` is true, and `[name = value]` accepts `true`/`false` and `yes`/`no`.
+    fn get_bool(&self, name: &str) -> ParamResult<bool>;
+
+    /// Gets an integer value, as in `[name = 42]`.
+    fn get_int(&self, name: &str) -> ParamResult<i64>;
+
+    /// Gets a number, as in `[name = 1.5]`.
+    fn get_float(&self, name: &str) -> ParamResult<f64>;
+
+    /// Gets a length, as in `[name = 50%]`, see [`Dimension`].
+    fn get_dimension(&self, name: &str) -> ParamResult<Dimension>;
+
+    /// Gets an evaluation span value, as in `[name = { value }]`.
+    fn get_stream(&self, name: &str) -> ParamResult<&Stream<'b>>;
+}
+
+impl<'b> ParamsExt<'b> for Params<'b> {
+    fn get_str(&self, name: &str) -> ParamResult<&'b str> {
+        text(self, name, "a textual value")
+    }
+
+    fn get_bool(&self, name: &str) -> ParamResult<bool> {
+        match self.get(name) {
+            Some(ParamValue::None) => Ok(Some(true)),
+            _ => match text(self, name, "a boolean")? {
+                Some("true" | "yes") => Ok(Some(true)),
+                Some("false" | "no") => Ok(Some(false)),
+                Some(value) => Err(ParamError::Invalid {
+                    name: name.to_owned(),
+                    expected: "a boolean",
+                    value: value.to_owned(),
+                }),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn get_int(&self, name: &str) -> ParamResult<i64> {
+        parsed(self, name, "an integer", |value| value.parse().ok())
+    }
+
+    fn get_float(&self, name: &str) -> ParamResult<f64> {
+        parsed(self, name, "a number", parse_float)
+    }
+
+    fn get_dimension(&self, name: &str) -> ParamResult<Dimension> {
+        parsed(
+            self,
+            name,
+            "a dimension, like 12px or 50%",
+            Dimension::parse,
+        )
+    }
+
+    fn get_stream(&self, name: &str) -> ParamResult<&Stream<'b>> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(ParamValue::Stream(s)) => Ok(Some(s)),
+            Some(ParamValue::None) => Err(ParamError::MissingValue {
+                name: name.to_owned(),
+                expected: "a {...} value",
+            }),
+            Some(ParamValue::Text(_)) => Err(ParamError::WrongForm {
+                name: name.to_owned(),
+                expected: "a {...} value",
+            }),
+        }
+    }
+}
+
+fn text<'b>(params: &Params<'b>, name: &str, expected: &'static str) -> ParamResult<&'b str> {
+    match params.get(name) {
+        None => Ok(None),
+        Some(ParamValue::Text(t)) => Ok(Some(t.trim())),
+        Some(ParamValue::None) => Err(ParamError::MissingValue {
+            name: name.to_owned(),
+            expected,
+        }),
+        Some(ParamValue::Stream(_)) => Err(ParamError::WrongForm {
+            name: name.to_owned(),
+            expected,
+        }),
+    }
+}
+
+/// Reads a textual value with `parse`, which gives [`None`] for invalid values.
+fn parsed<T>(
+    params: &Params,
+    name: &str,
+    expected: &'static str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> ParamResult<T> {
+    text(params, name, expected)?
+        .map(|value| {
+            parse(value).ok_or_else(|| ParamError::Invalid {
+                name: name.to_owned(),
+                expected,
+                value: value.to_owned(),
+            })
+        })
+        .transpose()
+}
@@ -0,0 +1,243 @@
+//! Parsing from a reader, without loading the whole document first.
+//!
+//! The input is read line by line, and parsed every time a paragraph break is found at the top
+//! level, outside of any command or block. As the parsed text is dropped afterwards, elements are
+//! given as [`OwnedElement`]s.
+
+use super::{
+    owned::{IntoOwned, OwnedElement},
+    parse_with, Location, ParseError, ParserOptions,
+};
+use std::{collections::VecDeque, fmt, io::BufRead};
+
+/// Error returned while parsing from a reader.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The reader failed, or gave invalid UTF-8
+    Io(std::io::Error),
+    /// The document can't be parsed
+    Parse {
+        /// The error message, see [`ParseError`]
+        message: String,
+        /// Where the error is in the whole input
+        location: Location,
+    },
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "can't read the document: {}", e),
+            ReadError::Parse { message, location } => write!(f, "{} at {}", message, location),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+/// Iterator over the top-level elements of a document read from `R`, see [`parse_reader`].
+pub struct Elements<R> {
+    reader: R,
+    options: ParserOptions,
+    /// Text read but not parsed yet
+    buffer: String,
+    /// Byte offset and line number of the start of `buffer` in the whole input
+    start: (usize, usize),
+    parsed: VecDeque<OwnedElement>,
+    done: bool,
+}
+
+impl<R: BufRead> Elements<R> {
+    fn error(&self, error: ParseError) -> ReadError {
+        let local = Location::at(&self.buffer, error.offset(&self.buffer));
+
+        ReadError::Parse {
+            message: error.to_string(),
+            location: Location {
+                offset: self.start.0 + local.offset,
+                line: self.start.1 + local.line - 1,
+                column: local.column,
+            },
+        }
+    }
+
+    /// Parses the buffer. Unless `last`, a failure means that the buffer ends inside a command or
+    /// block, and more has to be read.
+    fn parse_buffer(&mut self, last: bool) -> Result<bool, ReadError> {
+        match parse_with(&self.buffer, &self.options) {
+            Ok(stream) => self.parsed.extend(stream.into_owned()),
+            Err(_) if !last => return Ok(false),
+            Err(e) => return Err(self.error(e)),
+        }
+
+        self.start.0 += self.buffer.len();
+        self.start.1 += self.buffer.matches('\n').count();
+        self.buffer.clear();
+        Ok(true)
+    }
+
+    /// Reads until some elements are parsed, or the end of the input.
+    fn fill(&mut self) -> Result<(), ReadError> {
+        while self.parsed.is_empty() && !self.done {
+            let read = self
+                .reader
+                .read_line(&mut self.buffer)
+                .map_err(ReadError::Io)?;
+
+            if read == 0 {
+                self.done = true;
+                self.parse_buffer(true)?;
+            } else if self.buffer.ends_with("\n\n") || self.buffer.ends_with("\n\r\n") {
+                self.parse_buffer(false)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for Elements<R> {
+    type Item = Result<OwnedElement, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            self.done = true;
+            self.buffer.clear();
+            return Some(Err(e));
+        }
+
+        self.parsed.pop_front().map(Ok)
+    }
+}
+
+/// Parses a document from a reader, giving its top-level elements as they are read. The elements
+/// are the same as the ones [`parse`](super::parse) gives, except that text may be split at
+/// paragraph breaks.
+pub fn parse_reader<R: BufRead>(reader: R) -> Elements<R> {
+    parse_reader_with(reader, &ParserOptions::default())
+}
+
+/// Parses a document from a reader, like [`parse_reader`], with the given options.
+pub fn parse_reader_with<R: BufRead>(reader: R, options: &ParserOptions) -> Elements<R> {
+    Elements {
+        reader,
+        options: options.clone(),
+        buffer: String::new(),
+        start: (0, 1),
+        parsed: VecDeque::new(),
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, BufReader, Read};
+
+    /// Reads its chunks one by one, then fails with `error` if given.
+    struct Chunks<'c> {
+        chunks: VecDeque<&'c [u8]>,
+        error: Option<io::ErrorKind>,
+    }
+
+    impl Read for Chunks<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.chunks.pop_front() {
+                Some(chunk) => {
+                    let len = chunk.len().min(buf.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    if len < chunk.len() {
+                        self.chunks.push_front(&chunk[len..]);
+                    }
+                    Ok(len)
+                }
+                None => match self.error.take() {
+                    Some(kind) => Err(io::Error::new(kind, "broken")),
+                    None => Ok(0),
+                },
+            }
+        }
+    }
+
+    fn read_all(source: &str) -> Vec<OwnedElement> {
+        // A tiny buffer makes each line take several reads
+        let reader = BufReader::with_capacity(3, source.as_bytes());
+        parse_reader(reader).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_refills() {
+        let source = "Some text\n\n\\strong{split\n\nacross}\n\nend";
+        let elements = read_all(source);
+
+        assert_eq!(elements.len(), 4);
+        assert_eq!(elements[0], OwnedElement::Raw("Some text\n\n".to_owned()));
+        match &elements[1] {
+            OwnedElement::Command(c) => {
+                assert_eq!(c.name, "strong");
+                assert_eq!(c.source, "\\strong{split\n\nacross}");
+            }
+            other => panic!("Expected a command, got {:?}", other),
+        }
+        assert_eq!(elements[2], OwnedElement::Raw("\n\n".to_owned()));
+        assert_eq!(elements[3], OwnedElement::Raw("end".to_owned()));
+
+        // Paragraphs are only split where the parser would see a paragraph break
+        let elements = read_all("a\n \nb\n\n\nc");
+        assert_eq!(
+            elements,
+            ["a\n \nb\n\n", "\nc"].map(|t| OwnedElement::Raw(t.to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_line_breaks() {
+        let elements = read_all("a\r\n\r\nb\n\r\nc\\\r\n\r\nd");
+        assert_eq!(
+            elements,
+            [
+                OwnedElement::Raw("a\r\n\r\n".to_owned()),
+                OwnedElement::Raw("b\n\r\n".to_owned()),
+                OwnedElement::Raw("c".to_owned()),
+                OwnedElement::LineBreak("\\\r\n".to_owned()),
+                OwnedElement::Raw("\r\n".to_owned()),
+                OwnedElement::Raw("d".to_owned()),
+            ]
+        );
+
+        // Locations count the lines of the paragraphs already parsed
+        let mut elements = parse_reader("a\r\n\r\nb\n\r\n\\foo{".as_bytes());
+        elements.next();
+        elements.next();
+        match elements.next() {
+            Some(Err(ReadError::Parse { location, .. })) => {
+                assert_eq!((location.line, location.column), (5, 6));
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_io_error() {
+        let reader = Chunks {
+            chunks: VecDeque::from([&b"a\n\nb "[..], b"\\strong{c"]),
+            error: Some(io::ErrorKind::ConnectionReset),
+        };
+        let mut elements = parse_reader(BufReader::new(reader));
+
+        assert_eq!(
+            elements.next().unwrap().unwrap(),
+            OwnedElement::Raw("a\n\n".to_owned())
+        );
+        match elements.next() {
+            Some(Err(ReadError::Io(e))) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            other => panic!("Expected an I/O error, got {:?}", other),
+        }
+        assert!(elements.next().is_none());
+
+        // Invalid UTF-8 is an I/O error too
+        let mut elements = parse_reader(&b"a\n\n\xff"[..]);
+        assert!(elements.next().unwrap().is_ok());
+        assert!(matches!(elements.next(), Some(Err(ReadError::Io(_)))));
+    }
+}
@@ -0,0 +1,123 @@
+//! Recursive traversal of parsed streams.
+//!
+//! Implement [`Visitor`] and override the methods for the elements you are interested in, then
+//! call [`walk_stream`]. Methods going into nested streams (commands and parameters) call the
+//! matching `walk_*` function by default; when overriding them, call it too to keep going down.
+
+use super::{Command, Element, ParamValue};
+
+/// Callbacks for each kind of element found while walking a stream. Everything does nothing by
+/// default, apart from going into nested streams.
+pub trait Visitor<'b> {
+    fn visit_element(&mut self, element: &Element<'b>) {
+        walk_element(self, element);
+    }
+
+    fn visit_command(&mut self, command: &Command<'b>) {
+        walk_command(self, command);
+    }
+
+    /// Called for each parameter of a command, before its content.
+    fn visit_param(&mut self, name: &'b str, value: &ParamValue<'b>) {
+        let _ = name;
+        walk_param(self, value);
+    }
+
+    fn visit_raw(&mut self, _text: &'b str) {}
+
+    fn visit_comment(&mut self, _text: &'b str) {}
+
+    fn visit_line_break(&mut self) {}
+
+    fn visit_math(&mut self, _display: bool, _source: &'b str) {}
+}
+
+/// Visits every element of `stream`, in order.
+pub fn walk_stream<'b, V: Visitor<'b> + ?Sized>(visitor: &mut V, stream: &[Element<'b>]) {
+    for element in stream {
+        visitor.visit_element(element);
+    }
+}
+
+/// Dispatches `element` to the [`Visitor`] method for its kind.
+pub fn walk_element<'b, V: Visitor<'b> + ?Sized>(visitor: &mut V, element: &Element<'b>) {
+    match element {
+        Element::Command(command) => visitor.visit_command(command),
+        Element::Raw(text) => visitor.visit_raw(text),
+        Element::Comment(text) => visitor.visit_comment(text),
+        Element::LineBreak(_) => visitor.visit_line_break(),
+        Element::Math { display, source } => visitor.visit_math(*display, source),
+    }
+}
+
+/// Visits the comments attached to `command`, then its parameters, ordered by name, its content
+/// and its extra arguments.
+pub fn walk_command<'b, V: Visitor<'b> + ?Sized>(visitor: &mut V, command: &Command<'b>) {
+    for comment in &command.comments {
+        visitor.visit_comment(comment);
+    }
+
+    let mut params: Vec<_> = command.params.iter().collect();
+    params.sort_unstable_by_key(|&(name, _)| *name);
+
+    for (name, value) in params {
+        visitor.visit_param(name, value);
+    }
+
+    walk_stream(visitor, &command.content);
+    for argument in &command.arguments {
+        walk_stream(visitor, argument);
+    }
+}
+
+/// Visits the stream of a `[name = {...}]` parameter. Other parameters hold no elements.
+pub fn walk_param<'b, V: Visitor<'b> + ?Sized>(visitor: &mut V, value: &ParamValue<'b>) {
+    if let ParamValue::Stream(stream) = value {
+        walk_stream(visitor, stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    /// Records each visit, to check the order of the walk.
+    #[derive(Default)]
+    struct Trace(Vec<String>);
+
+    impl<'b> Visitor<'b> for Trace {
+        fn visit_command(&mut self, command: &Command<'b>) {
+            self.0.push(format!("\\{}", command.name));
+            walk_command(self, command);
+        }
+
+        fn visit_param(&mut self, name: &'b str, value: &ParamValue<'b>) {
+            self.0.push(format!("{}=", name));
+            walk_param(self, value);
+        }
+
+        fn visit_raw(&mut self, text: &'b str) {
+            self.0.push(text.to_owned());
+        }
+
+        fn visit_line_break(&mut self) {
+            self.0.push("\\\\".to_owned());
+        }
+    }
+
+    #[test]
+    fn test_walk_order() {
+        let source = "\\a[y = {\\b{p}}, x = t]{c \\d{e}{f\\\n}}{g}{\\h{i}{j}} k";
+        let mut trace = Trace::default();
+        walk_stream(&mut trace, &parse(source).unwrap());
+
+        assert_eq!(
+            trace.0,
+            [
+                "\\a", "x=", "y=", "\\b", "p", "c ", "\\d", "e", "f", "\\\\", "g", "\\h", "i", "j",
+                " k"
+            ]
+        );
+    }
+}